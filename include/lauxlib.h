@@ -0,0 +1,99 @@
+/*
+** The subset of the auxiliary library this interpreter implements, declared like the reference lauxlib.h. Buffers
+** (luaL_Buffer) are not available.
+*/
+
+#ifndef lauxlib_h
+#define lauxlib_h
+
+#include "lua.h"
+
+#define LUALIB_API LUA_API
+
+/* Status of the file loading functions */
+#define LUA_ERRFILE (LUA_ERRERR + 1)
+
+typedef struct luaL_Reg {
+    const char *name;
+    lua_CFunction func;
+} luaL_Reg;
+
+LUALIB_API int (luaL_getmetafield)(lua_State *L, int obj, const char *e);
+LUALIB_API const char *(luaL_tolstring)(lua_State *L, int idx, size_t *len);
+LUALIB_API int (luaL_argerror)(lua_State *L, int arg, const char *extramsg);
+LUALIB_API int (luaL_typeerror)(lua_State *L, int arg, const char *tname);
+LUALIB_API const char *(luaL_checklstring)(lua_State *L, int arg, size_t *l);
+LUALIB_API const char *(luaL_optlstring)(lua_State *L, int arg, const char *def, size_t *l);
+LUALIB_API lua_Number (luaL_checknumber)(lua_State *L, int arg);
+LUALIB_API lua_Number (luaL_optnumber)(lua_State *L, int arg, lua_Number def);
+LUALIB_API lua_Integer (luaL_checkinteger)(lua_State *L, int arg);
+LUALIB_API lua_Integer (luaL_optinteger)(lua_State *L, int arg, lua_Integer def);
+
+LUALIB_API void (luaL_checkstack)(lua_State *L, int sz, const char *msg);
+LUALIB_API void (luaL_checktype)(lua_State *L, int arg, int t);
+LUALIB_API void (luaL_checkany)(lua_State *L, int arg);
+
+LUALIB_API int (luaL_newmetatable)(lua_State *L, const char *tname);
+LUALIB_API void (luaL_setmetatable)(lua_State *L, const char *tname);
+LUALIB_API void *(luaL_testudata)(lua_State *L, int ud, const char *tname);
+LUALIB_API void *(luaL_checkudata)(lua_State *L, int ud, const char *tname);
+
+LUALIB_API void (luaL_where)(lua_State *L, int lvl);
+
+/* Predefined references */
+#define LUA_NOREF (-2)
+#define LUA_REFNIL (-1)
+
+LUALIB_API int (luaL_ref)(lua_State *L, int t);
+LUALIB_API void (luaL_unref)(lua_State *L, int t, int ref);
+
+LUALIB_API int (luaL_loadfilex)(lua_State *L, const char *filename, const char *mode);
+#define luaL_loadfile(L, f) luaL_loadfilex(L, f, NULL)
+
+LUALIB_API int (luaL_loadbufferx)(lua_State *L, const char *buff, size_t sz, const char *name, const char *mode);
+LUALIB_API int (luaL_loadstring)(lua_State *L, const char *s);
+
+LUALIB_API lua_State *(luaL_newstate)(void);
+
+LUALIB_API lua_Integer (luaL_len)(lua_State *L, int idx);
+
+LUALIB_API void (luaL_setfuncs)(lua_State *L, const luaL_Reg *l, int nup);
+
+LUALIB_API void (luaL_traceback)(lua_State *L, lua_State *L1, const char *msg, int level);
+
+/* Never returns, like lua_error, the int result allows `return luaL_error(L, ...)` */
+static inline int luaL_error(lua_State *L, const char *fmt, ...) {
+    va_list argp;
+    va_start(argp, fmt);
+    luaL_where(L, 1);
+    lua_pushvfstring(L, fmt, argp);
+    va_end(argp);
+    lua_concat(L, 2);
+    return lua_error(L);
+}
+
+/* Useful macros */
+#define luaL_newlibtable(L, l) lua_createtable(L, 0, sizeof(l) / sizeof((l)[0]) - 1)
+
+#define luaL_newlib(L, l) (luaL_newlibtable(L, l), luaL_setfuncs(L, l, 0))
+
+#define luaL_argcheck(L, cond, arg, extramsg) ((void)((cond) || luaL_argerror(L, (arg), (extramsg))))
+
+#define luaL_argexpected(L, cond, arg, tname) ((void)((cond) || luaL_typeerror(L, (arg), (tname))))
+
+#define luaL_checkstring(L, n) (luaL_checklstring(L, (n), NULL))
+#define luaL_optstring(L, n, d) (luaL_optlstring(L, (n), (d), NULL))
+
+#define luaL_typename(L, i) lua_typename(L, lua_type(L, (i)))
+
+#define luaL_dofile(L, fn) (luaL_loadfile(L, fn) || lua_pcall(L, 0, LUA_MULTRET, 0))
+
+#define luaL_dostring(L, s) (luaL_loadstring(L, s) || lua_pcall(L, 0, LUA_MULTRET, 0))
+
+#define luaL_getmetatable(L, n) (lua_getfield(L, LUA_REGISTRYINDEX, (n)))
+
+#define luaL_opt(L, f, n, d) (lua_isnoneornil(L, (n)) ? (d) : f(L, (n)))
+
+#define luaL_loadbuffer(L, s, sz, n) luaL_loadbufferx(L, s, sz, n, NULL)
+
+#endif
@@ -0,0 +1,228 @@
+/*
+** The subset of the Lua 5.4 C API this interpreter implements, declared like the reference lua.h so that C programs
+** and modules written against it compile unchanged. Link against the shared library the crate builds (libr_lua.so).
+**
+** Differences with the reference implementation:
+** - Errors unwind through C code like Rust panics rather than with longjmp, C code calling functions which can raise
+**   errors has to be built with unwind tables (-fexceptions, the default on x86-64 Linux).
+** - Each call of a C function gets a lua_State of its own, valid until the function returns.
+** - lua_newstate ignores the allocator, memory is managed by the interpreter.
+** - Continuations are ignored, C functions can call Lua code which yields without them.
+** - lua_pushfstring takes the formats of C's printf.
+** - __gc metamethods only run for tables.
+*/
+
+#ifndef lua_h
+#define lua_h
+
+#include <stdarg.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+#define LUA_VERSION_MAJOR "5"
+#define LUA_VERSION_MINOR "4"
+#define LUA_VERSION_NUM 504
+#define LUA_VERSION "Lua " LUA_VERSION_MAJOR "." LUA_VERSION_MINOR
+
+#define LUA_API extern
+#define LUA_MULTRET (-1)
+
+#define LUAI_MAXSTACK 1000000
+#define LUA_REGISTRYINDEX (-LUAI_MAXSTACK - 1000)
+#define lua_upvalueindex(i) (LUA_REGISTRYINDEX - (i))
+
+/* Thread status */
+#define LUA_OK 0
+#define LUA_YIELD 1
+#define LUA_ERRRUN 2
+#define LUA_ERRSYNTAX 3
+#define LUA_ERRMEM 4
+#define LUA_ERRERR 5
+
+typedef struct lua_State lua_State;
+
+/* Basic types */
+#define LUA_TNONE (-1)
+#define LUA_TNIL 0
+#define LUA_TBOOLEAN 1
+#define LUA_TLIGHTUSERDATA 2
+#define LUA_TNUMBER 3
+#define LUA_TSTRING 4
+#define LUA_TTABLE 5
+#define LUA_TFUNCTION 6
+#define LUA_TUSERDATA 7
+#define LUA_TTHREAD 8
+
+#define LUA_MINSTACK 20
+
+/* Predefined values in the registry */
+#define LUA_RIDX_MAINTHREAD 1
+#define LUA_RIDX_GLOBALS 2
+
+typedef double lua_Number;
+typedef long long lua_Integer;
+typedef unsigned long long lua_Unsigned;
+typedef intptr_t lua_KContext;
+
+typedef int (*lua_CFunction)(lua_State *L);
+typedef int (*lua_KFunction)(lua_State *L, int status, lua_KContext ctx);
+typedef const char *(*lua_Reader)(lua_State *L, void *ud, size_t *sz);
+typedef void *(*lua_Alloc)(void *ud, void *ptr, size_t osize, size_t nsize);
+
+/* State manipulation */
+LUA_API lua_State *(lua_newstate)(lua_Alloc f, void *ud);
+LUA_API void (lua_close)(lua_State *L);
+LUA_API lua_CFunction (lua_atpanic)(lua_State *L, lua_CFunction panicf);
+LUA_API lua_Number (lua_version)(lua_State *L);
+
+/* Basic stack manipulation */
+LUA_API int (lua_absindex)(lua_State *L, int idx);
+LUA_API int (lua_gettop)(lua_State *L);
+LUA_API void (lua_settop)(lua_State *L, int idx);
+LUA_API void (lua_pushvalue)(lua_State *L, int idx);
+LUA_API void (lua_rotate)(lua_State *L, int idx, int n);
+LUA_API void (lua_copy)(lua_State *L, int fromidx, int toidx);
+LUA_API int (lua_checkstack)(lua_State *L, int n);
+
+/* Access functions (stack -> C) */
+LUA_API int (lua_isnumber)(lua_State *L, int idx);
+LUA_API int (lua_isstring)(lua_State *L, int idx);
+LUA_API int (lua_iscfunction)(lua_State *L, int idx);
+LUA_API int (lua_isinteger)(lua_State *L, int idx);
+LUA_API int (lua_isuserdata)(lua_State *L, int idx);
+LUA_API int (lua_type)(lua_State *L, int idx);
+LUA_API const char *(lua_typename)(lua_State *L, int tp);
+
+LUA_API lua_Number (lua_tonumberx)(lua_State *L, int idx, int *isnum);
+LUA_API lua_Integer (lua_tointegerx)(lua_State *L, int idx, int *isnum);
+LUA_API int (lua_toboolean)(lua_State *L, int idx);
+LUA_API const char *(lua_tolstring)(lua_State *L, int idx, size_t *len);
+LUA_API lua_Unsigned (lua_rawlen)(lua_State *L, int idx);
+LUA_API void *(lua_touserdata)(lua_State *L, int idx);
+LUA_API const void *(lua_topointer)(lua_State *L, int idx);
+
+/* Comparison */
+#define LUA_OPEQ 0
+#define LUA_OPLT 1
+#define LUA_OPLE 2
+
+LUA_API int (lua_rawequal)(lua_State *L, int idx1, int idx2);
+LUA_API int (lua_compare)(lua_State *L, int idx1, int idx2, int op);
+
+/* Push functions (C -> stack) */
+LUA_API void (lua_pushnil)(lua_State *L);
+LUA_API void (lua_pushnumber)(lua_State *L, lua_Number n);
+LUA_API void (lua_pushinteger)(lua_State *L, lua_Integer n);
+LUA_API const char *(lua_pushlstring)(lua_State *L, const char *s, size_t len);
+LUA_API const char *(lua_pushstring)(lua_State *L, const char *s);
+LUA_API void (lua_pushcclosure)(lua_State *L, lua_CFunction fn, int n);
+LUA_API void (lua_pushboolean)(lua_State *L, int b);
+LUA_API void (lua_pushlightuserdata)(lua_State *L, void *p);
+
+/* Get functions (Lua -> stack) */
+LUA_API int (lua_getglobal)(lua_State *L, const char *name);
+LUA_API int (lua_gettable)(lua_State *L, int idx);
+LUA_API int (lua_getfield)(lua_State *L, int idx, const char *k);
+LUA_API int (lua_geti)(lua_State *L, int idx, lua_Integer n);
+LUA_API int (lua_rawget)(lua_State *L, int idx);
+LUA_API int (lua_rawgeti)(lua_State *L, int idx, lua_Integer n);
+LUA_API int (lua_rawgetp)(lua_State *L, int idx, const void *p);
+
+LUA_API void (lua_createtable)(lua_State *L, int narr, int nrec);
+LUA_API void *(lua_newuserdatauv)(lua_State *L, size_t sz, int nuvalue);
+LUA_API int (lua_getmetatable)(lua_State *L, int objindex);
+LUA_API int (lua_getiuservalue)(lua_State *L, int idx, int n);
+
+/* Set functions (stack -> Lua) */
+LUA_API void (lua_setglobal)(lua_State *L, const char *name);
+LUA_API void (lua_settable)(lua_State *L, int idx);
+LUA_API void (lua_setfield)(lua_State *L, int idx, const char *k);
+LUA_API void (lua_seti)(lua_State *L, int idx, lua_Integer n);
+LUA_API void (lua_rawset)(lua_State *L, int idx);
+LUA_API void (lua_rawseti)(lua_State *L, int idx, lua_Integer n);
+LUA_API void (lua_rawsetp)(lua_State *L, int idx, const void *p);
+LUA_API int (lua_setmetatable)(lua_State *L, int objindex);
+LUA_API int (lua_setiuservalue)(lua_State *L, int idx, int n);
+
+/* Load and call functions */
+LUA_API void (lua_callk)(lua_State *L, int nargs, int nresults, lua_KContext ctx, lua_KFunction k);
+#define lua_call(L, n, r) lua_callk(L, (n), (r), 0, NULL)
+
+LUA_API int (lua_pcallk)(lua_State *L, int nargs, int nresults, int errfunc, lua_KContext ctx, lua_KFunction k);
+#define lua_pcall(L, n, r, f) lua_pcallk(L, (n), (r), (f), 0, NULL)
+
+LUA_API int (lua_load)(lua_State *L, lua_Reader reader, void *dt, const char *chunkname, const char *mode);
+
+/* Miscellaneous functions */
+LUA_API int (lua_error)(lua_State *L);
+LUA_API int (lua_next)(lua_State *L, int idx);
+LUA_API void (lua_concat)(lua_State *L, int n);
+LUA_API void (lua_len)(lua_State *L, int idx);
+LUA_API size_t (lua_stringtonumber)(lua_State *L, const char *s);
+
+/* The formatted pushes only exist in this header, a Rust function cannot take a va_list */
+static inline const char *lua_pushvfstring(lua_State *L, const char *fmt, va_list argp) {
+    char small[256];
+    va_list copy;
+    va_copy(copy, argp);
+    int size = vsnprintf(small, sizeof(small), fmt, copy);
+    va_end(copy);
+    if (size < 0)
+        return lua_pushstring(L, fmt);
+    if ((size_t)size < sizeof(small))
+        return lua_pushlstring(L, small, (size_t)size);
+    char *buffer = (char *)malloc((size_t)size + 1);
+    if (buffer == NULL)
+        return lua_pushstring(L, fmt);
+    vsnprintf(buffer, (size_t)size + 1, fmt, argp);
+    const char *s = lua_pushlstring(L, buffer, (size_t)size);
+    free(buffer);
+    return s;
+}
+
+static inline const char *lua_pushfstring(lua_State *L, const char *fmt, ...) {
+    va_list argp;
+    va_start(argp, fmt);
+    const char *s = lua_pushvfstring(L, fmt, argp);
+    va_end(argp);
+    return s;
+}
+
+/* Useful macros */
+#define lua_tonumber(L, i) lua_tonumberx(L, (i), NULL)
+#define lua_tointeger(L, i) lua_tointegerx(L, (i), NULL)
+
+#define lua_pop(L, n) lua_settop(L, -(n)-1)
+
+#define lua_newtable(L) lua_createtable(L, 0, 0)
+
+#define lua_register(L, n, f) (lua_pushcfunction(L, (f)), lua_setglobal(L, (n)))
+
+#define lua_pushcfunction(L, f) lua_pushcclosure(L, (f), 0)
+
+#define lua_isfunction(L, n) (lua_type(L, (n)) == LUA_TFUNCTION)
+#define lua_istable(L, n) (lua_type(L, (n)) == LUA_TTABLE)
+#define lua_islightuserdata(L, n) (lua_type(L, (n)) == LUA_TLIGHTUSERDATA)
+#define lua_isnil(L, n) (lua_type(L, (n)) == LUA_TNIL)
+#define lua_isboolean(L, n) (lua_type(L, (n)) == LUA_TBOOLEAN)
+#define lua_isthread(L, n) (lua_type(L, (n)) == LUA_TTHREAD)
+#define lua_isnone(L, n) (lua_type(L, (n)) == LUA_TNONE)
+#define lua_isnoneornil(L, n) (lua_type(L, (n)) <= 0)
+
+#define lua_pushliteral(L, s) lua_pushstring(L, "" s)
+
+#define lua_pushglobaltable(L) ((void)lua_rawgeti(L, LUA_REGISTRYINDEX, LUA_RIDX_GLOBALS))
+
+#define lua_tostring(L, i) lua_tolstring(L, (i), NULL)
+
+#define lua_insert(L, idx) lua_rotate(L, (idx), 1)
+#define lua_remove(L, idx) (lua_rotate(L, (idx), -1), lua_pop(L, 1))
+#define lua_replace(L, idx) (lua_copy(L, -1, (idx)), lua_pop(L, 1))
+
+#define lua_newuserdata(L, s) lua_newuserdatauv(L, s, 1)
+#define lua_getuservalue(L, idx) lua_getiuservalue(L, idx, 1)
+#define lua_setuservalue(L, idx) lua_setiuservalue(L, idx, 1)
+
+#endif
@@ -0,0 +1,13 @@
+/*
+** The standard libraries, which every state of this interpreter has from the start
+*/
+
+#ifndef lualib_h
+#define lualib_h
+
+#include "lua.h"
+
+/* Does nothing, the libraries are always open */
+LUA_API void (luaL_openlibs)(lua_State *L);
+
+#endif
@@ -1,6 +1,21 @@
 #![allow(dead_code)]
 // TODO: Remove this once everything is being used
 
+use std::rc::Rc;
+
+// Where a node begins in the source, lines and columns are 1-based like pest reports them
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl From<(usize, usize)> for Span {
+    fn from((line, column): (usize, usize)) -> Self {
+        Self { line, column }
+    }
+}
+
 #[derive(Debug)]
 pub struct LuaProgram {
     pub block: Block,
@@ -8,7 +23,7 @@ pub struct LuaProgram {
 
 #[derive(Debug, Clone)]
 pub struct Block {
-    pub statements: Vec<Statement>,
+    pub statements: Vec<(Statement, Span)>,
     pub return_statement: Option<ReturnStatement>,
 }
 
@@ -26,14 +41,15 @@ pub enum Statement {
     IfBlock((Expression, Block), Vec<(Expression, Block)>, Option<Block>),
     ForEach(String, Expression, Expression, Option<Expression>, Block),
     ForList(NameList, ExpressionList, Block),
-    Function(FunctionName, FunctionBody),
-    LocalFunction(String, FunctionBody),
-    // TODO: local AttributeNameList = ExpressionList
+    Function(FunctionName, Rc<FunctionBody>),
+    LocalFunction(String, Rc<FunctionBody>),
+    LocalAttributeNameList(AttributeNameList, Option<ExpressionList>),
 }
 
 #[derive(Debug, Clone)]
 pub struct ReturnStatement {
     pub expression_list: Option<ExpressionList>,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone)]
@@ -46,10 +62,35 @@ pub struct NameList {
     pub names: Vec<String>,
 }
 
+#[derive(Debug, Clone)]
+pub struct AttributeNameList {
+    pub names: Vec<(String, Option<Attribute>)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Attribute {
+    Const, // local x <const> = 5
+    Close, // local x <close> = value_with_a_close_metamethod
+}
+
 #[derive(Debug, Clone)]
 pub enum Expression {
     Expr(Expr),
-    Binary(BinaryOperator, Expr, Expr),
+    Binary(BinaryOperator, Box<Expression>, Box<Expression>, Span), // The span is the operator's
+}
+
+// Chains like `1 + 2 + 3` nest to the left without a bound, their left operands are dropped one at a time instead of
+// recursing into them
+impl Drop for Expression {
+    fn drop(&mut self) {
+        let Expression::Binary(_, lhs, _, _) = self else {
+            return;
+        };
+        let mut next = std::mem::replace(&mut **lhs, Expression::Expr(Expr::Nil));
+        while let Expression::Binary(_, lhs, _, _) = &mut next {
+            next = std::mem::replace(&mut **lhs, Expression::Expr(Expr::Nil));
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -57,19 +98,19 @@ pub enum Expr {
     Nil,
     Boolean(bool),
     Numerical(NumberKind),
-    LiteralString(String),
+    LiteralString(Vec<u8>),
     Expansion(Expansion),
-    FunctionDef(FunctionBody),
+    FunctionDef(Rc<FunctionBody>),
     Prefix(Box<PrefixExpression>),
-    Unary(UnaryOperator, Box<Expression>)
-    // TODO: TableConstructor,
+    Unary(UnaryOperator, Box<Expression>, Span),
+    TableConstructor(TableConstructor),
 }
 
 #[derive(Debug, Clone)]
 pub enum PrefixExpression {
     Var(Var),
     FunctionCall(Box<FunctionCall>),
-    Expression(Expression)
+    Expression(Expression), // Parenthesized, this truncates multiple results to a single value
 }
 
 #[derive(Debug, Clone)]
@@ -80,7 +121,7 @@ pub enum BinaryOperator {
     BooleanOperator(BooleanOperator),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy)]
 pub enum MathOperator {
     Plus,
     Minus,
@@ -91,7 +132,7 @@ pub enum MathOperator {
     Mod,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy)]
 pub enum BitwiseOperator {
     And,
     Or,
@@ -100,7 +141,7 @@ pub enum BitwiseOperator {
     LeftShift,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy)]
 pub enum BooleanOperator {
     LessThan,
     LessThanEqualTo,
@@ -112,7 +153,7 @@ pub enum BooleanOperator {
     Or,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy)]
 pub enum UnaryOperator {
     UnaryMinus,
     Not,
@@ -125,17 +166,30 @@ pub struct VarList {
     pub vars: Vec<Var>,
 }
 
+// Where a name refers to, filled in by the resolver. Locals and upvalues are found `depth` scopes up from the scope the
+// name is used in, at `index` in the order that scope declared its locals.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Resolution {
+    Local { depth: usize, index: usize },
+    // A local of an enclosing function
+    Upvalue { depth: usize, index: usize },
+    #[default]
+    Global,
+}
+
+// The span of an access is the span of its suffix, `.bar` or `[bar]`
 #[derive(Debug, Clone)]
 pub enum Var {
-    NestedAccess(Vec<String>),
     #[allow(clippy::enum_variant_names)]
-    VarName(String),
-    TableAccess(String, Expression),
+    VarName(String, Resolution),
+    FieldAccess(Box<PrefixExpression>, String, Span), // foo.bar
+    TableAccess(Box<PrefixExpression>, Expression, Span), // foo[bar]
 }
 
 #[derive(Debug, Clone)]
 pub struct FunctionName {
     pub outer_name: String,
+    pub resolution: Resolution,
     pub accessors: Vec<String>,
     pub pass_self: Option<String>, // foo.bar.baz:thing
                                    // This results in { outer_name: "foo", accessor: vec!["bar", "baz"], pass_self: Some("thing") }
@@ -153,32 +207,61 @@ pub enum Parameters {
 pub enum FunctionCall {
     Static(StaticFunctionCall),
     SelfRef(SelfFunctionCall),
-    PassedExpression(Expression)
 }
 #[derive(Debug, Clone)]
 pub struct StaticFunctionCall {
-    pub prefix: Var,
-    pub args: Args
+    pub prefix: PrefixExpression,
+    pub args: Args,
+    pub span: Span,
 }
 #[derive(Debug, Clone)]
 pub struct SelfFunctionCall {
-    pub prefix: Var,
+    pub prefix: PrefixExpression,
     pub name: String,
-    pub args: Args
+    pub args: Args,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone)]
 pub struct FunctionBody {
     pub parameters: Option<Parameters>,
     pub block: Block,
+    pub span: Span, // Where the function is defined
+    pub end_line: usize, // The line of the closing `end`
+}
+
+impl FunctionBody {
+    pub fn parameter_names(&self) -> &[String] {
+        match &self.parameters {
+            Some(Parameters::Normal(name_list, _)) => &name_list.names,
+            _ => &[],
+        }
+    }
+    pub fn is_vararg(&self) -> bool {
+        matches!(
+            self.parameters,
+            Some(Parameters::Normal(_, Some(_))) | Some(Parameters::Expanded(_))
+        )
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum Args {
     ExpressionList(Option<ExpressionList>),
-    // TODO: Support for TableConstructor and LiteralString variants
-    // I think these are only useful if the recursion problem is fixed and PrefixExpression
-    // is added back to the grammar
+    TableConstructor(TableConstructor),
+    LiteralString(Vec<u8>),
+}
+
+#[derive(Debug, Clone)]
+pub struct TableConstructor {
+    pub fields: Vec<Field>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Field {
+    Bracketed(Expression, Expression), // [expr] = expr
+    Named(String, Expression),         // name = expr
+    Positional(Expression),            // expr
 }
 
 #[derive(Debug, Clone)]
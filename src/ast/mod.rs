@@ -1,49 +1,219 @@
-use pest::iterators::Pair;
+use std::cell::Cell;
+use std::rc::Rc;
+use std::sync::OnceLock;
+
+use pest::iterators::{Pair, Pairs};
+use pest::pratt_parser::{Assoc, Op, PrattParser};
 use pest::Parser;
 use pest_derive::Parser;
 use crate::err_handle::CompileError;
 
 pub mod lua_program;
+mod resolver;
+pub mod syntax_error;
+
+// Parsing stops after this many syntax errors, as later ones are more likely to be caused by the earlier ones
+const MAX_SYNTAX_ERRORS: usize = 10;
+
+// Nested blocks, expressions and suffixes are limited like the C levels of the reference implementation, so that the
+// recursion over the syntax tree in the resolver and the compiler can not overflow the native stack. Within an
+// expression, unary operators and the right operands of binary operators are levels, see `expression_levels`.
+const MAX_SYNTAX_LEVELS: usize = 200;
 
-// TODO: Check names for reserved keyword usage
-#[allow(dead_code)]
-const RESERVED_KEYWORDS: [&str; 22] = [
-    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if", "in",
-    "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
-];
+thread_local! {
+    static SYNTAX_LEVELS: Cell<usize> = const { Cell::new(0) };
+}
+
+// Holds syntax levels while the pairs nested in them are built, they are released when it is dropped
+struct SyntaxLevels(usize);
+
+impl SyntaxLevels {
+    fn enter(levels: usize, line_col: (usize, usize)) -> Result<Self, CompileError> {
+        let total = SYNTAX_LEVELS.get() + levels;
+        if total > MAX_SYNTAX_LEVELS {
+            let message = format!("chunk has too many syntax levels (limit is {})", MAX_SYNTAX_LEVELS);
+            return Err(CompileError::new(&message, line_col));
+        }
+        SYNTAX_LEVELS.set(total);
+        Ok(Self(levels))
+    }
+}
+
+impl Drop for SyntaxLevels {
+    fn drop(&mut self) {
+        SYNTAX_LEVELS.set(SYNTAX_LEVELS.get() - self.0);
+    }
+}
 
 #[derive(Parser)]
 #[grammar = "grammar.pest"]
 pub struct LuaTokenPairs;
 
-pub fn parse_lua_program(input: &str) -> Result<lua_program::LuaProgram, CompileError> {
+// Operator precedence, from lowest to highest, as defined in section 3.4.8 of the Lua 5.4 manual
+fn pratt_parser() -> &'static PrattParser<Rule> {
+    static PRATT_PARSER: OnceLock<PrattParser<Rule>> = OnceLock::new();
+    PRATT_PARSER.get_or_init(|| {
+        PrattParser::new()
+            .op(Op::infix(Rule::Or, Assoc::Left))
+            .op(Op::infix(Rule::And, Assoc::Left))
+            .op(Op::infix(Rule::LessThan, Assoc::Left)
+                | Op::infix(Rule::GreaterThan, Assoc::Left)
+                | Op::infix(Rule::LessThanEqualTo, Assoc::Left)
+                | Op::infix(Rule::GreaterThanEqualTo, Assoc::Left)
+                | Op::infix(Rule::Unequal, Assoc::Left)
+                | Op::infix(Rule::Equal, Assoc::Left))
+            .op(Op::infix(Rule::BitwiseOr, Assoc::Left))
+            .op(Op::infix(Rule::BitwiseExclusiveOr, Assoc::Left))
+            .op(Op::infix(Rule::BitwiseAnd, Assoc::Left))
+            .op(Op::infix(Rule::LeftShift, Assoc::Left) | Op::infix(Rule::RightShift, Assoc::Left))
+            .op(Op::infix(Rule::Concat, Assoc::Right))
+            .op(Op::infix(Rule::Plus, Assoc::Left) | Op::infix(Rule::Minus, Assoc::Left))
+            .op(Op::infix(Rule::Multiply, Assoc::Left)
+                | Op::infix(Rule::FloatDivision, Assoc::Left)
+                | Op::infix(Rule::FloorDivision, Assoc::Left)
+                | Op::infix(Rule::Mod, Assoc::Left))
+            .op(Op::prefix(Rule::UnaryMinus)
+                | Op::prefix(Rule::Not)
+                | Op::prefix(Rule::Length)
+                | Op::prefix(Rule::BitwiseUnaryNot))
+            .op(Op::infix(Rule::Exponent, Assoc::Right))
+    })
+}
+
+// The priorities of a binary operator on its left and its right, from the reference implementation's lparser.c. The
+// right-associative `..` and `^` bind less tightly on their right.
+fn binary_priority(rule: Rule) -> (u8, u8) {
+    match rule {
+        Rule::Or => (1, 1),
+        Rule::And => (2, 2),
+        Rule::LessThan
+        | Rule::GreaterThan
+        | Rule::LessThanEqualTo
+        | Rule::GreaterThanEqualTo
+        | Rule::Unequal
+        | Rule::Equal => (3, 3),
+        Rule::BitwiseOr => (4, 4),
+        Rule::BitwiseExclusiveOr => (5, 5),
+        Rule::BitwiseAnd => (6, 6),
+        Rule::LeftShift | Rule::RightShift => (7, 7),
+        Rule::Concat => (9, 8),
+        Rule::Plus | Rule::Minus => (10, 10),
+        Rule::Exponent => (14, 13),
+        // Multiplication, divisions and modulo
+        _ => (11, 11),
+    }
+}
+
+// The operand of a unary operator only takes `^`
+const UNARY_PRIORITY: u8 = 12;
+
+// How many levels an expression nests, like the recursion of `subexpr` in the reference implementation: the operand of a
+// unary operator and the right operand of a binary operator are a level deeper than the operator, until an operator
+// which binds less tightly ends them. `1 + 2 + 3` is two levels however long it gets, `2 ^ 3 ^ 4` and `- - 1` grow.
+// They are counted before the Pratt parser runs, as it recurses once per level.
+fn expression_levels(pairs: &Pairs<Rule>) -> usize {
+    // The priority each open level takes operators above
+    let mut limits = vec![0];
+    let mut levels = 1;
+    for pair in pairs.clone() {
+        match pair.as_rule() {
+            Rule::Expr => continue,
+            Rule::UnaryMinus | Rule::Not | Rule::Length | Rule::BitwiseUnaryNot => limits.push(UNARY_PRIORITY),
+            rule => {
+                let (left, right) = binary_priority(rule);
+                while limits.last().is_some_and(|limit| left <= *limit) {
+                    limits.pop();
+                }
+                limits.push(right);
+            }
+        }
+        levels = levels.max(limits.len());
+    }
+    levels
+}
+
+pub fn parse_lua_program(input: &str) -> Result<lua_program::LuaProgram, Vec<CompileError>> {
     match LuaTokenPairs::parse(Rule::Main, input) {
         Ok(mut parsed) => {
-            let pair = parsed.next().expect("Lua program must begin with a block");
-            let block = parse_block_pair(pair)?;
-            Ok(lua_program::LuaProgram { block })
+            let pair = parsed
+                .find(|pair| pair.as_rule() != Rule::Shebang)
+                .ok_or_else(|| vec![CompileError::new("Lua program must begin with a block", (1, 1))])?;
+            let block = parse_block_pair(pair).map_err(|e| vec![e])?;
+            let mut program = lua_program::LuaProgram { block };
+            resolver::resolve(&mut program)?;
+            Ok(program)
         }
-        Err(e) => Err(CompileError::from_pest_error(e)),
+        Err(_) => Err(syntax_errors(input)),
+    }
+}
+
+// Finds the syntax errors of a program which failed to parse. After each error a line is replaced so that parsing can
+// continue after it, which stops once an error does not come after the previous one or a block is never closed.
+fn syntax_errors(input: &str) -> Vec<CompileError> {
+    // Tracking the tokens which were expected slows the parser down, so it is only done to report failures
+    pest::set_error_detail(true);
+    let mut errors = Vec::new();
+    let mut source = input.to_owned();
+    while let Err(e) = LuaTokenPairs::parse(Rule::Main, &source) {
+        let error = CompileError::from_pest_error(e, &source);
+        if errors.last().is_some_and(|last: &CompileError| error.line() <= last.line()) {
+            break;
+        }
+        let line = error.line();
+        let unclosed = error.is_unclosed_block();
+        let starts_line = error.starts_line();
+        errors.push(error);
+        if unclosed || errors.len() == MAX_SYNTAX_ERRORS {
+            break;
+        }
+        // An error at the start of a line usually belongs to an unfinished statement on the line before it
+        let mut candidates = Vec::new();
+        if starts_line && line > 1 {
+            candidates.push(syntax_error::replace_line(&source, line - 1, true));
+        }
+        candidates.push(syntax_error::replace_line(&source, line, true));
+        candidates.push(syntax_error::replace_line(&source, line, false));
+        let last = candidates.len() - 1;
+        source = candidates
+            .into_iter()
+            .enumerate()
+            .find(|(index, candidate)| match LuaTokenPairs::parse(Rule::Main, candidate) {
+                Ok(_) => true,
+                Err(e) => error_line(&e) > line || *index == last,
+            })
+            .map(|(_, candidate)| candidate)
+            .unwrap_or(source);
+    }
+    pest::set_error_detail(false);
+    errors
+}
+
+fn error_line(e: &pest::error::Error<Rule>) -> usize {
+    match e.line_col {
+        pest::error::LineColLocation::Pos((line, _)) | pest::error::LineColLocation::Span((line, _), _) => line,
     }
 }
 
 fn parse_block_pair(block_pair: Pair<Rule>) -> Result<lua_program::Block, CompileError> {
     if block_pair.as_rule() != Rule::Block {
-        panic!("Expected pair to be a block when it was not")
+        return Err(CompileError::new("Expected pair to be a block when it was not", block_pair.line_col()));
     }
+    let line_col = block_pair.line_col();
+    let _levels = SyntaxLevels::enter(1, line_col)?;
     let inner = block_pair.into_inner();
-    let mut statements: Vec<lua_program::Statement> = Vec::new();
+    let mut statements: Vec<(lua_program::Statement, lua_program::Span)> = Vec::new();
     let mut return_statement = None;
     for next_token in inner {
         match next_token.as_rule() {
             Rule::Stat => {
+                let span = lua_program::Span::from(next_token.line_col());
                 let statement = parse_statement_pair(next_token)?;
-                statements.push(statement);
+                statements.push((statement, span));
             }
             Rule::RetStat => {
                 return_statement = Some(parse_return_statement_pair(next_token)?);
             }
-            _ => panic!("Block token must only contain Stat and RetStat inner tokens"),
+            _ => return Err(CompileError::new("Block token must only contain Stat and RetStat inner tokens", line_col)),
         }
     }
     Ok(lua_program::Block {
@@ -56,38 +226,34 @@ fn parse_statement_pair(
     statement_pair: Pair<Rule>,
 ) -> Result<lua_program::Statement, CompileError> {
     if statement_pair.as_rule() != Rule::Stat {
-        panic!("Expected pair to be a statement when it was not")
+        return Err(CompileError::new("Expected pair to be a statement when it was not", statement_pair.line_col()));
     }
+    let line_col = statement_pair.line_col();
     let mut inner = statement_pair.into_inner();
-    let next = inner.next().expect("Rule::Stat must have an inner value");
+    let next = inner.next().ok_or_else(|| CompileError::new("Rule::Stat must have an inner value", line_col))?;
     match next.as_rule() {
         Rule::EmptyStatement => Ok(lua_program::Statement::Empty),
-        Rule::ListAssignment => {
-            let mut list_assign_inner = next.into_inner();
-            let var_list_pair = list_assign_inner
-                .next()
-                .expect("ListAssignment must contain two inner values");
-            let exp_list_pair = list_assign_inner
+        Rule::AssignmentOrCall => parse_assignment_or_call_pair(next),
+        Rule::Label => {
+            let name_pair = next
+                .into_inner()
                 .next()
-                .expect("ListAssignment must contain two inner values");
-            let var_list = parse_var_list_pair(var_list_pair)?;
-            let exp_list = parse_expression_list_pair(exp_list_pair)?;
-            Ok(lua_program::Statement::MultipleAssignment(
-                var_list, exp_list,
-            ))
+                .ok_or_else(|| CompileError::new("Rule::Label must contain a name", line_col))?;
+            Ok(lua_program::Statement::Label(name_pair.as_str().to_owned()))
         }
-        Rule::FunctionCall => {
-            let function_call = parse_function_call_pair(next)?;
-            Ok(lua_program::Statement::FunctionCall(Box::new(function_call)))
-        }
-        Rule::Label => Ok(lua_program::Statement::Label(next.as_str().to_owned())),
         Rule::BreakStatement => Ok(lua_program::Statement::Break),
-        Rule::GotoStatement => Ok(lua_program::Statement::GoTo(next.as_str().to_owned())),
+        Rule::GotoStatement => {
+            let name_pair = next
+                .into_inner()
+                .next()
+                .ok_or_else(|| CompileError::new("Rule::GotoStatement must contain a name", line_col))?;
+            Ok(lua_program::Statement::GoTo(name_pair.as_str().to_owned()))
+        }
         Rule::DoBlockEndStatement => {
             let mut do_block_inner = next.into_inner();
             let block_pair = do_block_inner
                 .next()
-                .expect("Rule::DoBlockEndStatement must contain an inner block");
+                .ok_or_else(|| CompileError::new("Rule::DoBlockEndStatement must contain an inner block", line_col))?;
             let block = parse_block_pair(block_pair)?;
             Ok(lua_program::Statement::DoBlockEnd(block))
         }
@@ -95,10 +261,14 @@ fn parse_statement_pair(
             let mut while_expr_do_inner = next.into_inner();
             let expression_pair = while_expr_do_inner
                 .next()
-                .expect("Rule::WhileExprDoBlockStatement must contain an inner expression");
+                .ok_or_else(|| {
+                    CompileError::new("Rule::WhileExprDoBlockStatement must contain an inner expression", line_col)
+                })?;
             let block_pair = while_expr_do_inner
                 .next()
-                .expect("Rule::WhileExprDoBlockStatement must contain an inner block");
+                .ok_or_else(|| {
+                    CompileError::new("Rule::WhileExprDoBlockStatement must contain an inner block", line_col)
+                })?;
             let expression = parse_expression_pair(expression_pair)?;
             let block = parse_block_pair(block_pair)?;
             Ok(lua_program::Statement::WhileExprDoBlockEnd(
@@ -109,10 +279,14 @@ fn parse_statement_pair(
             let mut repeat_block_inner = next.into_inner();
             let block_pair = repeat_block_inner
                 .next()
-                .expect("Rule::RepeatBlockUntilStatement must contain an inner block");
+                .ok_or_else(|| {
+                    CompileError::new("Rule::RepeatBlockUntilStatement must contain an inner block", line_col)
+                })?;
             let expression_pair = repeat_block_inner
                 .next()
-                .expect("Rule::RepeatBlockUntilStatement must contain an inner expression");
+                .ok_or_else(|| {
+                    CompileError::new("Rule::RepeatBlockUntilStatement must contain an inner expression", line_col)
+                })?;
             let block = parse_block_pair(block_pair)?;
             let expression = parse_expression_pair(expression_pair)?;
             Ok(lua_program::Statement::RepeatBlockUntilExpr(
@@ -124,28 +298,25 @@ fn parse_statement_pair(
             let mut for_each_inner = next.into_inner();
             let name_pair = for_each_inner
                 .next()
-                .expect("Rule::ForEachStatement must have an inner name");
+                .ok_or_else(|| CompileError::new("Rule::ForEachStatement must have an inner name", line_col))?;
             let name = name_pair.as_str().to_owned();
             let expression_pair = for_each_inner
                 .next()
-                .expect("Rule::ForEachStatement must have an inner expression");
+                .ok_or_else(|| CompileError::new("Rule::ForEachStatement must have an inner expression", line_col))?;
             let first_expression = parse_expression_pair(expression_pair)?;
             let second_expression_pair = for_each_inner
                 .next()
-                .expect("Rule::ForEachStatement must have a second inner expression");
+                .ok_or_else(|| {
+                    CompileError::new("Rule::ForEachStatement must have a second inner expression", line_col)
+                })?;
             let second_expression = parse_expression_pair(second_expression_pair)?;
-            let optional_expression = match for_each_inner.peek().is_some()
-                && for_each_inner.peek().unwrap().as_rule() == Rule::Expression
-            {
-                true => {
-                    let optional_expression_pair = for_each_inner.next().unwrap();
-                    Some(parse_expression_pair(optional_expression_pair)?)
-                }
-                false => None,
+            let optional_expression = match next_if_rule(&mut for_each_inner, Rule::Expression) {
+                Some(optional_expression_pair) => Some(parse_expression_pair(optional_expression_pair)?),
+                None => None,
             };
             let block_pair = for_each_inner
                 .next()
-                .expect("Rule::ForEachStatement must end with a block pair");
+                .ok_or_else(|| CompileError::new("Rule::ForEachStatement must end with a block pair", line_col))?;
             let block = parse_block_pair(block_pair)?;
             Ok(lua_program::Statement::ForEach(
                 name,
@@ -159,15 +330,20 @@ fn parse_statement_pair(
             let mut for_list_inner = next.into_inner();
             let name_list_pair = for_list_inner
                 .next()
-                .expect("Rule::ForListStatement must have a name list pair");
+                .ok_or_else(|| CompileError::new("Rule::ForListStatement must have a name list pair", line_col))?;
             let name_list = parse_name_list_pair(name_list_pair)?;
+            let _in = for_list_inner
+                .next()
+                .ok_or_else(|| CompileError::new("Rule::ForListStatement must have an 'in' keyword", line_col))?;
             let expr_list_pair = for_list_inner
                 .next()
-                .expect("Rule::ForListStatement must have an expression list pair");
+                .ok_or_else(|| {
+                    CompileError::new("Rule::ForListStatement must have an expression list pair", line_col)
+                })?;
             let expr_list = parse_expression_list_pair(expr_list_pair)?;
             let block_pair = for_list_inner
                 .next()
-                .expect("Rule::ForListStatement must have a block");
+                .ok_or_else(|| CompileError::new("Rule::ForListStatement must have a block", line_col))?;
             let block = parse_block_pair(block_pair)?;
             Ok(lua_program::Statement::ForList(name_list, expr_list, block))
         }
@@ -175,60 +351,154 @@ fn parse_statement_pair(
             let mut function_inner = next.into_inner();
             let function_name_pair = function_inner
                 .next()
-                .expect("Rule::FunctionStatement must have a FunctionName pair");
+                .ok_or_else(|| CompileError::new("Rule::FunctionStatement must have a FunctionName pair", line_col))?;
             let function_name = parse_function_name_pair(function_name_pair)?;
             let function_body_pair = function_inner
                 .next()
-                .expect("Rule::FunctionStatement must have a FunctionBody pair");
-            let function_body = parse_function_body(function_body_pair)?;
+                .ok_or_else(|| CompileError::new("Rule::FunctionStatement must have a FunctionBody pair", line_col))?;
+            let mut function_body = parse_function_body(function_body_pair)?;
+            if function_name.pass_self.is_some() {
+                add_self_parameter(&mut function_body);
+            }
             Ok(lua_program::Statement::Function(
                 function_name,
-                function_body,
+                Rc::new(function_body),
             ))
         }
         Rule::LocalFunctionStatement => {
             let mut local_function_inner = next.into_inner();
             let function_name_pair = local_function_inner
                 .next()
-                .expect("Rule::LocalFunctionStatement must have a function name");
+                .ok_or_else(|| CompileError::new("Rule::LocalFunctionStatement must have a function name", line_col))?;
             let function_body_pair = local_function_inner
                 .next()
-                .expect("Rule::LocalFunctionStatement must have a function body");
+                .ok_or_else(|| CompileError::new("Rule::LocalFunctionStatement must have a function body", line_col))?;
             let function_body = parse_function_body(function_body_pair)?;
             Ok(lua_program::Statement::LocalFunction(
                 function_name_pair.as_str().to_owned(),
-                function_body,
+                Rc::new(function_body),
+            ))
+        }
+        Rule::LocalAttributeNameListStatement => {
+            let mut local_inner = next.into_inner();
+            let attribute_name_list_pair = local_inner
+                .next()
+                .ok_or_else(|| {
+                    let message = "Rule::LocalAttributeNameListStatement must have an attribute name list";
+                    CompileError::new(message, line_col)
+                })?;
+            let attribute_name_list = parse_attribute_name_list_pair(attribute_name_list_pair)?;
+            let expression_list = match local_inner.next() {
+                Some(expression_list_pair) => Some(parse_expression_list_pair(expression_list_pair)?),
+                None => None,
+            };
+            Ok(lua_program::Statement::LocalAttributeNameList(
+                attribute_name_list,
+                expression_list,
             ))
         }
-        Rule::LocalAttributeNameListStatement => todo!(),
-        _ => panic!("Matched on an undefined Stat inner"),
+        _ => Err(CompileError::new("Matched on an undefined Stat inner", line_col)),
+    }
+}
+
+fn parse_assignment_or_call_pair(
+    pair: Pair<Rule>,
+) -> Result<lua_program::Statement, CompileError> {
+    if pair.as_rule() != Rule::AssignmentOrCall {
+        return Err(CompileError::new("Expected pair to be an assignment or call when it was not", pair.line_col()));
+    }
+    let line_col = pair.line_col();
+    let mut inner = pair.into_inner();
+    let first_pair = inner
+        .next()
+        .ok_or_else(|| CompileError::new("Rule::AssignmentOrCall must begin with a suffixed expression", line_col))?;
+    let first = parse_suffixed_expression_pair(first_pair)?;
+    match inner.next() {
+        Some(tail_pair) => {
+            let mut vars = vec![prefix_into_var(first, line_col)?];
+            let mut expression_list = None;
+            for tail_inner in tail_pair.into_inner() {
+                match tail_inner.as_rule() {
+                    Rule::SuffixedExpression => {
+                        let var_line_col = tail_inner.line_col();
+                        let prefix = parse_suffixed_expression_pair(tail_inner)?;
+                        vars.push(prefix_into_var(prefix, var_line_col)?);
+                    }
+                    Rule::ExpList => expression_list = Some(parse_expression_list_pair(tail_inner)?),
+                    _ => return Err(CompileError::new("Matched on an undefined AssignmentTail inner", line_col)),
+                }
+            }
+            let expression_list = expression_list
+                .ok_or_else(|| CompileError::new("Rule::AssignmentTail must end with an ExpList", line_col))?;
+            Ok(lua_program::Statement::MultipleAssignment(
+                lua_program::VarList { vars },
+                expression_list,
+            ))
+        }
+        None => match first {
+            lua_program::PrefixExpression::FunctionCall(call) => Ok(lua_program::Statement::FunctionCall(call)),
+            _ => Err(CompileError::new("syntax error, expected a function call or an assignment", line_col)),
+        },
+    }
+}
+
+fn prefix_into_var(
+    prefix: lua_program::PrefixExpression,
+    line_col: (usize, usize),
+) -> Result<lua_program::Var, CompileError> {
+    match prefix {
+        lua_program::PrefixExpression::Var(var) => Ok(var),
+        _ => Err(CompileError::new("syntax error, cannot assign to this expression", line_col)),
     }
 }
 
+fn add_self_parameter(function_body: &mut lua_program::FunctionBody) {
+    let self_name = "self".to_owned();
+    function_body.parameters = match function_body.parameters.take() {
+        Some(lua_program::Parameters::Normal(mut name_list, expansion)) => {
+            name_list.names.insert(0, self_name);
+            Some(lua_program::Parameters::Normal(name_list, expansion))
+        }
+        Some(lua_program::Parameters::Expanded(expansion)) => Some(lua_program::Parameters::Normal(
+            lua_program::NameList { names: vec![self_name] },
+            Some(expansion),
+        )),
+        None => Some(lua_program::Parameters::Normal(
+            lua_program::NameList { names: vec![self_name] },
+            None,
+        )),
+    };
+}
+
 fn parse_if_statement_pair(
     if_statement_pair: Pair<Rule>,
 ) -> Result<lua_program::Statement, CompileError> {
     if if_statement_pair.as_rule() != Rule::IfStatement {
-        panic!("Expected pair to be an if statement when it was not")
+        return Err(CompileError::new(
+            "Expected pair to be an if statement when it was not",
+            if_statement_pair.line_col(),
+        ));
     }
+    let line_col = if_statement_pair.line_col();
     // if
     let mut if_inner = if_statement_pair.into_inner();
     let expr_pair = if_inner
         .next()
-        .expect("Rule::IfStatement must have an expression pair");
+        .ok_or_else(|| CompileError::new("Rule::IfStatement must have an expression pair", line_col))?;
     let expr = parse_expression_pair(expr_pair)?;
     let block_pair = if_inner
         .next()
-        .expect("Rule::IfStatement must have a block pair");
+        .ok_or_else(|| CompileError::new("Rule::IfStatement must have a block pair", line_col))?;
     let block = parse_block_pair(block_pair)?;
 
     // elseif
     let mut elseif: Vec<(lua_program::Expression, lua_program::Block)> = Vec::new();
-    while if_inner.peek().is_some() && if_inner.peek().unwrap().as_rule() == Rule::Expression {
-        let elseif_expr_pair = if_inner.next().unwrap();
+    while let Some(elseif_expr_pair) = next_if_rule(&mut if_inner, Rule::Expression) {
         let elseif_block_pair = if_inner
             .next()
-            .expect("Rule::IfStatement must always have a block after an optional expression");
+            .ok_or_else(|| {
+                CompileError::new("Rule::IfStatement must always have a block after an optional expression", line_col)
+            })?;
         let elseif_expr = parse_expression_pair(elseif_expr_pair)?;
         let elseif_block = parse_block_pair(elseif_block_pair)?;
         elseif.push((elseif_expr, elseif_block));
@@ -253,47 +523,70 @@ fn parse_return_statement_pair(
     statement_pair: Pair<Rule>,
 ) -> Result<lua_program::ReturnStatement, CompileError> {
     if statement_pair.as_rule() != Rule::RetStat {
-        panic!("Expected pair to be a return statement when it was not")
+        return Err(CompileError::new(
+            "Expected pair to be a return statement when it was not",
+            statement_pair.line_col(),
+        ));
     }
+    let span = lua_program::Span::from(statement_pair.line_col());
     let mut inner = statement_pair.into_inner();
     let expression_list = match inner.next() {
-        Some(inner_val) => match parse_expression_list_pair(inner_val) {
-            Ok(exp_list) => Some(exp_list),
-            Err(e) => return Err(e),
-        },
+        Some(inner_val) => Some(parse_expression_list_pair(inner_val)?),
         None => None,
     };
-    Ok(lua_program::ReturnStatement { expression_list })
+    Ok(lua_program::ReturnStatement { expression_list, span })
 }
 
 fn parse_function_name_pair(pair: Pair<Rule>) -> Result<lua_program::FunctionName, CompileError> {
     if pair.as_rule() != Rule::FunctionName {
-        panic!("Expected pair to be a function name when it was not")
+        return Err(CompileError::new("Expected pair to be a function name when it was not", pair.line_col()));
     }
+    let line_col = pair.line_col();
     let mut inner = pair.into_inner();
     let outer_name_pair = inner
         .next()
-        .expect("Rule::FunctionName must have a name inner");
+        .ok_or_else(|| CompileError::new("Rule::FunctionName must have a name inner", line_col))?;
     let mut accessors: Vec<String> = Vec::new();
-    while inner.peek().is_some() && inner.peek().unwrap().as_rule() == Rule::FunctionNameAccessor {
-        let next = inner.next().unwrap();
-        accessors.push(next.as_str().to_owned());
+    while let Some(next) = next_if_rule(&mut inner, Rule::FunctionNameAccessor) {
+        accessors.push(inner_name(next)?);
     }
-    let pass_self = inner
-        .next()
-        .map(|final_pair| final_pair.as_str().to_owned());
+    let pass_self = inner.next().map(inner_name).transpose()?;
     Ok(lua_program::FunctionName {
         outer_name: outer_name_pair.as_str().to_owned(),
+        resolution: lua_program::Resolution::default(),
         accessors,
         pass_self,
     })
 }
 
+// Takes the next pair if it matches `rule`, used for the optional parts of rules
+fn next_if_rule<'i>(pairs: &mut Pairs<'i, Rule>, rule: Rule) -> Option<Pair<'i, Rule>> {
+    match pairs.peek() {
+        Some(pair) if pair.as_rule() == rule => pairs.next(),
+        _ => None,
+    }
+}
+
+// Returns the Name contained in rules like `"." ~ Name`
+fn inner_name(pair: Pair<Rule>) -> Result<String, CompileError> {
+    let line_col = pair.line_col();
+    let name = pair
+        .into_inner()
+        .next()
+        .ok_or_else(|| CompileError::new("Pair must contain an inner name", line_col))?
+        .as_str()
+        .to_owned();
+    Ok(name)
+}
+
 fn parse_expression_list_pair(
     expr_list_pair: Pair<Rule>,
 ) -> Result<lua_program::ExpressionList, CompileError> {
     if expr_list_pair.as_rule() != Rule::ExpList {
-        panic!("Expected pair to be an expression list when it was not")
+        return Err(CompileError::new(
+            "Expected pair to be an expression list when it was not",
+            expr_list_pair.line_col(),
+        ));
     }
     let mut expressions: Vec<lua_program::Expression> = Vec::new();
     let inner = expr_list_pair.into_inner();
@@ -304,57 +597,130 @@ fn parse_expression_list_pair(
     Ok(lua_program::ExpressionList { expressions })
 }
 
-fn parse_var_list_pair(var_list_pair: Pair<Rule>) -> Result<lua_program::VarList, CompileError> {
-    if var_list_pair.as_rule() != Rule::VarList {
-        panic!("Expected pair to be a var list when it was not")
+fn parse_attribute_name_list_pair(
+    pair: Pair<Rule>,
+) -> Result<lua_program::AttributeNameList, CompileError> {
+    if pair.as_rule() != Rule::AttributeNameList {
+        return Err(CompileError::new("Expected pair to be an attribute name list when it was not", pair.line_col()));
     }
-    let mut vars: Vec<lua_program::Var> = Vec::new();
-    let inner = var_list_pair.into_inner();
-    for val in inner {
-        let var = parse_var_pair(val)?;
-        vars.push(var);
+    let line_col = pair.line_col();
+    let mut names = Vec::new();
+    for attribute_name_pair in pair.into_inner() {
+        let mut attribute_name_inner = attribute_name_pair.into_inner();
+        let name = attribute_name_inner
+            .next()
+            .ok_or_else(|| CompileError::new("Rule::AttributeName must have a name", line_col))?
+            .as_str()
+            .to_owned();
+        let attribute = match attribute_name_inner.next() {
+            Some(attribute_pair) => {
+                let line_col = attribute_pair.line_col();
+                match inner_name(attribute_pair)?.as_str() {
+                    "const" => Some(lua_program::Attribute::Const),
+                    "close" => Some(lua_program::Attribute::Close),
+                    other => {
+                        return Err(CompileError::new(
+                            format!("unknown attribute '{}'", other).as_str(),
+                            line_col,
+                        ))
+                    }
+                }
+            }
+            None => None,
+        };
+        names.push((name, attribute));
     }
-    Ok(lua_program::VarList { vars })
+    Ok(lua_program::AttributeNameList { names })
 }
 
-fn parse_var_pair(var_pair: Pair<Rule>) -> Result<lua_program::Var, CompileError> {
-    if var_pair.as_rule() != Rule::Var {
-        panic!("Expected pair to be a var when it was not")
+fn parse_suffixed_expression_pair(
+    pair: Pair<Rule>,
+) -> Result<lua_program::PrefixExpression, CompileError> {
+    if pair.as_rule() != Rule::SuffixedExpression {
+        return Err(CompileError::new("Expected pair to be a suffixed expression when it was not", pair.line_col()));
     }
-    let mut inner = var_pair.into_inner();
-    let next = inner.next().expect("Rule::Var must contain an inner value");
-    match next.as_rule() {
-        Rule::VarNestedAccess => {
-            let mut var_names: Vec<String> = Vec::new();
-            let nested_access_inner = next.into_inner();
-            for var_name in nested_access_inner {
-                var_names.push(var_name.as_str().to_owned())
-            }
-            Ok(lua_program::Var::NestedAccess(var_names))
+    let line_col = pair.line_col();
+    // Calls report the position where the whole expression begins, like the reference implementation
+    let span = lua_program::Span::from(pair.line_col());
+    let mut inner = pair.into_inner();
+    // Each suffix nests the expression to its left one level deeper
+    let _levels = SyntaxLevels::enter(inner.len() - 1, line_col)?;
+    let primary_pair = inner
+        .next()
+        .ok_or_else(|| CompileError::new("Rule::SuffixedExpression must begin with a primary expression", line_col))?;
+    let primary_inner = primary_pair
+        .into_inner()
+        .next()
+        .ok_or_else(|| CompileError::new("Rule::PrimaryExpression must have one inner", line_col))?;
+    let mut prefix = match primary_inner.as_rule() {
+        Rule::Name => {
+            let name = primary_inner.as_str().to_owned();
+            lua_program::PrefixExpression::Var(lua_program::Var::VarName(name, lua_program::Resolution::default()))
         }
-        Rule::VarName => Ok(lua_program::Var::VarName(next.as_str().to_owned())),
-        Rule::VarTableAccess => todo!(),
-        _ => panic!("Matched on an undefined var"),
+        Rule::Expression => lua_program::PrefixExpression::Expression(parse_expression_pair(primary_inner)?),
+        _ => return Err(CompileError::new("Matched on an undefined PrimaryExpression inner", line_col)),
+    };
+    // Each suffix wraps everything to its left, `a.b[c]:d()` becomes ((a.b)[c]):d()
+    for suffix in inner {
+        let suffix_span = lua_program::Span::from(suffix.line_col());
+        prefix = match suffix.as_rule() {
+            Rule::FieldSuffix => {
+                let name = inner_name(suffix)?;
+                lua_program::PrefixExpression::Var(lua_program::Var::FieldAccess(Box::new(prefix), name, suffix_span))
+            }
+            Rule::IndexSuffix => {
+                let expression_pair = suffix
+                    .into_inner()
+                    .next()
+                    .ok_or_else(|| CompileError::new("Rule::IndexSuffix must contain an expression", line_col))?;
+                let expression = parse_expression_pair(expression_pair)?;
+                lua_program::PrefixExpression::Var(lua_program::Var::TableAccess(Box::new(prefix), expression, suffix_span))
+            }
+            Rule::MethodCallSuffix => {
+                let mut method_inner = suffix.into_inner();
+                let name = method_inner
+                    .next()
+                    .ok_or_else(|| CompileError::new("Rule::MethodCallSuffix must have a name", line_col))?
+                    .as_str()
+                    .to_owned();
+                let args_pair = method_inner
+                    .next()
+                    .ok_or_else(|| CompileError::new("Rule::MethodCallSuffix must have args", line_col))?;
+                let args = parse_args_pair(args_pair)?;
+                let self_func = lua_program::SelfFunctionCall { prefix, name, args, span };
+                lua_program::PrefixExpression::FunctionCall(Box::new(lua_program::FunctionCall::SelfRef(self_func)))
+            }
+            Rule::CallSuffix => {
+                let args_pair = suffix
+                    .into_inner()
+                    .next()
+                    .ok_or_else(|| CompileError::new("Rule::CallSuffix must have args", line_col))?;
+                let args = parse_args_pair(args_pair)?;
+                let static_func = lua_program::StaticFunctionCall { prefix, args, span };
+                lua_program::PrefixExpression::FunctionCall(Box::new(lua_program::FunctionCall::Static(static_func)))
+            }
+            _ => return Err(CompileError::new("Matched on an undefined suffix", line_col)),
+        };
     }
+    Ok(prefix)
 }
 
 fn parse_args_pair(args_pair: Pair<Rule>) -> Result<lua_program::Args, CompileError> {
     if args_pair.as_rule() != Rule::Args {
-        panic!("Expected pair to be args when it was not")
+        return Err(CompileError::new("Expected pair to be args when it was not", args_pair.line_col()));
     }
+    let line_col = args_pair.line_col();
     let mut inner = args_pair.into_inner();
     match inner.next() {
-        Some(next) => {
-            match next.as_rule() {
-                Rule::ExpList => {
-                    let expression_list = parse_expression_list_pair(next)?;
-                    Ok(lua_program::Args::ExpressionList(Some(expression_list)))
-                }
-                // TODO: TableConstructor
-                // TODO: LiteralString
-                _ => panic!("Matched on an undefined arg"),
+        Some(next) => match next.as_rule() {
+            Rule::ExpList => {
+                let expression_list = parse_expression_list_pair(next)?;
+                Ok(lua_program::Args::ExpressionList(Some(expression_list)))
             }
-        }
+            Rule::TableConstructor => Ok(lua_program::Args::TableConstructor(parse_table_constructor_pair(next)?)),
+            Rule::LiteralString => Ok(lua_program::Args::LiteralString(parse_literal_string_pair(next)?)),
+            _ => Err(CompileError::new("Matched on an undefined arg", line_col)),
+        },
         None => Ok(lua_program::Args::ExpressionList(None)),
     }
 }
@@ -363,244 +729,383 @@ fn parse_expression_pair(
     statement_pair: Pair<Rule>,
 ) -> Result<lua_program::Expression, CompileError> {
     if statement_pair.as_rule() != Rule::Expression {
-        panic!("Expected pair to be an expression when it was not")
-    }
-    let mut inner = statement_pair.into_inner();
-    let next = inner.next().expect("Expression must have an inner value");
-    match next.as_rule() {
-        Rule::BinaryExpression => {
-            let mut binary_expr_inner = next.into_inner();
-            let first_expr_pair = binary_expr_inner.next().expect("Rule::BinaryExpression must have an Expr inner");
-            let operator_pair = binary_expr_inner.next().expect("Rule::BinaryExpression must have a BinaryOperator inner");
-            let second_expr_pair = binary_expr_inner.next().expect("Rule::BinaryExpression must have a second Expr inner");
-            let first_expr = parse_expr_pair(first_expr_pair)?;
-            let operation = parse_binary_operator_pair(operator_pair);
-            let second_expr = parse_expr_pair(second_expr_pair)?;
-            Ok(lua_program::Expression::Binary(
-                operation,
-                first_expr,
-                second_expr,
-            ))
-        }
-        Rule::Expr => {
-            Ok(lua_program::Expression::Expr(parse_expr_pair(next)?))
-        }
-        _ => panic!("Matched on an undefined Expression"),
+        return Err(CompileError::new("Expected pair to be an expression when it was not", statement_pair.line_col()));
     }
+    let line_col = statement_pair.line_col();
+    let pairs = statement_pair.into_inner();
+    let _levels = SyntaxLevels::enter(expression_levels(&pairs), line_col)?;
+    parse_operator_pairs(pairs)
+}
+
+fn parse_operator_pairs(pairs: Pairs<Rule>) -> Result<lua_program::Expression, CompileError> {
+    pratt_parser()
+        .map_primary(|primary| Ok(lua_program::Expression::Expr(parse_expr_pair(primary)?)))
+        .map_prefix(|op, operand| {
+            let span = lua_program::Span::from(op.line_col());
+            let op = parse_unary_operator_pair(op)?;
+            Ok(lua_program::Expression::Expr(lua_program::Expr::Unary(op, Box::new(operand?), span)))
+        })
+        .map_infix(|lhs, op, rhs| {
+            let span = lua_program::Span::from(op.line_col());
+            let op = parse_binary_operator_pair(op)?;
+            Ok(lua_program::Expression::Binary(op, Box::new(lhs?), Box::new(rhs?), span))
+        })
+        .parse(pairs)
 }
 
 fn parse_expr_pair(pair: Pair<Rule>) -> Result<lua_program::Expr, CompileError> {
     if pair.as_rule() != Rule::Expr {
-        panic!("Expected pair to be an expr when it was not")
+        return Err(CompileError::new("Expected pair to be an expr when it was not", pair.line_col()));
     }
+    let line_col = pair.line_col();
     let mut inner = pair.into_inner();
     let first = inner
         .next()
-        .expect("Rule::ExpressionInner must contain an inner pair");
-    let inner_str = first.as_str();
-    if inner_str == "nil" {
-        return Ok(lua_program::Expr::Nil);
-    };
-    if inner_str == "false" {
-        return Ok(lua_program::Expr::Boolean(false));
-    };
-    if inner_str == "true" {
-        return Ok(lua_program::Expr::Boolean(true));
-    };
+        .ok_or_else(|| CompileError::new("Rule::Expr must contain an inner pair", line_col))?;
     match first.as_rule() {
+        Rule::Nil => Ok(lua_program::Expr::Nil),
+        Rule::False => Ok(lua_program::Expr::Boolean(false)),
+        Rule::True => Ok(lua_program::Expr::Boolean(true)),
         Rule::Numerical => {
-            let number = parse_numerical_pair(first);
+            let number = parse_numerical_pair(first)?;
             Ok(lua_program::Expr::Numerical(number))
         }
-        Rule::LiteralString => {
-            let mut remove_quotes_inner = first.into_inner();
-            let str_value = remove_quotes_inner.next().expect(
-                "Rule::LiteralString must have an inner value used to strip quotation marks",
-            );
-            Ok(lua_program::Expr::LiteralString(
-                str_value.as_str().to_owned(),
-            ))
-        }
+        Rule::LiteralString => Ok(lua_program::Expr::LiteralString(parse_literal_string_pair(first)?)),
         Rule::Expansion => Ok(lua_program::Expr::Expansion(lua_program::Expansion)),
-        Rule::FunctionDef => Ok(lua_program::Expr::FunctionDef(parse_function_def_pair(first)?)),
-        Rule::PrefixExpression => {
-            let prefix_expr = parse_prefix_expression_pair(first)?;
+        Rule::FunctionDef => Ok(lua_program::Expr::FunctionDef(Rc::new(parse_function_def_pair(first)?))),
+        Rule::SuffixedExpression => {
+            let prefix_expr = parse_suffixed_expression_pair(first)?;
             Ok(lua_program::Expr::Prefix(Box::new(prefix_expr)))
         },
-        Rule::TableConstructor => todo!(),
-        Rule::UnaryExpression => {
-            let mut inner = first.into_inner();
-            let op_pair = inner.next().expect("Rule::UnaryExpression must have an operator pair");
-            let expression_pair = inner.next().expect("Rule::UnaryExpression must have an expression pair");
-            let op = parse_unary_operator_pair(op_pair);
-            let expression = parse_expression_pair(expression_pair)?;
-            Ok(lua_program::Expr::Unary(op, Box::new(expression)))
-        },
-        _ => panic!("Matched on an undefined ExpressionInner "),
+        Rule::TableConstructor => Ok(lua_program::Expr::TableConstructor(parse_table_constructor_pair(first)?)),
+        _ => Err(CompileError::new("Matched on an undefined Expr inner", line_col)),
     }
 }
 
-fn parse_binary_operator_pair(pair: Pair<Rule>) -> lua_program::BinaryOperator {
-    if pair.as_rule() != Rule::BinaryOperator {
-        panic!("Expected pair to be a binary operator")
+fn parse_table_constructor_pair(pair: Pair<Rule>) -> Result<lua_program::TableConstructor, CompileError> {
+    if pair.as_rule() != Rule::TableConstructor {
+        return Err(CompileError::new("Expected pair to be a table constructor", pair.line_col()));
     }
-    match pair.as_str() {
-        "+" => lua_program::BinaryOperator::MathOperator(lua_program::MathOperator::Plus),
-        "-" => lua_program::BinaryOperator::MathOperator(lua_program::MathOperator::Minus),
-        "*" => lua_program::BinaryOperator::MathOperator(lua_program::MathOperator::Multiply),
-        "/" => lua_program::BinaryOperator::MathOperator(lua_program::MathOperator::FloatDivision),
-        "//" => lua_program::BinaryOperator::MathOperator(lua_program::MathOperator::FloorDivision),
-        "^" => lua_program::BinaryOperator::MathOperator(lua_program::MathOperator::Exponent),
-        "%" => lua_program::BinaryOperator::MathOperator(lua_program::MathOperator::Mod),
-        "&" => lua_program::BinaryOperator::BitwiseOperator(lua_program::BitwiseOperator::And),
-        "~" => {
+    let line_col = pair.line_col();
+    let mut fields = Vec::new();
+    if let Some(field_list_pair) = pair.into_inner().next() {
+        for field_pair in field_list_pair.into_inner() {
+            let mut field_inner = field_pair.into_inner();
+            let first = field_inner
+                .next()
+                .ok_or_else(|| CompileError::new("Rule::Field must have an inner value", line_col))?;
+            let field = match (first.as_rule(), field_inner.next()) {
+                (Rule::Expression, Some(value_pair)) => lua_program::Field::Bracketed(
+                    parse_expression_pair(first)?,
+                    parse_expression_pair(value_pair)?,
+                ),
+                (Rule::Name, Some(value_pair)) => lua_program::Field::Named(
+                    first.as_str().to_owned(),
+                    parse_expression_pair(value_pair)?,
+                ),
+                (Rule::Expression, None) => lua_program::Field::Positional(parse_expression_pair(first)?),
+                _ => return Err(CompileError::new("Matched on an undefined Field inner", line_col)),
+            };
+            fields.push(field);
+        }
+    }
+    Ok(lua_program::TableConstructor { fields })
+}
+
+fn parse_binary_operator_pair(pair: Pair<Rule>) -> Result<lua_program::BinaryOperator, CompileError> {
+    let line_col = pair.line_col();
+    let op = match pair.as_rule() {
+        Rule::Plus => lua_program::BinaryOperator::MathOperator(lua_program::MathOperator::Plus),
+        Rule::Minus => lua_program::BinaryOperator::MathOperator(lua_program::MathOperator::Minus),
+        Rule::Multiply => lua_program::BinaryOperator::MathOperator(lua_program::MathOperator::Multiply),
+        Rule::FloatDivision => lua_program::BinaryOperator::MathOperator(lua_program::MathOperator::FloatDivision),
+        Rule::FloorDivision => lua_program::BinaryOperator::MathOperator(lua_program::MathOperator::FloorDivision),
+        Rule::Exponent => lua_program::BinaryOperator::MathOperator(lua_program::MathOperator::Exponent),
+        Rule::Mod => lua_program::BinaryOperator::MathOperator(lua_program::MathOperator::Mod),
+        Rule::BitwiseAnd => lua_program::BinaryOperator::BitwiseOperator(lua_program::BitwiseOperator::And),
+        Rule::BitwiseExclusiveOr => {
             lua_program::BinaryOperator::BitwiseOperator(lua_program::BitwiseOperator::ExclusiveOr)
         }
-        "|" => lua_program::BinaryOperator::BitwiseOperator(lua_program::BitwiseOperator::Or),
-        ">>" => {
+        Rule::BitwiseOr => lua_program::BinaryOperator::BitwiseOperator(lua_program::BitwiseOperator::Or),
+        Rule::RightShift => {
             lua_program::BinaryOperator::BitwiseOperator(lua_program::BitwiseOperator::RightShift)
         }
-        "<<" => {
+        Rule::LeftShift => {
             lua_program::BinaryOperator::BitwiseOperator(lua_program::BitwiseOperator::LeftShift)
         }
-        ".." => lua_program::BinaryOperator::Concat,
-        "<" => lua_program::BinaryOperator::BooleanOperator(lua_program::BooleanOperator::LessThan),
-        "<=" => lua_program::BinaryOperator::BooleanOperator(
+        Rule::Concat => lua_program::BinaryOperator::Concat,
+        Rule::LessThan => lua_program::BinaryOperator::BooleanOperator(lua_program::BooleanOperator::LessThan),
+        Rule::LessThanEqualTo => lua_program::BinaryOperator::BooleanOperator(
             lua_program::BooleanOperator::LessThanEqualTo,
         ),
-        ">" => {
+        Rule::GreaterThan => {
             lua_program::BinaryOperator::BooleanOperator(lua_program::BooleanOperator::GreaterThan)
         }
-        ">=" => lua_program::BinaryOperator::BooleanOperator(
+        Rule::GreaterThanEqualTo => lua_program::BinaryOperator::BooleanOperator(
             lua_program::BooleanOperator::GreaterThanEqualTo,
         ),
-        "==" => lua_program::BinaryOperator::BooleanOperator(lua_program::BooleanOperator::Equal),
-        "~=" => lua_program::BinaryOperator::BooleanOperator(lua_program::BooleanOperator::Unequal),
-        "and" => lua_program::BinaryOperator::BooleanOperator(lua_program::BooleanOperator::And),
-        "or" => lua_program::BinaryOperator::BooleanOperator(lua_program::BooleanOperator::Or),
-        _ => panic!("Matched on an undefined binary operator"),
-    }
+        Rule::Equal => lua_program::BinaryOperator::BooleanOperator(lua_program::BooleanOperator::Equal),
+        Rule::Unequal => lua_program::BinaryOperator::BooleanOperator(lua_program::BooleanOperator::Unequal),
+        Rule::And => lua_program::BinaryOperator::BooleanOperator(lua_program::BooleanOperator::And),
+        Rule::Or => lua_program::BinaryOperator::BooleanOperator(lua_program::BooleanOperator::Or),
+        _ => return Err(CompileError::new("Matched on an undefined binary operator", line_col)),
+    };
+    Ok(op)
 }
 
-fn parse_unary_operator_pair(pair: Pair<Rule>) -> lua_program::UnaryOperator {
-    if pair.as_rule() != Rule::UnaryOperator {
-        panic!("Expected pair to be a unary operator")
-    }
-    let inner = pair.into_inner();
-    match inner.as_str() {
-        "-" => lua_program::UnaryOperator::UnaryMinus,
-        "not" => lua_program::UnaryOperator::Not,
-        "#" => lua_program::UnaryOperator::Length,
-        "~" => lua_program::UnaryOperator::BitwiseUnaryNot,
-        _ => panic!("Matched on an undefined unary operator"),
-    }
+fn parse_unary_operator_pair(pair: Pair<Rule>) -> Result<lua_program::UnaryOperator, CompileError> {
+    let line_col = pair.line_col();
+    let op = match pair.as_rule() {
+        Rule::UnaryMinus => lua_program::UnaryOperator::UnaryMinus,
+        Rule::Not => lua_program::UnaryOperator::Not,
+        Rule::Length => lua_program::UnaryOperator::Length,
+        Rule::BitwiseUnaryNot => lua_program::UnaryOperator::BitwiseUnaryNot,
+        _ => return Err(CompileError::new("Matched on an undefined unary operator", line_col)),
+    };
+    Ok(op)
 }
 
-fn parse_prefix_expression_pair(pair: Pair<Rule>) -> Result<lua_program::PrefixExpression, CompileError> {
-    if pair.as_rule() != Rule::PrefixExpression {
-        panic!("Expected pair to be a prefix expression")
+fn parse_numerical_pair(pair: Pair<Rule>) -> Result<lua_program::NumberKind, CompileError> {
+    if pair.as_rule() != Rule::Numerical {
+        return Err(CompileError::new("Expected pair to be a numerical", pair.line_col()));
     }
+    let line_col = pair.line_col();
     let mut inner = pair.into_inner();
-    let next = inner.next().expect("Rule::PrefixExpression must have one inner");
+    let next = inner
+        .next()
+        .ok_or_else(|| CompileError::new("Rule::Numerical must contain an inner value", line_col))?;
+    let text = next.as_str();
+    let hex_digits = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X"));
     match next.as_rule() {
-        Rule::Var => Ok(lua_program::PrefixExpression::Var(parse_var_pair(next)?)),
-        Rule::FunctionCall => Ok(lua_program::PrefixExpression::FunctionCall(Box::new(parse_function_call_pair(next)?))),
-        Rule::Expression => Ok(lua_program::PrefixExpression::Expression(parse_expression_pair(next)?)),
-        _ => panic!("Matched on an undefined PrefixExpression inner pair")
+        Rule::Float => {
+            let float = match hex_digits {
+                Some(digits) => parse_hex_float(digits),
+                None => text.parse::<f64>().ok(),
+            };
+            match float {
+                Some(float) => Ok(lua_program::NumberKind::Float(float)),
+                None => Err(CompileError::new(format!("malformed number near '{}'", text).as_str(), line_col)),
+            }
+        }
+        Rule::Integer => match hex_digits {
+            // Hexadecimal integers wrap around on overflow
+            Some(digits) => {
+                let int = digits.chars().fold(0u64, |acc, c| {
+                    acc.wrapping_mul(16).wrapping_add(c.to_digit(16).unwrap_or(0) as u64)
+                });
+                Ok(lua_program::NumberKind::Int(int as i64))
+            }
+            // Decimal integers that do not fit in an i64 become floats
+            None => match text.parse::<i64>() {
+                Ok(int) => Ok(lua_program::NumberKind::Int(int)),
+                Err(_) => match text.parse::<f64>() {
+                    Ok(float) => Ok(lua_program::NumberKind::Float(float)),
+                    Err(_) => Err(CompileError::new(format!("malformed number near '{}'", text).as_str(), line_col)),
+                },
+            },
+        },
+        _ => Err(CompileError::new("Matched on an undefined numerical", line_col)),
     }
 }
 
-fn parse_function_call_pair(pair: Pair<Rule>) -> Result<lua_program::FunctionCall, CompileError> {
-    if pair.as_rule() != Rule::FunctionCall {
-        panic!("Expected pair to be a function call")
+// Parses the part of a hexadecimal float after the 0x prefix, like `1.8p3`
+pub fn parse_hex_float(digits: &str) -> Option<f64> {
+    let (mantissa, exponent) = match digits.find(['p', 'P']) {
+        Some(index) => (&digits[..index], digits[index + 1..].parse::<i32>().ok()?),
+        None => (digits, 0),
+    };
+    let mut value = 0f64;
+    let mut exponent = exponent;
+    let mut seen_dot = false;
+    let mut any_digit = false;
+    for c in mantissa.chars() {
+        if c == '.' {
+            if seen_dot {
+                return None;
+            }
+            seen_dot = true;
+            continue;
+        }
+        let digit = c.to_digit(16)?;
+        any_digit = true;
+        value = value * 16.0 + digit as f64;
+        if seen_dot {
+            exponent -= 4;
+        }
+    }
+    if !any_digit {
+        return None;
     }
-    let mut function_call_inner = pair.into_inner();
-    let first_pair = function_call_inner
+    Some(value * 2f64.powi(exponent))
+}
+
+fn parse_literal_string_pair(pair: Pair<Rule>) -> Result<Vec<u8>, CompileError> {
+    if pair.as_rule() != Rule::LiteralString {
+        return Err(CompileError::new("Expected pair to be a literal string", pair.line_col()));
+    }
+    let line_col = pair.line_col();
+    let inner = pair
+        .into_inner()
         .next()
-        .expect("Rule::FunctionCall must have a first inner which is a var");
-    match first_pair.as_rule() {
-        Rule::Var => {
-            let var = parse_var_pair(first_pair)?;
-            let second_pair = function_call_inner.next().expect("Rule::FunctionCall must have a second pair");
-            match second_pair.as_rule() {
-                Rule::Args => {
-                    let args = parse_args_pair(second_pair)?;
-                    let global_function = lua_program::StaticFunctionCall{ prefix: var, args };
-                    Ok(lua_program::FunctionCall::Static(global_function))
-                },
-                Rule::Name => {
-                    let name = second_pair.as_str().to_owned();
-                    let args_pair = function_call_inner.next().expect("Rule::FunctionCall must have a third inner when it's a local function");
-                    let args = parse_args_pair(args_pair)?;
-                    let self_func = lua_program::SelfFunctionCall{ prefix: var, name, args };
-                    Ok(lua_program::FunctionCall::SelfRef(self_func))
-                },
-                _ => panic!("Matched on an undefined FunctionCall inner pair")
+        .ok_or_else(|| {
+            CompileError::new("Rule::LiteralString must have an inner value used to strip quotation marks", line_col)
+        })?;
+    match inner.as_rule() {
+        Rule::DoubleQuotedString | Rule::SingleQuotedString => unescape_string(inner.as_str(), line_col),
+        Rule::LongString => {
+            let contents = inner
+                .into_inner()
+                .next()
+                .ok_or_else(|| CompileError::new("Rule::LongString must have an inner value", line_col))?
+                .as_str();
+            // A newline immediately following the opening long bracket is not part of the string
+            let contents = contents
+                .strip_prefix("\r\n")
+                .or_else(|| contents.strip_prefix("\n\r"))
+                .or_else(|| contents.strip_prefix('\n'))
+                .or_else(|| contents.strip_prefix('\r'))
+                .unwrap_or(contents);
+            Ok(contents.replace("\r\n", "\n").into_bytes())
+        }
+        _ => Err(CompileError::new("Matched on an undefined LiteralString inner", line_col)),
+    }
+}
+
+fn unescape_string(raw: &str, line_col: (usize, usize)) -> Result<Vec<u8>, CompileError> {
+    let invalid_escape = |near: &str| CompileError::new(format!("invalid escape sequence '\\{}'", near).as_str(), line_col);
+    let bytes = raw.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'\\' {
+            out.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        i += 1;
+        let escape = bytes[i];
+        i += 1;
+        match escape {
+            b'a' => out.push(0x07),
+            b'b' => out.push(0x08),
+            b'f' => out.push(0x0C),
+            b'n' => out.push(b'\n'),
+            b'r' => out.push(b'\r'),
+            b't' => out.push(b'\t'),
+            b'v' => out.push(0x0B),
+            b'\\' => out.push(b'\\'),
+            b'"' => out.push(b'"'),
+            b'\'' => out.push(b'\''),
+            b'\n' | b'\r' => {
+                out.push(b'\n');
+                // \r\n and \n\r count as a single newline
+                if i < bytes.len() && (bytes[i] == b'\n' || bytes[i] == b'\r') && bytes[i] != escape {
+                    i += 1;
+                }
             }
-        },
-        Rule::Expression => {
-            let resolved = parse_expression_pair(first_pair)?;
-            Ok(lua_program::FunctionCall::PassedExpression(resolved))
-        },
-        _ => panic!("Matched on an undefined FunctionCall inner pair")
+            b'z' => {
+                while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+                    i += 1;
+                }
+            }
+            b'x' => {
+                let hex = raw.get(i..i + 2).ok_or_else(|| invalid_escape("x"))?;
+                let byte = u8::from_str_radix(hex, 16).map_err(|_| invalid_escape(&format!("x{}", hex)))?;
+                out.push(byte);
+                i += 2;
+            }
+            b'u' => {
+                let rest = &raw[i..];
+                let end = rest.find('}').ok_or_else(|| invalid_escape("u"))?;
+                let hex = rest
+                    .strip_prefix('{')
+                    .map(|_| &rest[1..end])
+                    .ok_or_else(|| invalid_escape("u"))?;
+                let code_point = u32::from_str_radix(hex, 16)
+                    .ok()
+                    .filter(|code_point| *code_point < 0x8000_0000)
+                    .ok_or_else(|| invalid_escape(&format!("u{{{}}}", hex)))?;
+                encode_utf8(code_point, &mut out);
+                i += end + 1;
+            }
+            b'0'..=b'9' => {
+                let mut value = (escape - b'0') as u32;
+                let mut digits = 1;
+                while digits < 3 && i < bytes.len() && bytes[i].is_ascii_digit() {
+                    value = value * 10 + (bytes[i] - b'0') as u32;
+                    digits += 1;
+                    i += 1;
+                }
+                if value > 255 {
+                    return Err(invalid_escape(&value.to_string()));
+                }
+                out.push(value as u8);
+            }
+            other => return Err(invalid_escape(&(other as char).to_string())),
+        }
     }
+    Ok(out)
 }
 
-fn parse_numerical_pair(pair: Pair<Rule>) -> lua_program::NumberKind {
-    if pair.as_rule() != Rule::Numerical {
-        panic!("Expected pair to be a numerical")
+// Lua allows code points up to 2^31 in \u{XXX} escapes, which needs the original 6 byte form of UTF-8
+fn encode_utf8(code_point: u32, out: &mut Vec<u8>) {
+    if code_point < 0x80 {
+        out.push(code_point as u8);
+        return;
     }
-    let mut inner = pair.into_inner();
-    let next = inner
-        .next()
-        .expect("Rule::Numerical must contain an inner value");
-    match next.as_rule() {
-        Rule::Float => match next.as_str().parse::<f64>() {
-            Ok(float) => lua_program::NumberKind::Float(float),
-            Err(_) => panic!("Failed to parse a Rule::Float into an f64"),
-        },
-        Rule::Integer => match next.as_str().parse::<i64>() {
-            Ok(int) => lua_program::NumberKind::Int(int),
-            Err(_) => panic!("Failed to parse a Rule::Integer into an i64"),
-        },
-        _ => panic!("Matched on an undefined numerical"),
+    let mut continuation = Vec::new();
+    let mut remaining = code_point;
+    let mut first_byte_max = 0x3F;
+    while remaining > first_byte_max {
+        continuation.push(0x80 | (remaining & 0x3F) as u8);
+        remaining >>= 6;
+        first_byte_max >>= 1;
     }
+    let prefix = ((!first_byte_max) << 1) as u8;
+    out.push(prefix | remaining as u8);
+    out.extend(continuation.into_iter().rev());
 }
 
 fn parse_function_def_pair(pair: Pair<Rule>) -> Result<lua_program::FunctionBody, CompileError> {
     if pair.as_rule() != Rule::FunctionDef {
-        panic!("Expected pair to be a FunctionDef")
+        return Err(CompileError::new("Expected pair to be a FunctionDef", pair.line_col()));
     }
+    let line_col = pair.line_col();
     let mut inner = pair.into_inner();
     let function_body_pair = inner
         .next()
-        .expect("Rule::FunctionDef must contain one inner");
+        .ok_or_else(|| CompileError::new("Rule::FunctionDef must contain one inner", line_col))?;
     parse_function_body(function_body_pair)
 }
 
 fn parse_function_body(pair: Pair<Rule>) -> Result<lua_program::FunctionBody, CompileError> {
     if pair.as_rule() != Rule::FunctionBody {
-        panic!("Expected pair to be a FunctionBody")
+        return Err(CompileError::new("Expected pair to be a FunctionBody", pair.line_col()));
     }
+    let line_col = pair.line_col();
+    let span = lua_program::Span::from(pair.line_col());
+    let (end_line, _) = pair.as_span().end_pos().line_col();
     let mut function_body_inner = pair.into_inner();
-    let parameters = match function_body_inner.peek().is_some()
-        && function_body_inner.peek().unwrap().as_rule() == Rule::ParList
-    {
-        true => Some(parse_params_pair(function_body_inner.next().unwrap())?),
-        false => None,
+    let parameters = match next_if_rule(&mut function_body_inner, Rule::ParList) {
+        Some(params_pair) => Some(parse_params_pair(params_pair)?),
+        None => None,
     };
-    let block = parse_block_pair(function_body_inner.next().unwrap())?;
-    Ok(lua_program::FunctionBody { parameters, block })
+    let block_pair = function_body_inner
+        .next()
+        .ok_or_else(|| CompileError::new("Rule::FunctionBody must have a block", line_col))?;
+    let block = parse_block_pair(block_pair)?;
+    Ok(lua_program::FunctionBody { parameters, block, span, end_line })
 }
 
 fn parse_params_pair(pair: Pair<Rule>) -> Result<lua_program::Parameters, CompileError> {
     if pair.as_rule() != Rule::ParList {
-        panic!("Expected pair to be a ParList")
+        return Err(CompileError::new("Expected pair to be a ParList", pair.line_col()));
     }
+    let line_col = pair.line_col();
     let mut inner = pair.into_inner();
     let next = inner
         .next()
-        .expect("Rule::ParList must have at least one inner value");
+        .ok_or_else(|| CompileError::new("Rule::ParList must have at least one inner value", line_col))?;
     match next.as_rule() {
         Rule::NameList => {
             let name_list = parse_name_list_pair(next)?;
@@ -608,13 +1113,13 @@ fn parse_params_pair(pair: Pair<Rule>) -> Result<lua_program::Parameters, Compil
             Ok(lua_program::Parameters::Normal(name_list, expansion))
         }
         Rule::Expansion => Ok(lua_program::Parameters::Expanded(lua_program::Expansion)),
-        _ => panic!("Matched on an undefined ParList inner"),
+        _ => Err(CompileError::new("Matched on an undefined ParList inner", line_col)),
     }
 }
 
 fn parse_name_list_pair(pair: Pair<Rule>) -> Result<lua_program::NameList, CompileError> {
     if pair.as_rule() != Rule::NameList {
-        panic!("Expected pair to be a NameList")
+        return Err(CompileError::new("Expected pair to be a NameList", pair.line_col()));
     }
     let mut names: Vec<String> = Vec::new();
     let inner = pair.into_inner();
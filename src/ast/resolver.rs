@@ -0,0 +1,439 @@
+// Semantic analysis which runs between parsing and execution. It checks the rules of Lua that the grammar can not
+// express and resolves every name to the local, upvalue or global it refers to, so that the compiler only searches
+// its registers and upvalues for names which are not globals.
+
+use std::rc::Rc;
+
+use crate::ast::lua_program::{
+    Args, Attribute, Block, Expr, Expression, ExpressionList, Field, FunctionBody, FunctionCall, LuaProgram,
+    PrefixExpression, Resolution, Span, Statement, TableConstructor, Var,
+};
+use crate::err_handle::CompileError;
+
+const RESERVED_KEYWORDS: [&str; 22] = [
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if", "in",
+    "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+];
+
+struct Local {
+    name: String,
+    attribute: Option<Attribute>,
+}
+
+// A block, locals are declared in the same order as the compiler gives them registers
+struct Scope {
+    locals: Vec<Local>,
+    // Set on the outermost scope of a function, locals found past it are upvalues
+    function_boundary: bool,
+}
+
+struct Label {
+    name: String,
+    index: usize,
+    line: usize,
+    // Nothing but labels and empty statements follow the label, so the locals of the block are already out of scope
+    // when it is reached
+    at_end: bool,
+}
+
+// What a goto needs to know about a block it is inside of
+struct BlockInfo {
+    labels: Vec<Label>,
+    // The first local declared by each statement
+    declared_locals: Vec<Option<String>>,
+    // The statement being resolved
+    current: usize,
+}
+
+struct FunctionState {
+    vararg: bool,
+    loop_depth: usize,
+    // The index of the outermost block of the function in `Resolver::blocks`, labels of enclosing functions are not
+    // visible
+    first_block: usize,
+}
+
+struct Resolver {
+    scopes: Vec<Scope>,
+    blocks: Vec<BlockInfo>,
+    functions: Vec<FunctionState>,
+    // The statement being resolved, errors are reported at its position
+    span: Span,
+    errors: Vec<CompileError>,
+}
+
+pub fn resolve(program: &mut LuaProgram) -> Result<(), Vec<CompileError>> {
+    let mut resolver = Resolver {
+        scopes: Vec::new(),
+        blocks: Vec::new(),
+        functions: Vec::new(),
+        span: Span { line: 1, column: 1 },
+        errors: Vec::new(),
+    };
+    // The main chunk is a vararg function
+    resolver.functions.push(FunctionState { vararg: true, loop_depth: 0, first_block: 0 });
+    resolver.scopes.push(Scope { locals: Vec::new(), function_boundary: true });
+    resolver.block_in_scope(&mut program.block, true);
+    // Labels are checked when their block is entered, before the statements in front of them
+    resolver.errors.sort_by_key(CompileError::line);
+    match resolver.errors.is_empty() {
+        true => Ok(()),
+        false => Err(resolver.errors),
+    }
+}
+
+impl Resolver {
+    fn error(&mut self, message: String) {
+        self.errors.push(CompileError::new(&message, (self.span.line, self.span.column)));
+    }
+
+    fn check_name(&mut self, name: &str) {
+        if RESERVED_KEYWORDS.contains(&name) {
+            self.error(format!("cannot use reserved keyword '{}' as a name", name));
+        }
+    }
+
+    fn declare(&mut self, name: &str, attribute: Option<Attribute>) {
+        self.check_name(name);
+        let scope = self.scopes.last_mut().expect("A function scope is always open");
+        scope.locals.push(Local { name: name.to_owned(), attribute });
+    }
+
+    // Later declarations shadow earlier ones, including in the same scope
+    fn lookup(&self, name: &str) -> (Resolution, Option<&Local>) {
+        let mut crossed_function = false;
+        for (depth, scope) in self.scopes.iter().rev().enumerate() {
+            if let Some(index) = scope.locals.iter().rposition(|local| local.name == name) {
+                let resolution = match crossed_function {
+                    true => Resolution::Upvalue { depth, index },
+                    false => Resolution::Local { depth, index },
+                };
+                return (resolution, Some(&scope.locals[index]));
+            }
+            crossed_function |= scope.function_boundary;
+        }
+        (Resolution::Global, None)
+    }
+
+    fn resolve_name(&mut self, name: &str, assigned: bool) -> Resolution {
+        self.check_name(name);
+        let (resolution, local) = self.lookup(name);
+        let constant = local.is_some_and(|local| local.attribute.is_some());
+        if assigned && constant {
+            self.error(format!("attempt to assign to const variable '{}'", name));
+        }
+        resolution
+    }
+
+    fn function_state(&mut self) -> &mut FunctionState {
+        self.functions.last_mut().expect("The main chunk is always being resolved")
+    }
+
+    fn block(&mut self, block: &mut Block) {
+        self.scopes.push(Scope { locals: Vec::new(), function_boundary: false });
+        self.block_in_scope(block, true);
+        self.scopes.pop();
+    }
+
+    // Resolves a block in the current scope. `end_closes_scope` is false for the body of a repeat loop, whose locals
+    // are still visible in its condition.
+    fn block_in_scope(&mut self, block: &mut Block, end_closes_scope: bool) {
+        let info = self.block_info(block, end_closes_scope);
+        self.blocks.push(info);
+        for (index, (statement, span)) in block.statements.iter_mut().enumerate() {
+            self.blocks.last_mut().expect("The block was just pushed").current = index;
+            self.span = *span;
+            self.statement(statement);
+        }
+        if let Some(expression_list) = block.return_statement.as_mut().and_then(|ret| ret.expression_list.as_mut()) {
+            self.expression_list(expression_list);
+        }
+        self.blocks.pop();
+    }
+
+    fn block_info(&mut self, block: &Block, end_closes_scope: bool) -> BlockInfo {
+        let mut labels: Vec<Label> = Vec::new();
+        for (index, (statement, span)) in block.statements.iter().enumerate() {
+            let Statement::Label(name) = statement else {
+                continue;
+            };
+            self.span = *span;
+            self.check_name(name);
+            let first_block = self.functions.last().map(|function| function.first_block).unwrap_or(0);
+            let visible = labels.iter().chain(self.blocks[first_block..].iter().flat_map(|block| &block.labels));
+            if let Some(existing) = visible.into_iter().find(|label| label.name == *name) {
+                let line = existing.line;
+                self.error(format!("label '{}' already defined on line {}", name, line));
+            }
+            let only_labels_follow = block.statements[index + 1..]
+                .iter()
+                .all(|(statement, _)| matches!(statement, Statement::Label(_) | Statement::Empty));
+            labels.push(Label {
+                name: name.clone(),
+                index,
+                line: span.line,
+                at_end: end_closes_scope && only_labels_follow && block.return_statement.is_none(),
+            });
+        }
+        let declared_locals = block
+            .statements
+            .iter()
+            .map(|(statement, _)| match statement {
+                Statement::LocalAttributeNameList(attribute_name_list, _) => {
+                    attribute_name_list.names.first().map(|(name, _)| name.clone())
+                }
+                Statement::LocalFunction(name, _) => Some(name.clone()),
+                _ => None,
+            })
+            .collect();
+        BlockInfo { labels, declared_locals, current: 0 }
+    }
+
+    // A goto can jump to a label in its own block or any enclosing block of the same function, as long as it does not
+    // jump forward past the declaration of a local which is still in scope at the label
+    fn goto(&mut self, name: &str) {
+        let first_block = self.function_state().first_block;
+        let mut problem = Some(format!("no visible label '{}' for goto", name));
+        for block in self.blocks[first_block..].iter().rev() {
+            let Some(label) = block.labels.iter().find(|label| label.name == name) else {
+                continue;
+            };
+            problem = None;
+            if label.index > block.current && !label.at_end {
+                let skipped = block.declared_locals[block.current + 1..label.index].iter().flatten().next();
+                if let Some(local) = skipped {
+                    problem = Some(format!("<goto {}> jumps into the scope of local '{}'", name, local));
+                }
+            }
+            break;
+        }
+        if let Some(problem) = problem {
+            self.error(problem);
+        }
+    }
+
+    fn loop_body(&mut self, block: &mut Block, locals: &[String]) {
+        self.function_state().loop_depth += 1;
+        self.scopes.push(Scope { locals: Vec::new(), function_boundary: false });
+        for name in locals {
+            self.declare(name, None);
+        }
+        self.block_in_scope(block, true);
+        self.scopes.pop();
+        self.function_state().loop_depth -= 1;
+    }
+
+    fn statement(&mut self, statement: &mut Statement) {
+        match statement {
+            Statement::Empty | Statement::Label(_) => {}
+            Statement::MultipleAssignment(var_list, expression_list) => {
+                for var in var_list.vars.iter_mut() {
+                    match var {
+                        Var::VarName(name, resolution) => *resolution = self.resolve_name(name, true),
+                        var => self.var(var),
+                    }
+                }
+                self.expression_list(expression_list);
+            }
+            Statement::FunctionCall(call) => self.function_call(call),
+            Statement::Break => {
+                if self.function_state().loop_depth == 0 {
+                    self.error("break outside a loop".to_owned());
+                }
+            }
+            Statement::GoTo(label) => {
+                let label = label.clone();
+                self.check_name(&label);
+                self.goto(&label);
+            }
+            Statement::DoBlockEnd(block) => self.block(block),
+            Statement::WhileExprDoBlockEnd(expression, block) => {
+                self.expression(expression);
+                self.loop_body(block, &[]);
+            }
+            Statement::RepeatBlockUntilExpr(block, expression) => {
+                // The condition can see the locals declared inside of the loop body
+                self.function_state().loop_depth += 1;
+                self.scopes.push(Scope { locals: Vec::new(), function_boundary: false });
+                self.block_in_scope(block, false);
+                self.expression(expression);
+                self.scopes.pop();
+                self.function_state().loop_depth -= 1;
+            }
+            Statement::IfBlock((condition, block), elseif_blocks, else_block) => {
+                self.expression(condition);
+                self.block(block);
+                for (condition, block) in elseif_blocks.iter_mut() {
+                    self.expression(condition);
+                    self.block(block);
+                }
+                if let Some(block) = else_block {
+                    self.block(block);
+                }
+            }
+            Statement::ForEach(name, start, limit, step, block) => {
+                self.expression(start);
+                self.expression(limit);
+                if let Some(step) = step {
+                    self.expression(step);
+                }
+                let locals = [name.clone()];
+                self.loop_body(block, &locals);
+            }
+            Statement::ForList(name_list, expression_list, block) => {
+                self.expression_list(expression_list);
+                let locals = name_list.names.clone();
+                self.loop_body(block, &locals);
+            }
+            Statement::Function(function_name, body) => {
+                // Only `function name()` assigns to the variable, `function name.field()` assigns to a field of it
+                let assigned = function_name.accessors.is_empty() && function_name.pass_self.is_none();
+                function_name.resolution = self.resolve_name(&function_name.outer_name, assigned);
+                for name in function_name.accessors.iter().chain(function_name.pass_self.as_ref()) {
+                    self.check_name(name);
+                }
+                self.function(body);
+            }
+            Statement::LocalFunction(name, body) => {
+                // The local is in scope inside of the function so that it can call itself
+                self.declare(name, None);
+                self.function(body);
+            }
+            Statement::LocalAttributeNameList(attribute_name_list, expression_list) => {
+                // The values are evaluated before the locals are declared, `local x = x` reads the outer x
+                if let Some(expression_list) = expression_list {
+                    self.expression_list(expression_list);
+                }
+                let to_be_closed = attribute_name_list
+                    .names
+                    .iter()
+                    .filter(|(_, attribute)| *attribute == Some(Attribute::Close))
+                    .count();
+                if to_be_closed > 1 {
+                    self.error("multiple to-be-closed variables in local list".to_owned());
+                }
+                for (name, attribute) in attribute_name_list.names.iter() {
+                    self.declare(name, attribute.clone());
+                }
+            }
+        }
+    }
+
+    fn function(&mut self, body: &mut Rc<FunctionBody>) {
+        // Function bodies are only shared once the program starts running
+        let Some(body) = Rc::get_mut(body) else {
+            self.error("function body was shared before it was resolved".to_owned());
+            return;
+        };
+        let outer_span = self.span;
+        self.span = body.span;
+        let first_block = self.blocks.len();
+        self.functions.push(FunctionState { vararg: body.is_vararg(), loop_depth: 0, first_block });
+        self.scopes.push(Scope { locals: Vec::new(), function_boundary: true });
+        for name in body.parameter_names().to_vec() {
+            self.declare(&name, None);
+        }
+        self.block_in_scope(&mut body.block, true);
+        self.scopes.pop();
+        self.functions.pop();
+        self.span = outer_span;
+    }
+
+    fn expression_list(&mut self, expression_list: &mut ExpressionList) {
+        for expression in expression_list.expressions.iter_mut() {
+            self.expression(expression);
+        }
+    }
+
+    fn expression(&mut self, mut expression: &mut Expression) {
+        // Chains like `1 + 2 + 3` nest to the left without a bound, they are followed down without recursing
+        let mut right_operands = Vec::new();
+        loop {
+            match expression {
+                Expression::Expr(expr) => break self.expr(expr),
+                Expression::Binary(_, lhs, rhs, _) => {
+                    right_operands.push(rhs);
+                    expression = lhs;
+                }
+            }
+        }
+        for rhs in right_operands.into_iter().rev() {
+            self.expression(rhs);
+        }
+    }
+
+    fn expr(&mut self, expr: &mut Expr) {
+        match expr {
+            Expr::Nil | Expr::Boolean(_) | Expr::Numerical(_) | Expr::LiteralString(_) => {}
+            Expr::Expansion(_) => {
+                if !self.function_state().vararg {
+                    self.error("cannot use '...' outside a vararg function".to_owned());
+                }
+            }
+            Expr::FunctionDef(body) => self.function(body),
+            Expr::Prefix(prefix) => self.prefix(prefix),
+            Expr::Unary(_, expression, _) => self.expression(expression),
+            Expr::TableConstructor(table_constructor) => self.table_constructor(table_constructor),
+        }
+    }
+
+    fn prefix(&mut self, prefix: &mut PrefixExpression) {
+        match prefix {
+            PrefixExpression::Var(var) => self.var(var),
+            PrefixExpression::FunctionCall(call) => self.function_call(call),
+            PrefixExpression::Expression(expression) => self.expression(expression),
+        }
+    }
+
+    fn var(&mut self, var: &mut Var) {
+        match var {
+            Var::VarName(name, resolution) => *resolution = self.resolve_name(name, false),
+            Var::FieldAccess(prefix, name, _) => {
+                self.prefix(prefix);
+                self.check_name(name);
+            }
+            Var::TableAccess(prefix, key, _) => {
+                self.prefix(prefix);
+                self.expression(key);
+            }
+        }
+    }
+
+    fn function_call(&mut self, call: &mut FunctionCall) {
+        match call {
+            FunctionCall::Static(static_call) => {
+                self.prefix(&mut static_call.prefix);
+                self.args(&mut static_call.args);
+            }
+            FunctionCall::SelfRef(self_call) => {
+                self.prefix(&mut self_call.prefix);
+                self.check_name(&self_call.name);
+                self.args(&mut self_call.args);
+            }
+        }
+    }
+
+    fn args(&mut self, args: &mut Args) {
+        match args {
+            Args::ExpressionList(Some(expression_list)) => self.expression_list(expression_list),
+            Args::ExpressionList(None) | Args::LiteralString(_) => {}
+            Args::TableConstructor(table_constructor) => self.table_constructor(table_constructor),
+        }
+    }
+
+    fn table_constructor(&mut self, table_constructor: &mut TableConstructor) {
+        for field in table_constructor.fields.iter_mut() {
+            match field {
+                Field::Bracketed(key, value) => {
+                    self.expression(key);
+                    self.expression(value);
+                }
+                Field::Named(name, value) => {
+                    self.check_name(name);
+                    self.expression(value);
+                }
+                Field::Positional(value) => self.expression(value),
+            }
+        }
+    }
+}
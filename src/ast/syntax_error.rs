@@ -0,0 +1,209 @@
+// Helpers for describing syntax errors and recovering from them. pest only reports the position it failed at, so
+// the source is scanned again to find the token there and the blocks which are still open.
+
+const LONG_SYMBOLS: [&str; 10] = ["...", "..", "==", "~=", "<=", ">=", "//", "::", "<<", ">>"];
+
+struct Token<'a> {
+    text: &'a str,
+    start: usize,
+    line: usize,
+}
+
+// Splits the source into tokens, skipping whitespace and comments. Strings are single tokens, an unfinished one ends
+// at the end of its line.
+fn tokens(source: &str) -> Vec<Token<'_>> {
+    let bytes = source.as_bytes();
+    let mut tokens = Vec::new();
+    let mut line = 1;
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        let start_line = line;
+        match bytes[i] {
+            b'\n' => {
+                line += 1;
+                i += 1;
+                continue;
+            }
+            byte if byte.is_ascii_whitespace() => {
+                i += 1;
+                continue;
+            }
+            b'-' if bytes.get(i + 1) == Some(&b'-') => {
+                i += 2;
+                match long_bracket_end(bytes, i) {
+                    Some(end) => i = end,
+                    None => {
+                        while i < bytes.len() && bytes[i] != b'\n' {
+                            i += 1;
+                        }
+                    }
+                }
+                line += source[start..i].matches('\n').count();
+                continue;
+            }
+            b'[' if long_bracket_end(bytes, i).is_some() => {
+                i = long_bracket_end(bytes, i).unwrap_or(bytes.len());
+            }
+            quote @ (b'"' | b'\'') => {
+                i += 1;
+                while i < bytes.len() && bytes[i] != quote && bytes[i] != b'\n' {
+                    i += if bytes[i] == b'\\' { 2 } else { 1 };
+                }
+                if bytes.get(i) == Some(&quote) {
+                    i += 1;
+                }
+                i = i.min(bytes.len());
+            }
+            byte if byte.is_ascii_alphanumeric() || byte == b'_' => {
+                let number = byte.is_ascii_digit();
+                let continues = |byte: u8| byte.is_ascii_alphanumeric() || byte == b'_' || number && byte == b'.';
+                while i < bytes.len() && continues(bytes[i]) {
+                    // Exponents like 1e-5 have a sign inside the number
+                    let exponent = number && matches!(bytes[i], b'e' | b'E' | b'p' | b'P');
+                    i += 1;
+                    if exponent && matches!(bytes.get(i), Some(b'+' | b'-')) {
+                        i += 1;
+                    }
+                }
+            }
+            _ => {
+                i += LONG_SYMBOLS
+                    .iter()
+                    .find(|symbol| source[i..].starts_with(**symbol))
+                    .map(|symbol| symbol.len())
+                    .unwrap_or_else(|| source[i..].chars().next().map(char::len_utf8).unwrap_or(1));
+            }
+        }
+        line += source[start..i].matches('\n').count();
+        tokens.push(Token { text: &source[start..i], start, line: start_line });
+    }
+    tokens
+}
+
+// Returns the index just past a long bracket like [==[ ... ]==] starting at `start`, or the end of the source if it is
+// never closed
+fn long_bracket_end(bytes: &[u8], start: usize) -> Option<usize> {
+    if bytes.get(start) != Some(&b'[') {
+        return None;
+    }
+    let level = bytes[start + 1..].iter().take_while(|byte| **byte == b'=').count();
+    if bytes.get(start + 1 + level) != Some(&b'[') {
+        return None;
+    }
+    let mut closing = vec![b']'];
+    closing.extend(std::iter::repeat_n(b'=', level));
+    closing.push(b']');
+    let contents = start + level + 2;
+    let end = bytes[contents..]
+        .windows(closing.len())
+        .position(|window| window == closing.as_slice())
+        .map(|index| contents + index + closing.len())
+        .unwrap_or(bytes.len());
+    Some(end)
+}
+
+// The token of the source at `position`, which is None at the end of the source
+pub fn token_at(source: &str, position: usize) -> Option<&str> {
+    let rest = source.get(position..).unwrap_or("");
+    let offset = rest.len() - rest.trim_start().len();
+    tokens(&rest[offset..]).first().map(|token| token.text)
+}
+
+pub fn is_unfinished_string(token: &str) -> bool {
+    match token.chars().next() {
+        Some(quote @ ('"' | '\'')) => token.len() == 1 || !token.ends_with(quote),
+        _ => false,
+    }
+}
+
+struct OpenBlock<'a> {
+    opener: &'a str,
+    line: usize,
+    // `while` and `for` are closed by an `end` after their own `do`
+    awaiting_do: bool,
+}
+
+// The innermost block or bracket which is still open at `position`, with the token that closes it and the line it was
+// opened on
+pub fn unclosed_block(source: &str, position: usize) -> Option<(String, &'static str, usize)> {
+    let mut open: Vec<OpenBlock> = Vec::new();
+    for token in tokens(source).iter().take_while(|token| token.start < position) {
+        match token.text {
+            "function" | "if" | "repeat" | "(" | "{" | "[" => {
+                open.push(OpenBlock { opener: token.text, line: token.line, awaiting_do: false })
+            }
+            "while" | "for" => open.push(OpenBlock { opener: token.text, line: token.line, awaiting_do: true }),
+            "do" => match open.last_mut() {
+                Some(block) if block.awaiting_do => block.awaiting_do = false,
+                _ => open.push(OpenBlock { opener: token.text, line: token.line, awaiting_do: false }),
+            },
+            "end" | "until" | ")" | "}" | "]" if open.last().is_some_and(|block| closer(block.opener) == token.text) => {
+                open.pop();
+            }
+            _ => {}
+        }
+    }
+    open.last().map(|block| (block.opener.to_owned(), closer(block.opener), block.line))
+}
+
+fn closer(opener: &str) -> &'static str {
+    match opener {
+        "repeat" => "until",
+        "(" => ")",
+        "{" => "}",
+        "[" => "]",
+        _ => "end",
+    }
+}
+
+// Replaces a line which failed to parse so that parsing can continue after it. Blocks opened and closed on the line are
+// kept as `do` and `end` so that the rest of the file still lines up, unless that does not fit in the line. Every
+// other character becomes a space so that positions after the line do not change.
+pub fn replace_line(source: &str, line: usize, keep_blocks: bool) -> String {
+    let Some(start) = line_start(source, line) else {
+        return source.to_owned();
+    };
+    let end = source[start..].find('\n').map(|index| start + index).unwrap_or(source.len());
+    let text = &source[start..end];
+    let mut replacement = String::new();
+    if keep_blocks {
+        let mut awaiting_do = 0;
+        for token in tokens(text) {
+            let kept = match token.text {
+                "while" | "for" => {
+                    awaiting_do += 1;
+                    "do "
+                }
+                "do" if awaiting_do > 0 => {
+                    awaiting_do -= 1;
+                    ""
+                }
+                "function" | "if" | "do" => "do ",
+                "repeat" => "repeat ",
+                "end" => "end ",
+                "until" => "until 1 ",
+                _ => "",
+            };
+            replacement.push_str(kept);
+        }
+    }
+    if replacement.len() > text.len() {
+        replacement.clear();
+    }
+    // Multibyte characters are replaced by as many spaces as they have bytes
+    let padding = text.len() - replacement.len();
+    replacement.extend(std::iter::repeat_n(' ', padding));
+    let mut patched = String::with_capacity(source.len());
+    patched.push_str(&source[..start]);
+    patched.push_str(&replacement);
+    patched.push_str(&source[end..]);
+    patched
+}
+
+fn line_start(source: &str, line: usize) -> Option<usize> {
+    if line <= 1 {
+        return Some(0);
+    }
+    source.match_indices('\n').nth(line - 2).map(|(index, _)| index + 1)
+}
@@ -0,0 +1,1240 @@
+// A C API compatible with a subset of the one of the reference implementation, so that C programs embedding Lua and
+// simple C modules can use this interpreter. Its declarations are in include/lua.h, lauxlib.h and lualib.h, the crate
+// builds a shared library exporting it.
+//
+// Like in the reference implementation, each call of a C function gets a stack of its own holding its arguments. The
+// lua_State it gets is made for that call rather than being the one of its thread. Errors raised by the API unwind the
+// C code up to the call of the function like a panic, which is where the reference implementation's longjmp would land,
+// so every function C code calls is "C-unwind". An error raised where no C function is running calls the panic function
+// and aborts, like an unprotected error in the reference implementation.
+
+use std::cell::{Cell, RefCell};
+use std::ffi::{c_char, c_int, c_void, CStr};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr::{null, null_mut};
+use std::rc::Rc;
+
+use crate::err_handle::RuntimeFailure;
+use crate::frontend::data::{DataKind, LuaString, NumberKind};
+use crate::frontend::{self, operations, Context, FunctionRef, GlobalState, LightUserData, Limits, TableRef, UserData, UserDataRef};
+
+const LUA_OK: c_int = 0;
+const LUA_ERRRUN: c_int = 2;
+const LUA_ERRSYNTAX: c_int = 3;
+const LUA_ERRMEM: c_int = 4;
+const LUA_ERRFILE: c_int = 6;
+
+const LUA_TNONE: c_int = -1;
+const LUA_TNIL: c_int = 0;
+const LUA_TBOOLEAN: c_int = 1;
+const LUA_TLIGHTUSERDATA: c_int = 2;
+const LUA_TNUMBER: c_int = 3;
+const LUA_TSTRING: c_int = 4;
+const LUA_TTABLE: c_int = 5;
+const LUA_TFUNCTION: c_int = 6;
+const LUA_TUSERDATA: c_int = 7;
+const LUA_TTHREAD: c_int = 8;
+
+const LUA_MULTRET: c_int = -1;
+const LUA_REGISTRYINDEX: c_int = -1_001_000;
+const LUA_RIDX_MAINTHREAD: i64 = 1;
+const LUA_RIDX_GLOBALS: i64 = 2;
+const LUA_REFNIL: c_int = -1;
+
+const LUA_OPEQ: c_int = 0;
+const LUA_OPLT: c_int = 1;
+const LUA_OPLE: c_int = 2;
+
+type CFunction = unsafe extern "C-unwind" fn(*mut LuaState) -> c_int;
+type Reader = unsafe extern "C-unwind" fn(*mut LuaState, *mut c_void, *mut usize) -> *const c_char;
+
+#[repr(C)]
+pub struct LuaReg {
+    name: *const c_char,
+    func: Option<CFunction>,
+}
+
+// A lua_State: the one lua_newstate returns belongs to the host, the others to a call of a C function
+pub struct LuaState {
+    state: Rc<GlobalState>,
+    stack: RefCell<Vec<DataKind>>,
+    // The upvalues of the C function, reached with lua_upvalueindex
+    upvalues: Option<Rc<RefCell<Vec<DataKind>>>>,
+    // The context the C function was called in, None for the host which gets a new one for each call it makes
+    context: Option<*mut Context<'static>>,
+    // The first error the C function raised, raised in Lua once it returns
+    error: RefCell<Option<RuntimeFailure>>,
+    panic: Cell<Option<CFunction>>,
+}
+
+// The payload of the unwinding which carries an error raised by a C function to the call of the function
+struct Raised;
+
+// The memory of a full userdata made by C code, aligned for any C type like LUAI_MAXALIGN
+#[repr(C, align(16))]
+#[derive(Clone, Copy)]
+struct MaxAlign([u8; 16]);
+
+struct Block {
+    memory: Box<[MaxAlign]>,
+    size: usize,
+}
+
+impl UserData for Block {
+    fn type_name() -> &'static str {
+        "userdata"
+    }
+}
+
+enum Slot {
+    Stack(usize),
+    Registry,
+    Upvalue(usize),
+    Invalid,
+}
+
+impl LuaState {
+    fn slot(&self, index: c_int) -> Slot {
+        let top = self.stack.borrow().len() as i64;
+        let index = index as i64;
+        let registry = LUA_REGISTRYINDEX as i64;
+        match index {
+            1.. if index <= top => Slot::Stack(index as usize - 1),
+            _ if index < 0 && index > registry && -index <= top => Slot::Stack((top + index) as usize),
+            _ if index == registry => Slot::Registry,
+            _ if index < registry => Slot::Upvalue((registry - index) as usize - 1),
+            _ => Slot::Invalid,
+        }
+    }
+    // The value at an index, None for an index past the top or an upvalue the function does not have
+    fn get(&self, index: c_int) -> Option<DataKind> {
+        match self.slot(index) {
+            Slot::Stack(slot) => Some(self.stack.borrow()[slot].clone()),
+            Slot::Registry => Some(DataKind::Table(self.state.registry.clone())),
+            Slot::Upvalue(n) => self.upvalues.as_ref().and_then(|upvalues| upvalues.borrow().get(n).cloned()),
+            Slot::Invalid => None,
+        }
+    }
+    fn value(&self, index: c_int) -> DataKind {
+        self.get(index).unwrap_or(DataKind::Null)
+    }
+    fn replace(&self, index: c_int, value: DataKind) {
+        match self.slot(index) {
+            Slot::Stack(slot) => self.stack.borrow_mut()[slot] = value,
+            Slot::Upvalue(n) => {
+                if let Some(upvalues) = &self.upvalues {
+                    if let Some(slot) = upvalues.borrow_mut().get_mut(n) {
+                        *slot = value;
+                    }
+                }
+            }
+            Slot::Registry | Slot::Invalid => {}
+        }
+    }
+    fn push(&self, value: DataKind) {
+        self.stack.borrow_mut().push(value);
+    }
+    fn pop(&self) -> DataKind {
+        self.stack.borrow_mut().pop().unwrap_or(DataKind::Null)
+    }
+    // The `n` values on top of the stack, in order
+    fn pop_values(&self, n: c_int) -> Vec<DataKind> {
+        let mut stack = self.stack.borrow_mut();
+        let at = stack.len().saturating_sub(n.max(0) as usize);
+        stack.split_off(at)
+    }
+    // Pushes the results of a call adjusted to `wanted` values, all of them for LUA_MULTRET
+    fn push_results(&self, mut values: Vec<DataKind>, wanted: c_int) {
+        if wanted != LUA_MULTRET {
+            values.resize(wanted.max(0) as usize, DataKind::Null);
+        }
+        self.stack.borrow_mut().extend(values);
+    }
+    fn push_string(&self, string: LuaString) -> *const c_char {
+        let pointer = string.as_c_ptr();
+        self.push(DataKind::String(string));
+        pointer
+    }
+}
+
+unsafe fn lua<'a>(l: *mut LuaState) -> &'a LuaState {
+    &*l
+}
+
+unsafe fn c_string(string: *const c_char) -> LuaString {
+    LuaString::from(CStr::from_ptr(string).to_bytes())
+}
+
+// Runs an operation in the context of the running C function, or in a new one for the host
+fn with_context<R>(lua: &LuaState, operation: impl FnOnce(&mut Context) -> R) -> R {
+    match lua.context {
+        // The C function runs inside of the call which got that context, nothing else uses it meanwhile
+        Some(context) => operation(unsafe { &mut *context }),
+        None => lua.state.on_host_stack(|| operation(&mut Context::new(lua.state.clone(), None))),
+    }
+}
+
+// Raises an error through the API. Inside of a C function it unwinds the C code to the call of the function, which
+// raises the error in Lua.
+unsafe fn raise(l: *mut LuaState, failure: RuntimeFailure) -> ! {
+    let lua = lua(l);
+    if lua.context.is_some() {
+        *lua.error.borrow_mut() = Some(failure);
+        std::panic::resume_unwind(Box::new(Raised));
+    }
+    let value = with_context(lua, |context| frontend::error_value(context, &failure));
+    lua.push(value.clone());
+    if let Some(panic) = lua.panic.get() {
+        panic(l);
+    }
+    eprintln!("PANIC: unprotected error in call to Lua API ({})", RuntimeFailure::ErrorObject(value).message());
+    std::process::abort();
+}
+
+// The value of an operation which can fail, its error is raised
+unsafe fn check<T>(l: *mut LuaState, result: Result<T, RuntimeFailure>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(failure) => raise(l, failure),
+    }
+}
+
+fn type_code(value: Option<&DataKind>) -> c_int {
+    match value {
+        None => LUA_TNONE,
+        Some(DataKind::Null) => LUA_TNIL,
+        Some(DataKind::Bool(_)) => LUA_TBOOLEAN,
+        Some(DataKind::LightUserData(_)) => LUA_TLIGHTUSERDATA,
+        Some(DataKind::Number(_)) => LUA_TNUMBER,
+        Some(DataKind::String(_)) => LUA_TSTRING,
+        Some(DataKind::Table(_)) => LUA_TTABLE,
+        Some(DataKind::Function(_)) => LUA_TFUNCTION,
+        Some(DataKind::UserData(_)) => LUA_TUSERDATA,
+        Some(DataKind::Thread(_)) => LUA_TTHREAD,
+    }
+}
+
+// Wraps a C function with its upvalues into a function Lua can call
+fn c_closure(function: CFunction, upvalues: Vec<DataKind>) -> FunctionRef {
+    let upvalues = Rc::new(RefCell::new(upvalues));
+    FunctionRef::internal("?", move |context, args| {
+        let call = LuaState {
+            state: context.state.clone(),
+            stack: RefCell::new(args),
+            upvalues: Some(upvalues.clone()),
+            context: Some((context as *mut Context).cast()),
+            error: RefCell::new(None),
+            panic: Cell::new(None),
+        };
+        let l = &call as *const LuaState as *mut LuaState;
+        let results = match panic::catch_unwind(AssertUnwindSafe(|| unsafe { function(l) })) {
+            Ok(results) => results,
+            Err(payload) if payload.is::<Raised>() => {
+                return Err(call.error.into_inner().expect("The raised error was recorded"));
+            }
+            Err(payload) => panic::resume_unwind(payload),
+        };
+        let mut stack = call.stack.into_inner();
+        let at = stack.len().saturating_sub(results.max(0) as usize);
+        Ok(stack.split_off(at))
+    })
+}
+
+// State manipulation
+
+#[no_mangle]
+pub extern "C-unwind" fn luaL_newstate() -> *mut LuaState {
+    let state = frontend::new_state(Limits::default());
+    let main_thread = DataKind::Thread(state.current_thread());
+    let _ = state.registry.borrow_mut().set(DataKind::integer(LUA_RIDX_MAINTHREAD), main_thread);
+    let _ = state.registry.borrow_mut().set(DataKind::integer(LUA_RIDX_GLOBALS), DataKind::Table(state.globals.clone()));
+    Box::into_raw(Box::new(LuaState {
+        state,
+        stack: RefCell::new(Vec::new()),
+        upvalues: None,
+        context: None,
+        error: RefCell::new(None),
+        panic: Cell::new(None),
+    }))
+}
+
+// Memory is managed by the interpreter, the allocator is never called
+#[no_mangle]
+pub extern "C-unwind" fn lua_newstate(_allocator: *const c_void, _user_data: *mut c_void) -> *mut LuaState {
+    luaL_newstate()
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_close(l: *mut LuaState) {
+    // The finalizers which run can still call C functions
+    frontend::close_state(&lua(l).state);
+    drop(Box::from_raw(l));
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_atpanic(l: *mut LuaState, panic: Option<CFunction>) -> Option<CFunction> {
+    lua(l).panic.replace(panic)
+}
+
+#[no_mangle]
+pub extern "C-unwind" fn lua_version(_l: *mut LuaState) -> f64 {
+    504.0
+}
+
+// The standard libraries are always open
+#[no_mangle]
+pub extern "C-unwind" fn luaL_openlibs(_l: *mut LuaState) {}
+
+// Basic stack manipulation
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_absindex(l: *mut LuaState, index: c_int) -> c_int {
+    match index {
+        _ if index > 0 || index <= LUA_REGISTRYINDEX => index,
+        _ => lua(l).stack.borrow().len() as c_int + index + 1,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_gettop(l: *mut LuaState) -> c_int {
+    lua(l).stack.borrow().len() as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_settop(l: *mut LuaState, index: c_int) {
+    let mut stack = lua(l).stack.borrow_mut();
+    let top = match index {
+        0.. => index as usize,
+        _ => (stack.len() as c_int + index + 1).max(0) as usize,
+    };
+    stack.resize(top, DataKind::Null);
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_pushvalue(l: *mut LuaState, index: c_int) {
+    let lua = lua(l);
+    lua.push(lua.value(index));
+}
+
+// Rotates the values from `index` to the top `n` positions towards the top, or away from it for a negative `n`
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_rotate(l: *mut LuaState, index: c_int, n: c_int) {
+    let start = (lua_absindex(l, index).max(1) - 1) as usize;
+    let mut stack = lua(l).stack.borrow_mut();
+    let Some(values) = stack.get_mut(start..) else {
+        return;
+    };
+    let len = values.len().max(1);
+    match n >= 0 {
+        true => values.rotate_right(n as usize % len),
+        false => values.rotate_left(n.unsigned_abs() as usize % len),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_copy(l: *mut LuaState, from: c_int, to: c_int) {
+    let lua = lua(l);
+    lua.replace(to, lua.value(from));
+}
+
+// The stack grows as needed
+#[no_mangle]
+pub extern "C-unwind" fn lua_checkstack(_l: *mut LuaState, _n: c_int) -> c_int {
+    1
+}
+
+#[no_mangle]
+pub extern "C-unwind" fn luaL_checkstack(_l: *mut LuaState, _size: c_int, _message: *const c_char) {}
+
+// Access functions (stack -> C)
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_type(l: *mut LuaState, index: c_int) -> c_int {
+    type_code(lua(l).get(index).as_ref())
+}
+
+#[no_mangle]
+pub extern "C-unwind" fn lua_typename(_l: *mut LuaState, type_code: c_int) -> *const c_char {
+    let name = match type_code {
+        LUA_TNONE => c"no value",
+        LUA_TNIL => c"nil",
+        LUA_TBOOLEAN => c"boolean",
+        LUA_TLIGHTUSERDATA | LUA_TUSERDATA => c"userdata",
+        LUA_TNUMBER => c"number",
+        LUA_TSTRING => c"string",
+        LUA_TTABLE => c"table",
+        LUA_TFUNCTION => c"function",
+        LUA_TTHREAD => c"thread",
+        _ => c"?",
+    };
+    name.as_ptr()
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_isnumber(l: *mut LuaState, index: c_int) -> c_int {
+    lua(l).value(index).coerce_to_number().is_some() as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_isstring(l: *mut LuaState, index: c_int) -> c_int {
+    matches!(lua(l).value(index), DataKind::String(_) | DataKind::Number(_)) as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_isinteger(l: *mut LuaState, index: c_int) -> c_int {
+    matches!(lua(l).value(index), DataKind::Number(NumberKind::Integer(_))) as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_iscfunction(l: *mut LuaState, index: c_int) -> c_int {
+    matches!(lua(l).value(index), DataKind::Function(function) if function.is_native()) as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_isuserdata(l: *mut LuaState, index: c_int) -> c_int {
+    matches!(lua(l).value(index), DataKind::UserData(_) | DataKind::LightUserData(_)) as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_tonumberx(l: *mut LuaState, index: c_int, is_number: *mut c_int) -> f64 {
+    let number = lua(l).value(index).coerce_to_number();
+    if !is_number.is_null() {
+        *is_number = number.is_some() as c_int;
+    }
+    number.map_or(0.0, |number| number.as_float())
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_tointegerx(l: *mut LuaState, index: c_int, is_number: *mut c_int) -> i64 {
+    let integer = lua(l).value(index).coerce_to_number().and_then(|number| number.as_integer());
+    if !is_number.is_null() {
+        *is_number = integer.is_some() as c_int;
+    }
+    integer.unwrap_or(0)
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_toboolean(l: *mut LuaState, index: c_int) -> c_int {
+    lua(l).value(index).is_true() as c_int
+}
+
+// Numbers are converted to strings in place, like in the reference implementation. The string stays valid while the
+// value is on the stack.
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_tolstring(l: *mut LuaState, index: c_int, len: *mut usize) -> *const c_char {
+    let lua = lua(l);
+    let string = match lua.get(index) {
+        Some(DataKind::String(string)) => string,
+        Some(DataKind::Number(number)) => {
+            let string = LuaString::from(number.to_string());
+            lua.replace(index, DataKind::String(string.clone()));
+            string
+        }
+        _ => {
+            if !len.is_null() {
+                *len = 0;
+            }
+            return null();
+        }
+    };
+    if !len.is_null() {
+        *len = string.len();
+    }
+    string.as_c_ptr()
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_rawlen(l: *mut LuaState, index: c_int) -> u64 {
+    match lua(l).value(index) {
+        DataKind::String(string) => string.len() as u64,
+        DataKind::Table(table) => table.length() as u64,
+        DataKind::UserData(userdata) => userdata.borrow::<Block>().map_or(0, |block| block.size as u64),
+        _ => 0,
+    }
+}
+
+// The memory of a full userdata made by lua_newuserdatauv, NULL for the userdata of Rust values
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_touserdata(l: *mut LuaState, index: c_int) -> *mut c_void {
+    match lua(l).value(index) {
+        DataKind::UserData(userdata) => userdata.borrow_mut::<Block>().map_or(null_mut(), |mut block| block.memory.as_mut_ptr().cast()),
+        DataKind::LightUserData(LightUserData(pointer)) => pointer,
+        _ => null_mut(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_topointer(l: *mut LuaState, index: c_int) -> *const c_void {
+    match lua(l).value(index) {
+        DataKind::Table(table) => table.as_ptr().cast(),
+        DataKind::Function(function) => function.as_ptr().cast(),
+        DataKind::Thread(thread) => thread.as_ptr().cast(),
+        DataKind::String(string) => string.as_c_ptr().cast(),
+        DataKind::UserData(userdata) => match lua_touserdata(l, index) {
+            pointer if pointer.is_null() => userdata.as_ptr().cast(),
+            pointer => pointer,
+        },
+        DataKind::LightUserData(LightUserData(pointer)) => pointer,
+        _ => null(),
+    }
+}
+
+// Comparison and arithmetic functions
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_rawequal(l: *mut LuaState, index1: c_int, index2: c_int) -> c_int {
+    let lua = lua(l);
+    match (lua.get(index1), lua.get(index2)) {
+        (Some(lhs), Some(rhs)) => (lhs == rhs) as c_int,
+        _ => 0,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_compare(l: *mut LuaState, index1: c_int, index2: c_int, op: c_int) -> c_int {
+    let lua = lua(l);
+    let (Some(lhs), Some(rhs)) = (lua.get(index1), lua.get(index2)) else {
+        return 0;
+    };
+    let result = with_context(lua, |context| match op {
+        LUA_OPEQ => operations::equals(context, &lhs, &rhs),
+        LUA_OPLT => operations::less_than(context, &lhs, &rhs),
+        LUA_OPLE => operations::less_equal(context, &lhs, &rhs),
+        _ => Ok(false),
+    });
+    check(l, result) as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_stringtonumber(l: *mut LuaState, string: *const c_char) -> usize {
+    let string = c_string(string);
+    match DataKind::String(string.clone()).coerce_to_number() {
+        Some(number) => {
+            lua(l).push(DataKind::Number(number));
+            string.len() + 1
+        }
+        None => 0,
+    }
+}
+
+// Push functions (C -> stack)
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_pushnil(l: *mut LuaState) {
+    lua(l).push(DataKind::Null);
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_pushnumber(l: *mut LuaState, number: f64) {
+    lua(l).push(DataKind::Number(NumberKind::Float(number)));
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_pushinteger(l: *mut LuaState, integer: i64) {
+    lua(l).push(DataKind::integer(integer));
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_pushlstring(l: *mut LuaState, string: *const c_char, len: usize) -> *const c_char {
+    let bytes = match len {
+        0 => &[][..],
+        len => std::slice::from_raw_parts(string.cast::<u8>(), len),
+    };
+    lua(l).push_string(LuaString::from(bytes))
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_pushstring(l: *mut LuaState, string: *const c_char) -> *const c_char {
+    if string.is_null() {
+        lua(l).push(DataKind::Null);
+        return null();
+    }
+    lua(l).push_string(c_string(string))
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_pushcclosure(l: *mut LuaState, function: CFunction, upvalues: c_int) {
+    let lua = lua(l);
+    let upvalues = lua.pop_values(upvalues);
+    lua.push(DataKind::Function(c_closure(function, upvalues)));
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_pushboolean(l: *mut LuaState, boolean: c_int) {
+    lua(l).push(DataKind::Bool(boolean != 0));
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_pushlightuserdata(l: *mut LuaState, pointer: *mut c_void) {
+    lua(l).push(DataKind::LightUserData(LightUserData(pointer)));
+}
+
+// Get functions (Lua -> stack)
+
+unsafe fn push_index(l: *mut LuaState, object: DataKind, key: DataKind) -> c_int {
+    let lua = lua(l);
+    let value = with_context(lua, |context| operations::index_value(context, &object, &key));
+    let value = check(l, value);
+    let type_code = type_code(Some(&value));
+    lua.push(value);
+    type_code
+}
+
+unsafe fn push_raw_index(l: *mut LuaState, object: DataKind, key: DataKind) -> c_int {
+    let value = match object {
+        DataKind::Table(table) => table.get(&key),
+        _ => DataKind::Null,
+    };
+    let type_code = type_code(Some(&value));
+    lua(l).push(value);
+    type_code
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_getglobal(l: *mut LuaState, name: *const c_char) -> c_int {
+    let globals = DataKind::Table(lua(l).state.globals.clone());
+    push_index(l, globals, DataKind::String(c_string(name)))
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_gettable(l: *mut LuaState, index: c_int) -> c_int {
+    let lua = lua(l);
+    let object = lua.value(index);
+    let key = lua.pop();
+    push_index(l, object, key)
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_getfield(l: *mut LuaState, index: c_int, key: *const c_char) -> c_int {
+    push_index(l, lua(l).value(index), DataKind::String(c_string(key)))
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_geti(l: *mut LuaState, index: c_int, key: i64) -> c_int {
+    push_index(l, lua(l).value(index), DataKind::integer(key))
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_rawget(l: *mut LuaState, index: c_int) -> c_int {
+    let lua = lua(l);
+    let object = lua.value(index);
+    let key = lua.pop();
+    push_raw_index(l, object, key)
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_rawgeti(l: *mut LuaState, index: c_int, key: i64) -> c_int {
+    push_raw_index(l, lua(l).value(index), DataKind::integer(key))
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_rawgetp(l: *mut LuaState, index: c_int, key: *const c_void) -> c_int {
+    push_raw_index(l, lua(l).value(index), DataKind::LightUserData(LightUserData(key.cast_mut())))
+}
+
+// The sizes are only hints, tables grow as needed
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_createtable(l: *mut LuaState, _array_size: c_int, _hash_size: c_int) {
+    let lua = lua(l);
+    lua.push(DataKind::Table(TableRef::new(&lua.state.heap)));
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_newuserdatauv(l: *mut LuaState, size: usize, user_values: c_int) -> *mut c_void {
+    let lua = lua(l);
+    let chunks = size.div_ceil(std::mem::size_of::<MaxAlign>());
+    let mut block = Block { memory: vec![MaxAlign([0; 16]); chunks].into_boxed_slice(), size };
+    // Moving the block does not move its memory
+    let pointer = block.memory.as_mut_ptr().cast();
+    let userdata = UserDataRef::without_metatable(&lua.state, block);
+    if user_values > 0 {
+        userdata.set_user_value(user_values as usize, DataKind::Null);
+    }
+    lua.push(DataKind::UserData(userdata));
+    pointer
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_getmetatable(l: *mut LuaState, index: c_int) -> c_int {
+    let lua = lua(l);
+    let value = lua.value(index);
+    match with_context(lua, |context| operations::get_metatable(context, &value)) {
+        Some(metatable) => {
+            lua.push(DataKind::Table(metatable));
+            1
+        }
+        None => 0,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_getiuservalue(l: *mut LuaState, index: c_int, n: c_int) -> c_int {
+    let lua = lua(l);
+    match lua.value(index) {
+        DataKind::UserData(userdata) if userdata.has_user_value(n.max(0) as usize) => {
+            let value = userdata.user_value(n as usize);
+            let type_code = type_code(Some(&value));
+            lua.push(value);
+            type_code
+        }
+        _ => {
+            lua.push(DataKind::Null);
+            LUA_TNONE
+        }
+    }
+}
+
+// Set functions (stack -> Lua)
+
+unsafe fn set_index(l: *mut LuaState, object: DataKind, key: DataKind, value: DataKind) {
+    let result = with_context(lua(l), |context| operations::set_index(context, &object, key, value));
+    check(l, result);
+}
+
+unsafe fn set_raw_index(l: *mut LuaState, object: DataKind, key: DataKind, value: DataKind) {
+    if let DataKind::Table(table) = object {
+        let result = with_context(lua(l), |context| table.set(context, key, value));
+        check(l, result);
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_setglobal(l: *mut LuaState, name: *const c_char) {
+    let lua = lua(l);
+    let value = lua.pop();
+    set_index(l, DataKind::Table(lua.state.globals.clone()), DataKind::String(c_string(name)), value);
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_settable(l: *mut LuaState, index: c_int) {
+    let lua = lua(l);
+    let object = lua.value(index);
+    let mut pair = lua.pop_values(2).into_iter();
+    let (key, value) = (pair.next().unwrap_or(DataKind::Null), pair.next().unwrap_or(DataKind::Null));
+    set_index(l, object, key, value);
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_setfield(l: *mut LuaState, index: c_int, key: *const c_char) {
+    let lua = lua(l);
+    let object = lua.value(index);
+    let value = lua.pop();
+    set_index(l, object, DataKind::String(c_string(key)), value);
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_seti(l: *mut LuaState, index: c_int, key: i64) {
+    let lua = lua(l);
+    let object = lua.value(index);
+    let value = lua.pop();
+    set_index(l, object, DataKind::integer(key), value);
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_rawset(l: *mut LuaState, index: c_int) {
+    let lua = lua(l);
+    let object = lua.value(index);
+    let mut pair = lua.pop_values(2).into_iter();
+    let (key, value) = (pair.next().unwrap_or(DataKind::Null), pair.next().unwrap_or(DataKind::Null));
+    set_raw_index(l, object, key, value);
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_rawseti(l: *mut LuaState, index: c_int, key: i64) {
+    let lua = lua(l);
+    let object = lua.value(index);
+    let value = lua.pop();
+    set_raw_index(l, object, DataKind::integer(key), value);
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_rawsetp(l: *mut LuaState, index: c_int, key: *const c_void) {
+    let lua = lua(l);
+    let object = lua.value(index);
+    let value = lua.pop();
+    set_raw_index(l, object, DataKind::LightUserData(LightUserData(key.cast_mut())), value);
+}
+
+// Finalizers only run for tables, a __gc field in the metatable of a userdata is ignored
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_setmetatable(l: *mut LuaState, index: c_int) -> c_int {
+    let lua = lua(l);
+    let object = lua.value(index);
+    let metatable = match lua.pop() {
+        DataKind::Table(metatable) => Some(metatable),
+        _ => None,
+    };
+    match object {
+        DataKind::Table(table) => {
+            if metatable.as_ref().is_some_and(|metatable| !metatable.get_str("__gc").is_nil()) {
+                lua.state.heap.set_finalizer(&table);
+            }
+            table.set_metatable(metatable);
+        }
+        DataKind::UserData(userdata) => userdata.set_metatable(metatable),
+        _ => {}
+    }
+    1
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_setiuservalue(l: *mut LuaState, index: c_int, n: c_int) -> c_int {
+    let lua = lua(l);
+    let object = lua.value(index);
+    let value = lua.pop();
+    match object {
+        DataKind::UserData(userdata) if userdata.has_user_value(n.max(0) as usize) => {
+            userdata.set_user_value(n as usize, value);
+            1
+        }
+        _ => 0,
+    }
+}
+
+// Load and call functions. Continuations are not needed to yield across a C call, so `context` and `continuation` are
+// ignored.
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_callk(l: *mut LuaState, args: c_int, results: c_int, _context: isize, _continuation: *const c_void) {
+    let lua = lua(l);
+    let args = lua.pop_values(args);
+    let function = lua.pop();
+    let values = with_context(lua, |context| frontend::call_function(context, &function, args));
+    let values = check(l, values);
+    lua.push_results(values, results);
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_pcallk(
+    l: *mut LuaState,
+    args: c_int,
+    results: c_int,
+    handler: c_int,
+    _context: isize,
+    _continuation: *const c_void,
+) -> c_int {
+    let lua = lua(l);
+    let handler = (handler != 0).then(|| lua.value(handler));
+    let args = lua.pop_values(args);
+    let function = lua.pop();
+    let failure = match with_context(lua, |context| context.protected_call(&function, args, handler)) {
+        Ok(values) => {
+            lua.push_results(values, results);
+            return LUA_OK;
+        }
+        Err(failure) => failure,
+    };
+    let status = match failure {
+        RuntimeFailure::OutOfMemory => LUA_ERRMEM,
+        _ => LUA_ERRRUN,
+    };
+    lua.push(with_context(lua, |context| frontend::error_value(context, &failure)));
+    // Errors Lua code cannot catch go on unwinding once the C function returns
+    if !failure.is_catchable() && lua.context.is_some() {
+        raise(l, failure);
+    }
+    status
+}
+
+unsafe fn load(l: *mut LuaState, code: &[u8], chunk_name: &str, mode: *const c_char) -> c_int {
+    let lua = lua(l);
+    let mode = match mode.is_null() {
+        true => &b"bt"[..],
+        false => CStr::from_ptr(mode).to_bytes(),
+    };
+    let globals = DataKind::Table(lua.state.globals.clone());
+    match frontend::load_code(&lua.state, code, chunk_name, mode, globals) {
+        Ok(function) => {
+            lua.push(DataKind::Function(function));
+            LUA_OK
+        }
+        Err(message) => {
+            lua.push(DataKind::string(&message));
+            LUA_ERRSYNTAX
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_load(
+    l: *mut LuaState,
+    reader: Reader,
+    data: *mut c_void,
+    chunk_name: *const c_char,
+    mode: *const c_char,
+) -> c_int {
+    let mut code = Vec::new();
+    loop {
+        let mut size = 0;
+        let piece = reader(l, data, &mut size);
+        if piece.is_null() || size == 0 {
+            break;
+        }
+        code.extend_from_slice(std::slice::from_raw_parts(piece.cast::<u8>(), size));
+    }
+    let chunk_name = match chunk_name.is_null() {
+        true => "?".into(),
+        false => CStr::from_ptr(chunk_name).to_string_lossy(),
+    };
+    load(l, &code, &chunk_name, mode)
+}
+
+// Miscellaneous functions
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_error(l: *mut LuaState) -> c_int {
+    let value = lua(l).pop();
+    raise(l, RuntimeFailure::ErrorObject(value))
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_next(l: *mut LuaState, index: c_int) -> c_int {
+    let lua = lua(l);
+    let object = lua.value(index);
+    let key = lua.pop();
+    let DataKind::Table(table) = object else {
+        return 0;
+    };
+    let next = table.borrow().next(&key);
+    match next {
+        Ok(Some((key, value))) => {
+            lua.push(key);
+            lua.push(value);
+            1
+        }
+        Ok(None) => 0,
+        Err(()) => raise(l, RuntimeFailure::BadOperation("invalid key to 'next'".to_owned(), 0)),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_concat(l: *mut LuaState, n: c_int) {
+    let lua = lua(l);
+    let mut values = lua.pop_values(n);
+    let result = match values.pop() {
+        None => Ok(DataKind::string("")),
+        Some(last) => with_context(lua, |context| {
+            values.into_iter().rev().try_fold(last, |result, value| operations::concat(context, value, result))
+        }),
+    };
+    lua.push(check(l, result));
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_len(l: *mut LuaState, index: c_int) {
+    let lua = lua(l);
+    let value = lua.value(index);
+    let length = with_context(lua, |context| operations::length(context, value));
+    lua.push(check(l, length));
+}
+
+// Auxiliary library
+
+unsafe fn arg_error(l: *mut LuaState, arg: c_int, message: &str) -> ! {
+    let (name, position) = with_context(lua(l), |context| (context.function_name(), context.position_prefix(1)));
+    let message = format!("{}bad argument #{} to '{}' ({})", position, arg, name.as_deref().unwrap_or("?"), message);
+    raise(l, RuntimeFailure::ErrorObject(DataKind::string(&message)))
+}
+
+unsafe fn type_error(l: *mut LuaState, arg: c_int, expected: &str) -> ! {
+    let lua = lua(l);
+    let got = match lua.get(arg) {
+        None => "no value".to_owned(),
+        Some(DataKind::LightUserData(_)) => "light userdata".to_owned(),
+        Some(value) => match with_context(lua, |context| operations::get_metamethod(context, &value, "__name")) {
+            DataKind::String(name) => name.to_string(),
+            _ => value.type_name().to_owned(),
+        },
+    };
+    arg_error(l, arg, &format!("{} expected, got {}", expected, got))
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_argerror(l: *mut LuaState, arg: c_int, message: *const c_char) -> c_int {
+    arg_error(l, arg, &CStr::from_ptr(message).to_string_lossy())
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_typeerror(l: *mut LuaState, arg: c_int, type_name: *const c_char) -> c_int {
+    type_error(l, arg, &CStr::from_ptr(type_name).to_string_lossy())
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_checknumber(l: *mut LuaState, arg: c_int) -> f64 {
+    let mut is_number = 0;
+    let number = lua_tonumberx(l, arg, &mut is_number);
+    if is_number == 0 {
+        type_error(l, arg, "number");
+    }
+    number
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_optnumber(l: *mut LuaState, arg: c_int, default: f64) -> f64 {
+    match lua_type(l, arg) <= LUA_TNIL {
+        true => default,
+        false => luaL_checknumber(l, arg),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_checkinteger(l: *mut LuaState, arg: c_int) -> i64 {
+    let mut is_number = 0;
+    let integer = lua_tointegerx(l, arg, &mut is_number);
+    if is_number == 0 {
+        match lua_isnumber(l, arg) {
+            0 => type_error(l, arg, "number"),
+            _ => arg_error(l, arg, "number has no integer representation"),
+        };
+    }
+    integer
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_optinteger(l: *mut LuaState, arg: c_int, default: i64) -> i64 {
+    match lua_type(l, arg) <= LUA_TNIL {
+        true => default,
+        false => luaL_checkinteger(l, arg),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_checklstring(l: *mut LuaState, arg: c_int, len: *mut usize) -> *const c_char {
+    let string = lua_tolstring(l, arg, len);
+    if string.is_null() {
+        type_error(l, arg, "string");
+    }
+    string
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_optlstring(l: *mut LuaState, arg: c_int, default: *const c_char, len: *mut usize) -> *const c_char {
+    if lua_type(l, arg) > LUA_TNIL {
+        return luaL_checklstring(l, arg, len);
+    }
+    if !len.is_null() {
+        *len = if default.is_null() { 0 } else { CStr::from_ptr(default).count_bytes() };
+    }
+    default
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_checktype(l: *mut LuaState, arg: c_int, expected: c_int) {
+    if lua_type(l, arg) != expected {
+        type_error(l, arg, &CStr::from_ptr(lua_typename(l, expected)).to_string_lossy());
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_checkany(l: *mut LuaState, arg: c_int) {
+    if lua_type(l, arg) == LUA_TNONE {
+        arg_error(l, arg, "value expected");
+    }
+}
+
+// Pushes the "chunk:line: " position of the function `level` levels up the call stack, which luaL_error starts its
+// message with
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_where(l: *mut LuaState, level: c_int) {
+    let lua = lua(l);
+    let position = with_context(lua, |context| context.position_prefix(level.max(0) as usize));
+    lua.push(DataKind::string(&position));
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_tolstring(l: *mut LuaState, index: c_int, len: *mut usize) -> *const c_char {
+    let lua = lua(l);
+    let value = lua.value(index);
+    let string = with_context(lua, |context| operations::to_string(context, &value));
+    let string = check(l, string);
+    if !len.is_null() {
+        *len = string.len();
+    }
+    lua.push_string(string)
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_len(l: *mut LuaState, index: c_int) -> i64 {
+    lua_len(l, index);
+    let length = lua(l).pop();
+    match length {
+        DataKind::Number(NumberKind::Integer(length)) => length,
+        _ => raise(l, RuntimeFailure::BadOperation("object length is not an integer".to_owned(), 0)),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_getmetafield(l: *mut LuaState, index: c_int, event: *const c_char) -> c_int {
+    let lua = lua(l);
+    let value = lua.value(index);
+    let event = CStr::from_ptr(event).to_string_lossy();
+    let field = with_context(lua, |context| operations::get_metamethod(context, &value, &event));
+    match field {
+        DataKind::Null => LUA_TNIL,
+        field => {
+            let type_code = type_code(Some(&field));
+            lua.push(field);
+            type_code
+        }
+    }
+}
+
+// Only the stack of the running thread can be traced, `thread` is ignored
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_traceback(l: *mut LuaState, _thread: *mut LuaState, message: *const c_char, level: c_int) {
+    let lua = lua(l);
+    let traceback = with_context(lua, |context| context.traceback(level.max(0) as usize));
+    let traceback = match message.is_null() {
+        true => traceback,
+        false => format!("{}\n{}", CStr::from_ptr(message).to_string_lossy(), traceback),
+    };
+    lua.push(DataKind::string(&traceback));
+}
+
+// Stores the value on top of the stack in the table at `index` under a free integer key, which it returns
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_ref(l: *mut LuaState, index: c_int) -> c_int {
+    let lua = lua(l);
+    let object = lua.value(index);
+    let value = lua.pop();
+    match (object, value) {
+        (_, DataKind::Null) => LUA_REFNIL,
+        (DataKind::Table(table), value) => {
+            // The key after a border is always free
+            let reference = table.length() + 1;
+            let _ = table.borrow_mut().set(DataKind::integer(reference), value);
+            reference as c_int
+        }
+        _ => LUA_REFNIL,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_unref(l: *mut LuaState, index: c_int, reference: c_int) {
+    if let (DataKind::Table(table), 0..) = (lua(l).value(index), reference) {
+        let _ = table.borrow_mut().set(DataKind::integer(reference as i64), DataKind::Null);
+    }
+}
+
+// Pushes the metatable registered under `name`, making it first if there is none. Returns whether it was made.
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_newmetatable(l: *mut LuaState, name: *const c_char) -> c_int {
+    let lua = lua(l);
+    let name = c_string(name);
+    let existing = lua.state.registry.get(&DataKind::String(name.clone()));
+    if !existing.is_nil() {
+        lua.push(existing);
+        return 0;
+    }
+    let metatable = TableRef::new(&lua.state.heap);
+    metatable.set_str("__name", DataKind::String(name.clone()));
+    let _ = lua.state.registry.borrow_mut().set(DataKind::String(name), DataKind::Table(metatable.clone()));
+    lua.push(DataKind::Table(metatable));
+    1
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_setmetatable(l: *mut LuaState, name: *const c_char) {
+    let lua = lua(l);
+    lua.push(lua.state.registry.get(&DataKind::String(c_string(name))));
+    lua_setmetatable(l, -2);
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_testudata(l: *mut LuaState, index: c_int, name: *const c_char) -> *mut c_void {
+    let lua = lua(l);
+    let DataKind::UserData(userdata) = lua.value(index) else {
+        return null_mut();
+    };
+    match (userdata.metatable(), lua.state.registry.get(&DataKind::String(c_string(name)))) {
+        (Some(metatable), DataKind::Table(expected)) if metatable == expected => lua_touserdata(l, index),
+        _ => null_mut(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_checkudata(l: *mut LuaState, index: c_int, name: *const c_char) -> *mut c_void {
+    let pointer = luaL_testudata(l, index, name);
+    if pointer.is_null() {
+        type_error(l, index, &CStr::from_ptr(name).to_string_lossy());
+    }
+    pointer
+}
+
+// Sets the functions of a NULL terminated list in the table below the `upvalues` values on top of the stack, each of
+// them gets those values as its upvalues. A NULL function is a placeholder set to false.
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_setfuncs(l: *mut LuaState, list: *const LuaReg, upvalues: c_int) {
+    let lua = lua(l);
+    let upvalues = lua.pop_values(upvalues);
+    let table = lua.value(-1);
+    let mut entry = list;
+    while !(*entry).name.is_null() {
+        let value = match (*entry).func {
+            Some(function) => DataKind::Function(c_closure(function, upvalues.clone())),
+            None => DataKind::Bool(false),
+        };
+        set_index(l, table.clone(), DataKind::String(c_string((*entry).name)), value);
+        entry = entry.add(1);
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_loadstring(l: *mut LuaState, code: *const c_char) -> c_int {
+    let code = CStr::from_ptr(code);
+    load(l, code.to_bytes(), &code.to_string_lossy(), null())
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_loadbufferx(
+    l: *mut LuaState,
+    code: *const c_char,
+    size: usize,
+    chunk_name: *const c_char,
+    mode: *const c_char,
+) -> c_int {
+    let code = match size {
+        0 => &[][..],
+        size => std::slice::from_raw_parts(code.cast::<u8>(), size),
+    };
+    let chunk_name = match chunk_name.is_null() {
+        true => String::from_utf8_lossy(code),
+        false => CStr::from_ptr(chunk_name).to_string_lossy(),
+    };
+    load(l, code, &chunk_name, mode)
+}
+
+// Loads a file, or the standard input when `file_name` is NULL. A first line starting with '#' is skipped, so that
+// scripts can start with a shebang.
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_loadfilex(l: *mut LuaState, file_name: *const c_char, mode: *const c_char) -> c_int {
+    let (name, code) = match file_name.is_null() {
+        true => {
+            let mut code = Vec::new();
+            let result = std::io::Read::read_to_end(&mut std::io::stdin(), &mut code);
+            ("stdin".to_owned(), result.map(|_| code))
+        }
+        false => {
+            let name = CStr::from_ptr(file_name).to_string_lossy().into_owned();
+            let code = std::fs::read(&name);
+            (name, code)
+        }
+    };
+    let mut code = match code {
+        Ok(code) => code,
+        Err(error) => {
+            lua(l).push(DataKind::string(&format!("cannot open {}: {}", name, error)));
+            return LUA_ERRFILE;
+        }
+    };
+    // The newline stays so that lines keep their numbers
+    if code.starts_with(b"#") {
+        let end = code.iter().position(|&byte| byte == b'\n').unwrap_or(code.len());
+        code.drain(..end);
+    }
+    let chunk_name = match file_name.is_null() {
+        true => "=stdin".to_owned(),
+        false => format!("@{}", name),
+    };
+    load(l, &code, &chunk_name, mode)
+}
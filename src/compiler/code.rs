@@ -0,0 +1,1204 @@
+// Code generation for expressions, a port of the reference implementation's lcode.c. Expressions are compiled lazily:
+// an ExpDesc describes where a value can be found, and instructions are only emitted once the consumer of the value
+// decides which register it must end up in.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::ast::lua_program::{BinaryOperator, BitwiseOperator, BooleanOperator, MathOperator, Span, UnaryOperator};
+use crate::compiler::opcode::{Instruction, OpCode, MAX_B, MAX_BX, MAX_C, OFFSET_SBX, OFFSET_SC, OFFSET_SJ};
+use crate::compiler::prototype::Prototype;
+use crate::err_handle::CompileError;
+use crate::frontend::data::{bitwise_integers, float_to_integer, DataKind, LuaString, NumberKind};
+
+pub type CompileResult<T> = Result<T, CompileError>;
+
+// Marks the end of a jump list
+pub const NO_JUMP: isize = -1;
+// Register A of a TESTSET which only tests and does not copy its value
+pub const NO_REG: u32 = MAX_B;
+// Register 255 is never used so that NO_REG stays free
+const MAX_REGISTERS: u32 = 255;
+// The result count of a call or `...` which keeps all of its values
+pub const MULTIPLE_RESULTS: i32 = -1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExpKind {
+    // An empty expression list
+    Void,
+    Nil,
+    True,
+    False,
+    // A constant in the constant table, strings are always stored there
+    K(usize),
+    KFloat(f64),
+    KInt(i64),
+    // A value in a register
+    NonReloc(u32),
+    Local(u32),
+    Upval(u32),
+    // table[key] with both in registers
+    Indexed { table: u32, key: u32 },
+    // A field of an upvalue, the key is a string constant
+    IndexUp { table: u32, key: u32 },
+    // table[key] with an integer constant key
+    IndexInt { table: u32, key: u32 },
+    // table[key] with a string constant key
+    IndexStr { table: u32, key: u32 },
+    // A comparison, the pc of its jump
+    Jmp(usize),
+    // An instruction whose result register is not decided yet
+    Reloc(usize),
+    Call(usize),
+    Vararg(usize),
+}
+
+// An expression being compiled. `t` and `f` are the lists of jumps to take when the expression is true or false.
+#[derive(Debug, Clone, Copy)]
+pub struct ExpDesc {
+    pub kind: ExpKind,
+    pub t: isize,
+    pub f: isize,
+}
+
+impl ExpDesc {
+    pub fn new(kind: ExpKind) -> Self {
+        Self { kind, t: NO_JUMP, f: NO_JUMP }
+    }
+    fn has_jumps(&self) -> bool {
+        self.t != self.f
+    }
+    // The register of a discharged expression
+    pub fn register(&self) -> u32 {
+        match self.kind {
+            ExpKind::NonReloc(register) | ExpKind::Local(register) => register,
+            kind => unreachable!("Expression {:?} is not in a register", kind),
+        }
+    }
+    pub fn has_multiple_results(&self) -> bool {
+        matches!(self.kind, ExpKind::Call(_) | ExpKind::Vararg(_))
+    }
+    fn numeral(&self) -> Option<NumberKind> {
+        match self.kind {
+            ExpKind::KInt(int) if !self.has_jumps() => Some(NumberKind::Integer(int)),
+            ExpKind::KFloat(float) if !self.has_jumps() => Some(NumberKind::Float(float)),
+            _ => None,
+        }
+    }
+    // An integer which fits into the signed C operand
+    fn small_integer(&self) -> Option<i64> {
+        match self.kind {
+            ExpKind::KInt(int) if !self.has_jumps() && fits_c(int) => Some(int),
+            _ => None,
+        }
+    }
+    // An integer which fits into the C operand of GETI and SETI
+    fn index_integer(&self) -> Option<u32> {
+        match self.kind {
+            ExpKind::KInt(int) if !self.has_jumps() && (0..=MAX_C as i64).contains(&int) => Some(int as u32),
+            _ => None,
+        }
+    }
+    // A number which can be the immediate operand of a comparison, and whether it is a float
+    fn immediate_number(&self) -> Option<(i64, bool)> {
+        match self.kind {
+            ExpKind::KInt(int) if !self.has_jumps() && fits_c(int) => Some((int, false)),
+            ExpKind::KFloat(float) if !self.has_jumps() => match float_to_integer(float) {
+                Some(int) if fits_c(int) => Some((int, true)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+fn fits_c(int: i64) -> bool {
+    (-(OFFSET_SC as i64)..=(MAX_C as i64 - OFFSET_SC as i64)).contains(&int)
+}
+
+fn fits_bx(int: i64) -> bool {
+    (-(OFFSET_SBX as i64)..=(MAX_BX as i64 - OFFSET_SBX as i64)).contains(&int)
+}
+
+fn immediate(int: i64) -> u32 {
+    (int + OFFSET_SC as i64) as u32
+}
+
+// Constants are deduplicated by value, floats by their bits so that 0.0 and -0.0 stay apart
+#[derive(PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Nil,
+    Bool(bool),
+    Integer(i64),
+    Float(u64),
+    String(LuaString),
+}
+
+// A local in scope, the n-th active local lives in register n
+pub struct ActiveLocal {
+    pub name: String,
+    // Its entry in the prototype's local_variables
+    pub debug_index: usize,
+}
+
+pub struct BlockScope {
+    // The number of active locals when the block was entered
+    pub active_count: usize,
+    pub first_label: usize,
+    pub first_goto: usize,
+    pub is_loop: bool,
+    // Whether a local of the block is captured by a closure or to-be-closed, leaving the block must close it
+    pub has_upvalue: bool,
+    pub inside_tbc: bool,
+}
+
+pub struct LabelEntry {
+    pub name: String,
+    pub pc: usize,
+    pub active_count: usize,
+}
+
+// A goto waiting for its label to be declared
+pub struct GotoEntry {
+    pub name: String,
+    pub pc: usize,
+    pub active_count: usize,
+    // Whether the jump leaves the scope of a local which must be closed
+    pub close: bool,
+}
+
+// The state of a function being compiled
+pub struct FunctionState {
+    pub proto: Prototype,
+    constant_indices: HashMap<ConstantKey, usize>,
+    pub active: Vec<ActiveLocal>,
+    // The first register which is not in use
+    pub first_free: u32,
+    pub blocks: Vec<BlockScope>,
+    // Labels visible in the block being compiled
+    pub labels: Vec<LabelEntry>,
+    pub gotos: Vec<GotoEntry>,
+    // The last pc which is the target of a jump, instructions before it must not be merged with later ones
+    last_target: usize,
+    // Whether returning must close upvalues or to-be-closed variables
+    pub needs_close: bool,
+    // Where the code being compiled is, instructions get the line of this span
+    pub span: Span,
+}
+
+impl FunctionState {
+    pub fn new(source: Rc<str>, span: Span, last_line_defined: usize) -> Self {
+        Self {
+            proto: Prototype {
+                source,
+                line_defined: span.line,
+                last_line_defined,
+                num_params: 0,
+                is_vararg: false,
+                max_stack_size: 2,
+                code: Vec::new(),
+                line_info: Vec::new(),
+                constants: Vec::new(),
+                upvalues: Vec::new(),
+                prototypes: Vec::new(),
+                local_variables: Vec::new(),
+            },
+            constant_indices: HashMap::new(),
+            active: Vec::new(),
+            first_free: 0,
+            blocks: Vec::new(),
+            labels: Vec::new(),
+            gotos: Vec::new(),
+            last_target: 0,
+            needs_close: false,
+            span,
+        }
+    }
+
+    pub fn error(&self, message: &str) -> CompileError {
+        CompileError::new(message, (self.span.line, self.span.column))
+    }
+    // Describes the function for errors about its limits, like "main function" or "function at line 3"
+    pub fn limit_error(&self, limit: usize, what: &str) -> CompileError {
+        let location = match self.proto.line_defined {
+            0 => "main function".to_owned(),
+            line => format!("function at line {}", line),
+        };
+        self.error(&format!("too many {} (limit is {}) in {}", what, limit, location))
+    }
+
+    pub fn pc(&self) -> usize {
+        self.proto.code.len()
+    }
+    pub fn code(&mut self, instruction: Instruction) -> usize {
+        self.proto.code.push(instruction);
+        self.proto.line_info.push(self.span.line);
+        self.pc() - 1
+    }
+    pub fn code_abc(&mut self, op: OpCode, a: u32, b: u32, c: u32) -> usize {
+        self.code(Instruction::abc(op, a, b, c, false))
+    }
+    pub fn code_abck(&mut self, op: OpCode, a: u32, b: u32, c: u32, k: bool) -> usize {
+        self.code(Instruction::abc(op, a, b, c, k))
+    }
+    pub fn code_abx(&mut self, op: OpCode, a: u32, bx: u32) -> usize {
+        self.code(Instruction::abx(op, a, bx))
+    }
+    fn remove_last_instruction(&mut self) {
+        self.proto.code.pop();
+        self.proto.line_info.pop();
+    }
+    // The last instruction, unless a jump targets the current pc and it must be left alone
+    fn previous_instruction(&self) -> Option<usize> {
+        match self.pc() > self.last_target {
+            true => Some(self.pc() - 1),
+            false => None,
+        }
+    }
+
+    // Jumps
+
+    pub fn jump(&mut self) -> usize {
+        self.code(Instruction::sj(OpCode::Jmp, NO_JUMP as i32))
+    }
+    pub fn ret(&mut self, first: u32, count: i32) {
+        let op = match count {
+            0 => OpCode::Return0,
+            1 => OpCode::Return1,
+            _ => OpCode::Return,
+        };
+        self.code_abc(op, first, (count + 1) as u32, 0);
+    }
+    fn condition_jump(&mut self, op: OpCode, a: u32, b: u32, c: u32, k: bool) -> usize {
+        self.code_abck(op, a, b, c, k);
+        self.jump()
+    }
+    // Marks the current pc as a jump target and returns it
+    pub fn get_label(&mut self) -> usize {
+        self.last_target = self.pc();
+        self.pc()
+    }
+    fn get_jump(&self, pc: usize) -> isize {
+        match self.proto.code[pc].sj_arg() as isize {
+            NO_JUMP => NO_JUMP,
+            offset => pc as isize + 1 + offset,
+        }
+    }
+    fn fix_jump(&mut self, pc: usize, destination: usize) -> CompileResult<()> {
+        let offset = destination as isize - (pc as isize + 1);
+        if offset.abs() > OFFSET_SJ as isize {
+            return Err(self.error("control structure too long"));
+        }
+        self.proto.code[pc].set_sj(offset as i32);
+        Ok(())
+    }
+    pub fn concat_jumps(&mut self, list: &mut isize, other: isize) -> CompileResult<()> {
+        if other == NO_JUMP {
+            return Ok(());
+        }
+        if *list == NO_JUMP {
+            *list = other;
+            return Ok(());
+        }
+        let mut last = *list as usize;
+        while let next @ 0.. = self.get_jump(last) {
+            last = next as usize;
+        }
+        self.fix_jump(last, other as usize)
+    }
+    // The instruction which decides whether a jump is taken, the test before it if there is one
+    fn jump_control(&self, pc: usize) -> usize {
+        match pc >= 1 && self.proto.code[pc - 1].opcode().is_test() {
+            true => pc - 1,
+            false => pc,
+        }
+    }
+    // Makes the TESTSET controlling a jump copy its value to `register`, or turns it into a TEST when no register is
+    // given or the value is already there. False if the jump is not controlled by a TESTSET.
+    fn patch_test_register(&mut self, node: usize, register: u32) -> bool {
+        let index = self.jump_control(node);
+        let instruction = self.proto.code[index];
+        if instruction.opcode() != OpCode::TestSet {
+            return false;
+        }
+        if register != NO_REG && register != instruction.b() {
+            self.proto.code[index].set_a(register);
+        } else {
+            self.proto.code[index] = Instruction::abc(OpCode::Test, instruction.b(), 0, 0, instruction.k());
+        }
+        true
+    }
+    fn remove_values(&mut self, mut list: isize) {
+        while list != NO_JUMP {
+            self.patch_test_register(list as usize, NO_REG);
+            list = self.get_jump(list as usize);
+        }
+    }
+    // Jumps which produce a value go to `value_target` with the value in `register`, the others to `default_target`
+    fn patch_list_aux(&mut self, mut list: isize, value_target: usize, register: u32, default_target: isize) -> CompileResult<()> {
+        while list != NO_JUMP {
+            let next = self.get_jump(list as usize);
+            if self.patch_test_register(list as usize, register) {
+                self.fix_jump(list as usize, value_target)?;
+            } else {
+                self.fix_jump(list as usize, default_target as usize)?;
+            }
+            list = next;
+        }
+        Ok(())
+    }
+    pub fn patch_list(&mut self, list: isize, target: usize) -> CompileResult<()> {
+        self.patch_list_aux(list, target, NO_REG, target as isize)
+    }
+    pub fn patch_to_here(&mut self, list: isize) -> CompileResult<()> {
+        let here = self.get_label();
+        self.patch_list(list, here)
+    }
+
+    // Registers
+
+    // The number of registers holding locals
+    pub fn active_registers(&self) -> u32 {
+        self.active.len() as u32
+    }
+    pub fn check_stack(&mut self, count: u32) -> CompileResult<()> {
+        let new_stack = self.first_free + count;
+        if new_stack > self.proto.max_stack_size as u32 {
+            if new_stack >= MAX_REGISTERS {
+                return Err(self.error("function or expression needs too many registers"));
+            }
+            self.proto.max_stack_size = new_stack as u8;
+        }
+        Ok(())
+    }
+    pub fn reserve_registers(&mut self, count: u32) -> CompileResult<()> {
+        self.check_stack(count)?;
+        self.first_free += count;
+        Ok(())
+    }
+    // Temporaries are freed in the reverse order they were reserved in, locals are never freed here
+    fn free_register(&mut self, register: u32) {
+        if register >= self.active_registers() {
+            self.first_free -= 1;
+            debug_assert_eq!(register, self.first_free, "Registers must be freed in order");
+        }
+    }
+    fn free_registers(&mut self, first: u32, second: u32) {
+        if first > second {
+            self.free_register(first);
+            self.free_register(second);
+        } else {
+            self.free_register(second);
+            self.free_register(first);
+        }
+    }
+    fn free_expression(&mut self, e: &ExpDesc) {
+        if let ExpKind::NonReloc(register) = e.kind {
+            self.free_register(register);
+        }
+    }
+    fn free_expressions(&mut self, e1: &ExpDesc, e2: &ExpDesc) {
+        match (e1.kind, e2.kind) {
+            (ExpKind::NonReloc(first), ExpKind::NonReloc(second)) => self.free_registers(first, second),
+            (ExpKind::NonReloc(register), _) | (_, ExpKind::NonReloc(register)) => self.free_register(register),
+            _ => {}
+        }
+    }
+
+    // Constants
+
+    fn add_constant(&mut self, key: ConstantKey, value: DataKind) -> usize {
+        if let Some(index) = self.constant_indices.get(&key) {
+            return *index;
+        }
+        let index = self.proto.constants.len();
+        self.proto.constants.push(value);
+        self.constant_indices.insert(key, index);
+        index
+    }
+    pub fn string_constant(&mut self, string: &[u8]) -> usize {
+        let string = LuaString::from(string);
+        self.add_constant(ConstantKey::String(string.clone()), DataKind::String(string))
+    }
+    fn integer_constant(&mut self, int: i64) -> usize {
+        self.add_constant(ConstantKey::Integer(int), DataKind::integer(int))
+    }
+    fn float_constant(&mut self, float: f64) -> usize {
+        self.add_constant(ConstantKey::Float(float.to_bits()), DataKind::Number(NumberKind::Float(float)))
+    }
+    fn bool_constant(&mut self, bool: bool) -> usize {
+        self.add_constant(ConstantKey::Bool(bool), DataKind::Bool(bool))
+    }
+    fn nil_constant(&mut self) -> usize {
+        self.add_constant(ConstantKey::Nil, DataKind::Null)
+    }
+    fn is_string_constant(&self, e: &ExpDesc) -> bool {
+        match e.kind {
+            ExpKind::K(index) if !e.has_jumps() && index <= MAX_B as usize => {
+                matches!(self.proto.constants[index], DataKind::String(_))
+            }
+            _ => false,
+        }
+    }
+
+    // Loading values
+
+    pub fn load_nil(&mut self, from: u32, count: u32) {
+        // Merges with a LOADNIL right before this one when their ranges touch
+        if let Some(pc) = self.previous_instruction() {
+            let previous = self.proto.code[pc];
+            if previous.opcode() == OpCode::LoadNil {
+                let (previous_from, previous_last) = (previous.a(), previous.a() + previous.b());
+                let last = from + count - 1;
+                if (previous_from <= from && from <= previous_last + 1) || (from <= previous_from && previous_from <= last + 1) {
+                    let (from, last) = (from.min(previous_from), last.max(previous_last));
+                    self.proto.code[pc].set_a(from);
+                    self.proto.code[pc].set_b(last - from);
+                    return;
+                }
+            }
+        }
+        self.code_abc(OpCode::LoadNil, from, count - 1, 0);
+    }
+    fn load_constant(&mut self, register: u32, index: usize) {
+        if index <= MAX_BX as usize {
+            self.code_abx(OpCode::LoadK, register, index as u32);
+        } else {
+            self.code_abx(OpCode::LoadKX, register, 0);
+            self.code(Instruction::ax(OpCode::ExtraArg, index as u32));
+        }
+    }
+    pub fn load_integer(&mut self, register: u32, int: i64) {
+        if fits_bx(int) {
+            self.code(Instruction::asbx(OpCode::LoadI, register, int as i32));
+        } else {
+            let index = self.integer_constant(int);
+            self.load_constant(register, index);
+        }
+    }
+    fn load_float(&mut self, register: u32, float: f64) {
+        match float_to_integer(float) {
+            // LOADF cannot produce -0.0
+            Some(int) if fits_bx(int) && !(float == 0.0 && float.is_sign_negative()) => {
+                self.code(Instruction::asbx(OpCode::LoadF, register, int as i32));
+            }
+            _ => {
+                let index = self.float_constant(float);
+                self.load_constant(register, index);
+            }
+        }
+    }
+
+    // Discharging expressions into registers
+
+    // Turns variables into values, emitting the instruction that reads them
+    pub fn discharge_vars(&mut self, e: &mut ExpDesc) {
+        e.kind = match e.kind {
+            ExpKind::Local(register) => ExpKind::NonReloc(register),
+            ExpKind::Upval(index) => ExpKind::Reloc(self.code_abc(OpCode::GetUpval, 0, index, 0)),
+            ExpKind::IndexUp { table, key } => ExpKind::Reloc(self.code_abc(OpCode::GetTabUp, 0, table, key)),
+            ExpKind::IndexInt { table, key } => {
+                self.free_register(table);
+                ExpKind::Reloc(self.code_abc(OpCode::GetI, 0, table, key))
+            }
+            ExpKind::IndexStr { table, key } => {
+                self.free_register(table);
+                ExpKind::Reloc(self.code_abc(OpCode::GetField, 0, table, key))
+            }
+            ExpKind::Indexed { table, key } => {
+                self.free_registers(table, key);
+                ExpKind::Reloc(self.code_abc(OpCode::GetTable, 0, table, key))
+            }
+            ExpKind::Call(_) | ExpKind::Vararg(_) => {
+                self.set_one_result(e);
+                return;
+            }
+            kind => kind,
+        }
+    }
+    // Truncates a call or `...` to its first value
+    pub fn set_one_result(&mut self, e: &mut ExpDesc) {
+        match e.kind {
+            ExpKind::Call(pc) => e.kind = ExpKind::NonReloc(self.proto.code[pc].a()),
+            ExpKind::Vararg(pc) => {
+                self.proto.code[pc].set_c(2);
+                e.kind = ExpKind::Reloc(pc);
+            }
+            _ => {}
+        }
+    }
+    pub fn set_returns(&mut self, e: &mut ExpDesc, results: i32) -> CompileResult<()> {
+        match e.kind {
+            ExpKind::Call(pc) => self.proto.code[pc].set_c((results + 1) as u32),
+            ExpKind::Vararg(pc) => {
+                let register = self.first_free;
+                self.proto.code[pc].set_c((results + 1) as u32);
+                self.proto.code[pc].set_a(register);
+                self.reserve_registers(1)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+    fn discharge_to_register(&mut self, e: &mut ExpDesc, register: u32) {
+        self.discharge_vars(e);
+        match e.kind {
+            ExpKind::Nil => self.load_nil(register, 1),
+            ExpKind::False => {
+                self.code_abc(OpCode::LoadFalse, register, 0, 0);
+            }
+            ExpKind::True => {
+                self.code_abc(OpCode::LoadTrue, register, 0, 0);
+            }
+            ExpKind::K(index) => self.load_constant(register, index),
+            ExpKind::KFloat(float) => self.load_float(register, float),
+            ExpKind::KInt(int) => self.load_integer(register, int),
+            ExpKind::Reloc(pc) => self.proto.code[pc].set_a(register),
+            ExpKind::NonReloc(source) => {
+                if source != register {
+                    self.code_abc(OpCode::Move, register, source, 0);
+                }
+            }
+            ExpKind::Jmp(_) => return,
+            kind => unreachable!("Expression {:?} cannot be put in a register", kind),
+        }
+        e.kind = ExpKind::NonReloc(register);
+    }
+    fn discharge_to_any_register(&mut self, e: &mut ExpDesc) -> CompileResult<()> {
+        if !matches!(e.kind, ExpKind::NonReloc(_)) {
+            self.reserve_registers(1)?;
+            self.discharge_to_register(e, self.first_free - 1);
+        }
+        Ok(())
+    }
+    fn code_load_bool(&mut self, register: u32, op: OpCode) -> usize {
+        self.get_label();
+        self.code_abc(op, register, 0, 0)
+    }
+    // Whether a jump list has a jump that does not produce its value with a TESTSET
+    fn need_value(&self, mut list: isize) -> bool {
+        while list != NO_JUMP {
+            if self.proto.code[self.jump_control(list as usize)].opcode() != OpCode::TestSet {
+                return true;
+            }
+            list = self.get_jump(list as usize);
+        }
+        false
+    }
+    fn expression_to_register(&mut self, e: &mut ExpDesc, register: u32) -> CompileResult<()> {
+        self.discharge_to_register(e, register);
+        if let ExpKind::Jmp(pc) = e.kind {
+            self.concat_jumps(&mut e.t, pc as isize)?;
+        }
+        if e.has_jumps() {
+            let (mut load_false, mut load_true) = (NO_JUMP, NO_JUMP);
+            if self.need_value(e.t) || self.need_value(e.f) {
+                let skip = match e.kind {
+                    ExpKind::Jmp(_) => NO_JUMP,
+                    _ => self.jump() as isize,
+                };
+                load_false = self.code_load_bool(register, OpCode::LFalseSkip) as isize;
+                load_true = self.code_load_bool(register, OpCode::LoadTrue) as isize;
+                self.patch_to_here(skip)?;
+            }
+            let end = self.get_label();
+            self.patch_list_aux(e.f, end, register, load_false)?;
+            self.patch_list_aux(e.t, end, register, load_true)?;
+        }
+        *e = ExpDesc::new(ExpKind::NonReloc(register));
+        Ok(())
+    }
+    pub fn expression_to_next_register(&mut self, e: &mut ExpDesc) -> CompileResult<()> {
+        self.discharge_vars(e);
+        self.free_expression(e);
+        self.reserve_registers(1)?;
+        self.expression_to_register(e, self.first_free - 1)
+    }
+    pub fn expression_to_any_register(&mut self, e: &mut ExpDesc) -> CompileResult<u32> {
+        self.discharge_vars(e);
+        if let ExpKind::NonReloc(register) = e.kind {
+            if !e.has_jumps() {
+                return Ok(register);
+            }
+            // A temporary can take the value of its jumps itself
+            if register >= self.active_registers() {
+                self.expression_to_register(e, register)?;
+                return Ok(register);
+            }
+        }
+        self.expression_to_next_register(e)?;
+        Ok(e.register())
+    }
+    pub fn expression_to_any_register_or_upvalue(&mut self, e: &mut ExpDesc) -> CompileResult<()> {
+        if !matches!(e.kind, ExpKind::Upval(_)) || e.has_jumps() {
+            self.expression_to_any_register(e)?;
+        }
+        Ok(())
+    }
+    pub fn expression_to_value(&mut self, e: &mut ExpDesc) -> CompileResult<()> {
+        match e.has_jumps() {
+            true => self.expression_to_any_register(e).map(|_| ()),
+            false => {
+                self.discharge_vars(e);
+                Ok(())
+            }
+        }
+    }
+    // Moves a constant expression to the constant table, false when it is not a constant or its index is too large
+    fn expression_to_constant(&mut self, e: &mut ExpDesc) -> bool {
+        if e.has_jumps() {
+            return false;
+        }
+        let index = match e.kind {
+            ExpKind::True => self.bool_constant(true),
+            ExpKind::False => self.bool_constant(false),
+            ExpKind::Nil => self.nil_constant(),
+            ExpKind::KInt(int) => self.integer_constant(int),
+            ExpKind::KFloat(float) => self.float_constant(float),
+            ExpKind::K(index) => index,
+            _ => return false,
+        };
+        if index <= MAX_B as usize {
+            e.kind = ExpKind::K(index);
+            true
+        } else {
+            false
+        }
+    }
+    // Puts an operand in a register or the constant table, true for a constant
+    fn expression_to_rk(&mut self, e: &mut ExpDesc) -> CompileResult<bool> {
+        if self.expression_to_constant(e) {
+            return Ok(true);
+        }
+        self.expression_to_any_register(e)?;
+        Ok(false)
+    }
+    fn code_abrk(&mut self, op: OpCode, a: u32, b: u32, e: &mut ExpDesc) -> CompileResult<()> {
+        let c = match self.expression_to_rk(e)? {
+            true => match e.kind {
+                ExpKind::K(index) => index as u32,
+                _ => unreachable!("The operand was made a constant"),
+            },
+            false => e.register(),
+        };
+        self.code_abck(op, a, b, c, matches!(e.kind, ExpKind::K(_)));
+        Ok(())
+    }
+
+    // Variables and indexing
+
+    pub fn store_var(&mut self, var: &ExpDesc, e: &mut ExpDesc) -> CompileResult<()> {
+        match var.kind {
+            ExpKind::Local(register) => {
+                self.free_expression(e);
+                return self.expression_to_register(e, register);
+            }
+            ExpKind::Upval(index) => {
+                let register = self.expression_to_any_register(e)?;
+                self.code_abc(OpCode::SetUpval, register, index, 0);
+            }
+            ExpKind::IndexUp { table, key } => self.code_abrk(OpCode::SetTabUp, table, key, e)?,
+            ExpKind::IndexInt { table, key } => self.code_abrk(OpCode::SetI, table, key, e)?,
+            ExpKind::IndexStr { table, key } => self.code_abrk(OpCode::SetField, table, key, e)?,
+            ExpKind::Indexed { table, key } => self.code_abrk(OpCode::SetTable, table, key, e)?,
+            kind => unreachable!("Cannot assign to {:?}", kind),
+        }
+        self.free_expression(e);
+        Ok(())
+    }
+    // object:method, puts the method and the object in two consecutive registers
+    pub fn self_method(&mut self, e: &mut ExpDesc, key: &mut ExpDesc) -> CompileResult<()> {
+        let object = self.expression_to_any_register(e)?;
+        self.free_expression(e);
+        let base = self.first_free;
+        e.kind = ExpKind::NonReloc(base);
+        self.reserve_registers(2)?;
+        self.code_abrk(OpCode::SelfMethod, base, object, key)?;
+        self.free_expression(key);
+        Ok(())
+    }
+    // table[key], the table must already be in a register or an upvalue
+    pub fn indexed(&mut self, table: &mut ExpDesc, key: &mut ExpDesc) -> CompileResult<()> {
+        // Upvalues can only be indexed by string constants
+        if matches!(table.kind, ExpKind::Upval(_)) && !self.is_string_constant(key) {
+            self.expression_to_any_register(table)?;
+        }
+        table.kind = match (table.kind, key.kind) {
+            (ExpKind::Upval(upvalue), ExpKind::K(index)) => ExpKind::IndexUp { table: upvalue, key: index as u32 },
+            (ExpKind::Local(register) | ExpKind::NonReloc(register), key_kind) => {
+                if self.is_string_constant(key) {
+                    let ExpKind::K(index) = key_kind else { unreachable!("String constants are K expressions") };
+                    ExpKind::IndexStr { table: register, key: index as u32 }
+                } else if let Some(int) = key.index_integer() {
+                    ExpKind::IndexInt { table: register, key: int }
+                } else {
+                    ExpKind::Indexed { table: register, key: self.expression_to_any_register(key)? }
+                }
+            }
+            (kind, _) => unreachable!("Cannot index {:?}", kind),
+        };
+        Ok(())
+    }
+    // Prepares an expression list target before `var` is assigned: earlier targets that index with `var` must use its
+    // old value, which is copied to a free register
+    pub fn check_conflict(&mut self, previous: &mut [ExpDesc], var: &ExpDesc) -> CompileResult<()> {
+        let extra = self.first_free;
+        let mut conflict = false;
+        for target in previous.iter_mut() {
+            match (&mut target.kind, var.kind) {
+                (ExpKind::IndexUp { table, key }, ExpKind::Upval(upvalue)) if *table == upvalue => {
+                    conflict = true;
+                    target.kind = ExpKind::IndexStr { table: extra, key: *key };
+                }
+                (ExpKind::IndexStr { table, .. } | ExpKind::IndexInt { table, .. }, ExpKind::Local(register))
+                    if *table == register =>
+                {
+                    conflict = true;
+                    *table = extra;
+                }
+                (ExpKind::Indexed { table, key }, ExpKind::Local(register)) => {
+                    if *table == register {
+                        conflict = true;
+                        *table = extra;
+                    }
+                    if *key == register {
+                        conflict = true;
+                        *key = extra;
+                    }
+                }
+                _ => {}
+            }
+        }
+        if conflict {
+            match var.kind {
+                ExpKind::Local(register) => self.code_abc(OpCode::Move, extra, register, 0),
+                ExpKind::Upval(index) => self.code_abc(OpCode::GetUpval, extra, index, 0),
+                kind => unreachable!("Only variables can conflict, got {:?}", kind),
+            };
+            self.reserve_registers(1)?;
+        }
+        Ok(())
+    }
+
+    // Conditions
+
+    fn negate_condition(&mut self, pc: usize) {
+        let index = self.jump_control(pc);
+        let k = self.proto.code[index].k();
+        self.proto.code[index].set_k(!k);
+    }
+    // Emits a jump taken when the expression's truthiness is `condition`
+    fn jump_on_condition(&mut self, e: &mut ExpDesc, condition: bool) -> CompileResult<usize> {
+        if let ExpKind::Reloc(pc) = e.kind {
+            let instruction = self.proto.code[pc];
+            // `not x` tests x with the condition flipped
+            if instruction.opcode() == OpCode::Not {
+                self.remove_last_instruction();
+                return Ok(self.condition_jump(OpCode::Test, instruction.b(), 0, 0, !condition));
+            }
+        }
+        self.discharge_to_any_register(e)?;
+        self.free_expression(e);
+        Ok(self.condition_jump(OpCode::TestSet, NO_REG, e.register(), 0, condition))
+    }
+    // Falls through when the expression is true, jumps through its false list otherwise
+    pub fn go_if_true(&mut self, e: &mut ExpDesc) -> CompileResult<()> {
+        self.discharge_vars(e);
+        let jump = match e.kind {
+            ExpKind::Jmp(pc) => {
+                self.negate_condition(pc);
+                pc as isize
+            }
+            ExpKind::K(_) | ExpKind::KFloat(_) | ExpKind::KInt(_) | ExpKind::True => NO_JUMP,
+            _ => self.jump_on_condition(e, false)? as isize,
+        };
+        self.concat_jumps(&mut e.f, jump)?;
+        self.patch_to_here(e.t)?;
+        e.t = NO_JUMP;
+        Ok(())
+    }
+    pub fn go_if_false(&mut self, e: &mut ExpDesc) -> CompileResult<()> {
+        self.discharge_vars(e);
+        let jump = match e.kind {
+            ExpKind::Jmp(pc) => pc as isize,
+            ExpKind::Nil | ExpKind::False => NO_JUMP,
+            _ => self.jump_on_condition(e, true)? as isize,
+        };
+        self.concat_jumps(&mut e.t, jump)?;
+        self.patch_to_here(e.f)?;
+        e.f = NO_JUMP;
+        Ok(())
+    }
+    fn code_not(&mut self, e: &mut ExpDesc) -> CompileResult<()> {
+        match e.kind {
+            ExpKind::Nil | ExpKind::False => e.kind = ExpKind::True,
+            ExpKind::K(_) | ExpKind::KFloat(_) | ExpKind::KInt(_) | ExpKind::True => e.kind = ExpKind::False,
+            ExpKind::Jmp(pc) => self.negate_condition(pc),
+            ExpKind::Reloc(_) | ExpKind::NonReloc(_) => {
+                self.discharge_to_any_register(e)?;
+                self.free_expression(e);
+                let register = e.register();
+                e.kind = ExpKind::Reloc(self.code_abc(OpCode::Not, 0, register, 0));
+            }
+            kind => unreachable!("Cannot negate {:?}", kind),
+        }
+        std::mem::swap(&mut e.t, &mut e.f);
+        self.remove_values(e.f);
+        self.remove_values(e.t);
+        Ok(())
+    }
+
+    // Operators
+
+    pub fn prefix(&mut self, op: UnaryOperator, e: &mut ExpDesc) -> CompileResult<()> {
+        self.discharge_vars(e);
+        match op {
+            UnaryOperator::UnaryMinus | UnaryOperator::BitwiseUnaryNot => {
+                if let Some(folded) = fold_unary(op, e) {
+                    e.kind = folded;
+                    return Ok(());
+                }
+                let op = match op {
+                    UnaryOperator::UnaryMinus => OpCode::Unm,
+                    _ => OpCode::BNot,
+                };
+                self.code_unary(op, e)
+            }
+            UnaryOperator::Length => self.code_unary(OpCode::Len, e),
+            UnaryOperator::Not => self.code_not(e),
+        }
+    }
+    fn code_unary(&mut self, op: OpCode, e: &mut ExpDesc) -> CompileResult<()> {
+        let register = self.expression_to_any_register(e)?;
+        self.free_expression(e);
+        e.kind = ExpKind::Reloc(self.code_abc(op, 0, register, 0));
+        Ok(())
+    }
+    // Prepares the first operand of a binary operator before the second one is compiled
+    pub fn infix(&mut self, op: &BinaryOperator, e: &mut ExpDesc) -> CompileResult<()> {
+        self.discharge_vars(e);
+        match op {
+            BinaryOperator::BooleanOperator(BooleanOperator::And) => self.go_if_true(e),
+            BinaryOperator::BooleanOperator(BooleanOperator::Or) => self.go_if_false(e),
+            BinaryOperator::Concat => self.expression_to_next_register(e),
+            // Numerals are kept for constant folding and immediate operands
+            BinaryOperator::MathOperator(_) | BinaryOperator::BitwiseOperator(_) => {
+                if e.numeral().is_none() {
+                    self.expression_to_any_register(e)?;
+                }
+                Ok(())
+            }
+            BinaryOperator::BooleanOperator(BooleanOperator::Equal | BooleanOperator::Unequal) => {
+                if e.numeral().is_none() {
+                    self.expression_to_rk(e)?;
+                }
+                Ok(())
+            }
+            BinaryOperator::BooleanOperator(_) => {
+                if e.immediate_number().is_none() {
+                    self.expression_to_any_register(e)?;
+                }
+                Ok(())
+            }
+        }
+    }
+    // Finishes a binary operator once both of its operands are compiled, the result is left in e1
+    pub fn posfix(&mut self, op: &BinaryOperator, e1: &mut ExpDesc, mut e2: ExpDesc) -> CompileResult<()> {
+        self.discharge_vars(&mut e2);
+        if let Some(folded) = fold_binary(op, e1, &e2) {
+            e1.kind = folded;
+            return Ok(());
+        }
+        match op {
+            BinaryOperator::BooleanOperator(BooleanOperator::And) => {
+                self.concat_jumps(&mut e2.f, e1.f)?;
+                *e1 = e2;
+            }
+            BinaryOperator::BooleanOperator(BooleanOperator::Or) => {
+                self.concat_jumps(&mut e2.t, e1.t)?;
+                *e1 = e2;
+            }
+            BinaryOperator::Concat => {
+                self.expression_to_next_register(&mut e2)?;
+                self.code_concat(e1, &e2);
+            }
+            BinaryOperator::MathOperator(op @ (MathOperator::Plus | MathOperator::Multiply)) => {
+                self.code_commutative(*op, e1, &mut e2)?;
+            }
+            BinaryOperator::MathOperator(op) => self.code_arithmetic(*op, e1, &mut e2, false)?,
+            BinaryOperator::BitwiseOperator(
+                op @ (BitwiseOperator::And | BitwiseOperator::Or | BitwiseOperator::ExclusiveOr),
+            ) => self.code_bitwise(*op, e1, &mut e2)?,
+            BinaryOperator::BitwiseOperator(BitwiseOperator::LeftShift) => {
+                if e1.small_integer().is_some() {
+                    // `imm << x` has its own instruction
+                    std::mem::swap(e1, &mut e2);
+                    self.code_binary_immediate(OpCode::ShlI, e1, &e2, false)?;
+                } else {
+                    self.code_binary_registers(OpCode::Shl, e1, &mut e2)?;
+                }
+            }
+            BinaryOperator::BitwiseOperator(BitwiseOperator::RightShift) => {
+                if e2.small_integer().is_some() {
+                    self.code_binary_immediate(OpCode::ShrI, e1, &e2, false)?;
+                } else {
+                    self.code_binary_registers(OpCode::Shr, e1, &mut e2)?;
+                }
+            }
+            BinaryOperator::BooleanOperator(BooleanOperator::Equal) => self.code_equal(true, e1, &mut e2)?,
+            BinaryOperator::BooleanOperator(BooleanOperator::Unequal) => self.code_equal(false, e1, &mut e2)?,
+            BinaryOperator::BooleanOperator(BooleanOperator::LessThan) => self.code_order(false, e1, &mut e2)?,
+            BinaryOperator::BooleanOperator(BooleanOperator::LessThanEqualTo) => self.code_order(true, e1, &mut e2)?,
+            // a > b is compiled as b < a
+            BinaryOperator::BooleanOperator(BooleanOperator::GreaterThan) => {
+                std::mem::swap(e1, &mut e2);
+                self.code_order(false, e1, &mut e2)?;
+            }
+            BinaryOperator::BooleanOperator(BooleanOperator::GreaterThanEqualTo) => {
+                std::mem::swap(e1, &mut e2);
+                self.code_order(true, e1, &mut e2)?;
+            }
+        }
+        Ok(())
+    }
+    // Emits a binary instruction reading e1 from a register and `operand` as its C operand
+    fn finish_binary(&mut self, op: OpCode, e1: &mut ExpDesc, e2: &ExpDesc, operand: u32, flip: bool) -> CompileResult<()> {
+        let register = self.expression_to_any_register(e1)?;
+        let pc = self.code_abck(op, 0, register, operand, flip);
+        self.free_expressions(e1, e2);
+        e1.kind = ExpKind::Reloc(pc);
+        Ok(())
+    }
+    fn code_binary_registers(&mut self, op: OpCode, e1: &mut ExpDesc, e2: &mut ExpDesc) -> CompileResult<()> {
+        let register = self.expression_to_any_register(e2)?;
+        self.finish_binary(op, e1, e2, register, false)
+    }
+    fn code_binary_immediate(&mut self, op: OpCode, e1: &mut ExpDesc, e2: &ExpDesc, flip: bool) -> CompileResult<()> {
+        let int = e2.small_integer().expect("The operand is a small integer");
+        self.finish_binary(op, e1, e2, immediate(int), flip)
+    }
+    fn code_binary_constant(&mut self, op: OpCode, e1: &mut ExpDesc, e2: &ExpDesc, flip: bool) -> CompileResult<()> {
+        let ExpKind::K(index) = e2.kind else { unreachable!("The operand is a constant") };
+        self.finish_binary(op, e1, e2, index as u32, flip)
+    }
+    // The operands were swapped to put a constant second, without a constant they are swapped back
+    fn code_binary_no_constant(&mut self, op: OpCode, e1: &mut ExpDesc, e2: &mut ExpDesc, flip: bool) -> CompileResult<()> {
+        if flip {
+            std::mem::swap(e1, e2);
+        }
+        self.code_binary_registers(op, e1, e2)
+    }
+    fn code_arithmetic(&mut self, op: MathOperator, e1: &mut ExpDesc, e2: &mut ExpDesc, flip: bool) -> CompileResult<()> {
+        if e2.numeral().is_some() && self.expression_to_constant(e2) {
+            self.code_binary_constant(arithmetic_constant_opcode(op), e1, e2, flip)
+        } else {
+            self.code_binary_no_constant(arithmetic_opcode(op), e1, e2, flip)
+        }
+    }
+    // Puts a numeral operand second, where it can be a constant or an immediate
+    fn code_commutative(&mut self, op: MathOperator, e1: &mut ExpDesc, e2: &mut ExpDesc) -> CompileResult<()> {
+        let mut flip = false;
+        if e1.numeral().is_some() {
+            std::mem::swap(e1, e2);
+            flip = true;
+        }
+        if matches!(op, MathOperator::Plus) && e2.small_integer().is_some() {
+            self.code_binary_immediate(OpCode::AddI, e1, e2, flip)
+        } else {
+            self.code_arithmetic(op, e1, e2, flip)
+        }
+    }
+    fn code_bitwise(&mut self, op: BitwiseOperator, e1: &mut ExpDesc, e2: &mut ExpDesc) -> CompileResult<()> {
+        let mut flip = false;
+        if matches!(e1.kind, ExpKind::KInt(_)) {
+            std::mem::swap(e1, e2);
+            flip = true;
+        }
+        if matches!(e2.kind, ExpKind::KInt(_)) && self.expression_to_constant(e2) {
+            self.code_binary_constant(bitwise_constant_opcode(op), e1, e2, flip)
+        } else {
+            self.code_binary_no_constant(bitwise_opcode(op), e1, e2, flip)
+        }
+    }
+    // `a .. b .. c` is right associative, a CONCAT of b and c is extended to take a as well
+    fn code_concat(&mut self, e1: &mut ExpDesc, e2: &ExpDesc) {
+        if let Some(pc) = self.previous_instruction() {
+            let previous = self.proto.code[pc];
+            if previous.opcode() == OpCode::Concat {
+                self.free_expression(e2);
+                self.proto.code[pc].set_a(e1.register());
+                self.proto.code[pc].set_b(previous.b() + 1);
+                return;
+            }
+        }
+        self.code_abc(OpCode::Concat, e1.register(), 2, 0);
+        self.free_expression(e2);
+    }
+    fn code_equal(&mut self, equal: bool, e1: &mut ExpDesc, e2: &mut ExpDesc) -> CompileResult<()> {
+        // A constant or numeral first operand is moved second
+        if !matches!(e1.kind, ExpKind::NonReloc(_)) {
+            std::mem::swap(e1, e2);
+        }
+        let first = self.expression_to_any_register(e1)?;
+        let (op, second, is_float) = if let Some((int, is_float)) = e2.immediate_number() {
+            (OpCode::EqI, immediate(int), is_float)
+        } else if self.expression_to_rk(e2)? {
+            let ExpKind::K(index) = e2.kind else { unreachable!("The operand was made a constant") };
+            (OpCode::EqK, index as u32, false)
+        } else {
+            (OpCode::Eq, e2.register(), false)
+        };
+        self.free_expressions(e1, e2);
+        e1.kind = ExpKind::Jmp(self.condition_jump(op, first, second, is_float as u32, equal));
+        Ok(())
+    }
+    fn code_order(&mut self, or_equal: bool, e1: &mut ExpDesc, e2: &mut ExpDesc) -> CompileResult<()> {
+        let (op, first, second, is_float) = if let Some((int, is_float)) = e2.immediate_number() {
+            let first = self.expression_to_any_register(e1)?;
+            let op = if or_equal { OpCode::LeI } else { OpCode::LtI };
+            (op, first, immediate(int), is_float)
+        } else if let Some((int, is_float)) = e1.immediate_number() {
+            // imm < x is x > imm
+            let first = self.expression_to_any_register(e2)?;
+            let op = if or_equal { OpCode::GeI } else { OpCode::GtI };
+            (op, first, immediate(int), is_float)
+        } else {
+            let first = self.expression_to_any_register(e1)?;
+            let second = self.expression_to_any_register(e2)?;
+            let op = if or_equal { OpCode::Le } else { OpCode::Lt };
+            (op, first, second, false)
+        };
+        self.free_expressions(e1, e2);
+        e1.kind = ExpKind::Jmp(self.condition_jump(op, first, second, is_float as u32, true));
+        Ok(())
+    }
+
+    // Table constructors
+
+    // Stores `count` list items above the table register starting after `stored` items, MULTIPLE_RESULTS stores all
+    // values up to the top of the stack
+    pub fn set_list(&mut self, base: u32, stored: u32, count: i32) -> CompileResult<()> {
+        let b = match count {
+            MULTIPLE_RESULTS => 0,
+            count => count as u32,
+        };
+        if stored <= MAX_C {
+            self.code_abc(OpCode::SetList, base, b, stored);
+        } else {
+            let extra = stored / (MAX_C + 1);
+            self.code_abck(OpCode::SetList, base, b, stored % (MAX_C + 1), true);
+            self.code(Instruction::ax(OpCode::ExtraArg, extra));
+        }
+        self.first_free = base + 1;
+        Ok(())
+    }
+    // Sizes the table of a NEWTABLE, at `pc` with its EXTRAARG after it
+    pub fn set_table_size(&mut self, pc: usize, register: u32, array_size: u32, hash_size: u32) {
+        let hash_bits = match hash_size {
+            0 => 0,
+            size => size.next_power_of_two().trailing_zeros() + 1,
+        };
+        let extra = array_size / (MAX_C + 1);
+        let array_low = array_size % (MAX_C + 1);
+        self.proto.code[pc] = Instruction::abc(OpCode::NewTable, register, hash_bits.min(MAX_B), array_low, extra > 0);
+        self.proto.code[pc + 1] = Instruction::ax(OpCode::ExtraArg, extra);
+    }
+
+    // Finishing the function
+
+    // Returns of functions that must close something close before returning, which RETURN0 and RETURN1 cannot do
+    pub fn finish(&mut self) {
+        if !self.needs_close {
+            return;
+        }
+        for instruction in self.proto.code.iter_mut() {
+            match instruction.opcode() {
+                OpCode::Return0 | OpCode::Return1 | OpCode::Return => {
+                    instruction.set_opcode(OpCode::Return);
+                    instruction.set_k(true);
+                }
+                OpCode::TailCall => instruction.set_k(true),
+                _ => {}
+            }
+        }
+    }
+}
+
+fn arithmetic_opcode(op: MathOperator) -> OpCode {
+    match op {
+        MathOperator::Plus => OpCode::Add,
+        MathOperator::Minus => OpCode::Sub,
+        MathOperator::Multiply => OpCode::Mul,
+        MathOperator::FloatDivision => OpCode::Div,
+        MathOperator::FloorDivision => OpCode::IDiv,
+        MathOperator::Exponent => OpCode::Pow,
+        MathOperator::Mod => OpCode::Mod,
+    }
+}
+
+fn arithmetic_constant_opcode(op: MathOperator) -> OpCode {
+    match op {
+        MathOperator::Plus => OpCode::AddK,
+        MathOperator::Minus => OpCode::SubK,
+        MathOperator::Multiply => OpCode::MulK,
+        MathOperator::FloatDivision => OpCode::DivK,
+        MathOperator::FloorDivision => OpCode::IDivK,
+        MathOperator::Exponent => OpCode::PowK,
+        MathOperator::Mod => OpCode::ModK,
+    }
+}
+
+fn bitwise_opcode(op: BitwiseOperator) -> OpCode {
+    match op {
+        BitwiseOperator::And => OpCode::BAnd,
+        BitwiseOperator::Or => OpCode::BOr,
+        BitwiseOperator::ExclusiveOr => OpCode::BXor,
+        BitwiseOperator::LeftShift => OpCode::Shl,
+        BitwiseOperator::RightShift => OpCode::Shr,
+    }
+}
+
+fn bitwise_constant_opcode(op: BitwiseOperator) -> OpCode {
+    match op {
+        BitwiseOperator::And => OpCode::BAndK,
+        BitwiseOperator::Or => OpCode::BOrK,
+        BitwiseOperator::ExclusiveOr => OpCode::BXorK,
+        _ => unreachable!("Shifts have no constant operand form"),
+    }
+}
+
+fn number_expression(number: NumberKind) -> Option<ExpKind> {
+    match number {
+        NumberKind::Integer(int) => Some(ExpKind::KInt(int)),
+        // NaN and zeros are not folded, -0.0 and 0.0 would be merged as constants
+        NumberKind::Float(float) if float.is_nan() || float == 0.0 => None,
+        NumberKind::Float(float) => Some(ExpKind::KFloat(float)),
+    }
+}
+
+fn fold_unary(op: UnaryOperator, e: &ExpDesc) -> Option<ExpKind> {
+    let number = e.numeral()?;
+    match op {
+        UnaryOperator::UnaryMinus => number_expression(number.negate()),
+        UnaryOperator::BitwiseUnaryNot => Some(ExpKind::KInt(!number.as_integer()?)),
+        _ => None,
+    }
+}
+
+// Computes operators on numerals at compile time, unless the result would be an error or an odd float
+fn fold_binary(op: &BinaryOperator, e1: &ExpDesc, e2: &ExpDesc) -> Option<ExpKind> {
+    let (lhs, rhs) = (e1.numeral()?, e2.numeral()?);
+    match op {
+        BinaryOperator::MathOperator(op) => {
+            let divides = matches!(op, MathOperator::FloatDivision | MathOperator::FloorDivision | MathOperator::Mod);
+            if divides && rhs.as_float() == 0.0 {
+                return None;
+            }
+            number_expression(lhs.arithmetic(*op, &rhs)?)
+        }
+        BinaryOperator::BitwiseOperator(op) => {
+            Some(ExpKind::KInt(bitwise_integers(*op, lhs.as_integer()?, rhs.as_integer()?)))
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,600 @@
+// Saves compiled functions as binary chunks and loads them back, like the reference implementation's ldump.c and
+// lundump.c. The layout follows Lua 5.4's, but our opcodes are numbered differently, so the header carries a format of
+// our own and chunks made by `luac` are refused. A checksum after the function catches chunks changed after they were
+// written, and loaded code is verified so that its operands name registers of its frame, constants and upvalues which
+// exist and jumps stay in its code. Which registers hold live variables is not verified: a changed chunk can leave an
+// upvalue open after its register is gone or jump into a numeric for loop past its FORPREP, the virtual machine raises
+// an error when it gets there.
+
+use std::rc::Rc;
+
+use crate::compiler::opcode::{Instruction, OpCode};
+use crate::compiler::prototype::{LocalVariable, Prototype, UpvalueDescriptor};
+use crate::frontend::data::{DataKind, LuaString, NumberKind};
+
+pub const SIGNATURE: &[u8] = b"\x1bLua";
+const VERSION: u8 = 0x54;
+const FORMAT: u8 = 0x52;
+// Catches chunks mangled by a text mode transfer, which changes line endings
+const DATA: &[u8] = b"\x19\x93\r\n\x1a\n";
+// Catch chunks written by a machine with another integer or float representation
+const TEST_INTEGER: i64 = 0x5678;
+const TEST_FLOAT: f64 = 370.5;
+
+// Type tags of constants, the same as the reference implementation's
+const TAG_NIL: u8 = 0x00;
+const TAG_FALSE: u8 = 0x01;
+const TAG_TRUE: u8 = 0x11;
+const TAG_INTEGER: u8 = 0x03;
+const TAG_FLOAT: u8 = 0x13;
+const TAG_STRING: u8 = 0x04;
+
+// Deeper nesting than the compiler allows is refused instead of recursing without a bound
+const MAX_NESTING: usize = 200;
+
+// The name of the source of a function whose debug information was stripped
+const STRIPPED_SOURCE: &str = "=?";
+
+// Serializes a function and the functions nested in it. Stripping drops the debug information: line numbers, the names
+// of locals and upvalues and the source name.
+pub fn dump(prototype: &Prototype, strip: bool) -> Vec<u8> {
+    let mut writer = Writer { bytes: Vec::new(), strip };
+    writer.bytes.extend_from_slice(SIGNATURE);
+    writer.bytes.push(VERSION);
+    writer.bytes.push(FORMAT);
+    writer.bytes.extend_from_slice(DATA);
+    writer.bytes.push(std::mem::size_of::<Instruction>() as u8);
+    writer.bytes.push(std::mem::size_of::<i64>() as u8);
+    writer.bytes.push(std::mem::size_of::<f64>() as u8);
+    writer.bytes.extend_from_slice(&TEST_INTEGER.to_le_bytes());
+    writer.bytes.extend_from_slice(&TEST_FLOAT.to_le_bytes());
+    writer.bytes.push(prototype.upvalues.len() as u8);
+    let body_start = writer.bytes.len();
+    writer.function(prototype, None);
+    let checksum = checksum(&writer.bytes[body_start..]);
+    writer.bytes.extend_from_slice(&checksum.to_le_bytes());
+    writer.bytes
+}
+
+// Loads a binary chunk, errors are the reason the chunk is refused like "truncated chunk"
+pub fn undump(bytes: &[u8]) -> Result<Rc<Prototype>, String> {
+    let mut reader = Reader { bytes, position: 0 };
+    reader.header()?;
+    let upvalue_count = reader.byte()?;
+    let body_start = reader.position;
+    let prototype = reader.function(None, 0)?;
+    let body_end = reader.position;
+    let checksum_bytes = reader.take(8)?;
+    if checksum(&bytes[body_start..body_end]).to_le_bytes() != checksum_bytes {
+        return Err("checksum mismatch".to_owned());
+    }
+    if prototype.upvalues.len() != upvalue_count as usize {
+        return Err("corrupted chunk".to_owned());
+    }
+    verify(&prototype)?;
+    Ok(Rc::new(prototype))
+}
+
+// The most bytes a size takes once written, 7 bits of it per byte
+const MAX_SIZE_BYTES: usize = 10;
+
+// An upper bound of the length of the chunk `dump` makes, so that the memory limit can be checked before writing it
+pub fn dumped_size(prototype: &Prototype, strip: bool) -> usize {
+    let header = SIGNATURE.len() + DATA.len() + 5 + 16 + 1;
+    header + function_size(prototype, strip) + 8
+}
+
+fn function_size(prototype: &Prototype, strip: bool) -> usize {
+    let string = |len: usize| MAX_SIZE_BYTES + len;
+    let constants = prototype.constants.iter().map(|constant| match constant {
+        DataKind::String(constant) => 1 + string(constant.len()),
+        _ => 9,
+    });
+    let children = prototype.prototypes.iter().map(|child| function_size(child, strip));
+    let mut size = string(prototype.source.len())
+        + 8 * MAX_SIZE_BYTES
+        + 3
+        + prototype.code.len() * std::mem::size_of::<Instruction>()
+        + constants.sum::<usize>()
+        + prototype.upvalues.len() * 2
+        + children.sum::<usize>();
+    if !strip {
+        size += prototype.line_info.len() * MAX_SIZE_BYTES;
+        size += prototype.local_variables.iter().map(|local| string(local.name.len()) + 2 * MAX_SIZE_BYTES).sum::<usize>();
+        size += prototype.upvalues.iter().map(|upvalue| string(upvalue.name.len())).sum::<usize>();
+    }
+    size
+}
+
+// FNV-1a, enough to notice a changed byte without pulling in a dependency
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+    strip: bool,
+}
+
+impl Writer {
+    // Sizes are written most significant group first, 7 bits per byte, with the high bit marking the last byte
+    fn size(&mut self, value: usize) {
+        let mut groups = vec![(value & 0x7f) as u8 | 0x80];
+        let mut rest = value >> 7;
+        while rest != 0 {
+            groups.push((rest & 0x7f) as u8);
+            rest >>= 7;
+        }
+        self.bytes.extend(groups.into_iter().rev());
+    }
+
+    // Strings are prefixed with their size plus one, a size of 0 is a missing string
+    fn string(&mut self, string: Option<&[u8]>) {
+        match string {
+            Some(string) => {
+                self.size(string.len() + 1);
+                self.bytes.extend_from_slice(string);
+            }
+            None => self.size(0),
+        }
+    }
+
+    fn function(&mut self, prototype: &Prototype, parent_source: Option<&str>) {
+        // Nested functions share the source of the function they are in, so it is only written when it differs
+        let source = match self.strip || parent_source == Some(&*prototype.source) {
+            true => None,
+            false => Some(prototype.source.as_bytes()),
+        };
+        self.string(source);
+        self.size(prototype.line_defined);
+        self.size(prototype.last_line_defined);
+        self.bytes.push(prototype.num_params);
+        self.bytes.push(prototype.is_vararg as u8);
+        self.bytes.push(prototype.max_stack_size);
+
+        self.size(prototype.code.len());
+        for instruction in &prototype.code {
+            self.bytes.extend_from_slice(&instruction.0.to_le_bytes());
+        }
+
+        self.size(prototype.constants.len());
+        for constant in &prototype.constants {
+            match constant {
+                DataKind::Null => self.bytes.push(TAG_NIL),
+                DataKind::Bool(false) => self.bytes.push(TAG_FALSE),
+                DataKind::Bool(true) => self.bytes.push(TAG_TRUE),
+                DataKind::Number(NumberKind::Integer(integer)) => {
+                    self.bytes.push(TAG_INTEGER);
+                    self.bytes.extend_from_slice(&integer.to_le_bytes());
+                }
+                DataKind::Number(NumberKind::Float(float)) => {
+                    self.bytes.push(TAG_FLOAT);
+                    self.bytes.extend_from_slice(&float.to_le_bytes());
+                }
+                DataKind::String(string) => {
+                    self.bytes.push(TAG_STRING);
+                    self.string(Some(string.as_bytes()));
+                }
+                constant => unreachable!("The compiler does not make {} constants", constant.type_name()),
+            }
+        }
+
+        self.size(prototype.upvalues.len());
+        for upvalue in &prototype.upvalues {
+            self.bytes.push(upvalue.in_stack as u8);
+            self.bytes.push(upvalue.index);
+        }
+
+        self.size(prototype.prototypes.len());
+        for child in &prototype.prototypes {
+            self.function(child, Some(&prototype.source));
+        }
+
+        // Debug information, stripped functions have none of it
+        let line_info: &[usize] = if self.strip { &[] } else { &prototype.line_info };
+        self.size(line_info.len());
+        for line in line_info {
+            self.size(*line);
+        }
+        let locals: &[LocalVariable] = if self.strip { &[] } else { &prototype.local_variables };
+        self.size(locals.len());
+        for local in locals {
+            self.string(Some(local.name.as_bytes()));
+            self.size(local.start_pc);
+            self.size(local.end_pc);
+        }
+        let upvalue_names = if self.strip { 0 } else { prototype.upvalues.len() };
+        self.size(upvalue_names);
+        for upvalue in &prototype.upvalues[..upvalue_names] {
+            self.string(Some(upvalue.name.as_bytes()));
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, count: usize) -> Result<&[u8], String> {
+        if self.bytes.len() - self.position < count {
+            return Err("truncated chunk".to_owned());
+        }
+        self.position += count;
+        Ok(&self.bytes[self.position - count..self.position])
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn size(&mut self) -> Result<usize, String> {
+        let mut value: usize = 0;
+        loop {
+            let byte = self.byte()?;
+            if value > usize::MAX >> 7 {
+                return Err("integer overflow".to_owned());
+            }
+            value = (value << 7) | (byte & 0x7f) as usize;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+        }
+    }
+
+    // The number of items in a list, every item takes at least one byte so a longer list can not be in the chunk
+    fn count(&mut self) -> Result<usize, String> {
+        let count = self.size()?;
+        match count > self.bytes.len() - self.position {
+            true => Err("truncated chunk".to_owned()),
+            false => Ok(count),
+        }
+    }
+
+    fn string(&mut self) -> Result<Option<LuaString>, String> {
+        match self.size()? {
+            0 => Ok(None),
+            size => Ok(Some(LuaString::from(self.take(size - 1)?))),
+        }
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let name = self.string()?.ok_or_else(|| "corrupted chunk".to_owned())?;
+        Ok(name.to_str_lossy().into_owned())
+    }
+
+    fn header(&mut self) -> Result<(), String> {
+        if self.take(SIGNATURE.len())? != SIGNATURE {
+            return Err("not a binary chunk".to_owned());
+        }
+        if self.byte()? != VERSION {
+            return Err("version mismatch".to_owned());
+        }
+        if self.byte()? != FORMAT {
+            return Err("format mismatch".to_owned());
+        }
+        if self.take(DATA.len())? != DATA {
+            return Err("corrupted chunk".to_owned());
+        }
+        let sizes = [
+            ("Instruction", std::mem::size_of::<Instruction>()),
+            ("lua_Integer", std::mem::size_of::<i64>()),
+            ("lua_Number", std::mem::size_of::<f64>()),
+        ];
+        for (name, size) in sizes {
+            if self.byte()? as usize != size {
+                return Err(format!("{} size mismatch", name));
+            }
+        }
+        if self.take(8)? != TEST_INTEGER.to_le_bytes() {
+            return Err("integer format mismatch".to_owned());
+        }
+        if self.take(8)? != TEST_FLOAT.to_le_bytes() {
+            return Err("float format mismatch".to_owned());
+        }
+        Ok(())
+    }
+
+    fn function(&mut self, parent_source: Option<&Rc<str>>, depth: usize) -> Result<Prototype, String> {
+        if depth > MAX_NESTING {
+            return Err("too deeply nested functions".to_owned());
+        }
+        let source = match (self.string()?, parent_source) {
+            (Some(source), _) => Rc::from(source.to_str_lossy().as_ref()),
+            (None, Some(parent_source)) => parent_source.clone(),
+            (None, None) => Rc::from(STRIPPED_SOURCE),
+        };
+        let line_defined = self.size()?;
+        let last_line_defined = self.size()?;
+        let num_params = self.byte()?;
+        let is_vararg = self.byte()? != 0;
+        let max_stack_size = self.byte()?;
+
+        let code_size = self.count()?;
+        let mut code = Vec::with_capacity(code_size);
+        for _ in 0..code_size {
+            let word = u32::from_le_bytes(self.take(4)?.try_into().expect("Four bytes were taken"));
+            if OpCode::from_u8((word & 0x7f) as u8).is_none() {
+                return Err(format!("invalid opcode {} at instruction {}", word & 0x7f, code.len() + 1));
+            }
+            code.push(Instruction(word));
+        }
+
+        let constant_count = self.count()?;
+        let mut constants = Vec::with_capacity(constant_count);
+        for _ in 0..constant_count {
+            let constant = match self.byte()? {
+                TAG_NIL => DataKind::Null,
+                TAG_FALSE => DataKind::Bool(false),
+                TAG_TRUE => DataKind::Bool(true),
+                TAG_INTEGER => DataKind::integer(i64::from_le_bytes(self.take(8)?.try_into().expect("Eight bytes"))),
+                TAG_FLOAT => {
+                    let float = f64::from_le_bytes(self.take(8)?.try_into().expect("Eight bytes were taken"));
+                    DataKind::Number(NumberKind::Float(float))
+                }
+                TAG_STRING => DataKind::String(self.string()?.ok_or_else(|| "corrupted chunk".to_owned())?),
+                tag => return Err(format!("invalid constant type {}", tag)),
+            };
+            constants.push(constant);
+        }
+
+        let upvalue_count = self.count()?;
+        let mut upvalues = Vec::with_capacity(upvalue_count);
+        for _ in 0..upvalue_count {
+            let in_stack = self.byte()? != 0;
+            let index = self.byte()?;
+            upvalues.push(UpvalueDescriptor { name: String::new(), in_stack, index });
+        }
+
+        let prototype_count = self.count()?;
+        let mut prototypes = Vec::with_capacity(prototype_count);
+        for _ in 0..prototype_count {
+            prototypes.push(Rc::new(self.function(Some(&source), depth + 1)?));
+        }
+
+        let line_count = self.count()?;
+        let mut line_info = Vec::with_capacity(line_count);
+        for _ in 0..line_count {
+            line_info.push(self.size()?);
+        }
+        let local_count = self.count()?;
+        let mut local_variables = Vec::with_capacity(local_count);
+        for _ in 0..local_count {
+            let name = self.name()?;
+            let (start_pc, end_pc) = (self.size()?, self.size()?);
+            local_variables.push(LocalVariable { name, start_pc, end_pc });
+        }
+        let upvalue_names = self.count()?;
+        if upvalue_names != 0 && upvalue_names != upvalues.len() {
+            return Err("corrupted chunk".to_owned());
+        }
+        for upvalue in upvalues.iter_mut().take(upvalue_names) {
+            upvalue.name = self.name()?;
+        }
+
+        Ok(Prototype {
+            source,
+            line_defined,
+            last_line_defined,
+            num_params,
+            is_vararg,
+            max_stack_size,
+            code,
+            line_info,
+            constants,
+            upvalues,
+            prototypes,
+            local_variables,
+        })
+    }
+}
+
+// Checks that every operand of every instruction is in range, so that the virtual machine does not have to. Operands
+// are checked one instruction at a time, not against the registers the code before it set.
+fn verify(prototype: &Prototype) -> Result<(), String> {
+    let code = &prototype.code;
+    let invalid = |pc: usize, what: &str| Err(format!("{} at instruction {}", what, pc + 1));
+    if !prototype.line_info.is_empty() && prototype.line_info.len() != code.len() {
+        return Err("corrupted chunk".to_owned());
+    }
+    if prototype.num_params > prototype.max_stack_size {
+        return Err("corrupted chunk".to_owned());
+    }
+    // Running off the end of the code is not possible when it ends in a return
+    match code.last().map(|instruction| instruction.opcode()) {
+        Some(OpCode::Return | OpCode::Return0 | OpCode::Return1) => (),
+        _ => return Err("function does not end in a return".to_owned()),
+    }
+    let registers = prototype.max_stack_size as u32;
+    let constants = prototype.constants.len() as u32;
+    let upvalues = prototype.upvalues.len() as u32;
+    for (pc, instruction) in code.iter().enumerate() {
+        let (a, b, c) = (instruction.a(), instruction.b(), instruction.c());
+        // Registers first..=last must be in the frame
+        let register_range = |first: u32, last: u32| match first <= last && last < registers {
+            true => Ok(()),
+            false => invalid(pc, "register out of range"),
+        };
+        let register = |register: u32| register_range(register, register);
+        let constant = |index: u32| match index < constants {
+            true => Ok(()),
+            false => invalid(pc, "constant out of range"),
+        };
+        let upvalue = |index: u32| match index < upvalues {
+            true => Ok(()),
+            false => invalid(pc, "upvalue out of range"),
+        };
+        let register_or_constant = |index: u32| match instruction.k() {
+            true => constant(index),
+            false => register(index),
+        };
+        let extra_argument = || match code.get(pc + 1).map(|next| next.opcode()) {
+            Some(OpCode::ExtraArg) => Ok(()),
+            _ => invalid(pc, "missing extra argument"),
+        };
+        // Jumps are relative to the next instruction, and can not land on the argument of the instruction before
+        let jump = |offset: isize| {
+            let target = pc as isize + 1 + offset;
+            match target >= 0 && (target as usize) < code.len() && code[target as usize].opcode() != OpCode::ExtraArg {
+                true => Ok(()),
+                false => invalid(pc, "jump out of range"),
+            }
+        };
+        match instruction.opcode() {
+            OpCode::Move | OpCode::Unm | OpCode::BNot | OpCode::Not | OpCode::Len | OpCode::AddI | OpCode::ShrI
+            | OpCode::ShlI | OpCode::GetI => {
+                register(a)?;
+                register(b)?;
+            }
+            OpCode::LoadI | OpCode::LoadF | OpCode::LoadFalse | OpCode::LoadTrue | OpCode::Close | OpCode::Tbc
+            | OpCode::Return1 => register(a)?,
+            OpCode::LFalseSkip => {
+                register(a)?;
+                jump(1)?;
+            }
+            OpCode::LoadK => {
+                register(a)?;
+                constant(instruction.bx())?;
+            }
+            OpCode::LoadKX => {
+                register(a)?;
+                extra_argument()?;
+                constant(code[pc + 1].ax_arg())?;
+            }
+            OpCode::LoadNil => register_range(a, a + b)?,
+            OpCode::GetUpval | OpCode::SetUpval => {
+                register(a)?;
+                upvalue(b)?;
+            }
+            OpCode::GetTabUp => {
+                register(a)?;
+                upvalue(b)?;
+                constant(c)?;
+            }
+            OpCode::GetTable | OpCode::Add | OpCode::Sub | OpCode::Mul | OpCode::Mod | OpCode::Pow | OpCode::Div
+            | OpCode::IDiv | OpCode::BAnd | OpCode::BOr | OpCode::BXor | OpCode::Shl | OpCode::Shr => {
+                register(a)?;
+                register(b)?;
+                register(c)?;
+            }
+            OpCode::GetField | OpCode::AddK | OpCode::SubK | OpCode::MulK | OpCode::ModK | OpCode::PowK
+            | OpCode::DivK | OpCode::IDivK | OpCode::BAndK | OpCode::BOrK | OpCode::BXorK => {
+                register(a)?;
+                register(b)?;
+                constant(c)?;
+            }
+            OpCode::SetTabUp => {
+                upvalue(a)?;
+                constant(b)?;
+                register_or_constant(c)?;
+            }
+            OpCode::SetTable => {
+                register(a)?;
+                register(b)?;
+                register_or_constant(c)?;
+            }
+            OpCode::SetI => {
+                register(a)?;
+                register_or_constant(c)?;
+            }
+            OpCode::SetField => {
+                register(a)?;
+                constant(b)?;
+                register_or_constant(c)?;
+            }
+            OpCode::NewTable => {
+                register(a)?;
+                extra_argument()?;
+            }
+            OpCode::SelfMethod => {
+                register_range(a, a + 1)?;
+                register(b)?;
+                register_or_constant(c)?;
+            }
+            OpCode::Concat => match b {
+                0 => return invalid(pc, "empty concatenation"),
+                b => register_range(a, a + b - 1)?,
+            },
+            OpCode::Jmp => jump(instruction.sj_arg() as isize)?,
+            OpCode::Eq | OpCode::Lt | OpCode::Le | OpCode::TestSet => {
+                register(a)?;
+                register(b)?;
+                jump(1)?;
+            }
+            OpCode::EqK => {
+                register(a)?;
+                constant(b)?;
+                jump(1)?;
+            }
+            OpCode::EqI | OpCode::LtI | OpCode::LeI | OpCode::GtI | OpCode::GeI | OpCode::Test => {
+                register(a)?;
+                jump(1)?;
+            }
+            // A call reads the function and B - 1 arguments after it, a return B - 1 values
+            OpCode::Call | OpCode::TailCall => match b {
+                0 => register(a)?,
+                b => register_range(a, a + b - 1)?,
+            },
+            OpCode::Return => match b {
+                0 => register(a)?,
+                1 => (),
+                b => register_range(a, a + b - 2)?,
+            },
+            OpCode::Return0 | OpCode::VarArgPrep => (),
+            OpCode::ForLoop => {
+                register_range(a, a + 3)?;
+                jump(-(instruction.bx() as isize))?;
+            }
+            OpCode::ForPrep => {
+                register_range(a, a + 3)?;
+                jump(instruction.bx() as isize + 1)?;
+            }
+            OpCode::TForPrep => {
+                register_range(a, a + 3)?;
+                jump(instruction.bx() as isize)?;
+            }
+            OpCode::TForCall => register_range(a, a + 3 + c)?,
+            OpCode::TForLoop => {
+                register_range(a, a + 4)?;
+                jump(-(instruction.bx() as isize))?;
+            }
+            OpCode::SetList => {
+                register_range(a, a + b)?;
+                if instruction.k() {
+                    extra_argument()?;
+                }
+            }
+            OpCode::Closure => {
+                register(a)?;
+                let child = match prototype.prototypes.get(instruction.bx() as usize) {
+                    Some(child) => child,
+                    None => return invalid(pc, "function out of range"),
+                };
+                // The upvalues of a new closure are registers or upvalues of the function creating it
+                for descriptor in &child.upvalues {
+                    match descriptor.in_stack {
+                        true => register(descriptor.index as u32)?,
+                        false => upvalue(descriptor.index as u32)?,
+                    }
+                }
+            }
+            OpCode::VarArg => match c {
+                0 => register(a)?,
+                c => register_range(a, a + c - 1)?,
+            },
+            // An extra argument is only valid right after the instruction it belongs to
+            OpCode::ExtraArg => {
+                let owner = pc.checked_sub(1).map(|owner| code[owner]);
+                match owner.map(|owner| (owner.opcode(), owner.k())) {
+                    Some((OpCode::LoadKX | OpCode::NewTable, _)) | Some((OpCode::SetList, true)) => (),
+                    _ => return invalid(pc, "misplaced extra argument"),
+                }
+            }
+        }
+    }
+    for child in &prototype.prototypes {
+        verify(child)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,243 @@
+// Lists compiled functions in the format of `luac -l -l`: a header for every function, its instructions with their
+// source lines and a comment naming the constants, upvalues or jump targets they use, then its constants, locals and
+// upvalues. Functions nested in it follow.
+
+use std::fmt::Write;
+use std::rc::Rc;
+
+use crate::compiler::opcode::{Instruction, OpCode, MAX_C};
+use crate::compiler::prototype::Prototype;
+use crate::frontend::data::{DataKind, NumberKind};
+
+const COMMENT: &str = "\t; ";
+
+pub fn list(prototype: &Prototype) -> String {
+    let mut out = String::new();
+    list_function(&mut out, prototype);
+    out
+}
+
+fn list_function(out: &mut String, prototype: &Prototype) {
+    header(out, prototype);
+    for pc in 0..prototype.code.len() {
+        instruction(out, prototype, pc);
+    }
+    debug_information(out, prototype);
+    for child in &prototype.prototypes {
+        list_function(out, child);
+    }
+}
+
+fn plural(count: usize) -> &'static str {
+    if count == 1 { "" } else { "s" }
+}
+
+fn header(out: &mut String, prototype: &Prototype) {
+    let kind = if prototype.line_defined == 0 { "main" } else { "function" };
+    let code = prototype.code.len();
+    let _ = writeln!(
+        out,
+        "\n{} <{}:{},{}> ({} instruction{} at {:p})",
+        kind,
+        prototype.source,
+        prototype.line_defined,
+        prototype.last_line_defined,
+        code,
+        plural(code),
+        prototype
+    );
+    let params = prototype.num_params as usize;
+    let slots = prototype.max_stack_size as usize;
+    let upvalues = prototype.upvalues.len();
+    let locals = prototype.local_variables.len();
+    let constants = prototype.constants.len();
+    let functions = prototype.prototypes.len();
+    let _ = writeln!(
+        out,
+        "{}{} param{}, {} slot{}, {} upvalue{}, {} local{}, {} constant{}, {} function{}",
+        params,
+        if prototype.is_vararg { "+" } else { "" },
+        plural(params),
+        slots,
+        plural(slots),
+        upvalues,
+        plural(upvalues),
+        locals,
+        plural(locals),
+        constants,
+        plural(constants),
+        functions,
+        plural(functions)
+    );
+}
+
+// Upvalue names are gone from stripped functions
+fn upvalue_name(prototype: &Prototype, index: u32) -> &str {
+    match prototype.upvalues.get(index as usize).map(|upvalue| upvalue.name.as_str()) {
+        Some("") | None => "-",
+        Some(name) => name,
+    }
+}
+
+fn constant(prototype: &Prototype, index: u32) -> String {
+    match prototype.constants.get(index as usize) {
+        Some(DataKind::String(string)) => quoted(string.as_bytes()),
+        Some(constant) => constant.to_string(),
+        None => "?".to_owned(),
+    }
+}
+
+// Strings are shown with the escapes that would write them in Lua source
+fn quoted(bytes: &[u8]) -> String {
+    let mut quoted = String::from("\"");
+    for byte in bytes {
+        match byte {
+            b'"' => quoted.push_str("\\\""),
+            b'\\' => quoted.push_str("\\\\"),
+            0x07 => quoted.push_str("\\a"),
+            0x08 => quoted.push_str("\\b"),
+            0x0c => quoted.push_str("\\f"),
+            b'\n' => quoted.push_str("\\n"),
+            b'\r' => quoted.push_str("\\r"),
+            b'\t' => quoted.push_str("\\t"),
+            0x0b => quoted.push_str("\\v"),
+            byte if byte.is_ascii_graphic() || *byte == b' ' => quoted.push(*byte as char),
+            byte => quoted.push_str(&format!("\\{:03}", byte)),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// The C operand of NEWTABLE and SETLIST continues in the EXTRAARG after them when k is set
+fn extended_c(prototype: &Prototype, pc: usize, instruction: Instruction) -> u32 {
+    let extra = match instruction.k() {
+        true => prototype.code.get(pc + 1).map_or(0, |extra| extra.ax_arg() * (MAX_C + 1)),
+        false => 0,
+    };
+    instruction.c() + extra
+}
+
+fn instruction(out: &mut String, prototype: &Prototype, pc: usize) {
+    let instruction = prototype.code[pc];
+    let op = instruction.opcode();
+    let (a, b, c) = (instruction.a(), instruction.b(), instruction.c());
+    let (bx, sb, sc) = (instruction.bx() as i64, instruction.sb(), instruction.sc());
+    let k = if instruction.k() { "k" } else { "" };
+    let is_k = instruction.k() as u32;
+    let pc = pc as i64;
+    let line = match prototype.line_at(pc as usize) {
+        0 => "[-]".to_owned(),
+        line => format!("[{}]", line),
+    };
+    let _ = write!(out, "\t{}\t{}\t{:<9}\t", pc + 1, line, op.name());
+    let operands = match op {
+        OpCode::Move | OpCode::Unm | OpCode::BNot | OpCode::Not | OpCode::Len | OpCode::Concat => {
+            format!("{} {}", a, b)
+        }
+        OpCode::LoadI | OpCode::LoadF => format!("{} {}", a, instruction.sbx()),
+        OpCode::LoadK => format!("{} {}{}{}", a, bx, COMMENT, constant(prototype, bx as u32)),
+        OpCode::LoadKX => {
+            let index = prototype.code.get(pc as usize + 1).map_or(0, |extra| extra.ax_arg());
+            format!("{}{}{}", a, COMMENT, constant(prototype, index))
+        }
+        OpCode::LoadFalse | OpCode::LFalseSkip | OpCode::LoadTrue | OpCode::Close | OpCode::Tbc | OpCode::Return1
+        | OpCode::VarArgPrep => format!("{}", a),
+        OpCode::LoadNil => format!("{} {}{}{} out", a, b, COMMENT, b + 1),
+        OpCode::GetUpval | OpCode::SetUpval => format!("{} {}{}{}", a, b, COMMENT, upvalue_name(prototype, b)),
+        OpCode::GetTabUp => {
+            let (upvalue, key) = (upvalue_name(prototype, b), constant(prototype, c));
+            format!("{} {} {}{}{} {}", a, b, c, COMMENT, upvalue, key)
+        }
+        OpCode::GetTable | OpCode::GetI | OpCode::Add | OpCode::Sub | OpCode::Mul | OpCode::Mod | OpCode::Pow
+        | OpCode::Div | OpCode::IDiv | OpCode::BAnd | OpCode::BOr | OpCode::BXor | OpCode::Shl | OpCode::Shr => {
+            format!("{} {} {}", a, b, c)
+        }
+        OpCode::GetField | OpCode::AddK | OpCode::SubK | OpCode::MulK | OpCode::ModK | OpCode::PowK
+        | OpCode::DivK | OpCode::IDivK | OpCode::BAndK | OpCode::BOrK | OpCode::BXorK => {
+            format!("{} {} {}{}{}", a, b, c, COMMENT, constant(prototype, c))
+        }
+        OpCode::SetTabUp => {
+            let mut operands = format!("{} {} {}{}{}", a, b, c, k, COMMENT);
+            operands.push_str(&format!("{} {}", upvalue_name(prototype, a), constant(prototype, b)));
+            if instruction.k() {
+                operands.push_str(&format!(" {}", constant(prototype, c)));
+            }
+            operands
+        }
+        OpCode::SetTable | OpCode::SetI | OpCode::SelfMethod => match instruction.k() {
+            true => format!("{} {} {}{}{}{}", a, b, c, k, COMMENT, constant(prototype, c)),
+            false => format!("{} {} {}", a, b, c),
+        },
+        OpCode::SetField => {
+            let mut operands = format!("{} {} {}{}{}{}", a, b, c, k, COMMENT, constant(prototype, b));
+            if instruction.k() {
+                operands.push_str(&format!(" {}", constant(prototype, c)));
+            }
+            operands
+        }
+        OpCode::NewTable => format!("{} {} {}{}{}", a, b, c, COMMENT, extended_c(prototype, pc as usize, instruction)),
+        OpCode::AddI | OpCode::ShrI | OpCode::ShlI => format!("{} {} {}", a, b, sc),
+        OpCode::Jmp => {
+            let offset = instruction.sj_arg() as i64;
+            format!("{}{}to {}", offset, COMMENT, offset + pc + 2)
+        }
+        OpCode::Eq | OpCode::Lt | OpCode::Le | OpCode::TestSet => format!("{} {} {}", a, b, is_k),
+        OpCode::EqK => format!("{} {} {}{}{}", a, b, is_k, COMMENT, constant(prototype, b)),
+        OpCode::EqI | OpCode::LtI | OpCode::LeI | OpCode::GtI | OpCode::GeI => format!("{} {} {}", a, sb, is_k),
+        OpCode::Test => format!("{} {}", a, is_k),
+        OpCode::Call => {
+            let arguments = if b == 0 { "all".to_owned() } else { (b - 1).to_string() };
+            let results = if c == 0 { "all".to_owned() } else { (c - 1).to_string() };
+            format!("{} {} {}{}{} in {} out", a, b, c, COMMENT, arguments, results)
+        }
+        OpCode::TailCall => format!("{} {} {}{}{}{} in", a, b, c, k, COMMENT, b as i64 - 1),
+        OpCode::Return => {
+            let results = if b == 0 { "all".to_owned() } else { (b - 1).to_string() };
+            format!("{} {} {}{}{}{} out", a, b, c, k, COMMENT, results)
+        }
+        OpCode::Return0 => String::new(),
+        OpCode::ForLoop | OpCode::TForLoop => format!("{} {}{}to {}", a, bx, COMMENT, pc - bx + 2),
+        OpCode::ForPrep => format!("{} {}{}exit to {}", a, bx, COMMENT, pc + bx + 3),
+        OpCode::TForPrep => format!("{} {}{}to {}", a, bx, COMMENT, pc + bx + 2),
+        OpCode::TForCall => format!("{} {}", a, c),
+        OpCode::SetList => match instruction.k() {
+            true => format!("{} {} {}{}{}", a, b, c, COMMENT, extended_c(prototype, pc as usize, instruction)),
+            false => format!("{} {} {}", a, b, c),
+        },
+        OpCode::Closure => {
+            let child = prototype.prototypes.get(bx as usize);
+            format!("{} {}{}{:p}", a, bx, COMMENT, child.map_or(std::ptr::null(), Rc::as_ptr))
+        }
+        OpCode::VarArg => {
+            let results = if c == 0 { "all".to_owned() } else { (c - 1).to_string() };
+            format!("{} {}{}{} out", a, c, COMMENT, results)
+        }
+        OpCode::ExtraArg => format!("{}", instruction.ax_arg()),
+    };
+    let _ = writeln!(out, "{}", operands);
+}
+
+fn debug_information(out: &mut String, prototype: &Prototype) {
+    let _ = writeln!(out, "constants ({}) for {:p}:", prototype.constants.len(), prototype);
+    for (index, value) in prototype.constants.iter().enumerate() {
+        let kind = match value {
+            DataKind::Null => "N",
+            DataKind::Bool(_) => "B",
+            DataKind::Number(NumberKind::Float(_)) => "F",
+            DataKind::Number(NumberKind::Integer(_)) => "I",
+            DataKind::String(_) => "S",
+            _ => "?",
+        };
+        let _ = writeln!(out, "\t{}\t{}\t{}", index, kind, constant(prototype, index as u32));
+    }
+    let _ = writeln!(out, "locals ({}) for {:p}:", prototype.local_variables.len(), prototype);
+    for (index, local) in prototype.local_variables.iter().enumerate() {
+        let _ = writeln!(out, "\t{}\t{}\t{}\t{}", index, local.name, local.start_pc + 1, local.end_pc + 1);
+    }
+    let _ = writeln!(out, "upvalues ({}) for {:p}:", prototype.upvalues.len(), prototype);
+    for (index, upvalue) in prototype.upvalues.iter().enumerate() {
+        let name = upvalue_name(prototype, index as u32);
+        let _ = writeln!(out, "\t{}\t{}\t{}\t{}", index, name, upvalue.in_stack as u8, upvalue.index);
+    }
+}
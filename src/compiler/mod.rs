@@ -0,0 +1,866 @@
+// Compiles a resolved program to the register based bytecode the virtual machine runs. This follows the reference
+// implementation's lparser.c, which generates code while it parses, over our syntax tree instead. The code for
+// expressions lives in `code`.
+
+mod code;
+pub mod dump;
+pub mod listing;
+pub mod opcode;
+pub mod prototype;
+
+use std::rc::Rc;
+
+use crate::ast;
+use crate::ast::lua_program::{
+    Args, Attribute, AttributeNameList, Block, Expr, Expression, ExpressionList, Field, FunctionBody, FunctionCall,
+    FunctionName, LuaProgram, NumberKind, PrefixExpression, Resolution, ReturnStatement, Span, Statement,
+    TableConstructor, Var, VarList,
+};
+use crate::err_handle::CompileError;
+
+use code::{
+    ActiveLocal, BlockScope, CompileResult, ExpDesc, ExpKind, FunctionState, GotoEntry, LabelEntry, MULTIPLE_RESULTS,
+    NO_JUMP,
+};
+use opcode::{Instruction, OpCode, FIELDS_PER_FLUSH, MAX_BX};
+use prototype::{LocalVariable, Prototype, UpvalueDescriptor};
+
+// Limits of a single function, the same as the reference implementation's
+const MAX_LOCALS: usize = 200;
+const MAX_UPVALUES: usize = 255;
+
+// The name of the hidden locals that hold the state of for loops
+const FOR_STATE: &str = "(for state)";
+
+// The parser and the compiler recurse once per syntax level, they run on a stack of this size whatever the stack of
+// the thread loading the code is. Its memory is only committed as it is used.
+const COMPILER_STACK_SIZE: usize = 16 * 1024 * 1024;
+
+struct Compiler {
+    // The function being compiled is last, the ones enclosing it come before it
+    functions: Vec<FunctionState>,
+    source: Rc<str>,
+}
+
+// Parses and compiles source code on a stack of its own. The parser checks the stack it has left through stacker,
+// which knows the size of this one, so nesting past the syntax levels fails with an error on any thread or coroutine.
+pub fn compile_source(source: &str, chunk_name: &str) -> Result<Rc<Prototype>, Vec<CompileError>> {
+    stacker::grow(COMPILER_STACK_SIZE, || {
+        ast::parse_lua_program(source).and_then(|program| compile(&program, chunk_name).map_err(|e| vec![e]))
+    })
+}
+
+pub fn compile(program: &LuaProgram, chunk_name: &str) -> Result<Rc<Prototype>, CompileError> {
+    let source: Rc<str> = Rc::from(chunk_name);
+    let mut main = FunctionState::new(source.clone(), Span::default(), 0);
+    // The main chunk is a vararg function whose only upvalue is the global environment
+    main.proto.is_vararg = true;
+    main.proto.upvalues.push(UpvalueDescriptor { name: "_ENV".to_owned(), in_stack: true, index: 0 });
+    let mut compiler = Compiler { functions: vec![main], source };
+    compiler.enter_block(false);
+    // Like the rest of the chunk's code, its first instruction belongs to a line
+    compiler.fs().span.line = 1;
+    compiler.fs().code_abc(OpCode::VarArgPrep, 0, 0, 0);
+    compiler.statements(&program.block, true)?;
+    Ok(Rc::new(compiler.close_function()?))
+}
+
+impl Compiler {
+    fn fs(&mut self) -> &mut FunctionState {
+        self.functions.last_mut().expect("A function is always being compiled")
+    }
+
+    // Functions
+
+    fn close_function(&mut self) -> CompileResult<Prototype> {
+        let fs = self.fs();
+        let level = fs.active_registers();
+        fs.ret(level, 0);
+        self.leave_block()?;
+        let mut fs = self.functions.pop().expect("A function is always being compiled");
+        fs.finish();
+        Ok(fs.proto)
+    }
+
+    // Compiles a function and creates a closure of it in the next free register
+    fn function_body(&mut self, body: &FunctionBody) -> CompileResult<ExpDesc> {
+        let parent_span = self.fs().span;
+        self.functions.push(FunctionState::new(self.source.clone(), body.span, body.end_line));
+        self.enter_block(false);
+        for name in body.parameter_names() {
+            self.activate_local(name)?;
+        }
+        let fs = self.fs();
+        let num_params = fs.active_registers();
+        fs.proto.num_params = num_params as u8;
+        if body.is_vararg() {
+            fs.proto.is_vararg = true;
+            fs.code_abc(OpCode::VarArgPrep, num_params, 0, 0);
+        }
+        fs.reserve_registers(num_params)?;
+        self.statements(&body.block, true)?;
+        self.fs().span.line = body.end_line;
+        let proto = self.close_function()?;
+
+        let fs = self.fs();
+        fs.span = parent_span;
+        let index = fs.proto.prototypes.len();
+        if index > MAX_BX as usize {
+            return Err(fs.error("too many nested functions"));
+        }
+        fs.proto.prototypes.push(Rc::new(proto));
+        let mut closure = ExpDesc::new(ExpKind::Reloc(fs.code_abx(OpCode::Closure, 0, index as u32)));
+        fs.expression_to_next_register(&mut closure)?;
+        Ok(closure)
+    }
+
+    // Blocks and scopes
+
+    fn enter_block(&mut self, is_loop: bool) {
+        let fs = self.fs();
+        let inside_tbc = fs.blocks.last().is_some_and(|block| block.inside_tbc);
+        fs.blocks.push(BlockScope {
+            active_count: fs.active.len(),
+            first_label: fs.labels.len(),
+            first_goto: fs.gotos.len(),
+            is_loop,
+            has_upvalue: false,
+            inside_tbc,
+        });
+    }
+
+    fn leave_block(&mut self) -> CompileResult<()> {
+        let fs = self.fs();
+        let block = fs.blocks.last().expect("A block is always open");
+        let (active_count, first_label, first_goto, is_loop, has_upvalue) =
+            (block.active_count, block.first_label, block.first_goto, block.is_loop, block.has_upvalue);
+        let level = active_count as u32;
+        fs.remove_locals(active_count);
+        // Breaks jump to a label at the end of the loop
+        let has_close = match is_loop {
+            true => self.create_label("break", false)?,
+            false => false,
+        };
+        let fs = self.fs();
+        if !has_close && fs.blocks.len() > 1 && has_upvalue {
+            fs.code_abc(OpCode::Close, level, 0, 0);
+        }
+        fs.first_free = level;
+        fs.labels.truncate(first_label);
+        fs.blocks.pop();
+        // Pending gotos now jump out of this block, leaving its locals
+        if !fs.blocks.is_empty() {
+            for goto in &mut fs.gotos[first_goto..] {
+                if goto.active_count > active_count {
+                    goto.close |= has_upvalue;
+                    goto.active_count = active_count;
+                }
+            }
+        } else if let Some(goto) = fs.gotos.first() {
+            let message = match goto.name.as_str() {
+                "break" => "break outside a loop".to_owned(),
+                name => format!("no visible label '{}' for goto", name),
+            };
+            return Err(fs.error(&message));
+        }
+        Ok(())
+    }
+
+    fn block(&mut self, block: &Block) -> CompileResult<()> {
+        self.enter_block(false);
+        self.statements(block, true)?;
+        self.leave_block()
+    }
+
+    // `end_closes_scope` is false for the body of a repeat loop, whose locals are still visible to its condition
+    fn statements(&mut self, block: &Block, end_closes_scope: bool) -> CompileResult<()> {
+        for (index, (statement, span)) in block.statements.iter().enumerate() {
+            self.fs().span = *span;
+            // A label with nothing but labels and empty statements after it is outside the scope of the block's locals
+            let last = end_closes_scope
+                && block.return_statement.is_none()
+                && block.statements[index + 1..]
+                    .iter()
+                    .all(|(statement, _)| matches!(statement, Statement::Label(_) | Statement::Empty));
+            self.statement(statement, *span, last)?;
+            let fs = self.fs();
+            fs.first_free = fs.active_registers();
+        }
+        if let Some(return_statement) = &block.return_statement {
+            self.return_statement(return_statement)?;
+        }
+        Ok(())
+    }
+
+    // Locals
+
+    fn activate_local(&mut self, name: &str) -> CompileResult<u32> {
+        let fs = self.fs();
+        if fs.active.len() >= MAX_LOCALS {
+            return Err(fs.limit_error(MAX_LOCALS, "local variables"));
+        }
+        let debug_index = fs.proto.local_variables.len();
+        fs.proto.local_variables.push(LocalVariable { name: name.to_owned(), start_pc: fs.pc(), end_pc: 0 });
+        fs.active.push(ActiveLocal { name: name.to_owned(), debug_index });
+        Ok(fs.active_registers() - 1)
+    }
+
+    fn mark_to_be_closed(&mut self) {
+        let fs = self.fs();
+        let block = fs.blocks.last_mut().expect("A block is always open");
+        block.has_upvalue = true;
+        block.inside_tbc = true;
+        fs.needs_close = true;
+    }
+
+    // Finds a name in the function at `level` or the ones enclosing it, creating the upvalues needed to reach it.
+    // None if it is not a local of any of them.
+    fn find_variable(&mut self, level: usize, name: &str, base: bool) -> CompileResult<Option<ExpKind>> {
+        let fs = &mut self.functions[level];
+        if let Some(register) = fs.active.iter().rposition(|local| local.name == name) {
+            // A local used by an inner function must be closed when it goes out of scope
+            if !base {
+                fs.mark_upvalue(register as u32);
+            }
+            return Ok(Some(ExpKind::Local(register as u32)));
+        }
+        if let Some(index) = fs.proto.upvalues.iter().position(|upvalue| upvalue.name == name) {
+            return Ok(Some(ExpKind::Upval(index as u32)));
+        }
+        if level == 0 {
+            return Ok(None);
+        }
+        let (in_stack, index) = match self.find_variable(level - 1, name, false)? {
+            Some(ExpKind::Local(register)) => (true, register),
+            Some(ExpKind::Upval(index)) => (false, index),
+            _ => return Ok(None),
+        };
+        let fs = &mut self.functions[level];
+        if fs.proto.upvalues.len() >= MAX_UPVALUES {
+            return Err(fs.limit_error(MAX_UPVALUES, "upvalues"));
+        }
+        fs.proto.upvalues.push(UpvalueDescriptor { name: name.to_owned(), in_stack, index: index as u8 });
+        Ok(Some(ExpKind::Upval(fs.proto.upvalues.len() as u32 - 1)))
+    }
+
+    fn variable(&mut self, name: &str, resolution: Resolution) -> CompileResult<ExpDesc> {
+        let level = self.functions.len() - 1;
+        if resolution != Resolution::Global || name == "_ENV" {
+            if let Some(kind) = self.find_variable(level, name, true)? {
+                return Ok(ExpDesc::new(kind));
+            }
+        }
+        // Globals are fields of the environment
+        let env = self.find_variable(level, "_ENV", true)?.expect("The main chunk has an _ENV upvalue");
+        let mut env = ExpDesc::new(env);
+        let fs = self.fs();
+        fs.expression_to_any_register_or_upvalue(&mut env)?;
+        let mut key = ExpDesc::new(ExpKind::K(fs.string_constant(name.as_bytes())));
+        fs.indexed(&mut env, &mut key)?;
+        Ok(env)
+    }
+
+    // Adjusts the values of an expression list to `variables` values in consecutive registers
+    fn adjust_assign(&mut self, variables: usize, expressions: usize, last: &mut ExpDesc) -> CompileResult<()> {
+        let fs = self.fs();
+        let needed = variables as i32 - expressions as i32;
+        if last.has_multiple_results() {
+            let extra = (needed + 1).max(0);
+            fs.set_returns(last, extra)?;
+        } else {
+            if !matches!(last.kind, ExpKind::Void) {
+                fs.expression_to_next_register(last)?;
+            }
+            if needed > 0 {
+                let first = fs.first_free;
+                fs.load_nil(first, needed as u32);
+            }
+        }
+        if needed > 0 {
+            fs.reserve_registers(needed as u32)?;
+        } else {
+            // Extra values are dropped
+            fs.first_free = (fs.first_free as i32 + needed) as u32;
+        }
+        Ok(())
+    }
+
+    // Labels and gotos
+
+    // Declares a label and patches the pending gotos of the current block to it. True if a CLOSE was needed for them.
+    fn create_label(&mut self, name: &str, last: bool) -> CompileResult<bool> {
+        let fs = self.fs();
+        let pc = fs.get_label();
+        let active_count = match last {
+            true => fs.blocks.last().expect("A block is always open").active_count,
+            false => fs.active.len(),
+        };
+        fs.labels.push(LabelEntry { name: name.to_owned(), pc, active_count });
+        let first_goto = fs.blocks.last().expect("A block is always open").first_goto;
+        let mut needs_close = false;
+        let mut index = first_goto;
+        while index < fs.gotos.len() {
+            if fs.gotos[index].name == name {
+                let goto = fs.gotos.remove(index);
+                needs_close |= goto.close;
+                fs.patch_list(goto.pc as isize, pc)?;
+            } else {
+                index += 1;
+            }
+        }
+        if needs_close {
+            let level = fs.active_registers();
+            fs.code_abc(OpCode::Close, level, 0, 0);
+        }
+        Ok(needs_close)
+    }
+
+    fn goto_statement(&mut self, name: &str) -> CompileResult<()> {
+        let fs = self.fs();
+        let label = fs.labels.iter().rev().find(|label| label.name == name).map(|label| (label.pc, label.active_count));
+        match label {
+            // A backward jump closes the locals declared after the label
+            Some((target, active_count)) => {
+                if fs.active.len() > active_count {
+                    fs.code_abc(OpCode::Close, active_count as u32, 0, 0);
+                }
+                let jump = fs.jump();
+                fs.patch_list(jump as isize, target)?;
+            }
+            None => {
+                let pc = fs.jump();
+                let active_count = fs.active.len();
+                fs.gotos.push(GotoEntry { name: name.to_owned(), pc, active_count, close: false });
+            }
+        }
+        Ok(())
+    }
+
+    // Statements
+
+    fn statement(&mut self, statement: &Statement, span: Span, last: bool) -> CompileResult<()> {
+        match statement {
+            Statement::Empty => {}
+            Statement::MultipleAssignment(var_list, expression_list) => self.assignment(var_list, expression_list)?,
+            Statement::FunctionCall(call) => {
+                let call = self.function_call(call)?;
+                // The results of a call statement are dropped
+                let ExpKind::Call(pc) = call.kind else { unreachable!("A call compiles to a CALL") };
+                self.fs().proto.code[pc].set_c(1);
+            }
+            Statement::Label(name) => {
+                self.create_label(name, last)?;
+            }
+            Statement::Break => self.break_statement(),
+            Statement::GoTo(name) => self.goto_statement(name)?,
+            Statement::DoBlockEnd(block) => self.block(block)?,
+            Statement::WhileExprDoBlockEnd(condition, block) => self.while_statement(condition, block)?,
+            Statement::RepeatBlockUntilExpr(block, condition) => self.repeat_statement(block, condition)?,
+            Statement::IfBlock(first, else_ifs, else_block) => self.if_statement(first, else_ifs, else_block.as_ref())?,
+            Statement::ForEach(name, start, limit, step, block) => {
+                self.numeric_for(name, start, limit, step.as_ref(), block, span)?;
+            }
+            Statement::ForList(name_list, expression_list, block) => {
+                self.generic_for(&name_list.names, expression_list, block, span)?;
+            }
+            Statement::Function(name, body) => self.function_statement(name, body)?,
+            Statement::LocalFunction(name, body) => {
+                let register = self.activate_local(name)?;
+                self.function_body(body)?;
+                // The local's scope starts after the closure is created, its debug information must agree
+                let fs = self.fs();
+                let debug_index = fs.active[register as usize].debug_index;
+                fs.proto.local_variables[debug_index].start_pc = fs.pc();
+            }
+            Statement::LocalAttributeNameList(names, expression_list) => {
+                self.local_statement(names, expression_list.as_ref())?;
+            }
+        }
+        Ok(())
+    }
+
+    // A break is a goto to the label every loop declares at its end
+    fn break_statement(&mut self) {
+        let fs = self.fs();
+        let pc = fs.jump();
+        let active_count = fs.active.len();
+        fs.gotos.push(GotoEntry { name: "break".to_owned(), pc, active_count, close: false });
+    }
+
+    fn local_statement(&mut self, names: &AttributeNameList, expression_list: Option<&ExpressionList>) -> CompileResult<()> {
+        let (count, mut last) = match expression_list {
+            Some(expression_list) => self.expression_list(expression_list)?,
+            None => (0, ExpDesc::new(ExpKind::Void)),
+        };
+        self.adjust_assign(names.names.len(), count, &mut last)?;
+        let mut to_be_closed = None;
+        for (name, attribute) in &names.names {
+            let register = self.activate_local(name)?;
+            if *attribute == Some(Attribute::Close) {
+                to_be_closed = Some(register);
+            }
+        }
+        if let Some(register) = to_be_closed {
+            self.mark_to_be_closed();
+            self.fs().code_abc(OpCode::Tbc, register, 0, 0);
+        }
+        Ok(())
+    }
+
+    fn assignment(&mut self, var_list: &VarList, expression_list: &ExpressionList) -> CompileResult<()> {
+        let mut targets = Vec::with_capacity(var_list.vars.len());
+        for var in &var_list.vars {
+            let target = self.var(var)?;
+            if matches!(target.kind, ExpKind::Local(_) | ExpKind::Upval(_)) {
+                self.fs().check_conflict(&mut targets, &target)?;
+            }
+            targets.push(target);
+        }
+        let (count, mut last) = self.expression_list(expression_list)?;
+        if count == targets.len() {
+            let fs = self.fs();
+            fs.set_one_result(&mut last);
+            let target = targets.pop().expect("An assignment has a target");
+            fs.store_var(&target, &mut last)?;
+        } else {
+            self.adjust_assign(targets.len(), count, &mut last)?;
+        }
+        // The other values are in consecutive registers, the last target takes the highest one
+        let fs = self.fs();
+        while let Some(target) = targets.pop() {
+            let mut value = ExpDesc::new(ExpKind::NonReloc(fs.first_free - 1));
+            fs.store_var(&target, &mut value)?;
+        }
+        Ok(())
+    }
+
+    // Compiles a condition that falls through when it is true, returns the jumps taken when it is false
+    fn condition(&mut self, condition: &Expression) -> CompileResult<isize> {
+        let mut e = self.expression(condition)?;
+        // All falses are equal here
+        if matches!(e.kind, ExpKind::Nil) {
+            e.kind = ExpKind::False;
+        }
+        self.fs().go_if_true(&mut e)?;
+        Ok(e.f)
+    }
+
+    fn if_statement(
+        &mut self,
+        first: &(Expression, Block),
+        else_ifs: &[(Expression, Block)],
+        else_block: Option<&Block>,
+    ) -> CompileResult<()> {
+        let mut escape = NO_JUMP;
+        let clauses = std::iter::once(first).chain(else_ifs);
+        for (index, (condition, block)) in clauses.enumerate() {
+            let false_jumps = self.condition(condition)?;
+            self.block(block)?;
+            let fs = self.fs();
+            if index < else_ifs.len() || else_block.is_some() {
+                let jump = fs.jump() as isize;
+                fs.concat_jumps(&mut escape, jump)?;
+            }
+            fs.patch_to_here(false_jumps)?;
+        }
+        if let Some(block) = else_block {
+            self.block(block)?;
+        }
+        self.fs().patch_to_here(escape)
+    }
+
+    fn while_statement(&mut self, condition: &Expression, block: &Block) -> CompileResult<()> {
+        let start = self.fs().get_label();
+        let exit = self.condition(condition)?;
+        self.enter_block(true);
+        self.block(block)?;
+        let fs = self.fs();
+        let jump = fs.jump();
+        fs.patch_list(jump as isize, start)?;
+        self.leave_block()?;
+        self.fs().patch_to_here(exit)
+    }
+
+    fn repeat_statement(&mut self, block: &Block, condition: &Expression) -> CompileResult<()> {
+        let start = self.fs().get_label();
+        self.enter_block(true);
+        self.enter_block(false);
+        self.statements(block, false)?;
+        // The condition is inside of the scope of the body
+        let mut exit = self.condition(condition)?;
+        let fs = self.fs();
+        let scope = fs.blocks.last().expect("The body block is open");
+        let (has_upvalue, level) = (scope.has_upvalue, scope.active_count as u32);
+        self.leave_block()?;
+        // Repeating leaves the scope of the body too, its locals are closed before jumping back
+        if has_upvalue {
+            let fs = self.fs();
+            let done = fs.jump();
+            fs.patch_to_here(exit)?;
+            fs.code_abc(OpCode::Close, level, 0, 0);
+            exit = fs.jump() as isize;
+            fs.patch_to_here(done as isize)?;
+        }
+        self.fs().patch_list(exit, start)?;
+        self.leave_block()
+    }
+
+    fn numeric_for(
+        &mut self,
+        name: &str,
+        start: &Expression,
+        limit: &Expression,
+        step: Option<&Expression>,
+        block: &Block,
+        span: Span,
+    ) -> CompileResult<()> {
+        self.enter_block(true);
+        let base = self.fs().first_free;
+        for expression in [start, limit] {
+            let mut e = self.expression(expression)?;
+            self.fs().expression_to_next_register(&mut e)?;
+        }
+        match step {
+            Some(step) => {
+                let mut e = self.expression(step)?;
+                self.fs().expression_to_next_register(&mut e)?;
+            }
+            None => {
+                let fs = self.fs();
+                let register = fs.first_free;
+                fs.load_integer(register, 1);
+                fs.reserve_registers(1)?;
+            }
+        }
+        for _ in 0..3 {
+            self.activate_local(FOR_STATE)?;
+        }
+        self.for_body(base, &[name.to_owned()], block, false, span)?;
+        self.leave_block()
+    }
+
+    fn generic_for(&mut self, names: &[String], expression_list: &ExpressionList, block: &Block, span: Span) -> CompileResult<()> {
+        self.enter_block(true);
+        let base = self.fs().first_free;
+        let (count, mut last) = self.expression_list(expression_list)?;
+        // The iterator, its state, the control variable and a value closed when the loop ends
+        self.adjust_assign(4, count, &mut last)?;
+        for _ in 0..4 {
+            self.activate_local(FOR_STATE)?;
+        }
+        self.mark_to_be_closed();
+        // Room to call the iterator
+        self.fs().check_stack(3)?;
+        self.for_body(base, names, block, true, span)?;
+        self.leave_block()
+    }
+
+    fn for_body(&mut self, base: u32, names: &[String], block: &Block, generic: bool, span: Span) -> CompileResult<()> {
+        let fs = self.fs();
+        fs.span = span;
+        let prep = fs.code_abx(if generic { OpCode::TForPrep } else { OpCode::ForPrep }, base, 0);
+        self.enter_block(false);
+        for name in names {
+            self.activate_local(name)?;
+        }
+        self.fs().reserve_registers(names.len() as u32)?;
+        self.statements(block, true)?;
+        self.leave_block()?;
+        let fs = self.fs();
+        fs.span = span;
+        let here = fs.get_label();
+        fix_for_jump(fs, prep, here, false)?;
+        if generic {
+            fs.code_abc(OpCode::TForCall, base, 0, names.len() as u32);
+        }
+        let end = fs.code_abx(if generic { OpCode::TForLoop } else { OpCode::ForLoop }, base, 0);
+        fix_for_jump(fs, end, prep + 1, true)
+    }
+
+    fn function_statement(&mut self, name: &FunctionName, body: &FunctionBody) -> CompileResult<()> {
+        let mut target = self.variable(&name.outer_name, name.resolution)?;
+        for field in name.accessors.iter().chain(name.pass_self.iter()) {
+            let fs = self.fs();
+            fs.expression_to_any_register_or_upvalue(&mut target)?;
+            let mut key = ExpDesc::new(ExpKind::K(fs.string_constant(field.as_bytes())));
+            fs.indexed(&mut target, &mut key)?;
+        }
+        let mut function = self.function_body(body)?;
+        self.fs().store_var(&target, &mut function)
+    }
+
+    fn return_statement(&mut self, return_statement: &ReturnStatement) -> CompileResult<()> {
+        self.fs().span = return_statement.span;
+        let mut first = self.fs().active_registers();
+        let count = match &return_statement.expression_list {
+            None => 0,
+            Some(expression_list) => {
+                let (count, mut last) = self.expression_list(expression_list)?;
+                let fs = self.fs();
+                if last.has_multiple_results() {
+                    fs.set_returns(&mut last, MULTIPLE_RESULTS)?;
+                    // `return f(x)` reuses the frame of the returning function, unless a variable has to be closed
+                    // after the call
+                    let inside_tbc = fs.blocks.last().is_some_and(|block| block.inside_tbc);
+                    if let (ExpKind::Call(pc), 1, false) = (last.kind, count, inside_tbc) {
+                        fs.proto.code[pc].set_opcode(OpCode::TailCall);
+                    }
+                    MULTIPLE_RESULTS
+                } else if count == 1 {
+                    first = fs.expression_to_any_register(&mut last)?;
+                    1
+                } else {
+                    fs.expression_to_next_register(&mut last)?;
+                    count as i32
+                }
+            }
+        };
+        self.fs().ret(first, count);
+        Ok(())
+    }
+
+    // Expressions
+
+    // Compiles all but the last expression of a list to consecutive registers, the last one is left for the caller to
+    // adjust to the number of values it needs
+    fn expression_list(&mut self, expression_list: &ExpressionList) -> CompileResult<(usize, ExpDesc)> {
+        let (last, rest) = expression_list.expressions.split_last().expect("Expression lists are never empty");
+        for expression in rest {
+            let mut e = self.expression(expression)?;
+            self.fs().expression_to_next_register(&mut e)?;
+        }
+        Ok((expression_list.expressions.len(), self.expression(last)?))
+    }
+
+    fn expression(&mut self, mut expression: &Expression) -> CompileResult<ExpDesc> {
+        // Chains like `1 + 2 + 3` nest to the left without a bound, the operators are collected on the way down to the
+        // leftmost operand and applied from the innermost one out
+        let mut operators = Vec::new();
+        let mut e1 = loop {
+            match expression {
+                Expression::Expr(expr) => break self.expr(expr)?,
+                Expression::Binary(op, lhs, rhs, span) => {
+                    operators.push((op, rhs, *span));
+                    expression = lhs;
+                }
+            }
+        };
+        for (op, rhs, span) in operators.into_iter().rev() {
+            self.fs().span = span;
+            self.fs().infix(op, &mut e1)?;
+            let e2 = self.expression(rhs)?;
+            // The operator's instructions are on its line
+            let fs = self.fs();
+            fs.span = span;
+            fs.posfix(op, &mut e1, e2)?;
+        }
+        Ok(e1)
+    }
+
+    fn expr(&mut self, expr: &Expr) -> CompileResult<ExpDesc> {
+        let kind = match expr {
+            Expr::Nil => ExpKind::Nil,
+            Expr::Boolean(true) => ExpKind::True,
+            Expr::Boolean(false) => ExpKind::False,
+            Expr::Numerical(NumberKind::Int(int)) => ExpKind::KInt(*int),
+            Expr::Numerical(NumberKind::Float(float)) => ExpKind::KFloat(*float),
+            Expr::LiteralString(string) => ExpKind::K(self.fs().string_constant(string)),
+            Expr::Expansion(_) => ExpKind::Vararg(self.fs().code_abc(OpCode::VarArg, 0, 0, 1)),
+            Expr::FunctionDef(body) => return self.function_body(body),
+            Expr::Prefix(prefix) => return self.prefix_expression(prefix),
+            Expr::Unary(op, operand, span) => {
+                let mut e = self.expression(operand)?;
+                let fs = self.fs();
+                fs.span = *span;
+                fs.prefix(*op, &mut e)?;
+                return Ok(e);
+            }
+            Expr::TableConstructor(table_constructor) => return self.table_constructor(table_constructor),
+        };
+        Ok(ExpDesc::new(kind))
+    }
+
+    fn prefix_expression(&mut self, prefix: &PrefixExpression) -> CompileResult<ExpDesc> {
+        match prefix {
+            PrefixExpression::Var(var) => self.var(var),
+            PrefixExpression::FunctionCall(call) => self.function_call(call),
+            // Parentheses truncate to one value
+            PrefixExpression::Expression(expression) => {
+                let mut e = self.expression(expression)?;
+                self.fs().discharge_vars(&mut e);
+                Ok(e)
+            }
+        }
+    }
+
+    fn var(&mut self, var: &Var) -> CompileResult<ExpDesc> {
+        match var {
+            Var::VarName(name, resolution) => self.variable(name, *resolution),
+            Var::FieldAccess(prefix, name, span) => {
+                let mut table = self.prefix_expression(prefix)?;
+                let fs = self.fs();
+                fs.expression_to_any_register_or_upvalue(&mut table)?;
+                fs.span = *span;
+                let mut key = ExpDesc::new(ExpKind::K(fs.string_constant(name.as_bytes())));
+                fs.indexed(&mut table, &mut key)?;
+                Ok(table)
+            }
+            Var::TableAccess(prefix, key, span) => {
+                let mut table = self.prefix_expression(prefix)?;
+                self.fs().expression_to_any_register_or_upvalue(&mut table)?;
+                let mut key = self.expression(key)?;
+                let fs = self.fs();
+                fs.expression_to_value(&mut key)?;
+                fs.span = *span;
+                fs.indexed(&mut table, &mut key)?;
+                Ok(table)
+            }
+        }
+    }
+
+    fn function_call(&mut self, call: &FunctionCall) -> CompileResult<ExpDesc> {
+        match call {
+            FunctionCall::Static(call) => {
+                let mut function = self.prefix_expression(&call.prefix)?;
+                self.fs().expression_to_next_register(&mut function)?;
+                self.call_arguments(function, &call.args, call.span)
+            }
+            FunctionCall::SelfRef(call) => {
+                let mut object = self.prefix_expression(&call.prefix)?;
+                let fs = self.fs();
+                fs.span = call.span;
+                let mut key = ExpDesc::new(ExpKind::K(fs.string_constant(call.name.as_bytes())));
+                fs.self_method(&mut object, &mut key)?;
+                self.call_arguments(object, &call.args, call.span)
+            }
+        }
+    }
+
+    // Compiles the arguments of a call whose function is in a register, followed by the CALL
+    fn call_arguments(&mut self, function: ExpDesc, args: &Args, span: Span) -> CompileResult<ExpDesc> {
+        let base = function.register();
+        let mut last = match args {
+            Args::ExpressionList(None) => ExpDesc::new(ExpKind::Void),
+            Args::ExpressionList(Some(expression_list)) => self.expression_list(expression_list)?.1,
+            Args::TableConstructor(table_constructor) => self.table_constructor(table_constructor)?,
+            Args::LiteralString(string) => ExpDesc::new(ExpKind::K(self.fs().string_constant(string))),
+        };
+        let fs = self.fs();
+        let b = if last.has_multiple_results() {
+            fs.set_returns(&mut last, MULTIPLE_RESULTS)?;
+            0
+        } else {
+            if !matches!(last.kind, ExpKind::Void) {
+                fs.expression_to_next_register(&mut last)?;
+            }
+            fs.first_free - base
+        };
+        fs.span = span;
+        let pc = fs.code_abc(OpCode::Call, base, b, 2);
+        fs.first_free = base + 1;
+        Ok(ExpDesc::new(ExpKind::Call(pc)))
+    }
+
+    fn table_constructor(&mut self, table_constructor: &TableConstructor) -> CompileResult<ExpDesc> {
+        let fs = self.fs();
+        let pc = fs.code_abc(OpCode::NewTable, 0, 0, 0);
+        fs.code(Instruction::ax(OpCode::ExtraArg, 0));
+        let table = fs.first_free;
+        fs.reserve_registers(1)?;
+        // A list item is only stored once it is known whether it is the last one, which may have multiple results
+        let mut pending = ExpDesc::new(ExpKind::Void);
+        let (mut array_size, mut hash_size, mut to_store) = (0u32, 0u32, 0u32);
+        for field in &table_constructor.fields {
+            if !matches!(pending.kind, ExpKind::Void) {
+                let fs = self.fs();
+                fs.expression_to_next_register(&mut pending)?;
+                pending = ExpDesc::new(ExpKind::Void);
+                if to_store == FIELDS_PER_FLUSH {
+                    fs.set_list(table, array_size, to_store as i32)?;
+                    array_size += to_store;
+                    to_store = 0;
+                }
+            }
+            match field {
+                Field::Positional(value) => {
+                    pending = self.expression(value)?;
+                    to_store += 1;
+                }
+                Field::Named(name, value) => {
+                    hash_size += 1;
+                    let key = ExpDesc::new(ExpKind::K(self.fs().string_constant(name.as_bytes())));
+                    self.record_field(table, key, value)?;
+                }
+                Field::Bracketed(key, value) => {
+                    hash_size += 1;
+                    let mut key = self.expression(key)?;
+                    self.fs().expression_to_value(&mut key)?;
+                    self.record_field(table, key, value)?;
+                }
+            }
+        }
+        let fs = self.fs();
+        if to_store > 0 {
+            if pending.has_multiple_results() {
+                fs.set_returns(&mut pending, MULTIPLE_RESULTS)?;
+                fs.set_list(table, array_size, MULTIPLE_RESULTS)?;
+                to_store -= 1;
+            } else {
+                if !matches!(pending.kind, ExpKind::Void) {
+                    fs.expression_to_next_register(&mut pending)?;
+                }
+                fs.set_list(table, array_size, to_store as i32)?;
+            }
+            array_size += to_store;
+        }
+        fs.set_table_size(pc, table, array_size, hash_size);
+        Ok(ExpDesc::new(ExpKind::NonReloc(table)))
+    }
+
+    fn record_field(&mut self, table: u32, mut key: ExpDesc, value: &Expression) -> CompileResult<()> {
+        let fs = self.fs();
+        let first_free = fs.first_free;
+        let mut target = ExpDesc::new(ExpKind::NonReloc(table));
+        fs.indexed(&mut target, &mut key)?;
+        let mut value = self.expression(value)?;
+        let fs = self.fs();
+        fs.store_var(&target, &mut value)?;
+        fs.first_free = first_free;
+        Ok(())
+    }
+}
+
+impl FunctionState {
+    // Ends the scope of the locals above `level`
+    fn remove_locals(&mut self, level: usize) {
+        while self.active.len() > level {
+            let local = self.active.pop().expect("The local is active");
+            self.proto.local_variables[local.debug_index].end_pc = self.pc();
+        }
+    }
+
+    // Marks the block declaring the local in `register` as having a captured local
+    fn mark_upvalue(&mut self, register: u32) {
+        let block = self
+            .blocks
+            .iter_mut()
+            .rev()
+            .find(|block| block.active_count as u32 <= register)
+            .expect("The local was declared in an open block");
+        block.has_upvalue = true;
+        self.needs_close = true;
+    }
+}
+
+// Points a for loop instruction at `destination`, backward jumps store their distance as a positive number
+fn fix_for_jump(fs: &mut FunctionState, pc: usize, destination: usize, back: bool) -> CompileResult<()> {
+    let offset = match back {
+        true => pc + 1 - destination,
+        false => destination - (pc + 1),
+    };
+    if offset > MAX_BX as usize {
+        return Err(fs.error("control structure too long"));
+    }
+    fs.proto.code[pc].set_bx(offset as u32);
+    Ok(())
+}
@@ -0,0 +1,389 @@
+// The instruction set of the virtual machine, modelled on the one of Lua 5.4. Instructions are 32 bits wide with the
+// opcode in the low 7 bits and the operands packed above it in one of these layouts:
+//
+//   iABC   C(8) | B(8) | k(1) | A(8) | op(7)
+//   iABx         Bx(17)     | A(8) | op(7)
+//   iAsBx       sBx(17)     | A(8) | op(7)
+//   iAx               Ax(25)       | op(7)
+//   isJ               sJ(25)       | op(7)
+//
+// Signed operands are stored with an offset, the excess-K encoding the reference implementation uses.
+
+use std::fmt::{Debug, Formatter};
+
+pub const MAX_A: u32 = (1 << 8) - 1;
+pub const MAX_B: u32 = (1 << 8) - 1;
+pub const MAX_C: u32 = (1 << 8) - 1;
+pub const MAX_BX: u32 = (1 << 17) - 1;
+pub const MAX_AX: u32 = (1 << 25) - 1;
+pub const MAX_SJ: u32 = (1 << 25) - 1;
+pub const OFFSET_SBX: i32 = (MAX_BX >> 1) as i32;
+pub const OFFSET_SJ: i32 = (MAX_SJ >> 1) as i32;
+pub const OFFSET_SC: i32 = (MAX_C >> 1) as i32;
+
+// How many list items of a table constructor are stored by one SETLIST
+pub const FIELDS_PER_FLUSH: u32 = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OpMode {
+    Abc,
+    ABx,
+    AsBx,
+    Ax,
+    SJ,
+}
+
+// R[x] is a register, K[x] a constant, U[x] an upvalue and RK(x) is K[x] when k is set and R[x] otherwise. Arithmetic
+// with a constant or immediate operand uses k to mark that the operands were swapped, which the metamethods see.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum OpCode {
+    Move,       // A B      R[A] := R[B]
+    LoadI,      // A sBx    R[A] := sBx
+    LoadF,      // A sBx    R[A] := (float)sBx
+    LoadK,      // A Bx     R[A] := K[Bx]
+    LoadKX,     // A        R[A] := K[extra arg]
+    LoadFalse,  // A        R[A] := false
+    LFalseSkip, // A        R[A] := false; pc++
+    LoadTrue,   // A        R[A] := true
+    LoadNil,    // A B      R[A], ..., R[A+B] := nil
+    GetUpval,   // A B      R[A] := U[B]
+    SetUpval,   // A B      U[B] := R[A]
+    GetTabUp,   // A B C    R[A] := U[B][K[C]:string]
+    GetTable,   // A B C    R[A] := R[B][R[C]]
+    GetI,       // A B C    R[A] := R[B][C]
+    GetField,   // A B C    R[A] := R[B][K[C]:string]
+    SetTabUp,   // A B C    U[A][K[B]:string] := RK(C)
+    SetTable,   // A B C    R[A][R[B]] := RK(C)
+    SetI,       // A B C    R[A][B] := RK(C)
+    SetField,   // A B C    R[A][K[B]:string] := RK(C)
+    NewTable,   // A B C k  R[A] := {}
+    SelfMethod, // A B C    R[A+1] := R[B]; R[A] := R[B][RK(C):string]
+    AddI,       // A B sC   R[A] := R[B] + sC
+    AddK,       // A B C    R[A] := R[B] + K[C]:number
+    SubK,       // A B C    R[A] := R[B] - K[C]:number
+    MulK,       // A B C    R[A] := R[B] * K[C]:number
+    ModK,       // A B C    R[A] := R[B] % K[C]:number
+    PowK,       // A B C    R[A] := R[B] ^ K[C]:number
+    DivK,       // A B C    R[A] := R[B] / K[C]:number
+    IDivK,      // A B C    R[A] := R[B] // K[C]:number
+    BAndK,      // A B C    R[A] := R[B] & K[C]:integer
+    BOrK,       // A B C    R[A] := R[B] | K[C]:integer
+    BXorK,      // A B C    R[A] := R[B] ~ K[C]:integer
+    ShrI,       // A B sC   R[A] := R[B] >> sC
+    ShlI,       // A B sC   R[A] := sC << R[B]
+    Add,        // A B C    R[A] := R[B] + R[C]
+    Sub,        // A B C    R[A] := R[B] - R[C]
+    Mul,        // A B C    R[A] := R[B] * R[C]
+    Mod,        // A B C    R[A] := R[B] % R[C]
+    Pow,        // A B C    R[A] := R[B] ^ R[C]
+    Div,        // A B C    R[A] := R[B] / R[C]
+    IDiv,       // A B C    R[A] := R[B] // R[C]
+    BAnd,       // A B C    R[A] := R[B] & R[C]
+    BOr,        // A B C    R[A] := R[B] | R[C]
+    BXor,       // A B C    R[A] := R[B] ~ R[C]
+    Shl,        // A B C    R[A] := R[B] << R[C]
+    Shr,        // A B C    R[A] := R[B] >> R[C]
+    Unm,        // A B      R[A] := -R[B]
+    BNot,       // A B      R[A] := ~R[B]
+    Not,        // A B      R[A] := not R[B]
+    Len,        // A B      R[A] := #R[B]
+    Concat,     // A B      R[A] := R[A].. ... ..R[A + B - 1]
+    Close,      // A        close all upvalues and to-be-closed variables >= R[A]
+    Tbc,        // A        mark variable A "to be closed"
+    Jmp,        // sJ       pc += sJ
+    Eq,         // A B k    if ((R[A] == R[B]) ~= k) then pc++
+    Lt,         // A B k    if ((R[A] <  R[B]) ~= k) then pc++
+    Le,         // A B k    if ((R[A] <= R[B]) ~= k) then pc++
+    EqK,        // A B k    if ((R[A] == K[B]) ~= k) then pc++
+    EqI,        // A sB k   if ((R[A] == sB) ~= k) then pc++
+    LtI,        // A sB k   if ((R[A] < sB) ~= k) then pc++
+    LeI,        // A sB k   if ((R[A] <= sB) ~= k) then pc++
+    GtI,        // A sB k   if ((R[A] > sB) ~= k) then pc++
+    GeI,        // A sB k   if ((R[A] >= sB) ~= k) then pc++
+    Test,       // A k      if (not R[A] == k) then pc++
+    TestSet,    // A B k    if (not R[B] == k) then pc++ else R[A] := R[B]
+    Call,       // A B C    R[A], ... ,R[A+C-2] := R[A](R[A+1], ... ,R[A+B-1])
+    TailCall,   // A B C k  return R[A](R[A+1], ... ,R[A+B-1])
+    Return,     // A B C k  return R[A], ... ,R[A+B-2]
+    Return0,    //          return
+    Return1,    // A        return R[A]
+    ForLoop,    // A Bx     update counters; if loop continues then pc-=Bx;
+    ForPrep,    // A Bx     <check values and prepare counters>; if not to run then pc+=Bx+1;
+    TForPrep,   // A Bx     create upvalue for R[A + 3]; pc+=Bx
+    TForCall,   // A C      R[A+4], ... ,R[A+3+C] := R[A](R[A+1], R[A+2]);
+    TForLoop,   // A Bx     if R[A+4] ~= nil then { R[A+2]=R[A+4]; pc -= Bx }
+    SetList,    // A B C k  R[A][C+i] := R[A+i], 1 <= i <= B
+    Closure,    // A Bx     R[A] := closure(KPROTO[Bx])
+    VarArg,     // A C      R[A], R[A+1], ..., R[A+C-2] = vararg
+    VarArgPrep, // A        (adjust vararg parameters)
+    ExtraArg,   // Ax       extra (larger) argument for previous opcode
+}
+
+impl OpCode {
+    const ALL: [OpCode; 80] = [
+        OpCode::Move, OpCode::LoadI, OpCode::LoadF, OpCode::LoadK, OpCode::LoadKX, OpCode::LoadFalse,
+        OpCode::LFalseSkip, OpCode::LoadTrue, OpCode::LoadNil, OpCode::GetUpval, OpCode::SetUpval, OpCode::GetTabUp,
+        OpCode::GetTable, OpCode::GetI, OpCode::GetField, OpCode::SetTabUp, OpCode::SetTable, OpCode::SetI,
+        OpCode::SetField, OpCode::NewTable, OpCode::SelfMethod, OpCode::AddI, OpCode::AddK, OpCode::SubK, OpCode::MulK,
+        OpCode::ModK, OpCode::PowK, OpCode::DivK, OpCode::IDivK, OpCode::BAndK, OpCode::BOrK, OpCode::BXorK,
+        OpCode::ShrI, OpCode::ShlI, OpCode::Add, OpCode::Sub, OpCode::Mul, OpCode::Mod, OpCode::Pow, OpCode::Div,
+        OpCode::IDiv, OpCode::BAnd, OpCode::BOr, OpCode::BXor, OpCode::Shl, OpCode::Shr, OpCode::Unm, OpCode::BNot,
+        OpCode::Not, OpCode::Len, OpCode::Concat, OpCode::Close, OpCode::Tbc, OpCode::Jmp, OpCode::Eq, OpCode::Lt,
+        OpCode::Le, OpCode::EqK, OpCode::EqI, OpCode::LtI, OpCode::LeI, OpCode::GtI, OpCode::GeI, OpCode::Test,
+        OpCode::TestSet, OpCode::Call, OpCode::TailCall, OpCode::Return, OpCode::Return0, OpCode::Return1,
+        OpCode::ForLoop, OpCode::ForPrep, OpCode::TForPrep, OpCode::TForCall, OpCode::TForLoop, OpCode::SetList,
+        OpCode::Closure, OpCode::VarArg, OpCode::VarArgPrep, OpCode::ExtraArg,
+    ];
+
+    pub fn from_u8(value: u8) -> Option<OpCode> {
+        OpCode::ALL.get(value as usize).copied()
+    }
+
+    // The name used by listings, like "GETTABUP"
+    pub fn name(self) -> &'static str {
+        match self {
+            OpCode::Move => "MOVE",
+            OpCode::LoadI => "LOADI",
+            OpCode::LoadF => "LOADF",
+            OpCode::LoadK => "LOADK",
+            OpCode::LoadKX => "LOADKX",
+            OpCode::LoadFalse => "LOADFALSE",
+            OpCode::LFalseSkip => "LFALSESKIP",
+            OpCode::LoadTrue => "LOADTRUE",
+            OpCode::LoadNil => "LOADNIL",
+            OpCode::GetUpval => "GETUPVAL",
+            OpCode::SetUpval => "SETUPVAL",
+            OpCode::GetTabUp => "GETTABUP",
+            OpCode::GetTable => "GETTABLE",
+            OpCode::GetI => "GETI",
+            OpCode::GetField => "GETFIELD",
+            OpCode::SetTabUp => "SETTABUP",
+            OpCode::SetTable => "SETTABLE",
+            OpCode::SetI => "SETI",
+            OpCode::SetField => "SETFIELD",
+            OpCode::NewTable => "NEWTABLE",
+            OpCode::SelfMethod => "SELF",
+            OpCode::AddI => "ADDI",
+            OpCode::AddK => "ADDK",
+            OpCode::SubK => "SUBK",
+            OpCode::MulK => "MULK",
+            OpCode::ModK => "MODK",
+            OpCode::PowK => "POWK",
+            OpCode::DivK => "DIVK",
+            OpCode::IDivK => "IDIVK",
+            OpCode::BAndK => "BANDK",
+            OpCode::BOrK => "BORK",
+            OpCode::BXorK => "BXORK",
+            OpCode::ShrI => "SHRI",
+            OpCode::ShlI => "SHLI",
+            OpCode::Add => "ADD",
+            OpCode::Sub => "SUB",
+            OpCode::Mul => "MUL",
+            OpCode::Mod => "MOD",
+            OpCode::Pow => "POW",
+            OpCode::Div => "DIV",
+            OpCode::IDiv => "IDIV",
+            OpCode::BAnd => "BAND",
+            OpCode::BOr => "BOR",
+            OpCode::BXor => "BXOR",
+            OpCode::Shl => "SHL",
+            OpCode::Shr => "SHR",
+            OpCode::Unm => "UNM",
+            OpCode::BNot => "BNOT",
+            OpCode::Not => "NOT",
+            OpCode::Len => "LEN",
+            OpCode::Concat => "CONCAT",
+            OpCode::Close => "CLOSE",
+            OpCode::Tbc => "TBC",
+            OpCode::Jmp => "JMP",
+            OpCode::Eq => "EQ",
+            OpCode::Lt => "LT",
+            OpCode::Le => "LE",
+            OpCode::EqK => "EQK",
+            OpCode::EqI => "EQI",
+            OpCode::LtI => "LTI",
+            OpCode::LeI => "LEI",
+            OpCode::GtI => "GTI",
+            OpCode::GeI => "GEI",
+            OpCode::Test => "TEST",
+            OpCode::TestSet => "TESTSET",
+            OpCode::Call => "CALL",
+            OpCode::TailCall => "TAILCALL",
+            OpCode::Return => "RETURN",
+            OpCode::Return0 => "RETURN0",
+            OpCode::Return1 => "RETURN1",
+            OpCode::ForLoop => "FORLOOP",
+            OpCode::ForPrep => "FORPREP",
+            OpCode::TForPrep => "TFORPREP",
+            OpCode::TForCall => "TFORCALL",
+            OpCode::TForLoop => "TFORLOOP",
+            OpCode::SetList => "SETLIST",
+            OpCode::Closure => "CLOSURE",
+            OpCode::VarArg => "VARARG",
+            OpCode::VarArgPrep => "VARARGPREP",
+            OpCode::ExtraArg => "EXTRAARG",
+        }
+    }
+
+    pub fn mode(self) -> OpMode {
+        match self {
+            OpCode::LoadI | OpCode::LoadF => OpMode::AsBx,
+            OpCode::LoadK
+            | OpCode::ForLoop
+            | OpCode::ForPrep
+            | OpCode::TForPrep
+            | OpCode::TForLoop
+            | OpCode::Closure => OpMode::ABx,
+            OpCode::Jmp => OpMode::SJ,
+            OpCode::ExtraArg => OpMode::Ax,
+            _ => OpMode::Abc,
+        }
+    }
+
+    // Whether the instruction writes to register A, used to find where a register got its value from
+    pub fn sets_a(self) -> bool {
+        !matches!(
+            self,
+            OpCode::SetUpval
+                | OpCode::SetTabUp
+                | OpCode::SetTable
+                | OpCode::SetI
+                | OpCode::SetField
+                | OpCode::Close
+                | OpCode::Tbc
+                | OpCode::Jmp
+                | OpCode::Eq
+                | OpCode::Lt
+                | OpCode::Le
+                | OpCode::EqK
+                | OpCode::EqI
+                | OpCode::LtI
+                | OpCode::LeI
+                | OpCode::GtI
+                | OpCode::GeI
+                | OpCode::Test
+                | OpCode::TailCall
+                | OpCode::Return
+                | OpCode::Return0
+                | OpCode::Return1
+                | OpCode::TForPrep
+                | OpCode::TForCall
+                | OpCode::SetList
+                | OpCode::VarArgPrep
+                | OpCode::ExtraArg
+        )
+    }
+
+    // Whether the instruction is a test, which is always followed by a jump
+    pub fn is_test(self) -> bool {
+        matches!(
+            self,
+            OpCode::Eq
+                | OpCode::Lt
+                | OpCode::Le
+                | OpCode::EqK
+                | OpCode::EqI
+                | OpCode::LtI
+                | OpCode::LeI
+                | OpCode::GtI
+                | OpCode::GeI
+                | OpCode::Test
+                | OpCode::TestSet
+        )
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct Instruction(pub u32);
+
+impl Instruction {
+    pub fn abc(op: OpCode, a: u32, b: u32, c: u32, k: bool) -> Self {
+        debug_assert!(a <= MAX_A && b <= MAX_B && c <= MAX_C);
+        Self(op as u32 | a << 7 | (k as u32) << 15 | b << 16 | c << 24)
+    }
+    pub fn abx(op: OpCode, a: u32, bx: u32) -> Self {
+        debug_assert!(a <= MAX_A && bx <= MAX_BX);
+        Self(op as u32 | a << 7 | bx << 15)
+    }
+    pub fn asbx(op: OpCode, a: u32, sbx: i32) -> Self {
+        Self::abx(op, a, (sbx + OFFSET_SBX) as u32)
+    }
+    pub fn ax(op: OpCode, ax: u32) -> Self {
+        debug_assert!(ax <= MAX_AX);
+        Self(op as u32 | ax << 7)
+    }
+    pub fn sj(op: OpCode, sj: i32) -> Self {
+        Self(op as u32 | ((sj + OFFSET_SJ) as u32) << 7)
+    }
+
+    // Only valid for instructions which were built from an OpCode or checked when loaded
+    pub fn opcode(self) -> OpCode {
+        OpCode::ALL[(self.0 & 0x7f) as usize]
+    }
+    pub fn a(self) -> u32 {
+        (self.0 >> 7) & MAX_A
+    }
+    pub fn k(self) -> bool {
+        (self.0 >> 15) & 1 == 1
+    }
+    pub fn b(self) -> u32 {
+        (self.0 >> 16) & MAX_B
+    }
+    pub fn sb(self) -> i32 {
+        self.b() as i32 - OFFSET_SC
+    }
+    pub fn c(self) -> u32 {
+        self.0 >> 24
+    }
+    pub fn sc(self) -> i32 {
+        self.c() as i32 - OFFSET_SC
+    }
+    pub fn bx(self) -> u32 {
+        self.0 >> 15
+    }
+    pub fn sbx(self) -> i32 {
+        self.bx() as i32 - OFFSET_SBX
+    }
+    pub fn ax_arg(self) -> u32 {
+        self.0 >> 7
+    }
+    pub fn sj_arg(self) -> i32 {
+        (self.0 >> 7) as i32 - OFFSET_SJ
+    }
+
+    pub fn set_opcode(&mut self, op: OpCode) {
+        self.0 = (self.0 & !0x7f) | op as u32;
+    }
+    pub fn set_a(&mut self, a: u32) {
+        self.0 = (self.0 & !(MAX_A << 7)) | a << 7;
+    }
+    pub fn set_k(&mut self, k: bool) {
+        self.0 = (self.0 & !(1 << 15)) | (k as u32) << 15;
+    }
+    pub fn set_b(&mut self, b: u32) {
+        self.0 = (self.0 & !(MAX_B << 16)) | b << 16;
+    }
+    pub fn set_c(&mut self, c: u32) {
+        self.0 = (self.0 & !(MAX_C << 24)) | c << 24;
+    }
+    pub fn set_bx(&mut self, bx: u32) {
+        self.0 = (self.0 & 0x7fff) | bx << 15;
+    }
+    pub fn set_sj(&mut self, sj: i32) {
+        self.0 = (self.0 & 0x7f) | ((sj + OFFSET_SJ) as u32) << 7;
+    }
+}
+
+impl Debug for Instruction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let op = self.opcode();
+        match op.mode() {
+            OpMode::Abc => write!(f, "{} {} {} {}{}", op.name(), self.a(), self.b(), self.c(), if self.k() { "k" } else { "" }),
+            OpMode::ABx => write!(f, "{} {} {}", op.name(), self.a(), self.bx()),
+            OpMode::AsBx => write!(f, "{} {} {}", op.name(), self.a(), self.sbx()),
+            OpMode::Ax => write!(f, "{} {}", op.name(), self.ax_arg()),
+            OpMode::SJ => write!(f, "{} {}", op.name(), self.sj_arg()),
+        }
+    }
+}
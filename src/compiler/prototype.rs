@@ -0,0 +1,66 @@
+use std::rc::Rc;
+
+use crate::compiler::opcode::Instruction;
+use crate::frontend::data::DataKind;
+
+// A compiled function. Closures created from it share the prototype and only differ in their upvalues.
+#[derive(Debug)]
+pub struct Prototype {
+    // The chunk the function was compiled from, like "fib.lua"
+    pub source: Rc<str>,
+    // Lines of the `function` keyword and of the closing `end`, both are 0 for the main chunk
+    pub line_defined: usize,
+    pub last_line_defined: usize,
+    pub num_params: u8,
+    pub is_vararg: bool,
+    // The number of registers the function needs
+    pub max_stack_size: u8,
+    pub code: Vec<Instruction>,
+    // The source line of each instruction, empty when the debug information was stripped
+    pub line_info: Vec<usize>,
+    pub constants: Vec<DataKind>,
+    pub upvalues: Vec<UpvalueDescriptor>,
+    // Functions defined inside of this one, created by CLOSURE
+    pub prototypes: Vec<Rc<Prototype>>,
+    // Locals in the order they are declared, used to name variables in error messages
+    pub local_variables: Vec<LocalVariable>,
+}
+
+// Where a closure finds an upvalue when it is created
+#[derive(Debug, Clone)]
+pub struct UpvalueDescriptor {
+    pub name: String,
+    // Whether the upvalue is a register of the enclosing function, otherwise it is one of its upvalues
+    pub in_stack: bool,
+    pub index: u8,
+}
+
+// A local is in scope for the instructions in start_pc..end_pc, the n-th local in scope lives in register n
+#[derive(Debug, Clone)]
+pub struct LocalVariable {
+    pub name: String,
+    pub start_pc: usize,
+    pub end_pc: usize,
+}
+
+impl Prototype {
+    // The source line of the instruction at `pc`, 0 when it is unknown
+    pub fn line_at(&self, pc: usize) -> usize {
+        self.line_info.get(pc).copied().unwrap_or(0)
+    }
+    pub fn upvalue_name(&self, index: usize) -> &str {
+        match self.upvalues[index].name.as_str() {
+            "" => "?",
+            name => name,
+        }
+    }
+    // The name of the local held by `register` while the instruction at `pc` runs
+    pub fn local_name(&self, register: u32, pc: usize) -> Option<&str> {
+        self.local_variables
+            .iter()
+            .take_while(|local| local.start_pc <= pc)
+            .filter(|local| pc < local.end_pc)
+            .nth(register as usize)
+            .map(|local| local.name.as_str())
+    }
+}
@@ -0,0 +1,64 @@
+// Renders compile and runtime errors with the line of source they happened on, in the style of rustc:
+//
+// error: 'end' expected (to close 'function' at line 1) near <eof>
+//  --> example.lua:3:1
+//   |
+// 3 | print(f())
+//   | ^
+pub struct Diagnostic {
+    pub message: String,
+    // A line of 0 means the error has no position in the source
+    pub line: usize,
+    // A column of 0 underlines the whole line
+    pub column: usize,
+    // The number of characters underlined from the column
+    pub length: usize,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn render(&self, chunk_name: &str, source: &str) -> String {
+        let mut rendered = format!("error: {}\n", self.message);
+        let gutter = " ".repeat(self.line.to_string().len());
+        if self.line > 0 {
+            match self.column {
+                0 => rendered.push_str(&format!("{}--> {}:{}\n", gutter, chunk_name, self.line)),
+                column => rendered.push_str(&format!("{}--> {}:{}:{}\n", gutter, chunk_name, self.line, column)),
+            }
+            // The end of a file which ends with a newline is on an empty line past the last one
+            let text = source.lines().nth(self.line - 1).unwrap_or("");
+            rendered.push_str(&format!("{} |\n", gutter));
+            rendered.push_str(&format!("{} | {}\n", self.line, text));
+            rendered.push_str(&format!("{} | {}\n", gutter, self.underline(text)));
+        }
+        for note in &self.notes {
+            let mut lines = note.lines();
+            if let Some(first) = lines.next() {
+                rendered.push_str(&format!("{} = note: {}\n", gutter, first));
+            }
+            for line in lines {
+                rendered.push_str(&format!("{}   {}\n", gutter, line));
+            }
+        }
+        rendered
+    }
+
+    fn underline(&self, text: &str) -> String {
+        let (start, length) = match self.column {
+            0 => {
+                let start = text.chars().take_while(|c| c.is_whitespace()).count();
+                (start, text.trim().chars().count())
+            }
+            column => (column - 1, self.length),
+        };
+        // Tabs are kept so that the carets line up with the source line however wide tabs are shown
+        let mut underline: String = text
+            .chars()
+            .chain(std::iter::repeat(' '))
+            .take(start)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        underline.push_str(&"^".repeat(length.max(1)));
+        underline
+    }
+}
@@ -1,10 +1,20 @@
-use crate::ast::Rule;
+use crate::ast::{syntax_error, Rule};
+use crate::diagnostic::Diagnostic;
+use crate::frontend::data::DataKind;
 
 #[derive(Debug)]
 pub struct CompileError {
     error_msg: String,
     line: usize,
     column: usize,
+    // The number of characters the error covers, like the length of the unexpected token
+    length: usize,
+    // Tokens which would have been accepted where a syntax error happened, like "'end'" or "<name>"
+    expected: Vec<String>,
+    // Whether the error is a block which is never closed, which leaves nothing after it to recover at
+    unclosed_block: bool,
+    // Whether the error is at the first token of its line
+    starts_line: bool,
 }
 
 impl CompileError {
@@ -13,47 +23,218 @@ impl CompileError {
             error_msg: error_str.to_string(),
             line: line_col.0,
             column: line_col.1,
+            length: 1,
+            expected: Vec::new(),
+            unclosed_block: false,
+            starts_line: false,
         }
     }
-    pub fn from_pest_error(e: pest::error::Error<Rule>) -> Self {
-        println!("{:?}", e);
+    pub fn from_pest_error(e: pest::error::Error<Rule>, source: &str) -> Self {
         let line_col = match e.line_col {
             pest::error::LineColLocation::Pos(pos) => pos,
             pest::error::LineColLocation::Span(start, _end) => start,
         };
-        Self::new("Invalid Lua", line_col)
+        let position = match e.location {
+            pest::error::InputLocation::Pos(pos) => pos,
+            pest::error::InputLocation::Span((start, _end)) => start,
+        };
+        let token = syntax_error::token_at(source, position);
+        let near = token.map(|token| format!("'{}'", token)).unwrap_or_else(|| "<eof>".to_owned());
+        let length = token.map(|token| token.chars().count()).unwrap_or(1);
+        let expected = match &e.variant {
+            pest::error::ErrorVariant::ParsingError { positives, .. } => expected_tokens(&e, positives),
+            pest::error::ErrorVariant::CustomError { .. } => Vec::new(),
+        };
+        let unclosed = syntax_error::unclosed_block(source, position)
+            .filter(|(_, closer, _)| expected.contains(&format!("'{}'", closer)));
+        let unclosed_block = unclosed.is_some();
+        let error_msg = match (&e.variant, unclosed, expected.as_slice()) {
+            (pest::error::ErrorVariant::CustomError { message }, _, _) => format!("{} near {}", message, near),
+            _ if token.is_some_and(syntax_error::is_unfinished_string) => format!("unfinished string near {}", near),
+            // A block which is never closed is reported with the line it was opened on, like the reference
+            // implementation
+            (_, Some((opener, closer, line)), _) if line != line_col.0 => {
+                format!("'{}' expected (to close '{}' at line {}) near {}", closer, opener, line, near)
+            }
+            (_, Some((_, closer, _)), _) => format!("'{}' expected near {}", closer, near),
+            (_, None, [only]) if only != "<expression>" => format!("{} expected near {}", only, near),
+            _ => format!("unexpected symbol near {}", near),
+        };
+        let line_start = source[..position].rfind('\n').map(|index| index + 1).unwrap_or(0);
+        Self {
+            error_msg,
+            line: line_col.0,
+            column: line_col.1,
+            length,
+            expected,
+            unclosed_block,
+            starts_line: source[line_start..position].trim().is_empty(),
+        }
+    }
+    pub fn line(&self) -> usize {
+        self.line
     }
-    pub fn print_error(&self) {
-        eprintln!(
-            "Failed to compile Lua with error '{}' on line {} column {}",
-            self.error_msg, self.line, self.column
-        )
+    pub fn is_unclosed_block(&self) -> bool {
+        self.unclosed_block
+    }
+    pub fn starts_line(&self) -> bool {
+        self.starts_line
+    }
+    pub fn diagnostic(&self) -> Diagnostic {
+        let notes = match self.expected.len() {
+            0 | 1 => Vec::new(),
+            _ => vec![format!("expected one of {}", self.expected.join(", "))],
+        };
+        Diagnostic {
+            message: self.error_msg.clone(),
+            line: self.line,
+            column: self.column,
+            length: self.length,
+            notes,
+        }
     }
 }
 
-#[derive(Debug)]
+fn expected_tokens(e: &pest::error::Error<Rule>, positives: &[Rule]) -> Vec<String> {
+    let mut described: Vec<&str> = positives.iter().filter_map(|rule| describe_rule(*rule)).collect();
+    described.dedup();
+    let literal_expected = described.iter().any(|description| LITERALS.contains(description));
+    let mut expected: Vec<String> = match e.parse_attempts() {
+        Some(attempts) => attempts
+            .expected_tokens()
+            .into_iter()
+            .map(|token| token.to_string())
+            .filter(|token| is_lua_token(token, literal_expected))
+            .map(|token| format!("'{}'", token))
+            .collect(),
+        None => Vec::new(),
+    };
+    expected.extend(described.into_iter().map(str::to_owned));
+    expected
+}
+
+// Descriptions of the rules which begin with a literal value
+const LITERALS: [&str; 3] = ["<expression>", "<number>", "<string>"];
+
+// Describes the rules a syntax error can expect, the literal tokens of the grammar come from pest's parse attempts
+fn describe_rule(rule: Rule) -> Option<&'static str> {
+    match rule {
+        Rule::Name => Some("<name>"),
+        Rule::Expression | Rule::Expr => Some("<expression>"),
+        Rule::Numerical | Rule::Float | Rule::Integer => Some("<number>"),
+        Rule::LiteralString => Some("<string>"),
+        Rule::Block | Rule::Stat => Some("<statement>"),
+        Rule::Args | Rule::CallSuffix => Some("<arguments>"),
+        Rule::EOI => Some("<eof>"),
+        _ => None,
+    }
+}
+
+// Parse attempts also hold the characters that names, numbers and strings are made of, which are not Lua tokens on
+// their own. The '.' of ".5" and the '[' of a long string are only dropped when a literal was expected anyway.
+fn is_lua_token(token: &str, literal_expected: bool) -> bool {
+    let keyword = token.chars().all(|c| c.is_ascii_lowercase());
+    let symbol = token.chars().all(|c| c.is_ascii_punctuation()) && !["--", "\\", "_", "\"", "'"].contains(&token);
+    let literal_start = token == "." || token == "[";
+    !token.is_empty() && (keyword || symbol) && !(literal_expected && literal_start)
+}
+
+// The operand of an operation which had the wrong type, used to name the variable it came from in error messages
+#[derive(Debug, Clone, Copy)]
+pub enum Operand {
+    First,
+    Second,
+}
+
+#[derive(Debug, Clone)]
 pub enum RuntimeFailure {
-    VarNotFound(String, i32),
-    FuncNotFound(String, i32),
     BadFunctionArgs(String, i32),
-    BorrowError(String, i32),
-    DuplicateFunction(String),
-    InternalError(String),
-    WrongType(String, i32),
-    InvalidArgs(String, i32)
+    WrongType(String, Operand, i32),
+    BadOperation(String, i32),
+    NoIntegerRepresentation(i32),
+    CoroutineError(String, i32),
+    // A value raised with `error` or `assert`. String messages already carry their position.
+    ErrorObject(DataKind),
+    // Unwinds a suspended coroutine which is being closed by coroutine.close, this is never caught by Lua code
+    CoroutineClosed,
+    // An allocation would have taken the state past its memory limit. Lua code can catch it, but message handlers do
+    // not see it, like in the reference implementation.
+    OutOfMemory,
+    // Stops a script which ran out of its instruction budget or which a hook interrupted, Lua code cannot catch it
+    Interrupted(String),
+}
+
+// Errors raised by host functions from a message, the position of the caller is added when the function returns
+impl From<String> for RuntimeFailure {
+    fn from(msg: String) -> Self {
+        RuntimeFailure::BadOperation(msg, 0)
+    }
+}
+
+impl From<&str> for RuntimeFailure {
+    fn from(msg: &str) -> Self {
+        RuntimeFailure::BadOperation(msg.to_owned(), 0)
+    }
 }
 
 impl RuntimeFailure {
-    pub fn print_error(&self) {
+    pub fn message(&self) -> String {
+        match self {
+            RuntimeFailure::BadFunctionArgs(msg, _) => msg.clone(),
+            RuntimeFailure::WrongType(msg, _, _) => msg.clone(),
+            RuntimeFailure::BadOperation(msg, _) => msg.clone(),
+            RuntimeFailure::NoIntegerRepresentation(_) => "number has no integer representation".to_owned(),
+            RuntimeFailure::CoroutineError(msg, _) => msg.clone(),
+            RuntimeFailure::ErrorObject(value) => match value {
+                DataKind::String(_) | DataKind::Number(_) => value.to_string(),
+                _ => format!("(error object is a {} value)", value.type_name()),
+            },
+            RuntimeFailure::CoroutineClosed => "coroutine was closed".to_owned(),
+            RuntimeFailure::OutOfMemory => "not enough memory".to_owned(),
+            RuntimeFailure::Interrupted(msg) => msg.clone(),
+        }
+    }
+    // Whether pcall and coroutine.resume return the failure to Lua code, the others unwind everything
+    pub fn is_catchable(&self) -> bool {
+        !matches!(self, RuntimeFailure::CoroutineClosed | RuntimeFailure::Interrupted(_))
+    }
+    // The line an internal failure happened on, errors raised from Lua have their position in the error value
+    pub fn line(&self) -> Option<i32> {
         match self {
-            RuntimeFailure::VarNotFound(var_name, line) => eprintln!("Error on line {}: Variable '{}' not found", line, var_name),
-            RuntimeFailure::FuncNotFound(func_name, line) => eprintln!("Error on line {}: Function '{}' not found", line, func_name),
-            RuntimeFailure::BadFunctionArgs(msg, line) => eprintln!("Error on line {}: {}", line, msg),
-            RuntimeFailure::BorrowError(msg, line) => eprintln!("Error on line {}: {}", line, msg),
-            RuntimeFailure::DuplicateFunction(name) => eprintln!("Error: Function '{}' defined multiple times", name),
-            RuntimeFailure::InternalError(msg) => eprintln!("Internal error while {}", msg),
-            RuntimeFailure::WrongType(expected_type, line) => eprintln!("Error on line {}: Expected type '{}'", line, expected_type),
-            RuntimeFailure::InvalidArgs(msg, line) => eprintln!("Error on line {}: {}", line, msg)
+            RuntimeFailure::BadFunctionArgs(_, line)
+            | RuntimeFailure::WrongType(_, _, line)
+            | RuntimeFailure::BadOperation(_, line)
+            | RuntimeFailure::NoIntegerRepresentation(line)
+            | RuntimeFailure::CoroutineError(_, line) => Some(*line),
+            RuntimeFailure::ErrorObject(_)
+            | RuntimeFailure::OutOfMemory
+            | RuntimeFailure::CoroutineClosed
+            | RuntimeFailure::Interrupted(_) => None,
+        }
+    }
+    // Uncaught errors carry their traceback after the message and their position at its start, like
+    // "chunk:3: attempt to call a nil value"
+    pub fn diagnostic(&self, chunk_name: &str) -> Diagnostic {
+        let message = self.message();
+        let (message, traceback) = match message.split_once("\nstack traceback:") {
+            Some((message, traceback)) => (message.to_owned(), Some(format!("stack traceback:{}", traceback))),
+            None => (message, None),
+        };
+        let line = match self.line() {
+            Some(line) => line.max(0) as usize,
+            None => message
+                .strip_prefix(chunk_name)
+                .and_then(|rest| rest.strip_prefix(':'))
+                .and_then(|rest| rest.split_once(':'))
+                .and_then(|(line, _)| line.parse().ok())
+                .unwrap_or(0),
+        };
+        Diagnostic {
+            message,
+            line,
+            column: 0,
+            length: 0,
+            notes: traceback.into_iter().collect(),
         }
     }
 }
@@ -0,0 +1,150 @@
+use crate::compiler::prototype::Prototype;
+use crate::frontend::Context;
+use crate::frontend::data::DataKind;
+use crate::frontend::debug_info;
+use crate::frontend::function::{FunctionKind, FunctionRef};
+use crate::frontend::table::TableRef;
+
+// Long tracebacks only show this many levels from the top and from the bottom of the stack
+const TRACEBACK_HEAD: usize = 10;
+const TRACEBACK_TAIL: usize = 11;
+// Library tables searched when naming a function in a traceback, like "string.rep"
+const LIBRARIES: [&str; 3] = ["string", "coroutine", "debug"];
+
+// A running function
+pub struct CallInfo {
+    pub function: FunctionRef,
+    // The line of the caller when the call was made
+    pub call_line: i32,
+    // Where the registers of a Lua function start in the stack of the thread
+    pub base: usize,
+    // The instruction a Lua function is running, or the call it is waiting for
+    pub pc: usize,
+    // The arguments a vararg function got beyond its parameters
+    pub varargs: Vec<DataKind>,
+    // How many results the calling Lua function wants, -1 for all of them
+    pub wanted_results: i32,
+    // Set when the function was called by a tail call, which took the place of the caller's frame
+    pub tail_call: bool,
+}
+
+impl CallInfo {
+    pub fn native(function: FunctionRef, call_line: i32) -> Self {
+        Self { function, call_line, base: 0, pc: 0, varargs: Vec::new(), wanted_results: -1, tail_call: false }
+    }
+    pub fn is_native(&self) -> bool {
+        matches!(self.function.kind(), FunctionKind::Internal(_))
+    }
+    pub fn prototype(&self) -> Option<&Prototype> {
+        match self.function.kind() {
+            FunctionKind::External(closure) => Some(&closure.prototype),
+            FunctionKind::Internal(_) => None,
+        }
+    }
+    // The chunk a Lua function was loaded from, used in positions like "fib.lua:7"
+    pub fn source(&self) -> &str {
+        self.prototype().map_or("[C]", |prototype| &prototype.source)
+    }
+}
+
+impl Context<'_> {
+    // The line a function on the call stack is at, which for all but the innermost function is the line it made its
+    // call from
+    fn frame_line(&self, index: usize) -> i32 {
+        match self.call_stack.get(index + 1) {
+            Some(callee) => callee.call_line,
+            None => self.current_line,
+        }
+    }
+
+    // The "stack traceback:" section of an error report, starting `level` functions up the call stack with level 0
+    // being the innermost running function
+    pub fn traceback(&self, level: usize) -> String {
+        let mut traceback = String::from("stack traceback:");
+        let frames: Vec<usize> = (0..self.call_stack.len().saturating_sub(level)).rev().collect();
+        for (position, index) in frames.iter().enumerate() {
+            if frames.len() > TRACEBACK_HEAD + TRACEBACK_TAIL && position == TRACEBACK_HEAD {
+                let skipped = frames.len() - TRACEBACK_HEAD - TRACEBACK_TAIL;
+                traceback.push_str(&format!("\n\t...\t(skipping {} levels)", skipped));
+            }
+            if position >= TRACEBACK_HEAD && position < frames.len().saturating_sub(TRACEBACK_TAIL) {
+                continue;
+            }
+            let call_info = &self.call_stack[*index];
+            let location = match (call_info.is_native(), self.frame_line(*index)) {
+                (true, _) => "[C]".to_owned(),
+                (false, 0) => call_info.source().to_owned(),
+                (false, line) => format!("{}:{}", call_info.source(), line),
+            };
+            traceback.push_str(&format!("\n\t{}: in {}", location, self.describe_function(*index)));
+            if call_info.tail_call {
+                traceback.push_str("\n\t(...tail calls...)");
+            }
+        }
+        // The main chunk is called by the host
+        if !self.is_yieldable() && level <= self.call_stack.len() {
+            traceback.push_str("\n\t[C]: in ?");
+        }
+        traceback
+    }
+
+    fn describe_function(&self, index: usize) -> String {
+        let call_info = &self.call_stack[index];
+        if let Some(name) = self.global_function_name(&call_info.function) {
+            return format!("function '{}'", name);
+        }
+        // A Lua caller names the function after how its code referred to it, like "local 'fib'" or "method 'push'".
+        // The caller of a tail call is gone.
+        let caller_name = index.checked_sub(1).filter(|_| !call_info.tail_call).and_then(|caller| {
+            let caller = &self.call_stack[caller];
+            debug_info::function_name(caller.prototype()?, caller.pc)
+        });
+        if let Some((kind, name)) = caller_name {
+            return format!("{} '{}'", kind, name);
+        }
+        match call_info.prototype() {
+            // Only the main chunk has no position in the source
+            Some(prototype) if prototype.line_defined == 0 => "main chunk".to_owned(),
+            Some(prototype) => format!("function <{}:{}>", prototype.source, prototype.line_defined),
+            None => "?".to_owned(),
+        }
+    }
+
+    // The name of the innermost running function the way "bad argument" errors name it: the global or library field
+    // holding it, or how the Lua code calling it referred to it
+    pub fn function_name(&self) -> Option<String> {
+        let index = self.call_stack.len().checked_sub(1)?;
+        let call_info = &self.call_stack[index];
+        if let Some(name) = self.global_function_name(&call_info.function) {
+            return Some(name);
+        }
+        let caller = &self.call_stack[index.checked_sub(1).filter(|_| !call_info.tail_call)?];
+        debug_info::function_name(caller.prototype()?, caller.pc).map(|(_, name)| name)
+    }
+
+    // Finds the global or library field holding a function, which is how the reference implementation names them
+    fn global_function_name(&self, function: &FunctionRef) -> Option<String> {
+        let target = DataKind::Function(function.clone());
+        if let Some(name) = key_of(&self.state.globals, &target) {
+            return Some(name);
+        }
+        LIBRARIES.iter().find_map(|library| match self.state.globals.get_str(library) {
+            DataKind::Table(table) => key_of(&table, &target).map(|name| format!("{}.{}", library, name)),
+            _ => None,
+        })
+    }
+}
+
+fn key_of(table: &TableRef, target: &DataKind) -> Option<String> {
+    let table = table.borrow();
+    let mut key = DataKind::Null;
+    while let Ok(Some((next_key, value))) = table.next(&key) {
+        if value == *target {
+            if let DataKind::String(name) = &next_key {
+                return Some(name.to_string());
+            }
+        }
+        key = next_key;
+    }
+    None
+}
@@ -0,0 +1,503 @@
+// Conversions between Lua values and Rust types, used to call Rust functions with typed arguments from Lua.
+// Arguments are checked like the auxiliary library of the reference implementation checks them, numeric strings are
+// accepted where numbers are expected and numbers where strings are.
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::hash::Hash;
+
+use crate::err_handle::RuntimeFailure;
+use crate::frontend::Context;
+use crate::frontend::data::{DataKind, LuaString, NumberKind};
+use crate::frontend::coroutine::ThreadRef;
+use crate::frontend::function::FunctionRef;
+use crate::frontend::future;
+use crate::frontend::state::GlobalState;
+use crate::frontend::table::TableRef;
+
+// Why a value could not be converted, shown between the parentheses of a "bad argument" error
+#[derive(Debug, Clone)]
+pub enum ConversionError {
+    // The value has the wrong type, like "number expected, got nil"
+    WrongType { expected: &'static str, got: &'static str },
+    // The value has the right type but does not fit, like a float with no integer representation
+    Invalid(String),
+}
+
+impl ConversionError {
+    pub fn wrong_type(expected: &'static str, value: &DataKind) -> Self {
+        ConversionError::WrongType { expected, got: value.type_name() }
+    }
+}
+
+impl Display for ConversionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConversionError::WrongType { expected, got } => write!(f, "{} expected, got {}", expected, got),
+            ConversionError::Invalid(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+pub trait FromLua: Sized {
+    fn from_lua(value: DataKind, state: &GlobalState) -> Result<Self, ConversionError>;
+}
+
+pub trait IntoLua {
+    fn into_lua(self, state: &GlobalState) -> DataKind;
+}
+
+// The arguments of a call which have not been converted yet, they are numbered from 1 like in Lua error messages
+pub struct Arguments {
+    values: std::vec::IntoIter<DataKind>,
+    position: usize,
+}
+
+impl Arguments {
+    pub fn new(values: Vec<DataKind>) -> Self {
+        Self::starting_at(values, 1)
+    }
+    // Arguments which come after the ones already taken, like the ones following the self argument of a method
+    pub fn starting_at(values: Vec<DataKind>, position: usize) -> Self {
+        Self { values: values.into_iter(), position }
+    }
+}
+
+// A failed argument and the reason, turned into a "bad argument" error once the function name is known
+pub struct BadArgument {
+    pub position: usize,
+    pub error: ConversionError,
+}
+
+impl BadArgument {
+    pub fn into_failure(self, function_name: &str, context: &Context) -> RuntimeFailure {
+        RuntimeFailure::BadFunctionArgs(
+            format!("bad argument #{} to '{}' ({})", self.position, function_name, self.error),
+            context.current_line,
+        )
+    }
+}
+
+// Converts one or more arguments, a single value takes one of them and `Variadic` takes all that are left
+pub trait FromLuaMulti: Sized {
+    fn from_lua_args(args: &mut Arguments, state: &GlobalState) -> Result<Self, BadArgument>;
+}
+
+// Converts results, a single value is one result and `()` is none
+pub trait IntoLuaMulti {
+    fn into_lua_multi(self, state: &GlobalState) -> Vec<DataKind>;
+}
+
+// Any number of values of the same type, taken from the end of the arguments or returned as separate results
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Variadic<T>(pub Vec<T>);
+
+impl<T: FromLua> FromLuaMulti for T {
+    fn from_lua_args(args: &mut Arguments, state: &GlobalState) -> Result<Self, BadArgument> {
+        let position = args.position;
+        args.position += 1;
+        match args.values.next() {
+            Some(value) => T::from_lua(value, state).map_err(|error| BadArgument { position, error }),
+            // A missing argument is converted like nil, but it is reported as no value at all
+            None => T::from_lua(DataKind::Null, state).map_err(|error| {
+                let error = match error {
+                    ConversionError::WrongType { expected, .. } => ConversionError::WrongType { expected, got: "no value" },
+                    error => error,
+                };
+                BadArgument { position, error }
+            }),
+        }
+    }
+}
+
+impl<T: FromLua> FromLuaMulti for Variadic<T> {
+    fn from_lua_args(args: &mut Arguments, state: &GlobalState) -> Result<Self, BadArgument> {
+        let mut values = Vec::with_capacity(args.values.len());
+        for value in args.values.by_ref() {
+            let position = args.position;
+            args.position += 1;
+            values.push(T::from_lua(value, state).map_err(|error| BadArgument { position, error })?);
+        }
+        Ok(Variadic(values))
+    }
+}
+
+impl<T: IntoLua> IntoLuaMulti for T {
+    fn into_lua_multi(self, state: &GlobalState) -> Vec<DataKind> {
+        vec![self.into_lua(state)]
+    }
+}
+
+impl<T: IntoLua> IntoLuaMulti for Variadic<T> {
+    fn into_lua_multi(self, state: &GlobalState) -> Vec<DataKind> {
+        self.0.into_iter().map(|value| value.into_lua(state)).collect()
+    }
+}
+
+impl FromLuaMulti for () {
+    fn from_lua_args(_args: &mut Arguments, _state: &GlobalState) -> Result<Self, BadArgument> {
+        Ok(())
+    }
+}
+
+impl IntoLuaMulti for () {
+    fn into_lua_multi(self, _state: &GlobalState) -> Vec<DataKind> {
+        Vec::new()
+    }
+}
+
+macro_rules! impl_tuple_multi {
+    ($($name:ident),+) => {
+        impl<$($name: FromLuaMulti),+> FromLuaMulti for ($($name,)+) {
+            fn from_lua_args(args: &mut Arguments, state: &GlobalState) -> Result<Self, BadArgument> {
+                Ok(($($name::from_lua_args(args, state)?,)+))
+            }
+        }
+
+        impl<$($name: IntoLuaMulti),+> IntoLuaMulti for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn into_lua_multi(self, state: &GlobalState) -> Vec<DataKind> {
+                let ($($name,)+) = self;
+                let mut values = Vec::new();
+                $(values.extend($name.into_lua_multi(state));)+
+                values
+            }
+        }
+    };
+}
+
+impl_tuple_multi!(A);
+impl_tuple_multi!(A, B);
+impl_tuple_multi!(A, B, C);
+impl_tuple_multi!(A, B, C, D);
+impl_tuple_multi!(A, B, C, D, E);
+impl_tuple_multi!(A, B, C, D, E, F);
+impl_tuple_multi!(A, B, C, D, E, F, G);
+impl_tuple_multi!(A, B, C, D, E, F, G, H);
+
+// A Rust function which can be called from Lua. It is implemented for functions and closures of up to 8 arguments
+// which convert from Lua values and return a result which converts back, `Variadic` being allowed as the last argument.
+pub trait HostFunction<Args>: 'static {
+    fn call_host(&self, name: &str, context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure>;
+}
+
+macro_rules! impl_host_function {
+    ($($arg:ident),*) => {
+        impl<Func, Ret, $($arg),*> HostFunction<($($arg,)*)> for Func
+        where
+            Func: Fn($($arg),*) -> Result<Ret, RuntimeFailure> + 'static,
+            Ret: IntoLuaMulti,
+            $($arg: FromLuaMulti,)*
+        {
+            #[allow(non_snake_case, unused_mut, unused_variables)]
+            fn call_host(&self, name: &str, context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+                let mut args = Arguments::new(args);
+                $(let $arg = $arg::from_lua_args(&mut args, &context.state).map_err(|bad| bad.into_failure(name, context))?;)*
+                match self($($arg),*) {
+                    Ok(results) => Ok(results.into_lua_multi(&context.state)),
+                    Err(failure) => Err(host_error(context, failure)),
+                }
+            }
+        }
+    };
+}
+
+// Errors made from a message are raised at the position of the caller, like luaL_error
+pub fn host_error(context: &Context, failure: RuntimeFailure) -> RuntimeFailure {
+    match failure {
+        RuntimeFailure::BadOperation(msg, 0) => RuntimeFailure::BadOperation(msg, context.current_line),
+        failure => failure,
+    }
+}
+
+impl_host_function!();
+impl_host_function!(A);
+impl_host_function!(A, B);
+impl_host_function!(A, B, C);
+impl_host_function!(A, B, C, D);
+impl_host_function!(A, B, C, D, E);
+impl_host_function!(A, B, C, D, E, F);
+impl_host_function!(A, B, C, D, E, F, G);
+impl_host_function!(A, B, C, D, E, F, G, H);
+
+// A Rust function returning a future, implemented like `HostFunction` for functions and closures of up to 8 arguments.
+// The future must not borrow anything, it owns its arguments.
+pub trait AsyncHostFunction<Args>: 'static {
+    fn call_host(&self, name: &str, context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure>;
+}
+
+macro_rules! impl_async_host_function {
+    ($($arg:ident),*) => {
+        impl<Func, Fut, Ret, $($arg),*> AsyncHostFunction<($($arg,)*)> for Func
+        where
+            Func: Fn($($arg),*) -> Fut + 'static,
+            Fut: Future<Output = Result<Ret, RuntimeFailure>> + 'static,
+            Ret: IntoLuaMulti,
+            $($arg: FromLuaMulti,)*
+        {
+            #[allow(non_snake_case, unused_mut, unused_variables)]
+            fn call_host(&self, name: &str, context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+                let mut args = Arguments::new(args);
+                $(let $arg = $arg::from_lua_args(&mut args, &context.state).map_err(|bad| bad.into_failure(name, context))?;)*
+                match future::wait(context, name, Box::pin(self($($arg),*)))? {
+                    Ok(results) => Ok(results.into_lua_multi(&context.state)),
+                    Err(failure) => Err(host_error(context, failure)),
+                }
+            }
+        }
+    };
+}
+
+impl_async_host_function!();
+impl_async_host_function!(A);
+impl_async_host_function!(A, B);
+impl_async_host_function!(A, B, C);
+impl_async_host_function!(A, B, C, D);
+impl_async_host_function!(A, B, C, D, E);
+impl_async_host_function!(A, B, C, D, E, F);
+impl_async_host_function!(A, B, C, D, E, F, G);
+impl_async_host_function!(A, B, C, D, E, F, G, H);
+
+impl FromLua for DataKind {
+    fn from_lua(value: DataKind, _state: &GlobalState) -> Result<Self, ConversionError> {
+        Ok(value)
+    }
+}
+
+impl IntoLua for DataKind {
+    fn into_lua(self, _state: &GlobalState) -> DataKind {
+        self
+    }
+}
+
+// Any value is a boolean in Lua, only nil and false are false
+impl FromLua for bool {
+    fn from_lua(value: DataKind, _state: &GlobalState) -> Result<Self, ConversionError> {
+        Ok(value.is_true())
+    }
+}
+
+impl IntoLua for bool {
+    fn into_lua(self, _state: &GlobalState) -> DataKind {
+        DataKind::Bool(self)
+    }
+}
+
+fn to_integer(value: &DataKind) -> Result<i64, ConversionError> {
+    match value.coerce_to_number() {
+        Some(number) => number
+            .as_integer()
+            .ok_or_else(|| ConversionError::Invalid("number has no integer representation".to_owned())),
+        None => Err(ConversionError::wrong_type("number", value)),
+    }
+}
+
+macro_rules! impl_integer {
+    ($($int:ty),+) => {
+        $(
+            impl FromLua for $int {
+                fn from_lua(value: DataKind, _state: &GlobalState) -> Result<Self, ConversionError> {
+                    <$int>::try_from(to_integer(&value)?).map_err(|_| ConversionError::Invalid("value out of range".to_owned()))
+                }
+            }
+
+            // Integers which do not fit in a Lua integer become floats
+            impl IntoLua for $int {
+                fn into_lua(self, _state: &GlobalState) -> DataKind {
+                    match i64::try_from(self) {
+                        Ok(int) => DataKind::integer(int),
+                        Err(_) => DataKind::Number(NumberKind::Float(self as f64)),
+                    }
+                }
+            }
+        )+
+    };
+}
+
+impl_integer!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+macro_rules! impl_float {
+    ($($float:ty),+) => {
+        $(
+            impl FromLua for $float {
+                fn from_lua(value: DataKind, _state: &GlobalState) -> Result<Self, ConversionError> {
+                    match value.coerce_to_number() {
+                        Some(number) => Ok(number.as_float() as $float),
+                        None => Err(ConversionError::wrong_type("number", &value)),
+                    }
+                }
+            }
+
+            impl IntoLua for $float {
+                fn into_lua(self, _state: &GlobalState) -> DataKind {
+                    DataKind::Number(NumberKind::Float(self as f64))
+                }
+            }
+        )+
+    };
+}
+
+impl_float!(f32, f64);
+
+impl FromLua for LuaString {
+    fn from_lua(value: DataKind, _state: &GlobalState) -> Result<Self, ConversionError> {
+        match value {
+            DataKind::String(string) => Ok(string),
+            DataKind::Number(number) => Ok(LuaString::from(number.to_string())),
+            value => Err(ConversionError::wrong_type("string", &value)),
+        }
+    }
+}
+
+impl IntoLua for LuaString {
+    fn into_lua(self, _state: &GlobalState) -> DataKind {
+        DataKind::String(self)
+    }
+}
+
+// Lua strings are byte strings, only the ones which are valid UTF-8 convert to a String
+impl FromLua for String {
+    fn from_lua(value: DataKind, state: &GlobalState) -> Result<Self, ConversionError> {
+        let string = LuaString::from_lua(value, state)?;
+        String::from_utf8(string.as_bytes().to_vec())
+            .map_err(|_| ConversionError::Invalid("string is not valid UTF-8".to_owned()))
+    }
+}
+
+impl IntoLua for String {
+    fn into_lua(self, _state: &GlobalState) -> DataKind {
+        DataKind::String(LuaString::from(self))
+    }
+}
+
+impl IntoLua for &str {
+    fn into_lua(self, _state: &GlobalState) -> DataKind {
+        DataKind::string(self)
+    }
+}
+
+impl FromLua for TableRef {
+    fn from_lua(value: DataKind, _state: &GlobalState) -> Result<Self, ConversionError> {
+        match value {
+            DataKind::Table(table) => Ok(table),
+            value => Err(ConversionError::wrong_type("table", &value)),
+        }
+    }
+}
+
+impl IntoLua for TableRef {
+    fn into_lua(self, _state: &GlobalState) -> DataKind {
+        DataKind::Table(self)
+    }
+}
+
+impl FromLua for FunctionRef {
+    fn from_lua(value: DataKind, _state: &GlobalState) -> Result<Self, ConversionError> {
+        match value {
+            DataKind::Function(function) => Ok(function),
+            value => Err(ConversionError::wrong_type("function", &value)),
+        }
+    }
+}
+
+impl IntoLua for FunctionRef {
+    fn into_lua(self, _state: &GlobalState) -> DataKind {
+        DataKind::Function(self)
+    }
+}
+
+impl FromLua for ThreadRef {
+    fn from_lua(value: DataKind, _state: &GlobalState) -> Result<Self, ConversionError> {
+        match value {
+            DataKind::Thread(thread) => Ok(thread),
+            value => Err(ConversionError::wrong_type("thread", &value)),
+        }
+    }
+}
+
+impl IntoLua for ThreadRef {
+    fn into_lua(self, _state: &GlobalState) -> DataKind {
+        DataKind::Thread(self)
+    }
+}
+
+// An optional argument, nil and a missing argument are None
+impl<T: FromLua> FromLua for Option<T> {
+    fn from_lua(value: DataKind, state: &GlobalState) -> Result<Self, ConversionError> {
+        match value {
+            DataKind::Null => Ok(None),
+            value => T::from_lua(value, state).map(Some),
+        }
+    }
+}
+
+impl<T: IntoLua> IntoLua for Option<T> {
+    fn into_lua(self, state: &GlobalState) -> DataKind {
+        match self {
+            Some(value) => value.into_lua(state),
+            None => DataKind::Null,
+        }
+    }
+}
+
+// The sequence of a table, from index 1 up to its border. Metamethods are not consulted.
+impl<T: FromLua> FromLua for Vec<T> {
+    fn from_lua(value: DataKind, state: &GlobalState) -> Result<Self, ConversionError> {
+        let table = TableRef::from_lua(value, state)?;
+        let length = table.length();
+        let mut values = Vec::with_capacity(length as usize);
+        for index in 1..=length {
+            let value = table.get(&DataKind::integer(index));
+            values.push(T::from_lua(value, state).map_err(|error| element_error(&format!("value at index {}", index), error))?);
+        }
+        Ok(values)
+    }
+}
+
+impl<T: IntoLua> IntoLua for Vec<T> {
+    fn into_lua(self, state: &GlobalState) -> DataKind {
+        let table = TableRef::new(&state.heap);
+        for (index, value) in self.into_iter().enumerate() {
+            let _ = table.borrow_mut().set(DataKind::integer(index as i64 + 1), value.into_lua(state));
+        }
+        DataKind::Table(table)
+    }
+}
+
+// Every entry of a table. Metamethods are not consulted.
+impl<K: FromLua + Eq + Hash, V: FromLua> FromLua for HashMap<K, V> {
+    fn from_lua(value: DataKind, state: &GlobalState) -> Result<Self, ConversionError> {
+        let table = TableRef::from_lua(value, state)?;
+        let mut map = HashMap::new();
+        let mut key = DataKind::Null;
+        loop {
+            let (next_key, value) = match table.borrow().next(&key) {
+                Ok(Some(entry)) => entry,
+                _ => break,
+            };
+            let converted_key = K::from_lua(next_key.clone(), state).map_err(|error| element_error("table key", error))?;
+            let converted_value = V::from_lua(value, state)
+                .map_err(|error| element_error(&format!("value at key {}", next_key), error))?;
+            map.insert(converted_key, converted_value);
+            key = next_key;
+        }
+        Ok(map)
+    }
+}
+
+// Keys which cannot be table keys, like NaN, are left out
+impl<K: IntoLua, V: IntoLua> IntoLua for HashMap<K, V> {
+    fn into_lua(self, state: &GlobalState) -> DataKind {
+        let table = TableRef::new(&state.heap);
+        for (key, value) in self {
+            let _ = table.borrow_mut().set(key.into_lua(state), value.into_lua(state));
+        }
+        DataKind::Table(table)
+    }
+}
+
+// Describes which part of a table failed to convert, like "invalid value at index 3 (number expected, got string)"
+fn element_error(location: &str, error: ConversionError) -> ConversionError {
+    ConversionError::Invalid(format!("invalid {} ({})", location, error))
+}
@@ -0,0 +1,354 @@
+use std::cell::{Cell, RefCell};
+use std::fmt::{Debug, Formatter};
+use std::rc::{Rc, Weak};
+
+use corosensei::stack::DefaultStack;
+use corosensei::CoroutineResult;
+
+use crate::err_handle::RuntimeFailure;
+use crate::frontend::{call_function, gc, vm, Context};
+use crate::frontend::call_stack::CallInfo;
+use crate::frontend::data::DataKind;
+use crate::frontend::function::FunctionKind;
+use crate::frontend::gc::WeakObject;
+use crate::frontend::state::GlobalState;
+
+// Every coroutine runs on its own native stack, which lets a coroutine yield from anywhere in the interpreter.
+// A yield can happen inside nested Lua calls, metamethods or library functions calling back into Lua without any of
+// them having to know about coroutines.
+// Stack memory is only committed when it is used, so most coroutines only use a small part of this.
+const COROUTINE_STACK_SIZE: usize = 16 * 1024 * 1024;
+// The native stack a coroutine is charged for towards the memory limit, the few pages the frames of the interpreter
+// commit in an optimized build take about a quarter of this, a debug build commits about twice as much
+const COMMITTED_STACK_SIZE: usize = 16 * 1024;
+
+// The native stack the calls of the host run on, so that how deep Lua code can nest does not depend on the stack of the
+// thread the host calls from: the limits are made for the stack of a coroutine, this one is as large. It is kept for
+// the next call once it is allocated.
+#[derive(Default)]
+pub struct HostStack {
+    stack: RefCell<Option<DefaultStack>>,
+    // Set while a call runs on it, host functions calling back into the interpreter stay on it
+    entered: Cell<bool>,
+}
+
+impl HostStack {
+    pub fn run<R>(&self, call: impl FnOnce() -> R) -> R {
+        if self.entered.get() {
+            return call();
+        }
+        let mut stack = match self.stack.take() {
+            Some(stack) => stack,
+            // Without a stack of its own the call can still run on the host's
+            None => match DefaultStack::new(COROUTINE_STACK_SIZE) {
+                Ok(stack) => stack,
+                Err(_) => return call(),
+            },
+        };
+        // A panic leaves the stack and is raised again on the host's, which drops the stack
+        struct Leave<'a>(&'a Cell<bool>);
+        impl Drop for Leave<'_> {
+            fn drop(&mut self) {
+                self.0.set(false);
+            }
+        }
+        self.entered.set(true);
+        let leave = Leave(&self.entered);
+        let result = corosensei::on_stack(&mut stack, call);
+        drop(leave);
+        *self.stack.borrow_mut() = Some(stack);
+        result
+    }
+}
+
+pub type Yielder = corosensei::Yielder<ResumeSignal, Vec<DataKind>>;
+type CoroutineHandle = corosensei::Coroutine<ResumeSignal, Vec<DataKind>, Result<Vec<DataKind>, RuntimeFailure>>;
+
+pub enum ResumeSignal {
+    Resume(Vec<DataKind>),
+    // Sent by coroutine.close, the pending yield fails so that to-be-closed variables are closed while unwinding
+    Close,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CoroutineStatus {
+    Running,
+    Suspended,
+    Normal,
+    Dead,
+}
+
+impl CoroutineStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CoroutineStatus::Running => "running",
+            CoroutineStatus::Suspended => "suspended",
+            CoroutineStatus::Normal => "normal",
+            CoroutineStatus::Dead => "dead",
+        }
+    }
+}
+
+pub struct Coroutine {
+    status: CoroutineStatus,
+    // None for the main thread and for coroutines which are running or dead
+    handle: Option<CoroutineHandle>,
+    // The error a coroutine died with, reported again by coroutine.close
+    error: Option<RuntimeFailure>,
+    // The function a coroutine created from Lua runs, held here until it starts so that the collector sees it
+    function: Option<DataKind>,
+    // The context of a coroutine which started and has not returned. It lives on the coroutine's native stack, the
+    // collector reads it while the coroutine is suspended and nothing else uses it.
+    context: Option<*const Context<'static>>,
+}
+
+impl Coroutine {
+    fn new(status: CoroutineStatus, handle: Option<CoroutineHandle>) -> Self {
+        Self { status, handle, error: None, function: None, context: None }
+    }
+}
+
+#[derive(Clone)]
+pub struct ThreadRef(Rc<RefCell<Coroutine>>);
+
+impl ThreadRef {
+    pub fn new_main() -> Self {
+        Self(Rc::new(RefCell::new(Coroutine::new(CoroutineStatus::Running, None))))
+    }
+
+    pub fn new(context: &Context, function: DataKind) -> Result<Self, RuntimeFailure> {
+        let thread = Self::with_body(context, |context, args| {
+            let function = context.state.current_thread().0.borrow_mut().function.take();
+            match function.expect("A coroutine starts once") {
+                // The frame of a Lua function is then the only one holding it, where the collector sees it
+                DataKind::Function(function) if matches!(function.kind(), FunctionKind::External(_)) => {
+                    vm::call_closure(context, function, args)
+                }
+                function => call_function(context, &function, args),
+            }
+        })?;
+        thread.0.borrow_mut().function = Some(function);
+        Ok(thread)
+    }
+
+    // A coroutine which runs `body` with the arguments of its first resume
+    pub fn with_body<F>(context: &Context, body: F) -> Result<Self, RuntimeFailure>
+    where
+        F: FnOnce(&mut Context, Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> + 'static,
+    {
+        gc::allocate(context, COMMITTED_STACK_SIZE + std::mem::size_of::<Coroutine>())?;
+        let stack = DefaultStack::new(COROUTINE_STACK_SIZE).map_err(|e| {
+            RuntimeFailure::CoroutineError(format!("could not allocate a coroutine stack: {}", e), context.current_line)
+        })?;
+        let state: Rc<GlobalState> = context.state.clone();
+        let handle = CoroutineHandle::with_stack(stack, move |yielder, signal| {
+            let args = match signal {
+                ResumeSignal::Resume(args) => args,
+                // Closing a coroutine that never started has nothing to unwind
+                ResumeSignal::Close => return Ok(Vec::new()),
+            };
+            let mut coroutine_context = Context::new(state, Some(yielder));
+            let pointer = (&coroutine_context as *const Context).cast();
+            coroutine_context.state.current_thread().0.borrow_mut().context = Some(pointer);
+            body(&mut coroutine_context, args)
+        });
+        let thread = Self(Rc::new(RefCell::new(Coroutine::new(CoroutineStatus::Suspended, Some(handle)))));
+        context.state.heap.track(WeakObject::Thread(thread.downgrade()));
+        Ok(thread)
+    }
+
+    pub fn status(&self) -> CoroutineStatus {
+        self.0.borrow().status
+    }
+
+    fn set_status(&self, status: CoroutineStatus) {
+        self.0.borrow_mut().status = status;
+    }
+
+    // Runs the coroutine until it yields or returns. Errors raised inside of the coroutine are returned as Err, the
+    // coroutine is dead afterwards.
+    pub fn resume(&self, context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+        let handle = self.take_handle("resume")?;
+        let mut result = self.run(context, handle, ResumeSignal::Resume(args));
+        // A coroutine waiting on a future makes the one resuming it wait too, up to the host future polling the
+        // script. It is resumed again once that future is polled, which polls the future it waits on.
+        while result.is_ok() && context.state.async_state.is_pending() && context.yielder.is_some() {
+            if let Err(failure) = yield_values(context, Vec::new()) {
+                let _ = self.close(context);
+                return Err(failure);
+            }
+            let handle = self.take_handle("resume")?;
+            result = self.run(context, handle, ResumeSignal::Resume(Vec::new()));
+        }
+        result
+    }
+
+    // Closes a suspended or dead coroutine, running the `__close` metamethods of its pending to-be-closed variables
+    pub fn close(&self, context: &mut Context) -> Result<(), RuntimeFailure> {
+        match self.status() {
+            CoroutineStatus::Dead => match self.0.borrow_mut().error.take() {
+                Some(error) => Err(error),
+                None => Ok(()),
+            },
+            CoroutineStatus::Suspended => {
+                let handle = self.take_handle("close")?;
+                match self.run(context, handle, ResumeSignal::Close) {
+                    Ok(_) | Err(RuntimeFailure::CoroutineClosed) => Ok(()),
+                    Err(error) => {
+                        self.0.borrow_mut().error = None;
+                        Err(error)
+                    }
+                }
+            }
+            status => Err(RuntimeFailure::CoroutineError(
+                format!("cannot close a {} coroutine", status.as_str()),
+                context.current_line,
+            )),
+        }
+    }
+
+    fn take_handle(&self, action: &str) -> Result<CoroutineHandle, RuntimeFailure> {
+        let mut coroutine = self.0.borrow_mut();
+        match coroutine.status {
+            CoroutineStatus::Suspended => {
+                coroutine.status = CoroutineStatus::Running;
+                Ok(coroutine.handle.take().expect("A suspended coroutine must have a handle"))
+            }
+            // Like in the reference implementation these messages carry no position
+            CoroutineStatus::Dead => Err(RuntimeFailure::CoroutineError(format!("cannot {} dead coroutine", action), 0)),
+            _ => Err(RuntimeFailure::CoroutineError(format!("cannot {} non-suspended coroutine", action), 0)),
+        }
+    }
+
+    fn run(&self, context: &mut Context, mut handle: CoroutineHandle, signal: ResumeSignal) -> Result<Vec<DataKind>, RuntimeFailure> {
+        let resumer = context.state.current_thread();
+        resumer.set_status(CoroutineStatus::Normal);
+        context.state.push_thread(self.clone());
+        let result = handle.resume(signal);
+        context.state.pop_thread();
+        resumer.set_status(CoroutineStatus::Running);
+
+        let mut coroutine = self.0.borrow_mut();
+        match result {
+            CoroutineResult::Yield(values) => {
+                coroutine.status = CoroutineStatus::Suspended;
+                coroutine.handle = Some(handle);
+                Ok(values)
+            }
+            CoroutineResult::Return(result) => {
+                coroutine.status = CoroutineStatus::Dead;
+                coroutine.function = None;
+                coroutine.context = None;
+                if let Err(error) = &result {
+                    coroutine.error = Some(error.clone());
+                }
+                result
+            }
+        }
+    }
+
+    pub fn as_ptr(&self) -> *const () {
+        Rc::as_ptr(&self.0) as *const ()
+    }
+    pub(crate) fn from_weak(weak: &Weak<RefCell<Coroutine>>) -> Option<Self> {
+        weak.upgrade().map(Self)
+    }
+    pub(crate) fn downgrade(&self) -> Weak<RefCell<Coroutine>> {
+        Rc::downgrade(&self.0)
+    }
+    pub(crate) fn strong_count(&self) -> usize {
+        Rc::strong_count(&self.0)
+    }
+    // Calls `visit` with every object the coroutine references: the function it has yet to start, the registers,
+    // functions and open upvalues of its frames while it is suspended, or the error it died with. False while it runs
+    // or resumes another coroutine, its context is in use then and what it holds counts as references from outside.
+    pub(crate) fn references(&self, visit: &mut dyn FnMut(*const ())) -> bool {
+        let Ok(coroutine) = self.0.try_borrow() else {
+            return false;
+        };
+        if let Some(function) = &coroutine.function {
+            gc::visit_value(function, visit);
+        }
+        if let Some(RuntimeFailure::ErrorObject(value)) = &coroutine.error {
+            gc::visit_value(value, visit);
+        }
+        match (coroutine.status, coroutine.context) {
+            (CoroutineStatus::Suspended, Some(context)) => {
+                // The context outlives the suspended coroutine's stack frames, which are not running
+                let context = unsafe { &*context };
+                let Ok(stack) = context.stack.try_borrow() else {
+                    return false;
+                };
+                for value in stack.iter() {
+                    gc::visit_value(value, visit);
+                }
+                for frame in &context.call_stack {
+                    visit(frame.function.as_ptr());
+                    for value in &frame.varargs {
+                        gc::visit_value(value, visit);
+                    }
+                }
+                for (_, upvalue) in &context.open_upvalues {
+                    visit(upvalue.as_ptr());
+                }
+                for handler in context.message_handlers.iter().flatten() {
+                    gc::visit_value(handler, visit);
+                }
+                true
+            }
+            (CoroutineStatus::Suspended | CoroutineStatus::Dead, _) => true,
+            _ => false,
+        }
+    }
+    // An estimate of the memory the coroutine uses, its native stack is freed once it is dead
+    pub(crate) fn allocated_size(&self) -> usize {
+        let (status, context) = match self.0.try_borrow() {
+            Ok(coroutine) => (coroutine.status, coroutine.context),
+            Err(_) => (CoroutineStatus::Running, None),
+        };
+        let context = context.filter(|_| status == CoroutineStatus::Suspended);
+        let frames = context.map_or(0, |context| {
+            let context = unsafe { &*context };
+            let registers = context.stack.try_borrow().map_or(0, |stack| stack.len());
+            registers * std::mem::size_of::<DataKind>() + context.call_stack.len() * std::mem::size_of::<CallInfo>()
+        });
+        let stack = match status {
+            CoroutineStatus::Dead => 0,
+            _ => COMMITTED_STACK_SIZE,
+        };
+        std::mem::size_of::<Coroutine>() + stack + frames
+    }
+    // Empties a coroutine the collector found unreachable, it is dead afterwards. Dropping what is returned unwinds the
+    // native stack of a suspended coroutine, its to-be-closed variables are not closed, like in the reference
+    // implementation.
+    pub(crate) fn clear_references(&self) -> Option<Coroutine> {
+        let mut coroutine = self.0.try_borrow_mut().ok()?;
+        Some(std::mem::replace(&mut *coroutine, Coroutine::new(CoroutineStatus::Dead, None)))
+    }
+}
+
+impl PartialEq for ThreadRef {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Debug for ThreadRef {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "thread: {:p}", self.as_ptr())
+    }
+}
+
+// Suspends the running coroutine, returning the values passed to the coroutine.resume that continues it
+pub fn yield_values(context: &mut Context, values: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    match context.yielder {
+        Some(yielder) => match yielder.suspend(values) {
+            ResumeSignal::Resume(args) => Ok(args),
+            ResumeSignal::Close => Err(RuntimeFailure::CoroutineClosed),
+        },
+        None => Err(RuntimeFailure::CoroutineError(
+            "attempt to yield from outside a coroutine".to_owned(),
+            context.current_line,
+        )),
+    }
+}
@@ -1,120 +1,174 @@
-use std::cell::{Ref, RefCell, RefMut};
-use std::fmt::{Display, Formatter};
-use std::ops::{Add, Sub};
+use std::fmt::{Debug, Display, Formatter};
+use std::ops::{Add, Mul, Sub};
 use std::rc::Rc;
 
-use crate::ast::lua_program::{BooleanOperator, MathOperator};
-use crate::err_handle::RuntimeFailure;
+use crate::ast::lua_program::{BitwiseOperator, MathOperator};
+use crate::err_handle::{Operand, RuntimeFailure};
 use crate::frontend::Context;
+use crate::frontend::coroutine::ThreadRef;
+use crate::frontend::function::FunctionRef;
+use crate::frontend::table::TableRef;
+use crate::frontend::userdata::{LightUserData, UserDataRef};
 
-pub struct Data {
-    // Data is wrapped in a Rc<RefCell<>>
-    // The Rc is a reference counter which allows for multiple references to the inner value
-    // This is necessary to prevent variables from being cloned every time they are read, .clone()
-    // on an rc will just increment the reference count.
-    // The RefCell allows for data mutation in multiple places
-    handle: Rc<RefCell<DataKind>>
+// Lua strings are immutable byte strings, they are not required to be valid UTF-8. Like in the reference
+// implementation they are followed by a NUL byte which is not part of them, so the C API can hand them out as C strings.
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct LuaString(Rc<[u8]>);
+
+impl LuaString {
+    fn new(mut bytes: Vec<u8>) -> Self {
+        bytes.push(0);
+        Self(Rc::from(bytes))
+    }
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0[..self.0.len() - 1]
+    }
+    // The bytes followed by the NUL byte, valid for as long as the string is alive
+    pub fn as_c_ptr(&self) -> *const std::ffi::c_char {
+        self.0.as_ptr().cast()
+    }
+    pub fn to_str_lossy(&self) -> std::borrow::Cow<'_, str> {
+        String::from_utf8_lossy(self.as_bytes())
+    }
+    pub fn len(&self) -> usize {
+        self.0.len() - 1
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
-impl Data {
-    pub fn new(data_kind: DataKind) -> Self {
-        Self {
-            handle: Rc::new(RefCell::new(data_kind)),
-        }
+impl From<&str> for LuaString {
+    fn from(value: &str) -> Self {
+        Self::from(value.as_bytes())
     }
-    pub fn borrow(&self, context: &Context) -> Result<Ref<DataKind>, RuntimeFailure> {
-        match self.handle.try_borrow() {
-            // Must return a Ref<T> here, returning a Ref<T>::deref() will error.
-            // This happens because RefCell<T>::try_borrow returns a Ref<T> with the lifetime of the &self passed into
-            // this method. Calling deref on that Ref<T> will be a borrow of a borrow, where the second borrow will
-            // go out of scope when this function ends. The first borrow has the lifetime of &self and can be
-            // returned, because the caller gave us &self and knows what the lifetime is.
-            Ok(d) => Ok(d),
-            Err(_) => Err(RuntimeFailure::BorrowError(
-                "Cannot borrow a variable when it has a mutable reference in use".to_owned(),
-                context.current_line
-            )),
-        }
+}
+
+impl From<String> for LuaString {
+    fn from(value: String) -> Self {
+        Self::new(value.into_bytes())
     }
-    pub fn borrow_mut(&self, context: &Context) -> Result<RefMut<DataKind>, RuntimeFailure> {
-        match self.handle.try_borrow_mut() {
-            Ok(d) => Ok(d),
-            Err(_) => Err(RuntimeFailure::BorrowError(
-                "Cannot borrow a variable mutably when it already has a reference in use"
-                    .to_owned(),
-                context.current_line
-            )),
-        }
+}
+
+impl From<&[u8]> for LuaString {
+    fn from(value: &[u8]) -> Self {
+        let mut bytes = Vec::with_capacity(value.len() + 1);
+        bytes.extend_from_slice(value);
+        Self::new(bytes)
+    }
+}
+
+impl From<Vec<u8>> for LuaString {
+    fn from(value: Vec<u8>) -> Self {
+        Self::new(value)
+    }
+}
+
+impl Display for LuaString {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_str_lossy())
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+impl Debug for LuaString {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.to_str_lossy())
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum DataKind {
-    String(String),
+    String(LuaString),
     Number(NumberKind),
     Bool(bool),
     Null,
-    // TODO: Table(HashMap<String, DataKind>)
+    Table(TableRef),
+    Function(FunctionRef),
+    Thread(ThreadRef),
+    UserData(UserDataRef),
+    LightUserData(LightUserData),
 }
 
 impl DataKind {
     pub fn math_binary_op(&self, other: &Self, op: MathOperator, context: &Context) -> Result<NumberKind, RuntimeFailure> {
         let (l_num, r_num) = Self::both_numerical(context, self, other)?;
-        let l_copy = l_num.clone();
-        let r_copy = r_num.clone();
-        match op {
-            MathOperator::Plus => return Ok(l_copy.add(r_copy)),
-            MathOperator::Minus => return Ok(l_copy.sub(r_copy)),
-            MathOperator::Multiply => todo!("mul"),
-            MathOperator::FloatDivision => todo!("float_div"),
-            MathOperator::FloorDivision => todo!("floor_div"),
-            MathOperator::Exponent => todo!("exp"),
-            MathOperator::Mod => todo!("mod"),
+        l_num.arithmetic(op, &r_num).ok_or_else(|| {
+            let message = match op {
+                MathOperator::Mod => "attempt to perform 'n%%0'",
+                _ => "attempt to perform 'n//0'",
+            };
+            RuntimeFailure::BadOperation(message.to_owned(), context.current_line)
+        })
+    }
+    pub fn bitwise_binary_op(&self, other: &Self, op: BitwiseOperator, context: &Context) -> Result<i64, RuntimeFailure> {
+        let (l_num, r_num) = Self::both_numerical(context, self, other)?;
+        let lhs = l_num.to_bitwise_integer(context)?;
+        let rhs = r_num.to_bitwise_integer(context)?;
+        Ok(bitwise_integers(op, lhs, rhs))
+    }
+    fn both_numerical(context: &Context, first: &Self, second: &Self) -> Result<(NumberKind, NumberKind), RuntimeFailure> {
+        match (first.coerce_to_number(), second.coerce_to_number()) {
+            (Some(l_num), Some(r_num)) => Ok((l_num, r_num)),
+            (None, _) => Err(first.arithmetic_error(Operand::First, context)),
+            (_, None) => Err(second.arithmetic_error(Operand::Second, context)),
         }
     }
-    pub fn boolean_binary_op(&self, other: &Self, op: BooleanOperator, context: &Context) -> Result<bool, RuntimeFailure> {
-        match op {
-            BooleanOperator::LessThan => {
-                let (l_num, r_num) = Self::both_numerical(context, self, other)?;
-                Ok(l_num < r_num)
-            },
-            BooleanOperator::LessThanEqualTo => {
-                let (l_num, r_num) = Self::both_numerical(context, self, other)?;
-                Ok(l_num <= r_num)
-            },
-            BooleanOperator::GreaterThan => {
-                let (l_num, r_num) = Self::both_numerical(context, self, other)?;
-                Ok(l_num > r_num)
-            },
-            BooleanOperator::GreaterThanEqualTo => {
-                let (l_num, r_num) = Self::both_numerical(context, self, other)?;
-                Ok(l_num >= r_num)
-            },
-            BooleanOperator::Equal => Ok(self == other),
-            BooleanOperator::Unequal => Ok(self != other),
-            BooleanOperator::And => Ok(self.is_true() && other.is_true()),
-            BooleanOperator::Or => Ok(self.is_true() || other.is_true()),
-        }
-    }
-    fn both_numerical<'a>(context: &Context, first: &'a Self, second: &'a Self) -> Result<(&'a NumberKind, &'a NumberKind), RuntimeFailure> {
-        if let DataKind::Number(l_num) = first {
-            if let DataKind::Number(r_num) = second {
-                return Ok((l_num, r_num))
-            }
+    pub fn arithmetic_error(&self, operand: Operand, context: &Context) -> RuntimeFailure {
+        RuntimeFailure::WrongType(
+            format!("attempt to perform arithmetic on a {} value", self.type_name()),
+            operand,
+            context.current_line,
+        )
+    }
+    // Strings are converted to numbers when used in arithmetic
+    pub fn coerce_to_number(&self) -> Option<NumberKind> {
+        match self {
+            DataKind::Number(num) => Some(num.clone()),
+            DataKind::String(string) => str_to_number(string.as_bytes()),
+            _ => None,
         }
-        Err(RuntimeFailure::WrongType("Number".to_string(), context.current_line))
     }
     pub fn is_true(&self) -> bool {
+        // Only nil and false are falsy in Lua
+        !matches!(self, DataKind::Null | DataKind::Bool(false))
+    }
+    pub fn is_nil(&self) -> bool {
+        matches!(self, DataKind::Null)
+    }
+    pub fn type_name(&self) -> &'static str {
         match self {
-            DataKind::String(string) => !string.is_empty(),
-            DataKind::Number(num) => {
-                match num {
-                    NumberKind::Integer(int) => *int != 0,
-                    NumberKind::Float(float) => *float != 0.0f64
-                }
-            },
-            DataKind::Bool(bool) => *bool,
-            DataKind::Null => false
+            DataKind::String(_) => "string",
+            DataKind::Number(_) => "number",
+            DataKind::Bool(_) => "boolean",
+            DataKind::Null => "nil",
+            DataKind::Table(_) => "table",
+            DataKind::Function(_) => "function",
+            DataKind::Thread(_) => "thread",
+            DataKind::UserData(_) | DataKind::LightUserData(_) => "userdata",
+        }
+    }
+    pub fn string(value: &str) -> Self {
+        DataKind::String(LuaString::from(value))
+    }
+    pub fn integer(value: i64) -> Self {
+        DataKind::Number(NumberKind::Integer(value))
+    }
+}
+
+// Raw equality, this does not consult the __eq metamethod
+impl PartialEq for DataKind {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (DataKind::String(lhs), DataKind::String(rhs)) => lhs == rhs,
+            (DataKind::Number(lhs), DataKind::Number(rhs)) => lhs == rhs,
+            (DataKind::Bool(lhs), DataKind::Bool(rhs)) => lhs == rhs,
+            (DataKind::Null, DataKind::Null) => true,
+            (DataKind::Table(lhs), DataKind::Table(rhs)) => lhs == rhs,
+            (DataKind::Function(lhs), DataKind::Function(rhs)) => lhs == rhs,
+            (DataKind::Thread(lhs), DataKind::Thread(rhs)) => lhs == rhs,
+            (DataKind::UserData(lhs), DataKind::UserData(rhs)) => lhs == rhs,
+            (DataKind::LightUserData(lhs), DataKind::LightUserData(rhs)) => lhs == rhs,
+            _ => false,
         }
     }
 }
@@ -126,51 +180,188 @@ impl Display for DataKind {
             DataKind::Number(num) => write!(f, "{}", num),
             DataKind::Bool(bool) => write!(f, "{}", bool),
             DataKind::Null => write!(f, "nil"),
+            DataKind::Table(table) => write!(f, "table: {:p}", table.as_ptr()),
+            DataKind::Function(function) => write!(f, "function: {:p}", function.as_ptr()),
+            DataKind::Thread(thread) => write!(f, "thread: {:p}", thread.as_ptr()),
+            DataKind::UserData(userdata) => write!(f, "userdata: {:p}", userdata.as_ptr()),
+            DataKind::LightUserData(pointer) => write!(f, "userdata: {:p}", pointer.0),
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
+#[derive(Debug, Clone)]
 pub enum NumberKind {
     Integer(i64),
     Float(f64)
 }
 
+impl NumberKind {
+    pub fn as_float(&self) -> f64 {
+        match self {
+            NumberKind::Integer(int) => *int as f64,
+            NumberKind::Float(float) => *float,
+        }
+    }
+    // Floats with an exact integer representation can be used where integers are expected
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            NumberKind::Integer(int) => Some(*int),
+            NumberKind::Float(float) => float_to_integer(*float),
+        }
+    }
+    fn to_bitwise_integer(&self, context: &Context) -> Result<i64, RuntimeFailure> {
+        self.as_integer().ok_or(RuntimeFailure::NoIntegerRepresentation(context.current_line))
+    }
+    // None for an integer division or modulo by zero, which is an error rather than a value
+    pub fn arithmetic(&self, op: MathOperator, rhs: &NumberKind) -> Option<NumberKind> {
+        let (lhs, rhs) = (self.clone(), rhs.clone());
+        match op {
+            MathOperator::Plus => Some(lhs.add(rhs)),
+            MathOperator::Minus => Some(lhs.sub(rhs)),
+            MathOperator::Multiply => Some(lhs.mul(rhs)),
+            MathOperator::FloatDivision => Some(NumberKind::Float(lhs.as_float() / rhs.as_float())),
+            MathOperator::FloorDivision => lhs.floor_div(rhs),
+            MathOperator::Exponent => Some(NumberKind::Float(lhs.as_float().powf(rhs.as_float()))),
+            MathOperator::Mod => lhs.modulo(rhs),
+        }
+    }
+    fn floor_div(self, rhs: Self) -> Option<NumberKind> {
+        match (self, rhs) {
+            (NumberKind::Integer(_), NumberKind::Integer(0)) => None,
+            (NumberKind::Integer(lhs_int), NumberKind::Integer(rhs_int)) => {
+                let quotient = lhs_int.wrapping_div(rhs_int);
+                // Rust division truncates towards zero, Lua floors
+                if lhs_int.wrapping_rem(rhs_int) != 0 && (lhs_int ^ rhs_int) < 0 {
+                    Some(NumberKind::Integer(quotient - 1))
+                } else {
+                    Some(NumberKind::Integer(quotient))
+                }
+            }
+            (lhs, rhs) => Some(NumberKind::Float((lhs.as_float() / rhs.as_float()).floor())),
+        }
+    }
+    fn modulo(self, rhs: Self) -> Option<NumberKind> {
+        match (self, rhs) {
+            (NumberKind::Integer(_), NumberKind::Integer(0)) => None,
+            (NumberKind::Integer(lhs_int), NumberKind::Integer(rhs_int)) => {
+                let remainder = lhs_int.wrapping_rem(rhs_int);
+                // The result of a modulo has the sign of the divisor in Lua
+                if remainder != 0 && (remainder ^ rhs_int) < 0 {
+                    Some(NumberKind::Integer(remainder + rhs_int))
+                } else {
+                    Some(NumberKind::Integer(remainder))
+                }
+            }
+            (lhs, rhs) => {
+                let (lhs_float, rhs_float) = (lhs.as_float(), rhs.as_float());
+                let remainder = lhs_float % rhs_float;
+                if remainder != 0.0 && (remainder < 0.0) != (rhs_float < 0.0) {
+                    Some(NumberKind::Float(remainder + rhs_float))
+                } else {
+                    Some(NumberKind::Float(remainder))
+                }
+            }
+        }
+    }
+    pub fn negate(self) -> NumberKind {
+        match self {
+            NumberKind::Integer(int) => NumberKind::Integer(int.wrapping_neg()),
+            NumberKind::Float(float) => NumberKind::Float(-float),
+        }
+    }
+}
+
+// 2^63 is the first float outside of the i64 range, -2^63 is exactly i64::MIN
+pub const TWO_POW_63: f64 = (1u64 << 63) as f64;
+
+pub fn float_to_integer(float: f64) -> Option<i64> {
+    if float.fract() == 0.0 && (-TWO_POW_63..TWO_POW_63).contains(&float) {
+        Some(float as i64)
+    } else {
+        None
+    }
+}
+
+pub fn bitwise_integers(op: BitwiseOperator, lhs: i64, rhs: i64) -> i64 {
+    match op {
+        BitwiseOperator::And => lhs & rhs,
+        BitwiseOperator::Or => lhs | rhs,
+        BitwiseOperator::ExclusiveOr => lhs ^ rhs,
+        BitwiseOperator::LeftShift => shift_left(lhs, rhs),
+        BitwiseOperator::RightShift => shift_left(lhs, rhs.wrapping_neg()),
+    }
+}
+
+fn shift_left(value: i64, shift: i64) -> i64 {
+    // Shifts in Lua are logical, shifting by 64 or more bits results in 0
+    if shift <= -64 || shift >= 64 {
+        0
+    } else if shift >= 0 {
+        ((value as u64) << shift) as i64
+    } else {
+        ((value as u64) >> -shift) as i64
+    }
+}
+
 impl Display for NumberKind {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             NumberKind::Integer(int) => write!(f, "{}", int),
-            NumberKind::Float(float) => write!(f, "{}", float),
+            NumberKind::Float(float) => write!(f, "{}", format_float(*float)),
+        }
+    }
+}
+
+// Integers and floats compare by their mathematical value, 1 == 1.0
+impl PartialEq for NumberKind {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (NumberKind::Integer(lhs), NumberKind::Integer(rhs)) => lhs == rhs,
+            (NumberKind::Float(lhs), NumberKind::Float(rhs)) => lhs == rhs,
+            (NumberKind::Integer(int), NumberKind::Float(float))
+            | (NumberKind::Float(float), NumberKind::Integer(int)) => float_to_integer(*float) == Some(*int),
         }
     }
 }
 
+impl PartialOrd for NumberKind {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        match (self, other) {
+            (NumberKind::Integer(lhs), NumberKind::Integer(rhs)) => lhs.partial_cmp(rhs),
+            (NumberKind::Float(lhs), NumberKind::Float(rhs)) => lhs.partial_cmp(rhs),
+            (NumberKind::Integer(int), NumberKind::Float(float)) => compare_integer_float(*int, *float),
+            (NumberKind::Float(float), NumberKind::Integer(int)) => {
+                compare_integer_float(*int, *float).map(|ordering| ordering.reverse())
+            }
+        }
+    }
+}
+
+// Converting a large integer to a float loses precision, so the float is moved into the integer domain instead
+fn compare_integer_float(int: i64, float: f64) -> Option<std::cmp::Ordering> {
+    if float.is_nan() {
+        return None;
+    }
+    if float >= TWO_POW_63 {
+        return Some(std::cmp::Ordering::Less);
+    }
+    if float < -TWO_POW_63 {
+        return Some(std::cmp::Ordering::Greater);
+    }
+    let floor = float.floor() as i64;
+    match int.cmp(&floor) {
+        std::cmp::Ordering::Equal if float.fract() != 0.0 => Some(std::cmp::Ordering::Less),
+        ordering => Some(ordering),
+    }
+}
+
 impl Add for NumberKind {
     type Output = NumberKind;
 
     fn add(self, rhs: Self) -> Self::Output {
-        // There has to be a better way to do this
-        match self {
-            NumberKind::Integer(lhs_int) => {
-                match rhs {
-                    NumberKind::Integer(rhs_int) => {
-                        NumberKind::Integer(lhs_int + rhs_int)
-                    },
-                    NumberKind::Float(rhs_float) => {
-                        NumberKind::Float(lhs_int as f64 + rhs_float)
-                    }
-                }
-            },
-            NumberKind::Float(lhs_float) => {
-                match rhs {
-                    NumberKind::Integer(rhs_int) => {
-                        NumberKind::Float(lhs_float + rhs_int as f64)
-                    },
-                    NumberKind::Float(rhs_float) => {
-                        NumberKind::Float(lhs_float + rhs_float)
-                    }
-                }
-            }
+        match (self, rhs) {
+            (NumberKind::Integer(lhs_int), NumberKind::Integer(rhs_int)) => NumberKind::Integer(lhs_int.wrapping_add(rhs_int)),
+            (lhs, rhs) => NumberKind::Float(lhs.as_float() + rhs.as_float()),
         }
     }
 }
@@ -179,28 +370,95 @@ impl Sub for NumberKind {
     type Output = Self;
 
     fn sub(self, rhs: Self) -> Self::Output {
-        // There has to be a better way to do this
-        match self {
-            NumberKind::Integer(lhs_int) => {
-                match rhs {
-                    NumberKind::Integer(rhs_int) => {
-                        NumberKind::Integer(lhs_int - rhs_int)
-                    },
-                    NumberKind::Float(rhs_float) => {
-                        NumberKind::Float(lhs_int as f64 - rhs_float)
-                    }
-                }
-            },
-            NumberKind::Float(lhs_float) => {
-                match rhs {
-                    NumberKind::Integer(rhs_int) => {
-                        NumberKind::Float(lhs_float - rhs_int as f64)
-                    },
-                    NumberKind::Float(rhs_float) => {
-                        NumberKind::Float(lhs_float - rhs_float)
-                    }
-                }
-            }
+        match (self, rhs) {
+            (NumberKind::Integer(lhs_int), NumberKind::Integer(rhs_int)) => NumberKind::Integer(lhs_int.wrapping_sub(rhs_int)),
+            (lhs, rhs) => NumberKind::Float(lhs.as_float() - rhs.as_float()),
+        }
+    }
+}
+
+impl Mul for NumberKind {
+    type Output = Self;
+
+    fn mul(self, rhs: Self) -> Self::Output {
+        match (self, rhs) {
+            (NumberKind::Integer(lhs_int), NumberKind::Integer(rhs_int)) => NumberKind::Integer(lhs_int.wrapping_mul(rhs_int)),
+            (lhs, rhs) => NumberKind::Float(lhs.as_float() * rhs.as_float()),
+        }
+    }
+}
+
+// Formats a float the way Lua's "%.14g" does, adding ".0" to floats that look like integers
+pub fn format_float(float: f64) -> String {
+    if float.is_nan() {
+        return if float.is_sign_negative() { "-nan".to_owned() } else { "nan".to_owned() };
+    }
+    if float.is_infinite() {
+        return if float < 0.0 { "-inf".to_owned() } else { "inf".to_owned() };
+    }
+    const PRECISION: i32 = 14;
+    let scientific = format!("{:.*e}", (PRECISION - 1) as usize, float);
+    let (mantissa, exponent) = scientific.split_once('e').expect("Scientific notation must contain an exponent");
+    let exponent: i32 = exponent.parse().expect("Exponent must be an integer");
+    let formatted = if !(-4..PRECISION).contains(&exponent) {
+        let mantissa = trim_fraction_zeros(mantissa);
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", mantissa, sign, exponent.abs())
+    } else {
+        let decimals = (PRECISION - 1 - exponent) as usize;
+        trim_fraction_zeros(&format!("{:.*}", decimals, float)).to_owned()
+    };
+    if formatted.contains(['.', 'e']) {
+        formatted
+    } else {
+        formatted + ".0"
+    }
+}
+
+fn trim_fraction_zeros(number: &str) -> &str {
+    if number.contains('.') {
+        number.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        number
+    }
+}
+
+// Converts a string to a number following the rules of the Lua lexer, surrounding whitespace is allowed
+pub fn str_to_number(bytes: &[u8]) -> Option<NumberKind> {
+    let text = std::str::from_utf8(bytes).ok()?.trim_matches(|c: char| c.is_ascii_whitespace());
+    let (negative, unsigned) = match text.as_bytes().first()? {
+        b'-' => (true, &text[1..]),
+        b'+' => (false, &text[1..]),
+        _ => (false, text),
+    };
+    let number = if let Some(hex) = unsigned.strip_prefix("0x").or_else(|| unsigned.strip_prefix("0X")) {
+        if !hex.is_empty() && hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            let int = hex.bytes().fold(0u64, |acc, byte| {
+                acc.wrapping_mul(16).wrapping_add((byte as char).to_digit(16).unwrap_or(0) as u64)
+            });
+            NumberKind::Integer(int as i64)
+        } else {
+            NumberKind::Float(crate::ast::parse_hex_float(hex)?)
+        }
+    } else {
+        // Rust's float parser also accepts words like "inf" and "nan" which Lua does not
+        if unsigned.is_empty() || !unsigned.bytes().all(|byte| byte.is_ascii_digit() || matches!(byte, b'.' | b'e' | b'E' | b'+' | b'-')) {
+            return None;
+        }
+        if unsigned.bytes().all(|byte| byte.is_ascii_digit()) {
+            // Parsed with the sign applied so that i64::MIN is still an integer
+            let signed = if negative { format!("-{}", unsigned) } else { unsigned.to_owned() };
+            return match signed.parse::<i64>() {
+                Ok(int) => Some(NumberKind::Integer(int)),
+                Err(_) => Some(NumberKind::Float(signed.parse::<f64>().ok()?)),
+            };
+        } else {
+            NumberKind::Float(unsigned.parse::<f64>().ok()?)
         }
+    };
+    if negative {
+        Some(number.negate())
+    } else {
+        Some(number)
     }
 }
@@ -0,0 +1,134 @@
+// Names values in error messages and tracebacks the way the reference implementation's ldebug.c does, by reading the
+// bytecode for the instruction that loaded a register, like "global 'print'" or "method 'push'"
+
+use crate::compiler::opcode::OpCode;
+use crate::compiler::prototype::Prototype;
+use crate::frontend::data::DataKind;
+
+// What the value in `register` is while the instruction at `pc` runs, as a kind like "local" and a name
+pub fn object_name(prototype: &Prototype, pc: usize, register: u32) -> Option<(&'static str, String)> {
+    if let Some(name) = prototype.local_name(register, pc) {
+        return Some(("local", name.to_owned()));
+    }
+    let set_pc = find_set_register(prototype, pc, register)?;
+    let instruction = prototype.code[set_pc];
+    match instruction.opcode() {
+        OpCode::Move if instruction.b() < instruction.a() => object_name(prototype, set_pc, instruction.b()),
+        OpCode::GetTabUp => {
+            let kind = match prototype.upvalue_name(instruction.b() as usize) == "_ENV" {
+                true => "global",
+                false => "field",
+            };
+            Some((kind, constant_name(prototype, instruction.c() as usize)))
+        }
+        OpCode::GetTable => {
+            let key = register_name(prototype, set_pc, instruction.c());
+            Some((table_kind(prototype, set_pc, instruction.b()), key))
+        }
+        OpCode::GetI => Some(("field", "integer index".to_owned())),
+        OpCode::GetField => {
+            let key = constant_name(prototype, instruction.c() as usize);
+            Some((table_kind(prototype, set_pc, instruction.b()), key))
+        }
+        OpCode::GetUpval => Some(("upvalue", prototype.upvalue_name(instruction.b() as usize).to_owned())),
+        OpCode::LoadK => match &prototype.constants[instruction.bx() as usize] {
+            DataKind::String(string) => Some(("constant", string.to_string())),
+            _ => None,
+        },
+        OpCode::SelfMethod => {
+            let key = match instruction.k() {
+                true => constant_name(prototype, instruction.c() as usize),
+                false => register_name(prototype, set_pc, instruction.c()),
+            };
+            Some(("method", key))
+        }
+        _ => None,
+    }
+}
+
+// How the instruction a Lua function is running at `pc` called another function, like "local 'f'" or
+// "metamethod 'index'"
+pub fn function_name(prototype: &Prototype, pc: usize) -> Option<(&'static str, String)> {
+    let instruction = prototype.code[pc];
+    let event = match instruction.opcode() {
+        OpCode::Call | OpCode::TailCall => return object_name(prototype, pc, instruction.a()),
+        OpCode::TForCall => return Some(("for iterator", "for iterator".to_owned())),
+        OpCode::SelfMethod | OpCode::GetTabUp | OpCode::GetTable | OpCode::GetI | OpCode::GetField => "index",
+        OpCode::SetTabUp | OpCode::SetTable | OpCode::SetI | OpCode::SetField => "newindex",
+        OpCode::AddI | OpCode::AddK | OpCode::Add => "add",
+        OpCode::SubK | OpCode::Sub => "sub",
+        OpCode::MulK | OpCode::Mul => "mul",
+        OpCode::ModK | OpCode::Mod => "mod",
+        OpCode::PowK | OpCode::Pow => "pow",
+        OpCode::DivK | OpCode::Div => "div",
+        OpCode::IDivK | OpCode::IDiv => "idiv",
+        OpCode::BAndK | OpCode::BAnd => "band",
+        OpCode::BOrK | OpCode::BOr => "bor",
+        OpCode::BXorK | OpCode::BXor => "bxor",
+        OpCode::ShlI | OpCode::Shl => "shl",
+        OpCode::ShrI | OpCode::Shr => "shr",
+        OpCode::Unm => "unm",
+        OpCode::BNot => "bnot",
+        OpCode::Len => "len",
+        OpCode::Concat => "concat",
+        OpCode::Eq => "eq",
+        OpCode::Lt | OpCode::LtI | OpCode::GtI => "lt",
+        OpCode::Le | OpCode::LeI | OpCode::GeI => "le",
+        OpCode::Close | OpCode::Return => "close",
+        _ => return None,
+    };
+    Some(("metamethod", event.to_owned()))
+}
+
+// A table indexed by a constant key is a global when it is the environment
+fn table_kind(prototype: &Prototype, pc: usize, table: u32) -> &'static str {
+    match object_name(prototype, pc, table) {
+        Some((_, name)) if name == "_ENV" => "global",
+        _ => "field",
+    }
+}
+
+fn constant_name(prototype: &Prototype, index: usize) -> String {
+    match &prototype.constants[index] {
+        DataKind::String(string) => string.to_string(),
+        _ => "?".to_owned(),
+    }
+}
+
+// The name of a key held in a register, only string constants have one
+fn register_name(prototype: &Prototype, pc: usize, register: u32) -> String {
+    match object_name(prototype, pc, register) {
+        Some(("constant", name)) => name,
+        _ => "?".to_owned(),
+    }
+}
+
+// The last instruction before `last_pc` that changed `register`. An instruction skipped over by a forward jump may not
+// have run, so the register's value is unknown if that is where it was set.
+fn find_set_register(prototype: &Prototype, last_pc: usize, register: u32) -> Option<usize> {
+    let mut set = None;
+    let mut jump_target = 0;
+    for (pc, instruction) in prototype.code.iter().enumerate().take(last_pc) {
+        let a = instruction.a();
+        let changes = match instruction.opcode() {
+            OpCode::LoadNil => a <= register && register <= a + instruction.b(),
+            OpCode::TForCall => register >= a + 2,
+            OpCode::Call | OpCode::TailCall => register >= a,
+            OpCode::Jmp => {
+                let destination = pc as isize + 1 + instruction.sj_arg() as isize;
+                if destination as usize <= last_pc && destination as usize > jump_target {
+                    jump_target = destination as usize;
+                }
+                false
+            }
+            op => op.sets_a() && register == a,
+        };
+        if changes {
+            set = match pc < jump_target {
+                true => None,
+                false => Some(pc),
+            };
+        }
+    }
+    set
+}
@@ -1,36 +1,177 @@
-use crate::ast::lua_program::FunctionBody;
+use std::cell::{Ref, RefCell, RefMut};
+use std::fmt::{Debug, Formatter};
+use std::rc::{Rc, Weak};
+
+use crate::compiler::prototype::Prototype;
 use crate::err_handle::RuntimeFailure;
 use crate::frontend::Context;
+use crate::frontend::convert::{AsyncHostFunction, HostFunction};
 use crate::frontend::data::DataKind;
+use crate::frontend::gc::{Heap, WeakObject};
 
 pub enum FunctionKind {
-    External(FunctionBody),
-    Internal(InternalFunctionTypes)
-}
-
-// Todo: There _has_ to be a macro to take care of this for me
-pub enum InternalFunctionTypes {
-    TakesOneStr(Box<dyn Fn(&str)>)
-}
-
-impl InternalFunctionTypes {
-    // TODO: there _has_ to be a macro to take care of this for me
-    //       This is a very bad hack job to get the print function working for a hackathon demo
-    pub fn run_function_one_string(&self, context: &Context, args: Vec<DataKind>) -> Result<(), RuntimeFailure> {
-        match self {
-            InternalFunctionTypes::TakesOneStr(func) => {
-                if args.len() != 1 {
-                    return Err(RuntimeFailure::BadFunctionArgs("Function should have 1 arg".to_string(), context.current_line));
-                }
-                match &args[0] {
-                    DataKind::String(arg) => {
-                        func(arg.as_str());
-                        Ok(())
-                    },
-                    _ => return Err(RuntimeFailure::BadFunctionArgs("Function expected a string".to_string(), context.current_line))
-                }
-            },
-            _ => Err(RuntimeFailure::InternalError("running a function that takes one string".to_string()))
+    External(LuaClosure),
+    Internal(NativeFunction)
+}
+
+// A function defined in Lua, an instance of a compiled prototype with the variables it captured from the functions
+// enclosing it
+pub struct LuaClosure {
+    pub prototype: Rc<Prototype>,
+    pub upvalues: Vec<UpvalueRef>,
+}
+
+// The registers of the Lua functions running in a thread
+pub type ValueStack = Rc<RefCell<Vec<DataKind>>>;
+
+// A captured variable. It refers to its register while the function declaring it is running, and holds the value
+// itself once the variable goes out of scope.
+pub enum Upvalue {
+    Open(ValueStack, usize),
+    Closed(DataKind),
+}
+
+// Closures which capture the same variable share its upvalue
+#[derive(Clone)]
+pub struct UpvalueRef(Rc<RefCell<Upvalue>>);
+
+impl UpvalueRef {
+    pub fn open(heap: &Heap, stack: ValueStack, index: usize) -> Self {
+        Self::tracked(heap, Upvalue::Open(stack, index))
+    }
+    pub fn closed(heap: &Heap, value: DataKind) -> Self {
+        Self::tracked(heap, Upvalue::Closed(value))
+    }
+    fn tracked(heap: &Heap, upvalue: Upvalue) -> Self {
+        let upvalue = Self(Rc::new(RefCell::new(upvalue)));
+        heap.track(WeakObject::Upvalue(upvalue.downgrade()));
+        upvalue
+    }
+    pub fn from_weak(weak: &Weak<RefCell<Upvalue>>) -> Option<Self> {
+        weak.upgrade().map(Self)
+    }
+    pub fn downgrade(&self) -> Weak<RefCell<Upvalue>> {
+        Rc::downgrade(&self.0)
+    }
+    // The collector skips upvalues which are being modified while it runs
+    pub fn try_borrow(&self) -> Option<Ref<'_, Upvalue>> {
+        self.0.try_borrow().ok()
+    }
+    pub fn try_borrow_mut(&self) -> Option<RefMut<'_, Upvalue>> {
+        self.0.try_borrow_mut().ok()
+    }
+    pub fn strong_count(&self) -> usize {
+        Rc::strong_count(&self.0)
+    }
+    pub fn as_ptr(&self) -> *const () {
+        Rc::as_ptr(&self.0) as *const ()
+    }
+    // Reading or writing an open upvalue fails with None once its register is past the top of the stack, which only
+    // code that does not close its upvalues, like a corrupted binary chunk, can cause
+    pub fn get(&self) -> Option<DataKind> {
+        match &*self.0.borrow() {
+            Upvalue::Open(stack, index) => stack.borrow().get(*index).cloned(),
+            Upvalue::Closed(value) => Some(value.clone()),
+        }
+    }
+    pub fn set(&self, value: DataKind) -> Option<()> {
+        match &mut *self.0.borrow_mut() {
+            Upvalue::Open(stack, index) => *stack.borrow_mut().get_mut(*index)? = value,
+            Upvalue::Closed(closed) => *closed = value,
+        }
+        Some(())
+    }
+    // Moves the value out of the stack, the register is about to be reused
+    pub fn close(&self) -> Option<()> {
+        let value = self.get()?;
+        *self.0.borrow_mut() = Upvalue::Closed(value);
+        Some(())
+    }
+}
+
+type RawFunction = dyn Fn(&mut Context, Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure>;
+
+// A function implemented in Rust. It takes any number of arguments and returns any number of results, like a
+// lua_CFunction. Functions with typed arguments are wrapped into one by `FunctionRef::typed`.
+pub struct NativeFunction {
+    name: &'static str,
+    func: Box<RawFunction>,
+}
+
+impl NativeFunction {
+    pub fn call(&self, context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+        (self.func)(context, args)
+    }
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+#[derive(Clone)]
+pub struct FunctionRef(Rc<FunctionKind>);
+
+impl FunctionRef {
+    fn new(function: FunctionKind) -> Self {
+        Self(Rc::new(function))
+    }
+    pub fn closure(heap: &Heap, closure: LuaClosure) -> Self {
+        let function = Self::new(FunctionKind::External(closure));
+        heap.track(WeakObject::Closure(function.downgrade()));
+        function
+    }
+    pub fn from_weak(weak: &Weak<FunctionKind>) -> Option<Self> {
+        weak.upgrade().map(Self)
+    }
+    pub fn downgrade(&self) -> Weak<FunctionKind> {
+        Rc::downgrade(&self.0)
+    }
+    pub fn internal<F>(name: &'static str, func: F) -> Self
+    where
+        F: Fn(&mut Context, Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> + 'static,
+    {
+        Self::new(FunctionKind::Internal(NativeFunction { name, func: Box::new(func) }))
+    }
+    // Wraps a Rust function whose arguments and results convert to and from Lua values, a wrong argument fails like
+    // "bad argument #2 to 'name' (number expected, got nil)"
+    pub fn typed<F, Args>(name: &'static str, func: F) -> Self
+    where
+        F: HostFunction<Args>,
+    {
+        Self::internal(name, move |context, args| func.call_host(name, context, args))
+    }
+    // Wraps a Rust function which returns a future, Lua code calling it waits for the future without blocking the
+    // executor. It can only be called from Lua code run by `Lua::call_async` and the like.
+    pub fn typed_async<F, Args>(name: &'static str, func: F) -> Self
+    where
+        F: AsyncHostFunction<Args>,
+    {
+        Self::internal(name, move |context, args| func.call_host(name, context, args))
+    }
+    pub fn kind(&self) -> &FunctionKind {
+        &self.0
+    }
+    pub fn is_native(&self) -> bool {
+        matches!(*self.0, FunctionKind::Internal(_))
+    }
+    pub fn as_ptr(&self) -> *const () {
+        Rc::as_ptr(&self.0) as *const ()
+    }
+    pub fn strong_count(&self) -> usize {
+        Rc::strong_count(&self.0)
+    }
+}
+
+impl PartialEq for FunctionRef {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Debug for FunctionRef {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.kind() {
+            FunctionKind::External(_) => write!(f, "function: {:p}", self.as_ptr()),
+            FunctionKind::Internal(internal) => write!(f, "function: builtin '{}'", internal.name()),
         }
     }
 }
@@ -0,0 +1,94 @@
+// Rust futures as host functions. Lua code which calls one waits for it without blocking the executor: while the
+// future is pending its coroutine yields, and so do the coroutines resuming it, up to the `ThreadFuture` the host is
+// polling. That one returns Poll::Pending and resumes the script the next time it is polled, which polls the future
+// again where it was left.
+
+use std::cell::{Cell, RefCell};
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{self, Poll, Waker};
+
+use crate::err_handle::RuntimeFailure;
+use crate::frontend::coroutine::{yield_values, CoroutineStatus, ThreadRef};
+use crate::frontend::data::DataKind;
+use crate::frontend::state::GlobalState;
+use crate::frontend::Context;
+
+#[derive(Default)]
+pub struct AsyncState {
+    // The waker of the task polling the state, set while a `ThreadFuture` runs Lua code
+    waker: RefCell<Option<Waker>>,
+    // Set when a coroutine yields because a future it waits on is pending, rather than because Lua code yielded
+    pending: Cell<bool>,
+}
+
+impl AsyncState {
+    pub fn is_pending(&self) -> bool {
+        self.pending.get()
+    }
+}
+
+// Polls a future until it is ready, suspending the running coroutine each time it is pending
+pub fn wait<T>(context: &mut Context, name: &str, mut future: Pin<Box<dyn Future<Output = T>>>) -> Result<T, RuntimeFailure> {
+    loop {
+        let Some(waker) = context.state.async_state.waker.borrow().clone() else {
+            return Err(RuntimeFailure::BadOperation(
+                format!("async function '{}' called outside of an async call", name),
+                context.current_line,
+            ));
+        };
+        if let Poll::Ready(value) = future.as_mut().poll(&mut task::Context::from_waker(&waker)) {
+            return Ok(value);
+        }
+        context.state.async_state.pending.set(true);
+        yield_values(context, Vec::new())?;
+    }
+}
+
+// A coroutine run as a future, it completes with what its function returns. Dropping it before closes the coroutine,
+// which runs its pending to-be-closed variables.
+pub struct ThreadFuture {
+    state: Rc<GlobalState>,
+    thread: ThreadRef,
+    // The arguments of the first resume
+    args: Vec<DataKind>,
+}
+
+impl ThreadFuture {
+    pub fn new(state: Rc<GlobalState>, thread: ThreadRef, args: Vec<DataKind>) -> Self {
+        Self { state, thread, args }
+    }
+}
+
+impl Future for ThreadFuture {
+    type Output = Result<Vec<DataKind>, RuntimeFailure>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let async_state = &this.state.async_state;
+        // A future polled inside of an async host function must not take over the waker of the one polling it
+        let outer_waker = async_state.waker.replace(Some(cx.waker().clone()));
+        async_state.pending.set(false);
+        let result = this.thread.resume(&mut Context::new(this.state.clone(), None), std::mem::take(&mut this.args));
+        let pending = async_state.pending.replace(false);
+        *async_state.waker.borrow_mut() = outer_waker;
+        match result {
+            Ok(_) if pending => Poll::Pending,
+            // Lua code which yields gives the other tasks of the executor a turn, the values it yields are dropped
+            Ok(_) if this.thread.status() == CoroutineStatus::Suspended => {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+            result => Poll::Ready(result),
+        }
+    }
+}
+
+impl Drop for ThreadFuture {
+    fn drop(&mut self) {
+        if self.thread.status() == CoroutineStatus::Suspended {
+            let _ = self.thread.close(&mut Context::new(self.state.clone(), None));
+        }
+    }
+}
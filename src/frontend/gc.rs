@@ -0,0 +1,812 @@
+// The garbage collector. Reference counting frees tables, closures and upvalues as soon as nothing points to them,
+// but objects which point to each other, like a table holding a method that captured the table, keep each other
+// alive. The heap keeps a weak handle to every such object and a collection finds the ones only reachable from each
+// other: the references an object gets from the other objects of the heap are subtracted from its reference count,
+// whatever is left comes from outside of the heap, from registers or native functions, and makes the object a root.
+// Objects which cannot be reached from a root are garbage, emptying them breaks their cycles and lets reference
+// counting free them.
+//
+// Coroutines are objects as well. A suspended one references the registers, functions and open upvalues of its frames,
+// so a coroutine only reachable from its own locals is garbage, and emptying it drops its native stack. What native
+// functions it is suspended in hold, like the function a pcall it yielded from runs, cannot be seen and counts as a
+// reference from outside, as does the coroutine a function made by coroutine.wrap resumes. A running coroutine and the
+// ones waiting on it are in use and are roots.
+//
+// References from a table with a `__mode` of "k" or "v" count like any other while finding the roots, since they hold
+// the object all the same, but are not followed while marking. Entries whose weak key or value was not reached are
+// removed, which lets the object go. The value of a weak-keyed table is only marked once its key is, so a value
+// referencing its own key does not keep the entry alive. Tables given a metatable with a `__gc` field are held by the
+// heap until a collection finds them unreachable, then they are resurrected along with what they reference and their
+// finalizer runs once after the collection.
+//
+// The collector paces itself like the two modes of the reference implementation. In incremental mode the whole heap
+// is collected once it grew by the pause since the last collection. In generational mode the young objects, the ones
+// created since the last collection, are collected on their own with the old ones counting as roots, survivors become
+// old, and the whole heap is collected once the old generation grew by the major multiplier. A collection always runs
+// to completion instead of being interleaved with the program, so the step multiplier and size only scale how much
+// `collectgarbage("step", n)` brings the next collection forward.
+//
+// The heap also keeps an estimate of the memory the state uses, for collectgarbage("count") and the memory limit.
+// Objects, table growth, new strings and the native stacks of coroutines add to it as they are allocated, and bring
+// the next collection forward like objects do. Frees are only seen by a collection, which measures the objects that
+// survived it. An allocation past the limit runs a full collection first, like the emergency collections of the
+// reference implementation, and fails with "not enough memory" if that did not free enough.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Weak;
+
+use indexmap::IndexMap;
+
+use crate::err_handle::RuntimeFailure;
+use crate::frontend::{operations, vm, Context};
+use crate::frontend::coroutine::{Coroutine, ThreadRef};
+use crate::frontend::data::DataKind;
+use crate::frontend::function::{FunctionKind, FunctionRef, Upvalue, UpvalueRef};
+use crate::frontend::table::{Table, TableRef};
+use crate::frontend::userdata::{UserDataObject, UserDataRef};
+
+// The collector is paced by counting objects, this is roughly the memory one takes
+const OBJECT_SIZE: usize = 64;
+// A heap is collected no more often than if it held this many objects
+const MIN_THRESHOLD: usize = 256;
+
+// The parameters of both modes are kept so that switching back restores them, like the reference implementation
+struct Parameters {
+    generational: bool,
+    pause: u32,
+    step_multiplier: u32,
+    step_size: u32,
+    minor_multiplier: u32,
+    major_multiplier: u32,
+}
+
+pub struct Heap(RefCell<Collector>);
+
+struct Collector {
+    parameters: Parameters,
+    running: bool,
+    // Objects created since the last collection
+    young: Vec<WeakObject>,
+    // Objects which survived a collection
+    old: Vec<WeakObject>,
+    // Objects created since the last collection, with new strings and table growth counted in objects of the same
+    // size, plus what `collectgarbage("step")` asked for
+    debt: usize,
+    // Bytes the objects measured by the last collection take, plus the ones allocated since
+    allocated: usize,
+    // Objects left by the last collection, and by the last collection of the whole heap
+    live: usize,
+    live_after_major: usize,
+    // Freed objects leave their weak handle behind, the young generation drops them once it grows this long
+    prune_at: usize,
+    // Tables with a finalizer, in the order they got it. They are held here so that reference counting does not free
+    // them before a collection finds them unreachable.
+    finalizable: IndexMap<*const (), TableRef>,
+    // Tables found unreachable whose finalizer has not run yet, the last one runs first
+    to_finalize: Vec<TableRef>,
+    // Set while finalizers run, so that the ones added by a collection inside of a finalizer wait for the loop
+    finalizing: bool,
+    // Set when the program ends, objects do not get finalizers anymore
+    closing: bool,
+}
+
+pub enum WeakObject {
+    Table(Weak<RefCell<Table>>),
+    Closure(Weak<FunctionKind>),
+    Upvalue(Weak<RefCell<Upvalue>>),
+    UserData(Weak<UserDataObject>),
+    Thread(Weak<RefCell<Coroutine>>),
+}
+
+impl WeakObject {
+    fn upgrade(&self) -> Option<Object> {
+        match self {
+            WeakObject::Table(weak) => TableRef::from_weak(weak).map(Object::Table),
+            WeakObject::Closure(weak) => FunctionRef::from_weak(weak).map(Object::Closure),
+            WeakObject::Upvalue(weak) => UpvalueRef::from_weak(weak).map(Object::Upvalue),
+            WeakObject::UserData(weak) => UserDataRef::from_weak(weak).map(Object::UserData),
+            WeakObject::Thread(weak) => ThreadRef::from_weak(weak).map(Object::Thread),
+        }
+    }
+    fn is_alive(&self) -> bool {
+        match self {
+            WeakObject::Table(weak) => weak.strong_count() > 0,
+            WeakObject::Closure(weak) => weak.strong_count() > 0,
+            WeakObject::Upvalue(weak) => weak.strong_count() > 0,
+            WeakObject::UserData(weak) => weak.strong_count() > 0,
+            WeakObject::Thread(weak) => weak.strong_count() > 0,
+        }
+    }
+}
+
+enum Object {
+    Table(TableRef),
+    Closure(FunctionRef),
+    Upvalue(UpvalueRef),
+    UserData(UserDataRef),
+    Thread(ThreadRef),
+}
+
+impl Object {
+    fn as_ptr(&self) -> *const () {
+        match self {
+            Object::Table(table) => table.as_ptr(),
+            Object::Closure(function) => function.as_ptr(),
+            Object::Upvalue(upvalue) => upvalue.as_ptr(),
+            Object::UserData(userdata) => userdata.as_ptr(),
+            Object::Thread(thread) => thread.as_ptr(),
+        }
+    }
+    fn strong_count(&self) -> usize {
+        match self {
+            Object::Table(table) => table.strong_count(),
+            Object::Closure(function) => function.strong_count(),
+            Object::Upvalue(upvalue) => upvalue.strong_count(),
+            Object::UserData(userdata) => userdata.strong_count(),
+            Object::Thread(thread) => thread.strong_count(),
+        }
+    }
+    fn downgrade(&self) -> WeakObject {
+        match self {
+            Object::Table(table) => WeakObject::Table(table.downgrade()),
+            Object::Closure(function) => WeakObject::Closure(function.downgrade()),
+            Object::Upvalue(upvalue) => WeakObject::Upvalue(upvalue.downgrade()),
+            Object::UserData(userdata) => WeakObject::UserData(userdata.downgrade()),
+            Object::Thread(thread) => WeakObject::Thread(thread.downgrade()),
+        }
+    }
+    // Calls `visit` with every object this one references, false when the object is in use and was not traversed
+    fn references(&self, visit: &mut dyn FnMut(*const ())) -> bool {
+        match self {
+            Object::Table(table) => {
+                let Some(table) = table.try_borrow() else {
+                    return false;
+                };
+                for value in table.array_values() {
+                    visit_value(value, visit);
+                }
+                for (key, value) in table.hash_entries() {
+                    visit_value(key, visit);
+                    visit_value(value, visit);
+                }
+                if let Some(metatable) = &table.metatable {
+                    visit(metatable.as_ptr());
+                }
+                true
+            }
+            Object::Closure(function) => {
+                if let FunctionKind::External(closure) = function.kind() {
+                    for upvalue in &closure.upvalues {
+                        visit(upvalue.as_ptr());
+                    }
+                }
+                true
+            }
+            // Open upvalues point into the stack of a thread, the thread holds the value
+            Object::Upvalue(upvalue) => match upvalue.try_borrow().as_deref() {
+                Some(Upvalue::Closed(value)) => {
+                    visit_value(value, visit);
+                    true
+                }
+                Some(Upvalue::Open(..)) => true,
+                None => false,
+            },
+            // The Rust value of a userdata is opaque, what it holds counts as references from outside of the heap
+            Object::UserData(userdata) => {
+                let Some(user_values) = userdata.try_user_values() else {
+                    return false;
+                };
+                for value in user_values.iter() {
+                    visit_value(value, visit);
+                }
+                if let Some(metatable) = userdata.metatable() {
+                    visit(metatable.as_ptr());
+                }
+                true
+            }
+            Object::Thread(thread) => thread.references(visit),
+        }
+    }
+    // An estimate of the memory the object uses, with the strings it holds
+    fn size(&self) -> usize {
+        match self {
+            Object::Table(table) => match table.try_borrow() {
+                Some(table) => {
+                    let keys = table.hash_entries().map(|(key, _)| value_size(key)).sum::<usize>();
+                    let values = table.array_values().iter().chain(table.hash_entries().map(|(_, value)| value));
+                    table.allocated_size() + keys + values.map(value_size).sum::<usize>()
+                }
+                None => std::mem::size_of::<Table>(),
+            },
+            Object::Closure(function) => match function.kind() {
+                FunctionKind::External(closure) => {
+                    std::mem::size_of::<FunctionKind>() + closure.upvalues.len() * std::mem::size_of::<UpvalueRef>()
+                }
+                FunctionKind::Internal(_) => std::mem::size_of::<FunctionKind>(),
+            },
+            Object::Upvalue(upvalue) => match upvalue.try_borrow().as_deref() {
+                Some(Upvalue::Closed(value)) => std::mem::size_of::<Upvalue>() + value_size(value),
+                _ => std::mem::size_of::<Upvalue>(),
+            },
+            Object::UserData(userdata) => {
+                let user_values = userdata.try_user_values().map_or(0, |user_values| {
+                    user_values.len() * std::mem::size_of::<DataKind>() + user_values.iter().map(value_size).sum::<usize>()
+                });
+                std::mem::size_of::<UserDataObject>() + userdata.value_size() + user_values
+            }
+            Object::Thread(thread) => thread.allocated_size(),
+        }
+    }
+}
+
+// The memory a value takes beyond its slot, which only strings have
+fn value_size(value: &DataKind) -> usize {
+    match value {
+        DataKind::String(string) => string.len(),
+        _ => 0,
+    }
+}
+
+pub fn visit_value(value: &DataKind, visit: &mut dyn FnMut(*const ())) {
+    if let Some(pointer) = object_ptr(value) {
+        visit(pointer);
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self(RefCell::new(Collector {
+            parameters: Parameters {
+                generational: false,
+                pause: 200,
+                step_multiplier: 100,
+                step_size: 13,
+                minor_multiplier: 20,
+                major_multiplier: 100,
+            },
+            running: true,
+            young: Vec::new(),
+            old: Vec::new(),
+            debt: 0,
+            allocated: 0,
+            live: 0,
+            live_after_major: 0,
+            prune_at: MIN_THRESHOLD,
+            finalizable: IndexMap::new(),
+            to_finalize: Vec::new(),
+            finalizing: false,
+            closing: false,
+        }))
+    }
+    pub fn track(&self, object: WeakObject) {
+        let mut collector = self.0.borrow_mut();
+        collector.young.push(object);
+        collector.debt += 1;
+        collector.allocated += OBJECT_SIZE;
+        if collector.young.len() >= collector.prune_at {
+            collector.young.retain(WeakObject::is_alive);
+            collector.prune_at = (collector.young.len() * 2).max(MIN_THRESHOLD);
+        }
+    }
+    // Whether the program allocated enough since the last collection to run the next one
+    pub fn is_due(&self) -> bool {
+        let collector = self.0.borrow();
+        collector.running && collector.debt >= collector.threshold()
+    }
+    // Runs the collection the current mode calls for
+    pub fn collect_due(&self) {
+        let full = {
+            let collector = self.0.borrow();
+            let parameters = &collector.parameters;
+            let grown = collector.live * 100 > collector.live_after_major * (100 + parameters.major_multiplier as usize);
+            !parameters.generational || grown
+        };
+        match full {
+            true => self.full_collect(),
+            false => self.collect_young(),
+        }
+    }
+    pub fn full_collect(&self) {
+        let mut collector = self.0.borrow_mut();
+        let mut objects = std::mem::take(&mut collector.old);
+        objects.append(&mut collector.young);
+        let mut finalizable = std::mem::take(&mut collector.finalizable);
+        drop(collector);
+        let (survivors, unreachable, sizes) = collect(objects, &mut finalizable);
+        let mut collector = self.0.borrow_mut();
+        collector.finalizable = finalizable;
+        collector.to_finalize.extend(unreachable);
+        // Strings which are only in registers are not measured, they were charged when they were created
+        collector.allocated = sizes.survivors;
+        collector.live = survivors.len();
+        collector.live_after_major = survivors.len();
+        collector.old = survivors;
+        collector.finish_cycle();
+    }
+    fn collect_young(&self) {
+        let mut collector = self.0.borrow_mut();
+        let objects = std::mem::take(&mut collector.young);
+        let mut finalizable = std::mem::take(&mut collector.finalizable);
+        drop(collector);
+        let (survivors, unreachable, sizes) = collect(objects, &mut finalizable);
+        let mut collector = self.0.borrow_mut();
+        collector.finalizable = finalizable;
+        collector.to_finalize.extend(unreachable);
+        collector.allocated = collector.allocated.saturating_sub(sizes.collected) + sizes.survivors;
+        collector.old.retain(WeakObject::is_alive);
+        collector.old.extend(survivors);
+        collector.live = collector.old.len();
+        collector.finish_cycle();
+    }
+    // A step of `kilobytes` counts as that much allocation, scaled by the step multiplier. A step of 0 always runs a
+    // collection. Returns whether a collection ran.
+    pub fn step(&self, kilobytes: usize) -> bool {
+        {
+            let mut collector = self.0.borrow_mut();
+            let parameters = &collector.parameters;
+            let credit = match kilobytes {
+                0 => usize::MAX,
+                kilobytes => kilobytes.saturating_mul(1024 / OBJECT_SIZE).saturating_mul(parameters.step_multiplier as usize) / 100,
+            };
+            collector.debt = collector.debt.saturating_add(credit);
+            if collector.debt < collector.threshold() {
+                return false;
+            }
+        }
+        self.collect_due();
+        true
+    }
+    // An estimate of the memory used by the state, in bytes
+    pub fn allocated_size(&self) -> usize {
+        self.0.borrow().allocated
+    }
+    fn charge(&self, bytes: usize) {
+        let mut collector = self.0.borrow_mut();
+        collector.allocated = collector.allocated.saturating_add(bytes);
+        collector.debt = collector.debt.saturating_add(bytes / OBJECT_SIZE);
+    }
+    // Called when a table gets a metatable with a `__gc` field, setting the field later does not count
+    pub fn set_finalizer(&self, table: &TableRef) {
+        let mut collector = self.0.borrow_mut();
+        if !collector.closing {
+            collector.finalizable.entry(table.as_ptr()).or_insert_with(|| table.clone());
+        }
+    }
+    fn next_finalizer(&self) -> Option<TableRef> {
+        self.0.borrow_mut().to_finalize.pop()
+    }
+    pub fn is_running(&self) -> bool {
+        self.0.borrow().running
+    }
+    pub fn set_running(&self, running: bool) {
+        self.0.borrow_mut().running = running;
+    }
+    pub fn is_generational(&self) -> bool {
+        self.0.borrow().parameters.generational
+    }
+    // Switches to incremental mode, parameters which are None keep their value
+    pub fn set_incremental(&self, pause: Option<u32>, step_multiplier: Option<u32>, step_size: Option<u32>) {
+        let parameters = &mut self.0.borrow_mut().parameters;
+        parameters.generational = false;
+        parameters.pause = pause.unwrap_or(parameters.pause);
+        parameters.step_multiplier = step_multiplier.unwrap_or(parameters.step_multiplier);
+        parameters.step_size = step_size.unwrap_or(parameters.step_size);
+    }
+    pub fn set_generational(&self, minor_multiplier: Option<u32>, major_multiplier: Option<u32>) {
+        let parameters = &mut self.0.borrow_mut().parameters;
+        parameters.generational = true;
+        parameters.minor_multiplier = minor_multiplier.unwrap_or(parameters.minor_multiplier);
+        parameters.major_multiplier = major_multiplier.unwrap_or(parameters.major_multiplier);
+    }
+}
+
+impl Heap {
+    // Empties every object once the state is closed. The globals are a cycle through _G, so everything they hold would
+    // never be freed otherwise.
+    pub fn release_all(&self) {
+        let objects: Vec<Object> = {
+            let mut collector = self.0.borrow_mut();
+            collector.finalizable.clear();
+            collector.to_finalize.clear();
+            let mut objects = std::mem::take(&mut collector.old);
+            objects.append(&mut collector.young);
+            objects.iter().filter_map(WeakObject::upgrade).collect()
+        };
+        let garbage = empty(objects.iter());
+        drop(objects);
+        drop(garbage);
+    }
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Collector {
+    // How many objects can be created before the next collection
+    fn threshold(&self) -> usize {
+        let live = self.live.max(MIN_THRESHOLD);
+        let parameters = &self.parameters;
+        match parameters.generational {
+            true => live * parameters.minor_multiplier as usize / 100,
+            // The step size is the log2 of the bytes the reference implementation works through in one step, the
+            // heap waits for at least one step
+            false => (live * parameters.pause.saturating_sub(100) as usize / 100)
+                .max((1usize << parameters.step_size.min(30)) / OBJECT_SIZE),
+        }
+    }
+    fn finish_cycle(&mut self) {
+        self.debt = 0;
+        self.prune_at = MIN_THRESHOLD;
+    }
+}
+
+// Which references of a table are weak, from the `__mode` field of its metatable
+#[derive(Clone, Copy, Default)]
+struct Weakness {
+    keys: bool,
+    values: bool,
+}
+
+impl Weakness {
+    fn of(table: &TableRef) -> Self {
+        // Entries are removed from weak tables after marking, a table which is in use cannot have any
+        if table.try_borrow_mut().is_none() {
+            return Self::default();
+        }
+        let metatable = table.borrow().metatable.clone();
+        let mode = metatable.as_ref().and_then(TableRef::try_borrow).map(|metatable| metatable.get_str("__mode"));
+        match mode {
+            Some(DataKind::String(mode)) => Self { keys: mode.as_bytes().contains(&b'k'), values: mode.as_bytes().contains(&b'v') },
+            _ => Self::default(),
+        }
+    }
+    fn is_weak(&self) -> bool {
+        self.keys || self.values
+    }
+}
+
+// Marks the objects reachable from the roots of a collection
+struct Marker<'a> {
+    objects: &'a [Object],
+    index: HashMap<*const (), usize>,
+    reachable: Vec<bool>,
+    pending: Vec<usize>,
+    // The reachable tables with weak references
+    weak_tables: Vec<(usize, Weakness)>,
+}
+
+impl<'a> Marker<'a> {
+    fn new(objects: &'a [Object]) -> Self {
+        Self {
+            objects,
+            index: objects.iter().enumerate().map(|(i, object)| (object.as_ptr(), i)).collect(),
+            reachable: vec![false; objects.len()],
+            pending: Vec::new(),
+            weak_tables: Vec::new(),
+        }
+    }
+    fn mark(&mut self, i: usize) {
+        if !std::mem::replace(&mut self.reachable[i], true) {
+            self.pending.push(i);
+        }
+    }
+    fn mark_value(&mut self, value: &DataKind) {
+        if let Some(&i) = object_ptr(value).and_then(|pointer| self.index.get(&pointer)) {
+            self.mark(i);
+        }
+    }
+    // Values which are not objects of the collection, like strings, native functions, the main thread or objects
+    // outside of the generation being collected, are never removed from weak tables
+    fn is_marked(&self, value: &DataKind) -> bool {
+        match object_ptr(value).and_then(|pointer| self.index.get(&pointer)) {
+            Some(&i) => self.reachable[i],
+            None => true,
+        }
+    }
+    // Marks everything reachable from the objects marked so far
+    fn propagate(&mut self) {
+        loop {
+            while let Some(i) = self.pending.pop() {
+                self.traverse(i);
+            }
+            // A weak-keyed table's values are reachable through the keys marked so far, which can reach more keys
+            let objects = self.objects;
+            for (i, weakness) in self.weak_tables.clone() {
+                if let (Object::Table(table), Weakness { keys: true, values: false }) = (&objects[i], weakness) {
+                    for (key, value) in table.borrow().hash_entries() {
+                        if self.is_marked(key) {
+                            self.mark_value(value);
+                        }
+                    }
+                }
+            }
+            if self.pending.is_empty() {
+                return;
+            }
+        }
+    }
+    fn traverse(&mut self, i: usize) {
+        let objects = self.objects;
+        match &objects[i] {
+            Object::Table(table_ref) => {
+                let weakness = Weakness::of(table_ref);
+                let Some(table) = table_ref.try_borrow() else {
+                    return;
+                };
+                if weakness.is_weak() {
+                    self.weak_tables.push((i, weakness));
+                }
+                if !weakness.values {
+                    for value in table.array_values() {
+                        self.mark_value(value);
+                    }
+                }
+                for (key, value) in table.hash_entries() {
+                    if !weakness.keys {
+                        self.mark_value(key);
+                        if !weakness.values {
+                            self.mark_value(value);
+                        }
+                    }
+                }
+                if let Some(&i) = table.metatable.as_ref().and_then(|metatable| self.index.get(&metatable.as_ptr())) {
+                    self.mark(i);
+                }
+            }
+            Object::Closure(function) => {
+                if let FunctionKind::External(closure) = function.kind() {
+                    for upvalue in &closure.upvalues {
+                        if let Some(&i) = self.index.get(&upvalue.as_ptr()) {
+                            self.mark(i);
+                        }
+                    }
+                }
+            }
+            // The value of an open upvalue stays reachable through it even if its thread is not
+            Object::Upvalue(upvalue) => match upvalue.try_borrow().as_deref() {
+                Some(Upvalue::Closed(value)) => self.mark_value(value),
+                Some(Upvalue::Open(stack, index)) => {
+                    if let Some(value) = stack.try_borrow().ok().and_then(|stack| stack.get(*index).cloned()) {
+                        self.mark_value(&value);
+                    }
+                }
+                None => (),
+            },
+            Object::UserData(userdata) => {
+                if let Some(user_values) = userdata.try_user_values() {
+                    for value in user_values.iter() {
+                        self.mark_value(value);
+                    }
+                }
+                if let Some(&i) = userdata.metatable().and_then(|metatable| self.index.get(&metatable.as_ptr())) {
+                    self.mark(i);
+                }
+            }
+            Object::Thread(thread) => {
+                thread.references(&mut |pointer| {
+                    if let Some(&i) = self.index.get(&pointer) {
+                        self.mark(i);
+                    }
+                });
+            }
+        }
+    }
+    // Removes the entries of reachable weak tables whose weak key or value was not marked
+    fn clear_weak_tables(&self, keys: bool, values: bool) {
+        for &(i, weakness) in &self.weak_tables {
+            let Object::Table(table) = &self.objects[i] else {
+                continue;
+            };
+            let (clear_keys, clear_values) = (weakness.keys && keys, weakness.values && values);
+            if let Some(mut table) = table.try_borrow_mut() {
+                table.retain_entries(|key, value| {
+                    (!clear_keys || self.is_marked(key)) && (!clear_values || value.is_nil() || self.is_marked(value))
+                });
+            }
+        }
+    }
+}
+
+fn object_ptr(value: &DataKind) -> Option<*const ()> {
+    match value {
+        DataKind::Table(table) => Some(table.as_ptr()),
+        DataKind::Function(function) => Some(function.as_ptr()),
+        DataKind::UserData(userdata) => Some(userdata.as_ptr()),
+        DataKind::Thread(thread) => Some(thread.as_ptr()),
+        _ => None,
+    }
+}
+
+// Collects the cycles among `objects` which nothing outside of them points to. Returns the objects which survived and
+// the tables whose finalizer should run, which are taken out of `finalizable`.
+fn collect(
+    objects: Vec<WeakObject>,
+    finalizable: &mut IndexMap<*const (), TableRef>,
+) -> (Vec<WeakObject>, Vec<TableRef>, Sizes) {
+    let objects: Vec<Object> = objects.iter().filter_map(WeakObject::upgrade).collect();
+    let collected = objects.iter().map(Object::size).sum();
+    let mut marker = Marker::new(&objects);
+
+    // Neither the handle in `objects` nor the one the heap keeps to finalize an object is a reference from outside
+    let mut outside_references: Vec<usize> = objects.iter().map(|object| object.strong_count() - 1).collect();
+    for pointer in finalizable.keys() {
+        if let Some(&i) = marker.index.get(pointer) {
+            outside_references[i] -= 1;
+        }
+    }
+    for (i, object) in objects.iter().enumerate() {
+        let traversed = object.references(&mut |pointer| {
+            if let Some(&referenced) = marker.index.get(&pointer) {
+                outside_references[referenced] -= 1;
+            }
+        });
+        // An object which is being modified is kept along with everything it references
+        if !traversed {
+            marker.mark(i);
+        }
+    }
+    for (i, references) in outside_references.into_iter().enumerate() {
+        if references > 0 {
+            marker.mark(i);
+        }
+    }
+    marker.propagate();
+
+    // Objects with a finalizer are removed from weak values before it runs and from weak keys after it ran, so that a
+    // finalizer can still find the object in a weak-keyed table
+    marker.clear_weak_tables(false, true);
+    let mut unreachable = Vec::new();
+    finalizable.retain(|pointer, table| match marker.index.get(pointer) {
+        Some(&i) if !marker.reachable[i] => {
+            unreachable.push(table.clone());
+            false
+        }
+        _ => true,
+    });
+    for table in &unreachable {
+        marker.mark(marker.index[&table.as_ptr()]);
+    }
+    marker.propagate();
+    marker.clear_weak_tables(true, false);
+
+    let garbage = empty(objects.iter().zip(&marker.reachable).filter(|(_, reachable)| !**reachable).map(|(object, _)| object));
+    let survivors: Vec<&Object> =
+        objects.iter().zip(&marker.reachable).filter(|(_, reachable)| **reachable).map(|(object, _)| object).collect();
+    let sizes = Sizes { collected, survivors: survivors.iter().map(|object| object.size()).sum() };
+    let survivors = survivors.into_iter().map(Object::downgrade).collect();
+    drop(marker);
+    drop(objects);
+    drop(garbage);
+    (survivors, unreachable, sizes)
+}
+
+// The memory the objects given to a collection took before it and the memory the ones which survived it take
+struct Sizes {
+    collected: usize,
+    survivors: usize,
+}
+
+// Emptying tables, upvalues, userdata and threads breaks all of their cycles, closures cannot form one on their own.
+// The contents are returned to be dropped once everything is emptied so that freeing a long chain does not recurse
+// through it.
+fn empty<'a>(objects: impl Iterator<Item = &'a Object>) -> (Vec<Table>, Vec<Upvalue>, Vec<DataKind>, Vec<Coroutine>) {
+    let mut garbage_tables = Vec::new();
+    let mut garbage_upvalues = Vec::new();
+    let mut garbage_values = Vec::new();
+    let mut garbage_threads = Vec::new();
+    for object in objects {
+        match object {
+            Object::Table(table) => {
+                if let Some(mut table) = table.try_borrow_mut() {
+                    garbage_tables.push(std::mem::take(&mut *table));
+                }
+            }
+            // An open upvalue holds the stack of its thread, which can hold the upvalue
+            Object::Upvalue(upvalue) => {
+                if let Some(mut upvalue) = upvalue.try_borrow_mut() {
+                    garbage_upvalues.push(std::mem::replace(&mut *upvalue, Upvalue::Closed(DataKind::Null)));
+                }
+            }
+            Object::UserData(userdata) => {
+                let (user_values, metatable) = userdata.clear_references();
+                garbage_values.extend(user_values);
+                garbage_values.extend(metatable.map(DataKind::Table));
+            }
+            Object::Thread(thread) => garbage_threads.extend(thread.clear_references()),
+            Object::Closure(_) => (),
+        }
+    }
+    (garbage_tables, garbage_upvalues, garbage_values, garbage_threads)
+}
+
+// Runs a collection when one is due, the virtual machine checks after creating objects. Registers from `live_top` on
+// are not in use. Fails when the objects took the state past its memory limit.
+pub fn check(context: &mut Context, live_top: usize) -> Result<(), RuntimeFailure> {
+    if context.state.heap.is_due() {
+        clear_dead_registers(context, Some(live_top));
+        context.state.heap.collect_due();
+        run_finalizers(context);
+    }
+    allocate(context, 0)
+}
+
+// Accounts for `bytes` which are about to be allocated, or were just allocated
+pub fn allocate(context: &Context, bytes: usize) -> Result<(), RuntimeFailure> {
+    check_memory(context, bytes)?;
+    context.state.heap.charge(bytes);
+    Ok(())
+}
+
+// Fails if `bytes` more would take the state past its memory limit. A full collection runs first, its finalizers wait
+// for the next collection which is due.
+pub fn check_memory(context: &Context, bytes: usize) -> Result<(), RuntimeFailure> {
+    let heap = &context.state.heap;
+    let Some(limit) = context.state.limits.get().memory else {
+        return Ok(());
+    };
+    if heap.allocated_size().saturating_add(bytes) > limit {
+        clear_dead_registers(context, vm::call_top(context));
+        heap.full_collect();
+        if heap.allocated_size().saturating_add(bytes) > limit {
+            return Err(RuntimeFailure::OutOfMemory);
+        }
+    }
+    Ok(())
+}
+
+// Collections run by a native function, like collectgarbage
+pub fn full_collect(context: &mut Context) {
+    clear_dead_registers(context, vm::call_top(context));
+    context.state.heap.full_collect();
+    run_finalizers(context);
+}
+
+pub fn step(context: &mut Context, kilobytes: usize) -> bool {
+    clear_dead_registers(context, vm::call_top(context));
+    let collected = context.state.heap.step(kilobytes);
+    run_finalizers(context);
+    collected
+}
+
+// Registers past the ones in use still hold the values of finished calls and expressions, which the collector would
+// count as references from outside of the heap. They are cleared before a collection, like the reference
+// implementation does.
+fn clear_dead_registers(context: &Context, live_top: Option<usize>) {
+    if let Some(live_top) = live_top {
+        let mut stack = context.stack.borrow_mut();
+        let end = stack.len();
+        stack[live_top.min(end)..].fill(DataKind::Null);
+    }
+}
+
+// Calls the `__gc` metamethod of the tables collections found unreachable. Errors in finalizers are ignored, the
+// reference implementation only reports them as warnings.
+fn run_finalizers(context: &mut Context) {
+    let heap = &context.state.heap;
+    if std::mem::replace(&mut heap.0.borrow_mut().finalizing, true) {
+        return;
+    }
+    while let Some(table) = context.state.heap.next_finalizer() {
+        let object = DataKind::Table(table);
+        let finalizer = operations::get_metamethod(context, &object, "__gc");
+        if !finalizer.is_nil() {
+            let _ = context.protected_call(&finalizer, vec![object], None);
+        }
+    }
+    context.state.heap.0.borrow_mut().finalizing = false;
+}
+
+// Runs the finalizers of every table which still has one when the program ends, the newest first
+pub fn close(context: &mut Context) {
+    {
+        let mut collector = context.state.heap.0.borrow_mut();
+        collector.closing = true;
+        let finalizable = std::mem::take(&mut collector.finalizable);
+        collector.to_finalize.splice(0..0, finalizable.into_values());
+    }
+    run_finalizers(context);
+}
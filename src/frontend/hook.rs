@@ -0,0 +1,204 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use crate::compiler::prototype::Prototype;
+use crate::err_handle::RuntimeFailure;
+use crate::frontend::convert::host_error;
+use crate::frontend::coroutine::yield_values;
+use crate::frontend::data::DataKind;
+use crate::frontend::{call_function, Context};
+
+// The events a hook is called for, like the mask and count of debug.sethook
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HookTriggers {
+    pub on_calls: bool,
+    pub on_returns: bool,
+    // When a Lua function starts a new line, or jumps back to one like a loop does
+    pub every_line: bool,
+    pub every_nth_instruction: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HookEvent {
+    Call,
+    TailCall,
+    Return,
+    Line,
+    Count,
+}
+
+impl HookEvent {
+    // The name a Lua hook gets, like "tail call"
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HookEvent::Call => "call",
+            HookEvent::TailCall => "tail call",
+            HookEvent::Return => "return",
+            HookEvent::Line => "line",
+            HookEvent::Count => "count",
+        }
+    }
+}
+
+// What a host hook was called for and where the interpreter is
+#[derive(Clone, Debug)]
+pub struct HookInfo {
+    pub event: HookEvent,
+    // The line being run, for line and count events
+    pub line: Option<usize>,
+    // The chunk of the innermost Lua function
+    pub source: String,
+}
+
+// How execution goes on after a host hook returns. A hook which returns an error raises it where the interpreter is,
+// Lua code can catch it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HookAction {
+    Continue,
+    // Suspends the running coroutine, resuming it continues where it stopped. The main thread cannot yield.
+    Yield,
+    // Stops the script with an error that pcall cannot catch, for timeouts
+    Interrupt(String),
+}
+
+type HostHook = dyn Fn(&HookInfo) -> Result<HookAction, RuntimeFailure>;
+
+#[derive(Clone)]
+pub enum HookFunction {
+    // Set with debug.sethook
+    Lua(DataKind),
+    Host(Rc<HostHook>),
+}
+
+pub struct Hook {
+    pub triggers: HookTriggers,
+    pub function: HookFunction,
+}
+
+// The hook and instruction budget of a state. They apply to every coroutine, unlike in the reference implementation
+// where each thread has its own hook.
+#[derive(Default)]
+pub struct Hooks {
+    hook: RefCell<Option<Rc<Hook>>>,
+    // Instructions left to run before the script is interrupted, None for no limit
+    budget: Cell<Option<u64>>,
+    // Instructions left until the next count event
+    count_left: Cell<u32>,
+    // Hooks are not called again while one runs
+    running: Cell<bool>,
+    // Whether the virtual machine has to look at every instruction, which it checks before running each of them
+    watching_instructions: Cell<bool>,
+}
+
+impl Hooks {
+    pub fn hook(&self) -> Option<Rc<Hook>> {
+        self.hook.borrow().clone()
+    }
+    pub fn set_hook(&self, hook: Option<Hook>) {
+        let count = hook.as_ref().and_then(|hook| hook.triggers.every_nth_instruction).unwrap_or(0);
+        self.count_left.set(count);
+        *self.hook.borrow_mut() = hook.map(Rc::new);
+        self.update();
+    }
+    pub fn budget(&self) -> Option<u64> {
+        self.budget.get()
+    }
+    pub fn set_budget(&self, budget: Option<u64>) {
+        self.budget.set(budget);
+        self.update();
+    }
+    pub fn watches_instructions(&self) -> bool {
+        self.watching_instructions.get()
+    }
+    fn update(&self) {
+        let triggers = self.hook.borrow().as_ref().map(|hook| hook.triggers).unwrap_or_default();
+        let watching = self.budget.get().is_some() || triggers.every_line || triggers.every_nth_instruction.is_some();
+        self.watching_instructions.set(watching);
+    }
+    // The hook to call for an event, None when there is none or when one is already running
+    fn hook_for(&self, wanted: impl Fn(&HookTriggers) -> bool) -> Option<Rc<Hook>> {
+        match self.running.get() {
+            true => None,
+            false => self.hook.borrow().as_ref().filter(|hook| wanted(&hook.triggers)).cloned(),
+        }
+    }
+}
+
+// Called before each instruction while the state watches them, `previous` is the instruction the frame ran before
+pub fn on_instruction(
+    context: &mut Context,
+    prototype: &Prototype,
+    pc: usize,
+    previous: &mut Option<usize>,
+) -> Result<(), RuntimeFailure> {
+    let hooks = &context.state.hooks;
+    if let Some(budget) = hooks.budget.get() {
+        if budget == 0 {
+            return Err(RuntimeFailure::Interrupted("instruction budget exhausted".to_owned()));
+        }
+        hooks.budget.set(Some(budget - 1));
+    }
+    let previous = previous.replace(pc);
+    let Some(hook) = hooks.hook_for(|triggers| triggers.every_line || triggers.every_nth_instruction.is_some()) else {
+        return Ok(());
+    };
+    let line = prototype.line_at(pc);
+    if hook.triggers.every_nth_instruction.is_some() {
+        let count_left = hooks.count_left.get().saturating_sub(1);
+        match count_left {
+            0 => {
+                hooks.count_left.set(hook.triggers.every_nth_instruction.unwrap_or(0));
+                run_hook(context, &hook, HookEvent::Count, Some(line))?;
+            }
+            count_left => hooks.count_left.set(count_left),
+        }
+    }
+    let new_line = match previous {
+        None => true,
+        Some(previous) => pc <= previous || prototype.line_at(previous) != line,
+    };
+    if hook.triggers.every_line && new_line {
+        run_hook(context, &hook, HookEvent::Line, Some(line))?;
+    }
+    Ok(())
+}
+
+// Called once the frame of a called function is on the call stack
+pub fn on_call(context: &mut Context, event: HookEvent) -> Result<(), RuntimeFailure> {
+    match context.state.hooks.hook_for(|triggers| triggers.on_calls) {
+        Some(hook) => run_hook(context, &hook, event, None),
+        None => Ok(()),
+    }
+}
+
+// Called while the frame of a returning function is still on the call stack
+pub fn on_return(context: &mut Context) -> Result<(), RuntimeFailure> {
+    match context.state.hooks.hook_for(|triggers| triggers.on_returns) {
+        Some(hook) => run_hook(context, &hook, HookEvent::Return, None),
+        None => Ok(()),
+    }
+}
+
+fn run_hook(context: &mut Context, hook: &Hook, event: HookEvent, line: Option<usize>) -> Result<(), RuntimeFailure> {
+    context.state.hooks.running.set(true);
+    let result = match &hook.function {
+        // Like debug.sethook, a Lua hook gets the event and the new line of a line event
+        HookFunction::Lua(function) => {
+            let mut args = vec![DataKind::string(event.as_str())];
+            if event == HookEvent::Line {
+                args.extend(line.map(|line| DataKind::integer(line as i64)));
+            }
+            call_function(context, function, args).map(|_| HookAction::Continue)
+        }
+        HookFunction::Host(function) => {
+            let info = HookInfo { event, line, source: context.current_source().to_owned() };
+            function(&info).map_err(|failure| host_error(context, failure))
+        }
+    };
+    context.state.hooks.running.set(false);
+    match result? {
+        HookAction::Continue => Ok(()),
+        HookAction::Yield => yield_values(context, Vec::new()).map(|_| ()),
+        HookAction::Interrupt(msg) => Err(RuntimeFailure::Interrupted(msg)),
+    }
+}
@@ -0,0 +1,372 @@
+use std::io::Write;
+
+use crate::err_handle::RuntimeFailure;
+use crate::frontend::{call_function, error_value, first_value, gc, load_code, operations, Context};
+use crate::frontend::data::{DataKind, LuaString, NumberKind};
+use crate::frontend::function::FunctionRef;
+use crate::frontend::state::GlobalState;
+
+use super::{arg_error, check_any, check_integer, check_string, check_table, opt_integer, type_error, LibFunction};
+
+pub fn register(state: &GlobalState) {
+    let functions: [(&'static str, LibFunction); 20] = [
+        ("print", print),
+        ("type", lua_type),
+        ("tostring", tostring),
+        ("tonumber", tonumber),
+        ("ipairs", ipairs),
+        ("pairs", pairs),
+        ("next", next),
+        ("select", select),
+        ("rawget", rawget),
+        ("rawset", rawset),
+        ("rawequal", rawequal),
+        ("rawlen", rawlen),
+        ("setmetatable", setmetatable),
+        ("getmetatable", getmetatable),
+        ("assert", assert),
+        ("error", error),
+        ("pcall", pcall),
+        ("xpcall", xpcall),
+        ("load", load),
+        ("collectgarbage", collectgarbage),
+    ];
+    for (name, function) in functions {
+        state.globals.set_str(name, DataKind::Function(FunctionRef::internal(name, function)));
+    }
+    state.globals.set_str("_G", DataKind::Table(state.globals.clone()));
+    state.globals.set_str("_VERSION", DataKind::string("Lua 5.4"));
+}
+
+fn print(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let mut line = Vec::new();
+    for (index, arg) in args.iter().enumerate() {
+        if index > 0 {
+            line.push(b'\t');
+        }
+        line.extend_from_slice(operations::to_string(context, arg)?.as_bytes());
+    }
+    line.push(b'\n');
+    let mut stdout = std::io::stdout().lock();
+    let _ = stdout.write_all(&line);
+    let _ = stdout.flush();
+    Ok(Vec::new())
+}
+
+fn lua_type(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let value = check_any(context, "type", &args, 1)?;
+    Ok(vec![DataKind::string(value.type_name())])
+}
+
+fn tostring(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let value = check_any(context, "tostring", &args, 1)?;
+    Ok(vec![DataKind::String(operations::to_string(context, &value)?)])
+}
+
+fn tonumber(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let base = match opt_integer(context, "tonumber", &args, 2)? {
+        None => {
+            let value = check_any(context, "tonumber", &args, 1)?;
+            return match value.coerce_to_number() {
+                Some(number) => Ok(vec![DataKind::Number(number)]),
+                None => Ok(vec![DataKind::Null]),
+            };
+        }
+        Some(base) => base,
+    };
+    if !(2..=36).contains(&base) {
+        return Err(arg_error(context, "tonumber", 2, "base out of range"));
+    }
+    let digits = match args.first() {
+        Some(DataKind::String(string)) => string.clone(),
+        _ => return Err(type_error(context, "tonumber", 1, "string", &args)),
+    };
+    Ok(vec![parse_integer_in_base(digits.as_bytes(), base as u32).map_or(DataKind::Null, DataKind::integer)])
+}
+
+fn parse_integer_in_base(bytes: &[u8], base: u32) -> Option<i64> {
+    let text = std::str::from_utf8(bytes).ok()?.trim_matches(|c: char| c.is_ascii_whitespace());
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    if digits.is_empty() {
+        return None;
+    }
+    let mut value: i64 = 0;
+    for digit in digits.chars() {
+        let digit = digit.to_digit(base)?;
+        value = value.wrapping_mul(base as i64).wrapping_add(digit as i64);
+    }
+    Some(if negative { value.wrapping_neg() } else { value })
+}
+
+fn ipairs(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let value = check_any(context, "ipairs", &args, 1)?;
+    let iterator = FunctionRef::internal("ipairs_iterator", |context, args| {
+        let index = match args.get(1) {
+            Some(DataKind::Number(number)) => number.as_integer().unwrap_or(0).wrapping_add(1),
+            _ => 1,
+        };
+        let object = args.into_iter().next().unwrap_or(DataKind::Null);
+        let value = operations::index_value(context, &object, &DataKind::integer(index))?;
+        match value {
+            DataKind::Null => Ok(vec![DataKind::Null]),
+            value => Ok(vec![DataKind::integer(index), value]),
+        }
+    });
+    Ok(vec![DataKind::Function(iterator), value, DataKind::integer(0)])
+}
+
+fn pairs(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let value = check_any(context, "pairs", &args, 1)?;
+    let handler = operations::get_metamethod(context, &value, "__pairs");
+    if !handler.is_nil() {
+        let mut results = call_function(context, &handler, vec![value])?;
+        results.resize(3, DataKind::Null);
+        return Ok(results);
+    }
+    let table = check_table(context, "pairs", &args, 1)?;
+    Ok(vec![DataKind::Function(FunctionRef::internal("next", next)), DataKind::Table(table), DataKind::Null])
+}
+
+fn next(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let table = check_table(context, "next", &args, 1)?;
+    let key = args.get(1).cloned().unwrap_or(DataKind::Null);
+    let next = table.borrow().next(&key);
+    match next {
+        Ok(Some((key, value))) => Ok(vec![key, value]),
+        Ok(None) => Ok(vec![DataKind::Null]),
+        Err(()) => Err(RuntimeFailure::BadFunctionArgs(
+            "invalid key to 'next'".to_owned(),
+            context.current_line,
+        )),
+    }
+}
+
+fn select(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    if let Some(DataKind::String(string)) = args.first() {
+        if string.as_bytes() == b"#" {
+            return Ok(vec![DataKind::integer(args.len() as i64 - 1)]);
+        }
+    }
+    let index = check_integer(context, "select", &args, 1)?;
+    let count = args.len() as i64 - 1;
+    let start = if index < 0 {
+        if -index > count {
+            return Err(arg_error(context, "select", 1, "index out of range"));
+        }
+        count + index
+    } else if index == 0 {
+        return Err(arg_error(context, "select", 1, "index out of range"));
+    } else {
+        (index - 1).min(count)
+    };
+    Ok(args.into_iter().skip(1 + start as usize).collect())
+}
+
+fn rawget(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let table = check_table(context, "rawget", &args, 1)?;
+    let key = check_any(context, "rawget", &args, 2)?;
+    Ok(vec![table.get(&key)])
+}
+
+fn rawset(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let table = check_table(context, "rawset", &args, 1)?;
+    let key = check_any(context, "rawset", &args, 2)?;
+    let value = check_any(context, "rawset", &args, 3)?;
+    table.set(context, key, value)?;
+    Ok(vec![DataKind::Table(table)])
+}
+
+fn rawequal(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let lhs = check_any(context, "rawequal", &args, 1)?;
+    let rhs = check_any(context, "rawequal", &args, 2)?;
+    Ok(vec![DataKind::Bool(lhs == rhs)])
+}
+
+fn rawlen(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    match args.first() {
+        Some(DataKind::Table(table)) => Ok(vec![DataKind::integer(table.length())]),
+        Some(DataKind::String(string)) => Ok(vec![DataKind::integer(string.len() as i64)]),
+        _ => Err(arg_error(context, "rawlen", 1, "table or string expected")),
+    }
+}
+
+fn setmetatable(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let table = check_table(context, "setmetatable", &args, 1)?;
+    let metatable = match args.get(1) {
+        Some(DataKind::Table(metatable)) => Some(metatable.clone()),
+        Some(DataKind::Null) => None,
+        _ => return Err(type_error(context, "setmetatable", 2, "nil or table", &args)),
+    };
+    if let Some(existing) = table.metatable() {
+        if !existing.get_str("__metatable").is_nil() {
+            return Err(RuntimeFailure::BadOperation(
+                "cannot change a protected metatable".to_owned(),
+                context.current_line,
+            ));
+        }
+    }
+    // Only a __gc field present when the metatable is set makes the table finalizable
+    if metatable.as_ref().is_some_and(|metatable| !metatable.get_str("__gc").is_nil()) {
+        context.state.heap.set_finalizer(&table);
+    }
+    table.set_metatable(metatable);
+    Ok(vec![DataKind::Table(table)])
+}
+
+fn getmetatable(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let value = check_any(context, "getmetatable", &args, 1)?;
+    match operations::get_metatable(context, &value) {
+        Some(metatable) => {
+            let protected = metatable.get_str("__metatable");
+            match protected {
+                DataKind::Null => Ok(vec![DataKind::Table(metatable)]),
+                protected => Ok(vec![protected]),
+            }
+        }
+        None => Ok(vec![DataKind::Null]),
+    }
+}
+
+fn assert(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let value = check_any(context, "assert", &args, 1)?;
+    if value.is_true() {
+        return Ok(args);
+    }
+    // The message can be any value and is raised as it is, without a position
+    match args.into_iter().nth(1) {
+        None | Some(DataKind::Null) => Err(RuntimeFailure::ErrorObject(DataKind::string("assertion failed!"))),
+        Some(message) => Err(RuntimeFailure::ErrorObject(message)),
+    }
+}
+
+fn error(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let level = opt_integer(context, "error", &args, 2)?.unwrap_or(1);
+    let value = args.into_iter().next().unwrap_or(DataKind::Null);
+    match value {
+        DataKind::String(message) if level > 0 => {
+            let mut prefixed = context.position_prefix(level as usize).into_bytes();
+            prefixed.extend_from_slice(message.as_bytes());
+            Err(RuntimeFailure::ErrorObject(DataKind::String(LuaString::from(prefixed))))
+        }
+        value => Err(RuntimeFailure::ErrorObject(value)),
+    }
+}
+
+fn pcall(context: &mut Context, mut args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let function = check_any(context, "pcall", &args, 1)?;
+    args.remove(0);
+    match context.protected_call(&function, args, None) {
+        Ok(values) => Ok(with_status(true, values)),
+        Err(failure) if !failure.is_catchable() => Err(failure),
+        Err(failure) => Ok(vec![DataKind::Bool(false), error_value(context, &failure)]),
+    }
+}
+
+fn xpcall(context: &mut Context, mut args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let function = check_any(context, "xpcall", &args, 1)?;
+    let handler = check_any(context, "xpcall", &args, 2)?;
+    args.drain(..2);
+    // The handler is called where the error is raised, so it can inspect the stack with debug.traceback
+    match context.protected_call(&function, args, Some(handler)) {
+        Ok(values) => Ok(with_status(true, values)),
+        Err(failure) if !failure.is_catchable() => Err(failure),
+        Err(failure) => Ok(vec![DataKind::Bool(false), error_value(context, &failure)]),
+    }
+}
+
+fn with_status(status: bool, values: Vec<DataKind>) -> Vec<DataKind> {
+    let mut results = Vec::with_capacity(values.len() + 1);
+    results.push(DataKind::Bool(status));
+    results.extend(values);
+    results
+}
+
+// load(chunk [, chunkname [, mode [, env]]]), the chunk is a string or a function returning its pieces. Errors in it
+// are returned with nil rather than raised.
+fn load(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let code = match args.first() {
+        Some(DataKind::String(code)) => code.as_bytes().to_vec(),
+        Some(reader @ DataKind::Function(_)) => {
+            let mut code = Vec::new();
+            loop {
+                match first_value(call_function(context, reader, Vec::new())?) {
+                    DataKind::Null => break,
+                    DataKind::String(piece) if piece.is_empty() => break,
+                    DataKind::String(piece) => code.extend_from_slice(piece.as_bytes()),
+                    _ => return Ok(vec![DataKind::Null, DataKind::string("reader function must return a string")]),
+                }
+            }
+            code
+        }
+        _ => return Err(type_error(context, "load", 1, "string", &args)),
+    };
+    let chunk_name = match args.get(1) {
+        None | Some(DataKind::Null) => match args.first() {
+            Some(DataKind::String(code)) => code.to_str_lossy().into_owned(),
+            _ => "=(load)".to_owned(),
+        },
+        Some(_) => check_string(context, "load", &args, 2)?.to_str_lossy().into_owned(),
+    };
+    let mode = match args.get(2) {
+        None | Some(DataKind::Null) => LuaString::from("bt"),
+        Some(_) => check_string(context, "load", &args, 3)?,
+    };
+    // An environment which is passed becomes the one of the chunk even when it is nil
+    let environment = match args.get(3) {
+        Some(environment) => environment.clone(),
+        None => DataKind::Table(context.state.globals.clone()),
+    };
+    match load_code(&context.state, &code, &chunk_name, mode.as_bytes(), environment) {
+        Ok(function) => Ok(vec![DataKind::Function(function)]),
+        Err(msg) => Ok(vec![DataKind::Null, DataKind::string(&msg)]),
+    }
+}
+
+fn collectgarbage(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let option = match args.first() {
+        None | Some(DataKind::Null) => LuaString::from("collect"),
+        Some(_) => check_string(context, "collectgarbage", &args, 1)?,
+    };
+    // A parameter of 0 or nil keeps its current value
+    let parameter = |position| -> Result<Option<u32>, RuntimeFailure> {
+        let value = opt_integer(context, "collectgarbage", &args, position)?;
+        Ok(value.filter(|value| *value != 0).map(|value| value.clamp(0, u32::MAX as i64) as u32))
+    };
+    let heap = &context.state.heap;
+    let previous_mode = || DataKind::string(if heap.is_generational() { "generational" } else { "incremental" });
+    match option.as_bytes() {
+        b"collect" => {
+            gc::full_collect(context);
+            Ok(vec![DataKind::integer(0)])
+        }
+        b"stop" | b"restart" => {
+            heap.set_running(option.as_bytes() == b"restart");
+            Ok(vec![DataKind::integer(0)])
+        }
+        b"count" => Ok(vec![DataKind::Number(NumberKind::Float(heap.allocated_size() as f64 / 1024.0))]),
+        b"step" => {
+            let kilobytes = opt_integer(context, "collectgarbage", &args, 2)?.unwrap_or(0).max(0);
+            Ok(vec![DataKind::Bool(gc::step(context, kilobytes as usize))])
+        }
+        b"isrunning" => Ok(vec![DataKind::Bool(heap.is_running())]),
+        b"incremental" => {
+            let (pause, step_multiplier, step_size) = (parameter(2)?, parameter(3)?, parameter(4)?);
+            let previous = previous_mode();
+            heap.set_incremental(pause, step_multiplier, step_size);
+            Ok(vec![previous])
+        }
+        b"generational" => {
+            let (minor_multiplier, major_multiplier) = (parameter(2)?, parameter(3)?);
+            let previous = previous_mode();
+            heap.set_generational(minor_multiplier, major_multiplier);
+            Ok(vec![previous])
+        }
+        _ => {
+            let msg = format!("invalid option '{}'", option.to_str_lossy());
+            Err(arg_error(context, "collectgarbage", 1, &msg))
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use crate::err_handle::RuntimeFailure;
+use crate::frontend::{error_value, Context};
+use crate::frontend::coroutine::{yield_values, CoroutineStatus, ThreadRef};
+use crate::frontend::data::DataKind;
+use crate::frontend::function::FunctionRef;
+use crate::frontend::state::GlobalState;
+
+use super::{check_function, check_thread, new_library};
+
+pub fn register(state: &GlobalState) {
+    let library = new_library(state, &[
+        ("create", create),
+        ("resume", resume),
+        ("yield", lua_yield),
+        ("status", status),
+        ("running", running),
+        ("isyieldable", isyieldable),
+        ("wrap", wrap),
+        ("close", close),
+    ]);
+    state.globals.set_str("coroutine", DataKind::Table(library));
+}
+
+fn create(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let function = check_function(context, "create", &args, 1)?;
+    Ok(vec![DataKind::Thread(ThreadRef::new(context, function)?)])
+}
+
+fn resume(context: &mut Context, mut args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let thread = check_thread(context, "resume", &args, 1)?;
+    args.remove(0);
+    match thread.resume(context, args) {
+        Ok(values) => {
+            let mut results = Vec::with_capacity(values.len() + 1);
+            results.push(DataKind::Bool(true));
+            results.extend(values);
+            Ok(results)
+        }
+        Err(failure) if !failure.is_catchable() => Err(failure),
+        Err(failure) => Ok(vec![DataKind::Bool(false), error_value(context, &failure)]),
+    }
+}
+
+fn lua_yield(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    yield_values(context, args)
+}
+
+fn status(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let thread = check_thread(context, "status", &args, 1)?;
+    Ok(vec![DataKind::string(thread.status().as_str())])
+}
+
+fn running(context: &mut Context, _args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let thread = context.state.current_thread();
+    let is_main = context.state.is_main_thread(&thread);
+    Ok(vec![DataKind::Thread(thread), DataKind::Bool(is_main)])
+}
+
+fn isyieldable(context: &mut Context, _args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    Ok(vec![DataKind::Bool(context.is_yieldable())])
+}
+
+fn wrap(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let function = check_function(context, "wrap", &args, 1)?;
+    let thread = ThreadRef::new(context, function)?;
+    // Unlike resume, errors are propagated to the caller of the wrapped function
+    let wrapped = FunctionRef::internal("wrap", move |context, args| thread.resume(context, args));
+    Ok(vec![DataKind::Function(wrapped)])
+}
+
+fn close(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let thread = check_thread(context, "close", &args, 1)?;
+    // Closing a running or normal coroutine is an error, while an error that killed the coroutine is returned
+    if let CoroutineStatus::Running | CoroutineStatus::Normal = thread.status() {
+        return Err(RuntimeFailure::CoroutineError(
+            format!("cannot close a {} coroutine", thread.status().as_str()),
+            context.current_line,
+        ));
+    }
+    match thread.close(context) {
+        Ok(()) => Ok(vec![DataKind::Bool(true)]),
+        Err(failure) if !failure.is_catchable() => Err(failure),
+        Err(failure) => Ok(vec![DataKind::Bool(false), error_value(context, &failure)]),
+    }
+}
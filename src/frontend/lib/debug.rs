@@ -0,0 +1,141 @@
+use crate::err_handle::RuntimeFailure;
+use crate::frontend::Context;
+use crate::frontend::data::{DataKind, LuaString};
+use crate::frontend::hook::{Hook, HookFunction, HookTriggers};
+use crate::frontend::state::GlobalState;
+
+use super::{check_any, check_string, new_library, opt_integer, type_error};
+
+pub fn register(state: &GlobalState) {
+    let library = new_library(
+        state,
+        &[
+            ("traceback", traceback),
+            ("getregistry", getregistry),
+            ("sethook", sethook),
+            ("gethook", gethook),
+            ("getuservalue", getuservalue),
+            ("setuservalue", setuservalue),
+        ],
+    );
+    state.globals.set_str("debug", DataKind::Table(library));
+}
+
+// debug.traceback([thread,] [message [, level]])
+fn traceback(context: &mut Context, mut args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    // Only the running thread can be inspected, the stack of a suspended coroutine lives on its own native stack
+    let other_thread = match args.first() {
+        Some(DataKind::Thread(thread)) => {
+            let thread = thread.clone();
+            args.remove(0);
+            thread != context.state.current_thread()
+        }
+        _ => false,
+    };
+    let message = match args.first() {
+        None | Some(DataKind::Null) => None,
+        Some(DataKind::String(message)) => Some(message.clone()),
+        Some(DataKind::Number(number)) => Some(LuaString::from(number.to_string())),
+        // Other values are returned untouched, so that error objects survive being passed through a handler
+        Some(value) => return Ok(vec![value.clone()]),
+    };
+    let level = opt_integer(context, "traceback", &args, 2)?.unwrap_or(if other_thread { 0 } else { 1 });
+    let traceback = match other_thread {
+        true => "stack traceback:".to_owned(),
+        false => context.traceback(level.max(0) as usize),
+    };
+    let mut result = match message {
+        Some(message) => {
+            let mut result = message.as_bytes().to_vec();
+            result.push(b'\n');
+            result
+        }
+        None => Vec::new(),
+    };
+    result.extend_from_slice(traceback.as_bytes());
+    Ok(vec![DataKind::String(LuaString::from(result))])
+}
+
+// debug.getregistry()
+fn getregistry(context: &mut Context, _args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    Ok(vec![DataKind::Table(context.state.registry.clone())])
+}
+
+// debug.sethook([thread,] hook, mask [, count]), without arguments the hook is turned off. The mask holds 'c' for
+// calls, 'r' for returns and 'l' for lines, a count above zero calls the hook every count instructions. Hooks are set
+// for the whole state, so the thread is ignored.
+fn sethook(context: &mut Context, mut args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    if let Some(DataKind::Thread(_)) = args.first() {
+        args.remove(0);
+    }
+    let function = match args.first() {
+        None | Some(DataKind::Null) => {
+            context.state.hooks.set_hook(None);
+            return Ok(Vec::new());
+        }
+        Some(function @ DataKind::Function(_)) => function.clone(),
+        Some(_) => return Err(type_error(context, "sethook", 1, "function", &args)),
+    };
+    let mask = check_string(context, "sethook", &args, 2)?;
+    let count = opt_integer(context, "sethook", &args, 3)?.unwrap_or(0);
+    let mask = mask.as_bytes();
+    let triggers = HookTriggers {
+        on_calls: mask.contains(&b'c'),
+        on_returns: mask.contains(&b'r'),
+        every_line: mask.contains(&b'l'),
+        every_nth_instruction: u32::try_from(count).ok().filter(|count| *count > 0),
+    };
+    let hook = match triggers == HookTriggers::default() {
+        true => None,
+        false => Some(Hook { triggers, function: HookFunction::Lua(function) }),
+    };
+    context.state.hooks.set_hook(hook);
+    Ok(Vec::new())
+}
+
+// debug.gethook([thread]), the hook with its mask and count. A hook set by the host is "external hook".
+fn gethook(context: &mut Context, _args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let Some(hook) = context.state.hooks.hook() else {
+        return Ok(vec![DataKind::Null]);
+    };
+    let function = match &hook.function {
+        HookFunction::Lua(function) => function.clone(),
+        HookFunction::Host(_) => DataKind::string("external hook"),
+    };
+    let triggers = hook.triggers;
+    let mask: String = [(triggers.on_calls, 'c'), (triggers.on_returns, 'r'), (triggers.every_line, 'l')]
+        .into_iter()
+        .filter_map(|(set, flag)| set.then_some(flag))
+        .collect();
+    let count = triggers.every_nth_instruction.unwrap_or(0);
+    Ok(vec![function, DataKind::string(&mask), DataKind::integer(count as i64)])
+}
+
+// debug.getuservalue(u [, n]), the n-th user value of a full userdata and whether it has one
+fn getuservalue(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let n = opt_integer(context, "getuservalue", &args, 2)?.unwrap_or(1);
+    match args.first() {
+        Some(DataKind::UserData(userdata)) => {
+            let n = usize::try_from(n).unwrap_or(0);
+            Ok(vec![userdata.user_value(n), DataKind::Bool(userdata.has_user_value(n))])
+        }
+        _ => Ok(vec![DataKind::Null]),
+    }
+}
+
+// debug.setuservalue(udata, value [, n]), returns udata, or nil when n is not a valid user value index
+fn setuservalue(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let userdata = match args.first() {
+        Some(DataKind::UserData(userdata)) => userdata.clone(),
+        _ => return Err(type_error(context, "setuservalue", 1, "userdata", &args)),
+    };
+    let value = check_any(context, "setuservalue", &args, 2)?;
+    let n = opt_integer(context, "setuservalue", &args, 3)?.unwrap_or(1);
+    match usize::try_from(n) {
+        Ok(n) if n >= 1 => {
+            userdata.set_user_value(n, value);
+            Ok(vec![DataKind::UserData(userdata)])
+        }
+        _ => Ok(vec![DataKind::Null]),
+    }
+}
@@ -1,12 +1,101 @@
-use super::function_map::FunctionMap;
-use super::function::FunctionKind;
-use super::function::InternalFunctionTypes;
+mod base;
+mod coroutine;
+mod debug;
+mod pattern;
+mod string;
 
-pub fn register_std_lib(function_map: &mut FunctionMap) {
-    let kind = FunctionKind::Internal(InternalFunctionTypes::TakesOneStr(Box::new(print_fn)));
-    let _ = function_map.register_function("print".to_string(), kind);
+use crate::err_handle::RuntimeFailure;
+use super::Context;
+use super::coroutine::ThreadRef;
+use super::data::{DataKind, LuaString};
+use super::function::FunctionRef;
+use super::state::GlobalState;
+use super::table::TableRef;
+
+pub fn register_std_lib(state: &GlobalState) {
+    base::register(state);
+    coroutine::register(state);
+    debug::register(state);
+    string::register(state);
+}
+
+// Signature shared by all library functions
+type LibFunction = fn(&mut Context, Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure>;
+
+// Builds a library table like `coroutine` out of its functions
+fn new_library(state: &GlobalState, functions: &[(&'static str, LibFunction)]) -> TableRef {
+    let library = TableRef::new(&state.heap);
+    for (name, function) in functions {
+        library.set_str(name, DataKind::Function(FunctionRef::internal(name, *function)));
+    }
+    library
+}
+
+fn arg_error(context: &Context, function_name: &str, position: usize, msg: &str) -> RuntimeFailure {
+    RuntimeFailure::BadFunctionArgs(
+        format!("bad argument #{} to '{}' ({})", position, function_name, msg),
+        context.current_line,
+    )
+}
+
+fn type_error(context: &Context, function_name: &str, position: usize, expected: &str, args: &[DataKind]) -> RuntimeFailure {
+    let got = match args.get(position - 1) {
+        Some(value) => value.type_name(),
+        None => "no value",
+    };
+    arg_error(context, function_name, position, &format!("{} expected, got {}", expected, got))
+}
+
+// Argument positions are 1-based like in Lua error messages
+fn check_any(context: &Context, function_name: &str, args: &[DataKind], position: usize) -> Result<DataKind, RuntimeFailure> {
+    match args.get(position - 1) {
+        Some(value) => Ok(value.clone()),
+        None => Err(arg_error(context, function_name, position, "value expected")),
+    }
+}
+
+fn check_table(context: &Context, function_name: &str, args: &[DataKind], position: usize) -> Result<TableRef, RuntimeFailure> {
+    match args.get(position - 1) {
+        Some(DataKind::Table(table)) => Ok(table.clone()),
+        _ => Err(type_error(context, function_name, position, "table", args)),
+    }
+}
+
+// Numbers are accepted where strings are expected, like in the reference implementation
+fn check_string(context: &Context, function_name: &str, args: &[DataKind], position: usize) -> Result<LuaString, RuntimeFailure> {
+    match args.get(position - 1) {
+        Some(DataKind::String(string)) => Ok(string.clone()),
+        Some(DataKind::Number(number)) => Ok(LuaString::from(number.to_string())),
+        _ => Err(type_error(context, function_name, position, "string", args)),
+    }
+}
+
+fn check_function(context: &Context, function_name: &str, args: &[DataKind], position: usize) -> Result<DataKind, RuntimeFailure> {
+    match args.get(position - 1) {
+        Some(function @ DataKind::Function(_)) => Ok(function.clone()),
+        _ => Err(type_error(context, function_name, position, "function", args)),
+    }
+}
+
+fn check_thread(context: &Context, function_name: &str, args: &[DataKind], position: usize) -> Result<ThreadRef, RuntimeFailure> {
+    match args.get(position - 1) {
+        Some(DataKind::Thread(thread)) => Ok(thread.clone()),
+        _ => Err(type_error(context, function_name, position, "coroutine", args)),
+    }
+}
+
+fn check_integer(context: &Context, function_name: &str, args: &[DataKind], position: usize) -> Result<i64, RuntimeFailure> {
+    match args.get(position - 1).and_then(|value| value.coerce_to_number()) {
+        Some(number) => number
+            .as_integer()
+            .ok_or_else(|| arg_error(context, function_name, position, "number has no integer representation")),
+        None => Err(type_error(context, function_name, position, "number", args)),
+    }
 }
 
-fn print_fn(inp: &str) {
-    println!("{}", inp);
+fn opt_integer(context: &Context, function_name: &str, args: &[DataKind], position: usize) -> Result<Option<i64>, RuntimeFailure> {
+    match args.get(position - 1) {
+        None | Some(DataKind::Null) => Ok(None),
+        Some(_) => Ok(Some(check_integer(context, function_name, args, position)?)),
+    }
 }
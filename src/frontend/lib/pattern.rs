@@ -0,0 +1,359 @@
+// Lua pattern matching, following the matcher of the reference implementation in lstrlib.c
+
+const MAX_CAPTURES: usize = 32;
+// Limits the recursion of the matcher so that pathological patterns fail instead of overflowing the stack
+const MAX_MATCH_DEPTH: usize = 200;
+const SPECIALS: &[u8] = b"^$*+?.([%-";
+
+#[derive(Clone, Copy)]
+enum CaptureLen {
+    Unclosed,
+    Position,
+    Len(usize),
+}
+
+pub enum Capture<'a> {
+    Bytes(&'a [u8]),
+    // A `()` capture, the 1-based position in the subject
+    Position(usize),
+}
+
+struct MatchState<'a> {
+    src: &'a [u8],
+    pat: &'a [u8],
+    depth: usize,
+    captures: Vec<(usize, CaptureLen)>,
+}
+
+pub fn has_specials(pattern: &[u8]) -> bool {
+    pattern.iter().any(|byte| SPECIALS.contains(byte))
+}
+
+// Finds the first match of `pattern` in `src` starting at byte `init`, returning the span of the match and its
+// captures
+pub fn find<'a>(src: &'a [u8], pattern: &'a [u8], init: usize) -> Result<Option<(usize, usize, Vec<Capture<'a>>)>, String> {
+    let (anchor, pat) = match pattern.first() {
+        Some(b'^') => (true, &pattern[1..]),
+        _ => (false, pattern),
+    };
+    let mut start = init;
+    loop {
+        let mut state = MatchState { src, pat, depth: 0, captures: Vec::new() };
+        if let Some(end) = state.do_match(start, 0)? {
+            let captures = state.get_captures(start, end, false)?;
+            return Ok(Some((start, end, captures)));
+        }
+        start += 1;
+        if anchor || start > src.len() {
+            return Ok(None);
+        }
+    }
+}
+
+// Matches `pattern` exactly at `start`, used by gmatch and gsub which walk the subject themselves
+pub fn match_at<'a>(src: &'a [u8], pattern: &'a [u8], start: usize) -> Result<Option<(usize, Vec<Capture<'a>>)>, String> {
+    let mut state = MatchState { src, pat: pattern, depth: 0, captures: Vec::new() };
+    match state.do_match(start, 0)? {
+        Some(end) => {
+            let captures = state.get_captures(start, end, true)?;
+            Ok(Some((end, captures)))
+        }
+        None => Ok(None),
+    }
+}
+
+impl<'a> MatchState<'a> {
+    fn do_match(&mut self, mut s: usize, mut p: usize) -> Result<Option<usize>, String> {
+        self.depth += 1;
+        if self.depth > MAX_MATCH_DEPTH {
+            return Err("pattern too complex".to_owned());
+        }
+        let result = loop {
+            if p == self.pat.len() {
+                break Some(s);
+            }
+            match self.pat[p] {
+                b'(' => {
+                    break if self.pat.get(p + 1) == Some(&b')') {
+                        self.start_capture(s, p + 2, CaptureLen::Position)?
+                    } else {
+                        self.start_capture(s, p + 1, CaptureLen::Unclosed)?
+                    };
+                }
+                b')' => break self.end_capture(s, p + 1)?,
+                b'$' if p + 1 == self.pat.len() => {
+                    break if s == self.src.len() { Some(s) } else { None };
+                }
+                b'%' if self.pat.get(p + 1) == Some(&b'b') => {
+                    match self.match_balance(s, p + 2)? {
+                        Some(end) => {
+                            s = end;
+                            p += 4;
+                            continue;
+                        }
+                        None => break None,
+                    }
+                }
+                b'%' if self.pat.get(p + 1) == Some(&b'f') => {
+                    p += 2;
+                    if self.pat.get(p) != Some(&b'[') {
+                        return Err("missing '[' after '%f' in pattern".to_owned());
+                    }
+                    let end = self.class_end(p)?;
+                    let previous = if s == 0 { 0 } else { self.src[s - 1] };
+                    let current = self.src.get(s).copied().unwrap_or(0);
+                    if !self.match_bracket_class(previous, p, end - 1) && self.match_bracket_class(current, p, end - 1) {
+                        p = end;
+                        continue;
+                    }
+                    break None;
+                }
+                b'%' if self.pat.get(p + 1).is_some_and(|byte| byte.is_ascii_digit()) => {
+                    match self.match_capture(s, self.pat[p + 1])? {
+                        Some(end) => {
+                            s = end;
+                            p += 2;
+                            continue;
+                        }
+                        None => break None,
+                    }
+                }
+                _ => {
+                    let ep = self.class_end(p)?;
+                    let matches = s < self.src.len() && self.single_match(self.src[s], p, ep);
+                    match self.pat.get(ep) {
+                        Some(b'?') => {
+                            if matches {
+                                if let Some(end) = self.do_match(s + 1, ep + 1)? {
+                                    break Some(end);
+                                }
+                            }
+                            p = ep + 1;
+                            continue;
+                        }
+                        Some(b'+') => break if matches { self.max_expand(s + 1, p, ep)? } else { None },
+                        Some(b'*') => break self.max_expand(s, p, ep)?,
+                        Some(b'-') => break self.min_expand(s, p, ep)?,
+                        _ => {
+                            if !matches {
+                                break None;
+                            }
+                            s += 1;
+                            p = ep;
+                            continue;
+                        }
+                    }
+                }
+            }
+        };
+        self.depth -= 1;
+        Ok(result)
+    }
+
+    // Returns the index just past the single character class starting at `p`
+    fn class_end(&self, mut p: usize) -> Result<usize, String> {
+        let byte = self.pat[p];
+        p += 1;
+        if byte == b'%' {
+            if p >= self.pat.len() {
+                return Err("malformed pattern (ends with '%')".to_owned());
+            }
+            return Ok(p + 1);
+        }
+        if byte == b'[' {
+            if self.pat.get(p) == Some(&b'^') {
+                p += 1;
+            }
+            // The first character of a set is never its end, so "[]]" is a set containing ']'
+            loop {
+                if p >= self.pat.len() {
+                    return Err("malformed pattern (missing ']')".to_owned());
+                }
+                let current = self.pat[p];
+                p += 1;
+                if current == b'%' {
+                    p += 1;
+                }
+                if self.pat.get(p) == Some(&b']') {
+                    return Ok(p + 1);
+                }
+                if p >= self.pat.len() {
+                    return Err("malformed pattern (missing ']')".to_owned());
+                }
+            }
+        }
+        Ok(p)
+    }
+
+    fn single_match(&self, byte: u8, p: usize, ep: usize) -> bool {
+        match self.pat[p] {
+            b'.' => true,
+            b'%' => match_class(byte, self.pat[p + 1]),
+            b'[' => self.match_bracket_class(byte, p, ep - 1),
+            literal => literal == byte,
+        }
+    }
+
+    // `p` is at the '[' of the set and `ec` at its closing ']'
+    fn match_bracket_class(&self, byte: u8, mut p: usize, ec: usize) -> bool {
+        let mut negate = false;
+        if self.pat[p + 1] == b'^' {
+            negate = true;
+            p += 1;
+        }
+        p += 1;
+        while p < ec {
+            if self.pat[p] == b'%' {
+                p += 1;
+                if match_class(byte, self.pat[p]) {
+                    return !negate;
+                }
+                p += 1;
+            } else if self.pat.get(p + 1) == Some(&b'-') && p + 2 < ec {
+                if self.pat[p] <= byte && byte <= self.pat[p + 2] {
+                    return !negate;
+                }
+                p += 3;
+            } else {
+                if self.pat[p] == byte {
+                    return !negate;
+                }
+                p += 1;
+            }
+        }
+        negate
+    }
+
+    fn max_expand(&mut self, s: usize, p: usize, ep: usize) -> Result<Option<usize>, String> {
+        let mut count = 0;
+        while s + count < self.src.len() && self.single_match(self.src[s + count], p, ep) {
+            count += 1;
+        }
+        // Tries the longest repetition first and backs off one character at a time
+        loop {
+            if let Some(end) = self.do_match(s + count, ep + 1)? {
+                return Ok(Some(end));
+            }
+            if count == 0 {
+                return Ok(None);
+            }
+            count -= 1;
+        }
+    }
+
+    fn min_expand(&mut self, mut s: usize, p: usize, ep: usize) -> Result<Option<usize>, String> {
+        loop {
+            if let Some(end) = self.do_match(s, ep + 1)? {
+                return Ok(Some(end));
+            }
+            if s < self.src.len() && self.single_match(self.src[s], p, ep) {
+                s += 1;
+            } else {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn start_capture(&mut self, s: usize, p: usize, len: CaptureLen) -> Result<Option<usize>, String> {
+        if self.captures.len() >= MAX_CAPTURES {
+            return Err("too many captures".to_owned());
+        }
+        self.captures.push((s, len));
+        let result = self.do_match(s, p)?;
+        if result.is_none() {
+            self.captures.pop();
+        }
+        Ok(result)
+    }
+
+    fn end_capture(&mut self, s: usize, p: usize) -> Result<Option<usize>, String> {
+        let index = self
+            .captures
+            .iter()
+            .rposition(|(_, len)| matches!(len, CaptureLen::Unclosed))
+            .ok_or_else(|| "invalid pattern capture".to_owned())?;
+        self.captures[index].1 = CaptureLen::Len(s - self.captures[index].0);
+        let result = self.do_match(s, p)?;
+        if result.is_none() {
+            self.captures[index].1 = CaptureLen::Unclosed;
+        }
+        Ok(result)
+    }
+
+    fn match_balance(&self, s: usize, p: usize) -> Result<Option<usize>, String> {
+        if p + 1 >= self.pat.len() {
+            return Err("malformed pattern (missing arguments to '%b')".to_owned());
+        }
+        if s >= self.src.len() || self.src[s] != self.pat[p] {
+            return Ok(None);
+        }
+        let (open, close) = (self.pat[p], self.pat[p + 1]);
+        let mut depth = 1;
+        let mut current = s + 1;
+        while current < self.src.len() {
+            let byte = self.src[current];
+            if byte == close {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(Some(current + 1));
+                }
+            } else if byte == open {
+                depth += 1;
+            }
+            current += 1;
+        }
+        Ok(None)
+    }
+
+    fn match_capture(&self, s: usize, digit: u8) -> Result<Option<usize>, String> {
+        let index = (digit - b'1') as usize;
+        let (start, len) = match self.captures.get(index) {
+            Some((start, CaptureLen::Len(len))) if digit != b'0' => (*start, *len),
+            _ => return Err(format!("invalid capture index %{}", digit as char)),
+        };
+        let captured = &self.src[start..start + len];
+        if self.src.len() - s >= len && &self.src[s..s + len] == captured {
+            Ok(Some(s + len))
+        } else {
+            Ok(None)
+        }
+    }
+
+    // When the pattern has no captures, the whole match is the only capture unless `whole_if_none` is false
+    fn get_captures(&self, start: usize, end: usize, whole_if_none: bool) -> Result<Vec<Capture<'a>>, String> {
+        if self.captures.is_empty() {
+            return Ok(if whole_if_none { vec![Capture::Bytes(&self.src[start..end])] } else { Vec::new() });
+        }
+        self.captures
+            .iter()
+            .map(|(capture_start, len)| match len {
+                CaptureLen::Position => Ok(Capture::Position(capture_start + 1)),
+                CaptureLen::Len(len) => Ok(Capture::Bytes(&self.src[*capture_start..capture_start + len])),
+                CaptureLen::Unclosed => Err("unfinished capture".to_owned()),
+            })
+            .collect()
+    }
+}
+
+fn match_class(byte: u8, class: u8) -> bool {
+    let matches = match class.to_ascii_lowercase() {
+        b'a' => byte.is_ascii_alphabetic(),
+        b'c' => byte.is_ascii_control(),
+        b'd' => byte.is_ascii_digit(),
+        b'g' => byte.is_ascii_graphic(),
+        b'l' => byte.is_ascii_lowercase(),
+        b'p' => byte.is_ascii_punctuation(),
+        b's' => byte.is_ascii_whitespace() || byte == 0x0B,
+        b'u' => byte.is_ascii_uppercase(),
+        b'w' => byte.is_ascii_alphanumeric(),
+        b'x' => byte.is_ascii_hexdigit(),
+        // Any other character after '%' matches itself
+        _ => return class == byte,
+    };
+    // Upper case classes are the complement
+    if class.is_ascii_uppercase() {
+        !matches
+    } else {
+        matches
+    }
+}
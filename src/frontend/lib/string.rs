@@ -0,0 +1,650 @@
+use std::cell::Cell;
+
+use crate::err_handle::RuntimeFailure;
+use crate::frontend::{call_function, first_value, gc, operations, Context};
+use crate::frontend::data::{DataKind, LuaString, NumberKind};
+use crate::compiler::dump;
+use crate::frontend::function::{FunctionKind, FunctionRef};
+use crate::frontend::state::GlobalState;
+use crate::frontend::table::TableRef;
+
+use super::pattern::{self, Capture};
+use super::{arg_error, check_any, check_function, check_integer, check_string, new_library, opt_integer, type_error, LibFunction};
+
+// Longest string that string.rep is allowed to build
+const MAX_STRING_SIZE: usize = i32::MAX as usize;
+
+pub fn register(state: &GlobalState) {
+    let functions: [(&'static str, LibFunction); 13] = [
+        ("sub", sub),
+        ("upper", upper),
+        ("lower", lower),
+        ("reverse", reverse),
+        ("char", char),
+        ("rep", rep),
+        ("byte", byte),
+        ("format", format),
+        ("find", find),
+        ("match", lua_match),
+        ("gmatch", gmatch),
+        ("gsub", gsub),
+        ("dump", dump),
+    ];
+    let library = new_library(state, &functions);
+    library.set_str("len", DataKind::Function(FunctionRef::typed("len", len)));
+    state.globals.set_str("string", DataKind::Table(library.clone()));
+    // Strings share a metatable so that methods like ("x"):rep(3) find the string library
+    let metatable = TableRef::new(&state.heap);
+    metatable.set_str("__index", DataKind::Table(library));
+    *state.string_metatable.borrow_mut() = Some(metatable);
+}
+
+// Converts a relative start position, where negative values count from the end, to a 1-based index
+fn start_position(position: i64, len: usize) -> usize {
+    let len = len as i64;
+    if position > 0 {
+        position as usize
+    } else if position == 0 || position < -len {
+        1
+    } else {
+        (len + position + 1) as usize
+    }
+}
+
+// Like start_position, but for the inclusive end of a range which is clamped to the length
+fn end_position(position: i64, len: usize) -> usize {
+    let len = len as i64;
+    if position > len {
+        len as usize
+    } else if position >= 0 {
+        position as usize
+    } else if position < -len {
+        0
+    } else {
+        (len + position + 1) as usize
+    }
+}
+
+fn len(string: LuaString) -> Result<i64, RuntimeFailure> {
+    Ok(string.len() as i64)
+}
+
+fn sub(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let string = check_string(context, "sub", &args, 1)?;
+    let start = start_position(opt_integer(context, "sub", &args, 2)?.unwrap_or(1), string.len());
+    let end = end_position(opt_integer(context, "sub", &args, 3)?.unwrap_or(-1), string.len());
+    if start > end {
+        return Ok(vec![DataKind::string("")]);
+    }
+    Ok(vec![DataKind::String(LuaString::from(&string.as_bytes()[start - 1..end]))])
+}
+
+fn upper(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let string = check_string(context, "upper", &args, 1)?;
+    gc::check_memory(context, string.len())?;
+    Ok(vec![DataKind::String(LuaString::from(string.as_bytes().to_ascii_uppercase()))])
+}
+
+fn lower(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let string = check_string(context, "lower", &args, 1)?;
+    gc::check_memory(context, string.len())?;
+    Ok(vec![DataKind::String(LuaString::from(string.as_bytes().to_ascii_lowercase()))])
+}
+
+// string.rep(s, n [, sep]), the memory limit is checked before the result is built
+fn rep(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let string = check_string(context, "rep", &args, 1)?;
+    let count = check_integer(context, "rep", &args, 2)?;
+    let separator = match args.get(2) {
+        None | Some(DataKind::Null) => LuaString::from(""),
+        Some(_) => check_string(context, "rep", &args, 3)?,
+    };
+    if count <= 0 {
+        return Ok(vec![DataKind::string("")]);
+    }
+    let count = count as usize;
+    let total = (string.len() + separator.len())
+        .checked_mul(count)
+        .filter(|total| *total <= MAX_STRING_SIZE)
+        .ok_or_else(|| RuntimeFailure::BadOperation("resulting string too large".to_owned(), context.current_line))?;
+    gc::check_memory(context, total)?;
+    let mut result = Vec::with_capacity(total);
+    for index in 0..count {
+        if index > 0 {
+            result.extend_from_slice(separator.as_bytes());
+        }
+        result.extend_from_slice(string.as_bytes());
+    }
+    Ok(vec![DataKind::String(LuaString::from(result))])
+}
+
+fn reverse(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let string = check_string(context, "reverse", &args, 1)?;
+    gc::check_memory(context, string.len())?;
+    let mut bytes = string.as_bytes().to_vec();
+    bytes.reverse();
+    Ok(vec![DataKind::String(LuaString::from(bytes))])
+}
+
+fn byte(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let string = check_string(context, "byte", &args, 1)?;
+    let first = opt_integer(context, "byte", &args, 2)?.unwrap_or(1);
+    let start = start_position(first, string.len());
+    let end = end_position(opt_integer(context, "byte", &args, 3)?.unwrap_or(first), string.len());
+    if start > end {
+        return Ok(Vec::new());
+    }
+    Ok(string.as_bytes()[start - 1..end].iter().map(|byte| DataKind::integer(*byte as i64)).collect())
+}
+
+fn char(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    gc::check_memory(context, args.len())?;
+    let mut bytes = Vec::with_capacity(args.len());
+    for position in 1..=args.len() {
+        let code = check_integer(context, "char", &args, position)?;
+        let byte = u8::try_from(code).map_err(|_| arg_error(context, "char", position, "value out of range"))?;
+        bytes.push(byte);
+    }
+    Ok(vec![DataKind::String(LuaString::from(bytes))])
+}
+
+#[derive(Default)]
+struct FormatSpec {
+    left_align: bool,
+    zero_pad: bool,
+    plus_sign: bool,
+    space_sign: bool,
+    alternate: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+impl FormatSpec {
+    fn has_modifiers(&self) -> bool {
+        self.left_align || self.zero_pad || self.plus_sign || self.space_sign || self.alternate
+            || self.width > 0 || self.precision.is_some()
+    }
+    fn sign(&self, negative: bool) -> &'static str {
+        if negative {
+            "-"
+        } else if self.plus_sign {
+            "+"
+        } else if self.space_sign {
+            " "
+        } else {
+            ""
+        }
+    }
+    // Pads `body` to the width, zeros go between the prefix (a sign or "0x") and the digits
+    fn pad(&self, out: &mut Vec<u8>, prefix: &str, body: &[u8], zeros_allowed: bool) {
+        let fill = self.width.saturating_sub(prefix.len() + body.len());
+        if self.left_align {
+            out.extend_from_slice(prefix.as_bytes());
+            out.extend_from_slice(body);
+            out.resize(out.len() + fill, b' ');
+        } else if self.zero_pad && zeros_allowed {
+            out.extend_from_slice(prefix.as_bytes());
+            out.resize(out.len() + fill, b'0');
+            out.extend_from_slice(body);
+        } else {
+            out.resize(out.len() + fill, b' ');
+            out.extend_from_slice(prefix.as_bytes());
+            out.extend_from_slice(body);
+        }
+    }
+}
+
+fn format(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let format_string = check_string(context, "format", &args, 1)?;
+    let bytes = format_string.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut position = 1;
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] != b'%' {
+            out.push(bytes[index]);
+            index += 1;
+            continue;
+        }
+        index += 1;
+        if bytes.get(index) == Some(&b'%') {
+            out.push(b'%');
+            index += 1;
+            continue;
+        }
+        let spec_start = index;
+        let mut spec = FormatSpec::default();
+        while let Some(flag) = bytes.get(index) {
+            match flag {
+                b'-' => spec.left_align = true,
+                b'0' => spec.zero_pad = true,
+                b'+' => spec.plus_sign = true,
+                b' ' => spec.space_sign = true,
+                b'#' => spec.alternate = true,
+                _ => break,
+            }
+            index += 1;
+        }
+        let (width, width_digits) = read_digits(bytes, &mut index);
+        spec.width = width;
+        let mut precision_digits = 0;
+        if bytes.get(index) == Some(&b'.') {
+            index += 1;
+            let (precision, digits) = read_digits(bytes, &mut index);
+            spec.precision = Some(precision);
+            precision_digits = digits;
+        }
+        let conversion = bytes.get(index).copied();
+        index += 1;
+        let spec_text = String::from_utf8_lossy(&bytes[spec_start..index.min(bytes.len())]).into_owned();
+        // Like the reference implementation, widths and precisions are limited to two digits
+        if width_digits > 2 || precision_digits > 2 {
+            return Err(invalid_conversion(context, &spec_text));
+        }
+        position += 1;
+        match conversion {
+            Some(b'd' | b'i') => {
+                let int = check_integer(context, "format", &args, position)?;
+                let mut digits = int.unsigned_abs().to_string();
+                if let Some(precision) = spec.precision {
+                    digits = format!("{:0>width$}", digits, width = precision);
+                }
+                spec.pad(&mut out, spec.sign(int < 0), digits.as_bytes(), spec.precision.is_none());
+            }
+            Some(conversion @ (b'x' | b'X' | b'o')) => {
+                // Negative integers are formatted as their two's complement, like C does for unsigned conversions
+                let int = check_integer(context, "format", &args, position)? as u64;
+                let (mut digits, prefix) = match conversion {
+                    b'x' => (format!("{:x}", int), "0x"),
+                    b'X' => (format!("{:X}", int), "0X"),
+                    _ => (format!("{:o}", int), "0"),
+                };
+                if let Some(precision) = spec.precision {
+                    digits = format!("{:0>width$}", digits, width = precision);
+                }
+                let prefix = if spec.alternate && int != 0 { prefix } else { "" };
+                spec.pad(&mut out, prefix, digits.as_bytes(), spec.precision.is_none());
+            }
+            Some(b'c') => {
+                let code = check_integer(context, "format", &args, position)?;
+                spec.pad(&mut out, "", &[code as u8], false);
+            }
+            Some(conversion @ (b'f' | b'F' | b'e' | b'E' | b'g' | b'G')) => {
+                let number = check_number(context, &args, position)?;
+                let (negative, body) = format_number(number, conversion, &spec);
+                spec.pad(&mut out, spec.sign(negative), body.as_bytes(), number.is_finite());
+            }
+            Some(b's') => {
+                let value = check_any(context, "format", &args, position)?;
+                let string = operations::to_string(context, &value)?;
+                let mut body = string.as_bytes();
+                if let Some(precision) = spec.precision {
+                    body = &body[..precision.min(body.len())];
+                }
+                spec.pad(&mut out, "", body, false);
+            }
+            Some(b'q') => {
+                if spec.has_modifiers() {
+                    return Err(RuntimeFailure::BadFunctionArgs(
+                        "specifier '%q' cannot have modifiers".to_owned(),
+                        context.current_line,
+                    ));
+                }
+                let value = check_any(context, "format", &args, position)?;
+                quote_value(context, &value, position, &mut out)?;
+            }
+            _ => return Err(invalid_conversion(context, &spec_text)),
+        }
+        // A conversion adds at most a few times the size of its argument, the result is checked as it grows
+        gc::check_memory(context, out.len())?;
+    }
+    Ok(vec![DataKind::String(LuaString::from(out))])
+}
+
+fn read_digits(bytes: &[u8], index: &mut usize) -> (usize, usize) {
+    let mut value = 0usize;
+    let mut digits = 0;
+    while let Some(digit) = bytes.get(*index).filter(|byte| byte.is_ascii_digit()) {
+        value = value.saturating_mul(10).saturating_add((digit - b'0') as usize);
+        digits += 1;
+        *index += 1;
+    }
+    (value, digits)
+}
+
+fn invalid_conversion(context: &Context, spec: &str) -> RuntimeFailure {
+    RuntimeFailure::BadFunctionArgs(format!("invalid conversion '%{}' to 'format'", spec), context.current_line)
+}
+
+fn check_number(context: &Context, args: &[DataKind], position: usize) -> Result<f64, RuntimeFailure> {
+    match args.get(position - 1).and_then(|value| value.coerce_to_number()) {
+        Some(number) => Ok(number.as_float()),
+        None => Err(type_error(context, "format", position, "number", args)),
+    }
+}
+
+// Formats the magnitude of a float for %f, %e and %g, the sign is added by the caller
+fn format_number(number: f64, conversion: u8, spec: &FormatSpec) -> (bool, String) {
+    let negative = number.is_sign_negative() && !number.is_nan();
+    let magnitude = number.abs();
+    let upper = conversion.is_ascii_uppercase();
+    let body = if magnitude.is_infinite() {
+        "inf".to_owned()
+    } else if magnitude.is_nan() {
+        "nan".to_owned()
+    } else {
+        let precision = spec.precision.unwrap_or(6);
+        match conversion.to_ascii_lowercase() {
+            b'f' => format!("{:.*}", precision, magnitude),
+            b'e' => format_exponent(magnitude, precision),
+            _ => {
+                let precision = precision.max(1);
+                let exponent = format_exponent(magnitude, precision - 1)
+                    .split_once('e')
+                    .and_then(|(_, exponent)| exponent.parse::<i32>().ok())
+                    .unwrap_or(0);
+                let formatted = if exponent < -4 || exponent >= precision as i32 {
+                    format_exponent(magnitude, precision - 1)
+                } else {
+                    format!("{:.*}", (precision as i32 - 1 - exponent) as usize, magnitude)
+                };
+                if spec.alternate {
+                    formatted
+                } else {
+                    trim_zeros(&formatted)
+                }
+            }
+        }
+    };
+    (negative, if upper { body.to_ascii_uppercase() } else { body })
+}
+
+// Rust writes exponents like "1.5e3" while C writes "1.5e+03"
+fn format_exponent(number: f64, precision: usize) -> String {
+    let formatted = format!("{:.*e}", precision, number);
+    let (mantissa, exponent) = formatted.split_once('e').expect("Scientific notation must contain an exponent");
+    let exponent: i32 = exponent.parse().expect("Exponent must be an integer");
+    let sign = if exponent < 0 { '-' } else { '+' };
+    format!("{}e{}{:02}", mantissa, sign, exponent.abs())
+}
+
+// Removes the trailing zeros of the fraction, keeping any exponent
+fn trim_zeros(number: &str) -> String {
+    let (mantissa, exponent) = match number.find('e') {
+        Some(index) => number.split_at(index),
+        None => (number, ""),
+    };
+    let mantissa = if mantissa.contains('.') {
+        mantissa.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        mantissa
+    };
+    format!("{}{}", mantissa, exponent)
+}
+
+// %q writes a value so that it reads back as the same value in Lua source
+fn quote_value(context: &Context, value: &DataKind, position: usize, out: &mut Vec<u8>) -> Result<(), RuntimeFailure> {
+    match value {
+        DataKind::String(string) => {
+            out.push(b'"');
+            let bytes = string.as_bytes();
+            for (index, byte) in bytes.iter().enumerate() {
+                match byte {
+                    b'"' | b'\\' | b'\n' => {
+                        out.push(b'\\');
+                        out.push(*byte);
+                    }
+                    b'\r' => out.extend_from_slice(b"\\r"),
+                    0 => {
+                        // A following digit would otherwise be read as part of the escape
+                        if bytes.get(index + 1).is_some_and(|next| next.is_ascii_digit()) {
+                            out.extend_from_slice(b"\\000");
+                        } else {
+                            out.extend_from_slice(b"\\0");
+                        }
+                    }
+                    byte if byte.is_ascii_control() => {
+                        if bytes.get(index + 1).is_some_and(|next| next.is_ascii_digit()) {
+                            out.extend_from_slice(format!("\\{:03}", byte).as_bytes());
+                        } else {
+                            out.extend_from_slice(format!("\\{}", byte).as_bytes());
+                        }
+                    }
+                    byte => out.push(*byte),
+                }
+            }
+            out.push(b'"');
+        }
+        DataKind::Number(NumberKind::Integer(int)) => {
+            // The smallest integer has no positive literal
+            if *int == i64::MIN {
+                out.extend_from_slice(b"0x8000000000000000");
+            } else {
+                out.extend_from_slice(int.to_string().as_bytes());
+            }
+        }
+        DataKind::Number(NumberKind::Float(float)) => {
+            let text = if float.is_infinite() {
+                if *float > 0.0 { "1e9999".to_owned() } else { "-1e9999".to_owned() }
+            } else if float.is_nan() {
+                "(0/0)".to_owned()
+            } else if *float == float.trunc() {
+                format!("{:.1}", float)
+            } else {
+                // Rust prints the shortest representation that reads back as the same float
+                format!("{:e}", float)
+            };
+            out.extend_from_slice(text.as_bytes());
+        }
+        DataKind::Bool(_) | DataKind::Null => out.extend_from_slice(value.to_string().as_bytes()),
+        _ => return Err(arg_error(context, "format", position, "value has no literal form")),
+    }
+    Ok(())
+}
+
+fn capture_value(capture: Capture) -> DataKind {
+    match capture {
+        Capture::Bytes(bytes) => DataKind::String(LuaString::from(bytes)),
+        Capture::Position(position) => DataKind::integer(position as i64),
+    }
+}
+
+fn pattern_error(context: &Context, msg: String) -> RuntimeFailure {
+    RuntimeFailure::BadOperation(msg, context.current_line)
+}
+
+// Shared by find and match, which only differ in what they return
+fn find_aux(context: &mut Context, args: &[DataKind], function_name: &str, find: bool) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let subject = check_string(context, function_name, args, 1)?;
+    let pattern = check_string(context, function_name, args, 2)?;
+    let init = start_position(opt_integer(context, function_name, args, 3)?.unwrap_or(1), subject.len());
+    if init > subject.len() + 1 {
+        return Ok(vec![DataKind::Null]);
+    }
+    let plain = args.get(3).is_some_and(|value| value.is_true());
+    if find && (plain || !pattern::has_specials(pattern.as_bytes())) {
+        let haystack = &subject.as_bytes()[init - 1..];
+        let needle = pattern.as_bytes();
+        let found = if needle.is_empty() {
+            Some(0)
+        } else {
+            haystack.windows(needle.len()).position(|window| window == needle)
+        };
+        return Ok(match found {
+            Some(offset) => {
+                let start = init + offset;
+                vec![DataKind::integer(start as i64), DataKind::integer((start + needle.len() - 1) as i64)]
+            }
+            None => vec![DataKind::Null],
+        });
+    }
+    let found = pattern::find(subject.as_bytes(), pattern.as_bytes(), init - 1)
+        .map_err(|msg| pattern_error(context, msg))?;
+    let (start, end, captures) = match found {
+        Some(found) => found,
+        None => return Ok(vec![DataKind::Null]),
+    };
+    let mut results = Vec::with_capacity(captures.len() + 2);
+    if find {
+        results.push(DataKind::integer(start as i64 + 1));
+        results.push(DataKind::integer(end as i64));
+    } else if captures.is_empty() {
+        results.push(DataKind::String(LuaString::from(&subject.as_bytes()[start..end])));
+    }
+    results.extend(captures.into_iter().map(capture_value));
+    Ok(results)
+}
+
+fn find(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    find_aux(context, &args, "find", true)
+}
+
+fn lua_match(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    find_aux(context, &args, "match", false)
+}
+
+fn gmatch(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let subject = check_string(context, "gmatch", &args, 1)?;
+    let pattern = check_string(context, "gmatch", &args, 2)?;
+    let init = start_position(opt_integer(context, "gmatch", &args, 3)?.unwrap_or(1), subject.len());
+    let position = Cell::new(init - 1);
+    // The end of the previous match, an empty match right after it is skipped
+    let last_match = Cell::new(None);
+    let iterator = FunctionRef::internal("gmatch_iterator", move |context, _| {
+        let mut start = position.get();
+        while start <= subject.len() {
+            let found = pattern::match_at(subject.as_bytes(), pattern.as_bytes(), start)
+                .map_err(|msg| pattern_error(context, msg))?;
+            match found {
+                Some((end, captures)) if Some(end) != last_match.get() => {
+                    position.set(end);
+                    last_match.set(Some(end));
+                    return Ok(captures.into_iter().map(capture_value).collect());
+                }
+                _ => start += 1,
+            }
+        }
+        position.set(start);
+        Ok(vec![DataKind::Null])
+    });
+    Ok(vec![DataKind::Function(iterator)])
+}
+
+fn gsub(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let subject = check_string(context, "gsub", &args, 1)?;
+    let pattern = check_string(context, "gsub", &args, 2)?;
+    let replacement = match args.get(2) {
+        Some(DataKind::Number(number)) => DataKind::String(LuaString::from(number.to_string())),
+        Some(value @ (DataKind::String(_) | DataKind::Table(_) | DataKind::Function(_))) => value.clone(),
+        _ => return Err(type_error(context, "gsub", 3, "string/function/table", &args)),
+    };
+    let max_replacements = opt_integer(context, "gsub", &args, 4)?;
+    let (anchor, pattern_bytes) = match pattern.as_bytes().first() {
+        Some(b'^') => (true, &pattern.as_bytes()[1..]),
+        _ => (false, pattern.as_bytes()),
+    };
+    let src = subject.as_bytes();
+    let mut out = Vec::with_capacity(src.len());
+    let mut start = 0;
+    let mut last_match = None;
+    let mut count: i64 = 0;
+    while max_replacements.is_none_or(|max| count < max) {
+        let found = pattern::match_at(src, pattern_bytes, start).map_err(|msg| pattern_error(context, msg))?;
+        match found {
+            Some((end, captures)) if Some(end) != last_match => {
+                count += 1;
+                add_replacement(context, &replacement, &src[start..end], captures, &mut out)?;
+                // Replacements can make the result far longer than the subject, it is checked as it grows
+                gc::check_memory(context, out.len())?;
+                start = end;
+                last_match = Some(end);
+            }
+            _ if start < src.len() => {
+                out.push(src[start]);
+                start += 1;
+            }
+            _ => break,
+        }
+        if anchor {
+            break;
+        }
+    }
+    out.extend_from_slice(&src[start.min(src.len())..]);
+    Ok(vec![DataKind::String(LuaString::from(out)), DataKind::integer(count)])
+}
+
+fn add_replacement(
+    context: &mut Context,
+    replacement: &DataKind,
+    whole: &[u8],
+    captures: Vec<Capture>,
+    out: &mut Vec<u8>,
+) -> Result<(), RuntimeFailure> {
+    let value = match replacement {
+        DataKind::String(template) => {
+            let template = template.as_bytes();
+            let mut index = 0;
+            while index < template.len() {
+                let byte = template[index];
+                index += 1;
+                if byte != b'%' {
+                    out.push(byte);
+                    continue;
+                }
+                match template.get(index) {
+                    Some(b'%') => out.push(b'%'),
+                    Some(b'0') => out.extend_from_slice(whole),
+                    Some(digit @ b'1'..=b'9') => {
+                        let capture = captures.get((digit - b'1') as usize).ok_or_else(|| {
+                            pattern_error(context, format!("invalid capture index %{} in replacement string", *digit as char))
+                        })?;
+                        match capture {
+                            Capture::Bytes(bytes) => out.extend_from_slice(bytes),
+                            Capture::Position(position) => out.extend_from_slice(position.to_string().as_bytes()),
+                        }
+                    }
+                    _ => return Err(pattern_error(context, "invalid use of '%' in replacement string".to_owned())),
+                }
+                index += 1;
+            }
+            return Ok(());
+        }
+        DataKind::Table(_) => {
+            let key = captures.into_iter().next().map_or(DataKind::Null, capture_value);
+            operations::index_value(context, replacement, &key)?
+        }
+        _ => {
+            let captures = captures.into_iter().map(capture_value).collect();
+            first_value(call_function(context, replacement, captures)?)
+        }
+    };
+    match value {
+        // false or nil keeps the original match
+        DataKind::Null | DataKind::Bool(false) => out.extend_from_slice(whole),
+        DataKind::String(string) => out.extend_from_slice(string.as_bytes()),
+        DataKind::Number(number) => out.extend_from_slice(number.to_string().as_bytes()),
+        value => {
+            return Err(pattern_error(context, format!("invalid replacement value (a {})", value.type_name())));
+        }
+    }
+    Ok(())
+}
+
+// A binary chunk of a Lua function, which the interpreter runs like a source file. Passing true as the second argument
+// leaves out the debug information.
+fn dump(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let DataKind::Function(function) = check_function(context, "dump", &args, 1)? else {
+        unreachable!("check_function only accepts functions")
+    };
+    let strip = args.get(1).is_some_and(DataKind::is_true);
+    match function.kind() {
+        FunctionKind::External(closure) => {
+            gc::check_memory(context, dump::dumped_size(&closure.prototype, strip))?;
+            Ok(vec![DataKind::String(LuaString::from(dump::dump(&closure.prototype, strip)))])
+        }
+        FunctionKind::Internal(_) => Err(RuntimeFailure::BadOperation(
+            "unable to dump given function".to_owned(),
+            context.current_line,
+        )),
+    }
+}
@@ -1,280 +1,363 @@
-#![allow(dead_code)]
-#![allow(unused_variables)]
-// TODO: Remove this once everything is being used
-
-mod variable_map;
-mod data;
+mod call_stack;
+pub mod convert;
+mod debug_info;
+pub mod data;
 mod function;
+mod future;
+mod gc;
+pub mod hook;
 mod lib;
-mod function_map;
+pub mod serialize;
+mod table;
+mod coroutine;
+mod state;
+pub mod operations;
+mod userdata;
+mod vm;
+
+use std::rc::Rc;
 
-use crate::ast::lua_program::{Block, Expression, ExpressionList, FunctionCall, LuaProgram, ReturnStatement, Statement, Var, Args, Expr, NumberKind, PrefixExpression, BinaryOperator, Parameters, NameList, FunctionName};
-use crate::err_handle::RuntimeFailure;
+use crate::compiler;
+use crate::compiler::prototype::Prototype;
+use crate::err_handle::{Operand, RuntimeFailure};
 
-use data::Data;
-use variable_map::VariableMap;
-use function_map::FunctionMap;
-use function::FunctionKind;
-use crate::frontend::data::DataKind;
+use call_stack::CallInfo;
+use coroutine::Yielder;
+use data::{DataKind, LuaString};
+use function::{FunctionKind, NativeFunction, UpvalueRef, ValueStack};
+pub use coroutine::{CoroutineStatus, ThreadRef};
+pub use function::FunctionRef;
+pub use future::ThreadFuture;
+pub use hook::{HookAction, HookEvent, HookInfo, HookTriggers};
+pub use state::{GlobalState, Limits};
+pub use table::TableRef;
+pub use userdata::{LightUserData, UserData, UserDataFields, UserDataMethods, UserDataRef};
 
 pub struct Context<'a> {
-    pub current_line: i32, // Need to find a way to make this actually work. Likely need to just figure out how to store span info from Pest when generating the AST
-    variable_map: &'a mut VariableMap,
-    registered_functions: &'a mut FunctionMap
+    // The line of the statement or operation being run, 0 when no Lua code is running
+    pub current_line: i32,
+    pub state: Rc<GlobalState>,
+    // Set when running inside of a coroutine, used to suspend it
+    yielder: Option<&'a Yielder>,
+    // The functions running in this thread, the innermost call is last
+    call_stack: Vec<CallInfo>,
+    // The registers of the Lua functions running in this thread, each frame's start at its base
+    stack: ValueStack,
+    // Upvalues still pointing into the stack, sorted by the slot they point to
+    open_upvalues: Vec<(usize, UpvalueRef)>,
+    // Stack slots of the to-be-closed variables in scope, in declaration order
+    to_be_closed: Vec<usize>,
+    // One entry per active pcall or xpcall, the message handler of the innermost one is last
+    message_handlers: Vec<Option<DataKind>>,
+    // Set once the message handler has seen the error being raised, so that it is not handled again while unwinding
+    error_handled: bool,
+    // Calls running in this thread which recurse on the native stack
+    native_depth: usize,
+    // Set while a message handler runs, it gets some room past the native depth limit to report an overflow
+    handling_error: bool,
 }
 
-impl <'a> Context<'a> {
-    pub fn store_data(&mut self, var_name: String, data: Data) {
-        self.variable_map.insert(var_name, data);
+impl<'a> Context<'a> {
+    pub fn new(state: Rc<GlobalState>, yielder: Option<&'a Yielder>) -> Self {
+        Self {
+            current_line: 0,
+            state,
+            yielder,
+            call_stack: Vec::new(),
+            stack: ValueStack::default(),
+            open_upvalues: Vec::new(),
+            to_be_closed: Vec::new(),
+            message_handlers: Vec::new(),
+            error_handled: false,
+            native_depth: 0,
+            handling_error: false,
+        }
+    }
+    pub fn is_yieldable(&self) -> bool {
+        self.yielder.is_some()
+    }
+    // The "chunk:line: " prefix for an error raised by a native function `level` functions up the call stack, level 1
+    // being the function that called it. Empty when the function at that level is native or when there is none.
+    pub fn position_prefix(&self, level: usize) -> String {
+        if level == 0 || level >= self.call_stack.len() {
+            return String::new();
+        }
+        let caller = &self.call_stack[self.call_stack.len() - 1 - level];
+        match caller.is_native() {
+            true => String::new(),
+            // The line a function is at is the line it made the call one level down from
+            false => line_prefix(caller.source(), self.call_stack[self.call_stack.len() - level].call_line),
+        }
+    }
+    // The chunk of the innermost Lua function, which is where a failure at `current_line` happened
+    fn current_source(&self) -> &str {
+        self.call_stack.iter().rev().find_map(|call_info| call_info.prototype()).map_or("?", |prototype| &prototype.source)
+    }
+    // Counts a call which recurses on the native stack, it fails when too many are nested. Every successful call must
+    // be followed by `leave_native_call`.
+    fn enter_native_call(&mut self) -> Result<(), RuntimeFailure> {
+        let limit = self.state.limits.get().native_depth;
+        let limit = if self.handling_error { limit + limit / 10 } else { limit };
+        if self.native_depth >= limit {
+            return Err(RuntimeFailure::BadOperation("C stack overflow".to_owned(), self.current_line));
+        }
+        self.native_depth += 1;
+        Ok(())
+    }
+    fn leave_native_call(&mut self) {
+        self.native_depth -= 1;
+    }
+    // Runs a protected call, `handler` sees errors before the stack unwinds like the message handler of an xpcall
+    pub fn protected_call(
+        &mut self,
+        function: &DataKind,
+        args: Vec<DataKind>,
+        handler: Option<DataKind>,
+    ) -> Result<Vec<DataKind>, RuntimeFailure> {
+        // A protected call can run while an error unwinds, from a __close metamethod
+        let unwinding = std::mem::replace(&mut self.error_handled, false);
+        self.message_handlers.push(handler);
+        let result = call_function(self, function, args);
+        // A function which could not be called at all has not been through the handler yet
+        let result = result.map_err(|failure| self.handle_error(failure));
+        self.message_handlers.pop();
+        self.error_handled = unwinding;
+        result
+    }
+    // Passes an error to the message handler of the innermost xpcall, once
+    fn handle_error(&mut self, failure: RuntimeFailure) -> RuntimeFailure {
+        if self.error_handled || !failure.is_catchable() || matches!(failure, RuntimeFailure::OutOfMemory) {
+            return failure;
+        }
+        let handler = match self.message_handlers.last() {
+            Some(Some(handler)) => handler.clone(),
+            _ => return failure,
+        };
+        let error = error_value(self, &failure);
+        // Errors inside of the handler are not handled again
+        self.message_handlers.push(None);
+        let handling_error = std::mem::replace(&mut self.handling_error, true);
+        let result = call_function(self, &handler, vec![error]);
+        self.handling_error = handling_error;
+        self.message_handlers.pop();
+        self.error_handled = true;
+        match result {
+            Ok(values) => RuntimeFailure::ErrorObject(first_value(values)),
+            Err(failure) if !failure.is_catchable() => failure,
+            // An error inside of the message handler is raised in place of the handler's result
+            Err(failure) => RuntimeFailure::ErrorObject(error_value(self, &failure)),
+        }
     }
 }
 
-pub fn enter_program(input: LuaProgram) -> Result<DataKind, RuntimeFailure> {
-    let mut variable_map = VariableMap::new();
-    let mut registered_functions = FunctionMap::new();
-    lib::register_std_lib(&mut registered_functions);
+// Creates the state of an interpreter with the standard library loaded, it is kept across the chunks it runs
+pub fn new_state(limits: Limits) -> Rc<GlobalState> {
+    let state = GlobalState::new(limits);
+    lib::register_std_lib(&state);
+    // Measures the libraries, they were built without being accounted for
+    state.heap.full_collect();
+    state
+}
 
-    // Should ownership of var_map be passed here instead of a mut ref?
-    let mut context = Context{ variable_map: &mut variable_map, registered_functions: &mut registered_functions, current_line: 0 };
-    run_block(&mut context, input.block)
+// Makes a function out of a compiled chunk, whose first upvalue is its environment, usually the table of globals. A
+// function saved by string.dump can have more, they start out as nil.
+pub fn load_chunk(state: &GlobalState, main: Rc<Prototype>, environment: DataKind) -> FunctionRef {
+    let upvalues = (0..main.upvalues.len())
+        .map(|index| UpvalueRef::closed(&state.heap, if index == 0 { environment.clone() } else { DataKind::Null }))
+        .collect();
+    match vm::new_closure(&state.heap, main, upvalues) {
+        DataKind::Function(function) => function,
+        _ => unreachable!("A closure is a function"),
+    }
+}
+
+// Compiles source or loads a binary chunk like `load` does, `mode` holding 'b' and 't' for the kinds of chunk allowed.
+// Failures are the message `load` returns, like "[string "x ="]:1: unexpected symbol near <eof>".
+pub fn load_code(state: &GlobalState, code: &[u8], chunk_name: &str, mode: &[u8], environment: DataKind) -> Result<FunctionRef, String> {
+    let chunk_name = chunk_id(chunk_name);
+    let binary = code.starts_with(compiler::dump::SIGNATURE);
+    let (kind, allowed) = match binary {
+        true => ("binary", b'b'),
+        false => ("text", b't'),
+    };
+    if !mode.contains(&allowed) {
+        return Err(format!("attempt to load a {} chunk (mode is '{}')", kind, String::from_utf8_lossy(mode)));
+    }
+    let main = match binary {
+        true => compiler::dump::undump(code).map_err(|reason| format!("{}: bad binary format ({})", chunk_name, reason))?,
+        false => compiler::compile_source(&String::from_utf8_lossy(code), &chunk_name)
+            .map_err(|errors| {
+                let diagnostic = errors[0].diagnostic();
+                format!("{}:{}: {}", chunk_name, diagnostic.line, diagnostic.message)
+            })?,
+    };
+    Ok(load_chunk(state, main, environment))
 }
 
-pub fn run_block(context: &mut Context, block: Block) -> Result<DataKind, RuntimeFailure> {
-    for statement in block.statements {
-        run_statement(context, statement)?;
+// How a chunk name appears in messages: "=name" is used as is, "@file" names a file and anything else is the source
+// itself, shortened to its first line like `[string "local x = 1..."]`
+fn chunk_id(chunk_name: &str) -> String {
+    // The longest source shown, from LUA_IDSIZE in the reference implementation
+    const MAX_SOURCE: usize = 45;
+    if let Some(name) = chunk_name.strip_prefix('=').or_else(|| chunk_name.strip_prefix('@')) {
+        return name.to_owned();
     }
-    if block.return_statement.is_some() {
-        Ok(run_return_statement(context, block.return_statement.unwrap())?)
+    let first_line = chunk_name.lines().next().unwrap_or_default();
+    if first_line.len() == chunk_name.len() && first_line.len() < MAX_SOURCE {
+        return format!("[string \"{}\"]", chunk_name);
     }
-    else {
-        Ok(DataKind::Null)
+    let mut end = first_line.len().min(MAX_SOURCE);
+    while !first_line.is_char_boundary(end) {
+        end -= 1;
     }
+    format!("[string \"{}...\"]", &first_line[..end])
 }
 
-fn run_statement(context: &mut Context, statement: Statement) -> Result<DataKind, RuntimeFailure> {
-    let statement_res = match statement {
-        Statement::Empty => Ok(DataKind::Null),
-        Statement::MultipleAssignment(var_list, expr_list) => {
-            // TODO: Need to support multiple vars and multiple exprs
-            let var_name = match &var_list.vars[0] {
-                Var::NestedAccess(_) => todo!("nested access"),
-                Var::VarName(var_name) => var_name.to_owned(),
-                Var::TableAccess(_, _) => todo!("table access")
-            };
-            let resolved = resolve_expression(context, expr_list.expressions[0].clone())?;
-            let new_data = Data::new(resolved);
-            context.variable_map.insert(var_name, new_data);
-            Ok(DataKind::Null)
-        },
-        Statement::FunctionCall(call) => function_call(context, call),
-        Statement::Label(label) => todo!("Label"),
-        Statement::Break => todo!("Break"),
-        Statement::GoTo(goto) => todo!("goto"),
-        Statement::DoBlockEnd(block) => run_block(context, block),
-        Statement::WhileExprDoBlockEnd(expr, block) => {
-            todo!("while expr do block")
-        },
-        Statement::RepeatBlockUntilExpr(block, expr) => {
-            todo!("repeat block until expr")
-        },
-        Statement::IfBlock(if_block, elseif_block, else_block) => {
-            let resolved = resolve_expression(context, if_block.0)?;
-            if resolved.is_true() {
-                return run_block(context, if_block.1)
-            }
-            for elseif in elseif_block {
-                let resolved = resolve_expression(context, elseif.0)?;
-                if resolved.is_true() {
-                    return run_block(context, elseif.1)
-                }
-            }
-            match else_block {
-                Some(some_else_block) => {
-                    return run_block(context, some_else_block)
-                },
-                None => Ok(DataKind::Null)
-            }
-        },
-        Statement::ForEach(var_name, var_set_expr, expr, maybe_expr, block) => {
-            todo!("for foo=expr, expr, expr? do block end")
-        },
-        Statement::ForList(name_list, expr_list, block) => {
-            todo!("for namelist in exprlist do block end")
-        },
-        Statement::Function(name, body) => {
-            // TODO: Need to implement the rest of name
-            let function_name = name.outer_name.clone();
-            let function = FunctionKind::External(body);
-            context.registered_functions.register_function(function_name, function)?;
-            Ok(DataKind::Null)
-        },
-        Statement::LocalFunction(name, body) => {
-            todo!("local function name body")
-        }
-    };
-    statement_res
+// Calls a function from the host on the main thread. Errors which Lua code does not catch get a traceback.
+pub fn call_from_host(state: &Rc<GlobalState>, function: &DataKind, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    state.on_host_stack(|| {
+        let mut context = Context::new(state.clone(), None);
+        let handler = DataKind::Function(FunctionRef::internal("traceback", uncaught_error_handler));
+        context.protected_call(function, args, Some(handler))
+    })
 }
 
-fn function_call(context: &mut Context, call: Box<FunctionCall>) -> Result<DataKind, RuntimeFailure> {
-    match *call {
-        FunctionCall::Static(static_function) => {
-            let resolved_function_args = resolve_args(context, static_function.args)?;
-            match static_function.prefix {
-                Var::VarName(var_name) => {
-                    match context.registered_functions.get(var_name.as_str()) {
-                        Some(function) => {
-                            match function {
-                                FunctionKind::External(ref function_body) => {
-                                    if let Some(params) = &function_body.parameters {
-                                        let mut names: Vec<String> = Vec::new();
-                                        match params {
-                                            Parameters::Normal(name_list, exp) => {
-                                                names = name_list.names.clone();
-                                            }
-                                            Parameters::Expanded(_) => todo!("expanded params")
-                                        }
-                                        if names.len() != resolved_function_args.len() {
-                                            return Err(RuntimeFailure::InvalidArgs("Number of args passed are invalid".to_string(), context.current_line))
-                                        }
-                                        // This is not good, but I am out of time
-                                        // Insert passed args into var map
-                                        let mut counter = 0;
-                                        for data in resolved_function_args {
-                                            let to_insert = Data::new(data);
-                                            context.variable_map.insert(names[counter].clone(), to_insert);
-                                            counter += 1;
-                                        }
-                                        // Run the function
-                                        let res = run_block(context, function_body.block.clone())?;
-                                        // pop injected vars from var map
-                                        for var_name in names {
-                                            context.variable_map.remove(var_name.as_str())
-                                        }
-                                        Ok(res)
-                                    }
-                                    else {
-                                        Ok(run_block(context, function_body.block.clone())?)
-                                    }
-                                },
-                                FunctionKind::Internal(internal_function) => {
-                                    // This is a hack, right now the only internal function is 'print'
-                                    internal_function.run_function_one_string(context, resolved_function_args)?;
-                                    Ok(DataKind::Null)
-                                }
-                            }
-                        },
-                        None => return Err(RuntimeFailure::FuncNotFound(var_name, context.current_line))
-                    }
-                },
-                _ => todo!("Call function by nested access")
-            }
-        },
-        FunctionCall::SelfRef(self_function) => {
-            todo!("run self function")
-        },
-        FunctionCall::PassedExpression(expression) => {
-            Ok(resolve_expression(context, expression)?)
-        }
-    }
+// Indexes a value from the host, metamethods included
+pub fn index_from_host(state: &Rc<GlobalState>, object: &DataKind, key: &DataKind) -> Result<DataKind, RuntimeFailure> {
+    state.on_host_stack(|| operations::index_value(&mut Context::new(state.clone(), None), object, key))
 }
 
-fn run_return_statement(context: &mut Context, return_statement: ReturnStatement) -> Result<DataKind, RuntimeFailure> {
-    match return_statement.expression_list {
-        Some(expr_list) => {
-            let mut expressions = expr_list.expressions;
-            if expressions.len() == 0 {
-                return Ok(DataKind::Null)
-            }
-            // TODO: Need to support returning a list of expressions
-            if expressions.len() != 1 {
-                return Err(RuntimeFailure::InternalError("resolving return statement".to_string()))
-            }
-            Ok(resolve_expression(context, expressions.pop().unwrap())?)
-        },
-        None => Ok(DataKind::Null)
-    }
+pub fn set_index_from_host(state: &Rc<GlobalState>, object: &DataKind, key: DataKind, value: DataKind) -> Result<(), RuntimeFailure> {
+    state.on_host_stack(|| operations::set_index(&mut Context::new(state.clone(), None), object, key, value))
 }
 
-fn resolve_args(context: &mut Context, args: Args) -> Result<Vec<DataKind>, RuntimeFailure> {
-    match args {
-        Args::ExpressionList(maybe_expr_list) => {
-            match maybe_expr_list {
-                Some(expr_list) => {
-                    resolve_expr_list(context, expr_list)
-                },
-                None => Ok(Vec::new())
-            }
-        }
-    }
+// Starts a coroutine from the host, it runs `function` once it is resumed
+pub fn new_thread_from_host(state: &Rc<GlobalState>, function: &FunctionRef) -> Result<ThreadRef, RuntimeFailure> {
+    ThreadRef::new(&Context::new(state.clone(), None), DataKind::Function(function.clone()))
 }
 
-fn resolve_expr_list(context: &mut Context, expression_list: ExpressionList) -> Result<Vec<DataKind>, RuntimeFailure> {
-    let mut resolved_data = Vec::new();
-    for expression in expression_list.expressions {
-        let resolved = resolve_expression(context, expression)?;
-        resolved_data.push(resolved);
-    }
-    Ok(resolved_data)
+// Resumes a coroutine from the host, until it yields or returns
+pub fn resume_from_host(state: &Rc<GlobalState>, thread: &ThreadRef, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    thread.resume(&mut Context::new(state.clone(), None), args)
 }
 
-fn resolve_expression(context: &mut Context, expression: Expression) -> Result<DataKind, RuntimeFailure> {
-    match expression {
-        Expression::Expr(expr) => resolve_expr(context, expr),
-        Expression::Binary(binary_op, expr, second_expr) => {
-            let lhs = resolve_expr(context, expr)?;
-            let rhs = resolve_expr(context, second_expr)?;
-            match binary_op {
-                BinaryOperator::MathOperator(math_op) => Ok(DataKind::Number(lhs.math_binary_op(&rhs, math_op, context)?)),
-                BinaryOperator::BitwiseOperator(bitwise_op) => todo!("bitwise binary op"),
-                BinaryOperator::Concat => {
-                    let concat = format!("{}{}", lhs.to_string(), rhs.to_string());
-                    Ok(DataKind::String(concat))
-                },
-                BinaryOperator::BooleanOperator(boolean_op) => Ok(DataKind::Bool(lhs.boolean_binary_op(&rhs, boolean_op, context)?))
-            }
+// Calls a function from the host as a future, in a coroutine of its own so that async host functions can suspend it.
+// Errors get a traceback like the ones of `call_from_host`.
+pub fn call_async_from_host(state: &Rc<GlobalState>, function: &FunctionRef, args: Vec<DataKind>) -> Result<ThreadFuture, RuntimeFailure> {
+    let function = DataKind::Function(function.clone());
+    let thread = ThreadRef::with_body(&Context::new(state.clone(), None), move |context, args| {
+        let handler = DataKind::Function(FunctionRef::internal("traceback", uncaught_error_handler));
+        context.protected_call(&function, args, Some(handler))
+    })?;
+    Ok(ThreadFuture::new(state.clone(), thread, args))
+}
+
+// Converts a Rust value with serde, the tables it makes are accounted for like the ones of Lua code
+pub fn to_value_from_host<T: ?Sized + serde::Serialize>(
+    state: &Rc<GlobalState>,
+    value: &T,
+    options: serialize::SerializeOptions,
+) -> Result<DataKind, serialize::SerdeError> {
+    serialize::to_value(&Context::new(state.clone(), None), value, options)
+}
+
+// Runs the finalizers which are left once the host is done with the state, then frees every object. Hooks and the
+// instruction budget no longer apply, finalizers always run to completion.
+pub fn close_state(state: &Rc<GlobalState>) {
+    state.hooks.set_hook(None);
+    state.hooks.set_budget(None);
+    state.on_host_stack(|| gc::close(&mut Context::new(state.clone(), None)));
+    state.heap.release_all();
+}
+
+// The message handler of the main chunk, it adds a traceback to errors that are never caught
+fn uncaught_error_handler(context: &mut Context, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let value = first_value(args);
+    let message = match &value {
+        DataKind::String(_) | DataKind::Number(_) => value.to_string(),
+        // Error objects with a __tostring metamethod are reported with it
+        _ if !operations::get_metamethod(context, &value, "__tostring").is_nil() => {
+            operations::to_string(context, &value)?.to_string()
         }
-    }
+        _ => format!("(error object is a {} value)", value.type_name()),
+    };
+    Ok(vec![DataKind::string(&format!("{}\n{}", message, context.traceback(1)))])
 }
 
-fn resolve_expr(context: &mut Context, expr: Expr) -> Result<DataKind, RuntimeFailure> {
-    match expr {
-        Expr::Nil => Ok(DataKind::Null),
-        Expr::Boolean(bool) => Ok(DataKind::Bool(bool)),
-        Expr::Numerical(number_kind) => Ok(resolve_number_kind(number_kind)),
-        Expr::LiteralString(literal_string) => Ok(DataKind::String(literal_string)),
-        Expr::Expansion(expansion) => todo!("expr expansion"),
-        Expr::FunctionDef(function_body) => todo!("expr anonymous function def"),
-        Expr::Prefix(prefix) => resolve_prefix(context, prefix),
-        Expr::Unary(unary_op, inner_expr) => todo!("expr unary"),
+// The value Lua code sees when it catches a failure, internal failures become a message with their position
+pub fn error_value(context: &Context, failure: &RuntimeFailure) -> DataKind {
+    match failure {
+        RuntimeFailure::ErrorObject(value) => value.clone(),
+        failure => {
+            let prefix = failure.line().map(|line| line_prefix(context.current_source(), line)).unwrap_or_default();
+            DataKind::String(LuaString::from(prefix + &failure.message()))
+        }
     }
 }
 
-fn resolve_prefix(context: &mut Context, prefix: Box<PrefixExpression>) -> Result<DataKind, RuntimeFailure> {
-    match *prefix {
-        PrefixExpression::Var(var) => resolve_var(context, var),
-        PrefixExpression::FunctionCall(fc) => function_call(context, fc),
-        PrefixExpression::Expression(expr) => resolve_expression(context, expr)
+fn line_prefix(source: &str, line: i32) -> String {
+    match line {
+        0 => String::new(),
+        line => format!("{}:{}: ", source, line),
     }
 }
 
-fn resolve_var(context: &Context, var: Var) -> Result<DataKind, RuntimeFailure> {
-    match var {
-        Var::VarName(var_name) => {
-            let binding = context.variable_map.get(context, var_name.as_str())?.borrow(context)?;
-            Ok(binding.clone())
-        },
-        Var::NestedAccess(nested_accessors) => {
-            todo!("Nested var resolution")
+pub fn first_value(values: Vec<DataKind>) -> DataKind {
+    values.into_iter().next().unwrap_or(DataKind::Null)
+}
+
+pub fn call_function(context: &mut Context, function: &DataKind, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    match function {
+        DataKind::Function(function_ref) => match function_ref.kind() {
+            FunctionKind::External(_) => vm::call_closure(context, function_ref.clone(), args),
+            FunctionKind::Internal(native) => call_native(context, function_ref, native, args),
         },
-        Var::TableAccess(table_name, expression) => {
-            todo!("Table access")
+        _ => {
+            let handler = operations::get_metamethod(context, function, "__call");
+            if handler.is_nil() {
+                return Err(RuntimeFailure::WrongType(
+                    format!("attempt to call a {} value", function.type_name()),
+                    Operand::First,
+                    context.current_line,
+                ));
+            }
+            let mut handler_args = Vec::with_capacity(args.len() + 1);
+            handler_args.push(function.clone());
+            handler_args.extend(args);
+            call_function(context, &handler, handler_args)
         }
     }
 }
 
-fn resolve_number_kind(number_kind: NumberKind) -> DataKind {
-    match number_kind {
-        NumberKind::Int(int) => DataKind::Number(data::NumberKind::Integer(int)),
-        NumberKind::Float(float) => DataKind::Number(data::NumberKind::Float(float)),
+fn call_native(
+    context: &mut Context,
+    function: &FunctionRef,
+    native: &NativeFunction,
+    args: Vec<DataKind>,
+) -> Result<Vec<DataKind>, RuntimeFailure> {
+    context.enter_native_call()?;
+    let called_by_native = context.call_stack.last().is_some_and(|caller| caller.is_native());
+    context.call_stack.push(CallInfo::native(function.clone(), context.current_line));
+    // Errors raised by a native function are reported at the line of its caller, which a native caller lacks
+    if called_by_native {
+        context.current_line = 0;
     }
+    let result = hook::on_call(context, hook::HookEvent::Call).and_then(|_| native.call(context, args));
+    let result = result.and_then(|results| hook::on_return(context).map(|_| results));
+    // The strings a native function made are accounted for once it returns
+    let result = result.and_then(|results| {
+        let bytes = results.iter().map(|value| if let DataKind::String(string) = value { string.len() } else { 0 }).sum();
+        gc::allocate(context, bytes).map(|_| results)
+    });
+    let result = result.map_err(|failure| match failure {
+        // A wrong type that was not described where it happened must not be blamed on the called function
+        RuntimeFailure::WrongType(msg, _, line) => RuntimeFailure::BadOperation(msg, line),
+        failure => failure,
+    });
+    // The message handler runs before the call is popped so that it can see where the error happened
+    let result = result.map_err(|failure| context.handle_error(failure));
+    let call_info = context.call_stack.pop().expect("The call was pushed above");
+    context.current_line = call_info.call_line;
+    context.leave_native_call();
+    result
 }
@@ -0,0 +1,315 @@
+use crate::ast::lua_program::{BitwiseOperator, MathOperator};
+use crate::err_handle::{Operand, RuntimeFailure};
+use crate::frontend::{call_function, first_value, gc, Context};
+use crate::frontend::data::{DataKind, LuaString};
+use crate::frontend::table::TableRef;
+
+// Limits how many tables an __index or __newindex chain may go through, a loop of metatables would never end otherwise
+const MAX_META_CHAIN: usize = 2000;
+
+pub fn get_metatable(context: &Context, value: &DataKind) -> Option<TableRef> {
+    match value {
+        DataKind::Table(table) => table.metatable(),
+        DataKind::String(_) => context.state.string_metatable.borrow().clone(),
+        DataKind::UserData(userdata) => userdata.metatable(),
+        _ => None,
+    }
+}
+
+pub fn get_metamethod(context: &Context, value: &DataKind, event: &str) -> DataKind {
+    match get_metatable(context, value) {
+        Some(metatable) => metatable.get_str(event),
+        None => DataKind::Null,
+    }
+}
+
+// object[key], following __index
+pub fn index_value(context: &mut Context, object: &DataKind, key: &DataKind) -> Result<DataKind, RuntimeFailure> {
+    let mut object = object.clone();
+    for _ in 0..MAX_META_CHAIN {
+        let handler = match &object {
+            DataKind::Table(table) => {
+                let value = table.get(key);
+                if !value.is_nil() {
+                    return Ok(value);
+                }
+                match table.metatable() {
+                    Some(metatable) => metatable.get_str("__index"),
+                    None => return Ok(DataKind::Null),
+                }
+            }
+            _ => {
+                let handler = get_metamethod(context, &object, "__index");
+                if handler.is_nil() {
+                    return Err(RuntimeFailure::WrongType(
+                        format!("attempt to index a {} value", object.type_name()),
+                        Operand::First,
+                        context.current_line,
+                    ));
+                }
+                handler
+            }
+        };
+        match handler {
+            DataKind::Null => return Ok(DataKind::Null),
+            DataKind::Function(_) => {
+                return Ok(first_value(call_function(context, &handler, vec![object, key.clone()])?));
+            }
+            handler => object = handler,
+        }
+    }
+    Err(RuntimeFailure::BadOperation(
+        "'__index' chain too long; possible loop".to_owned(),
+        context.current_line,
+    ))
+}
+
+// object[key] = value, following __newindex
+pub fn set_index(context: &mut Context, object: &DataKind, key: DataKind, value: DataKind) -> Result<(), RuntimeFailure> {
+    let mut object = object.clone();
+    for _ in 0..MAX_META_CHAIN {
+        let handler = match &object {
+            DataKind::Table(table) => {
+                let handler = match table.metatable() {
+                    Some(metatable) => metatable.get_str("__newindex"),
+                    None => DataKind::Null,
+                };
+                // __newindex is only used for keys that are not present in the table
+                if handler.is_nil() || !table.get(&key).is_nil() {
+                    return table.set(context, key, value);
+                }
+                handler
+            }
+            _ => {
+                let handler = get_metamethod(context, &object, "__newindex");
+                if handler.is_nil() {
+                    return Err(RuntimeFailure::WrongType(
+                        format!("attempt to index a {} value", object.type_name()),
+                        Operand::First,
+                        context.current_line,
+                    ));
+                }
+                handler
+            }
+        };
+        match handler {
+            DataKind::Function(_) => {
+                call_function(context, &handler, vec![object, key, value])?;
+                return Ok(());
+            }
+            handler => object = handler,
+        }
+    }
+    Err(RuntimeFailure::BadOperation(
+        "'__newindex' chain too long; possible loop".to_owned(),
+        context.current_line,
+    ))
+}
+
+// Calls the metamethod for `event` of the first operand that has one, None if neither does
+fn call_binary_metamethod(
+    context: &mut Context,
+    event: &str,
+    lhs: &DataKind,
+    rhs: &DataKind,
+) -> Result<Option<DataKind>, RuntimeFailure> {
+    let mut handler = get_metamethod(context, lhs, event);
+    if handler.is_nil() {
+        handler = get_metamethod(context, rhs, event);
+    }
+    if handler.is_nil() {
+        return Ok(None);
+    }
+    Ok(Some(first_value(call_function(context, &handler, vec![lhs.clone(), rhs.clone()])?)))
+}
+
+pub fn arithmetic(context: &mut Context, op: MathOperator, lhs: DataKind, rhs: DataKind) -> Result<DataKind, RuntimeFailure> {
+    if lhs.coerce_to_number().is_some() && rhs.coerce_to_number().is_some() {
+        return Ok(DataKind::Number(lhs.math_binary_op(&rhs, op, context)?));
+    }
+    let event = match op {
+        MathOperator::Plus => "__add",
+        MathOperator::Minus => "__sub",
+        MathOperator::Multiply => "__mul",
+        MathOperator::FloatDivision => "__div",
+        MathOperator::FloorDivision => "__idiv",
+        MathOperator::Exponent => "__pow",
+        MathOperator::Mod => "__mod",
+    };
+    match call_binary_metamethod(context, event, &lhs, &rhs)? {
+        Some(result) => Ok(result),
+        // Reports the operand which is not a number
+        None => Err(lhs.math_binary_op(&rhs, op, context).expect_err("An operand is not a number")),
+    }
+}
+
+pub fn bitwise(context: &mut Context, op: BitwiseOperator, lhs: DataKind, rhs: DataKind) -> Result<DataKind, RuntimeFailure> {
+    if let (Some(_), Some(_)) = (lhs.coerce_to_number(), rhs.coerce_to_number()) {
+        return Ok(DataKind::integer(lhs.bitwise_binary_op(&rhs, op, context)?));
+    }
+    let event = match op {
+        BitwiseOperator::And => "__band",
+        BitwiseOperator::Or => "__bor",
+        BitwiseOperator::ExclusiveOr => "__bxor",
+        BitwiseOperator::LeftShift => "__shl",
+        BitwiseOperator::RightShift => "__shr",
+    };
+    match call_binary_metamethod(context, event, &lhs, &rhs)? {
+        Some(result) => Ok(result),
+        None => {
+            let (culprit, operand) = match lhs.coerce_to_number() {
+                None => (&lhs, Operand::First),
+                Some(_) => (&rhs, Operand::Second),
+            };
+            Err(RuntimeFailure::WrongType(
+                format!("attempt to perform bitwise operation on a {} value", culprit.type_name()),
+                operand,
+                context.current_line,
+            ))
+        }
+    }
+}
+
+pub fn unary_minus(context: &mut Context, value: DataKind) -> Result<DataKind, RuntimeFailure> {
+    if let Some(number) = value.coerce_to_number() {
+        return Ok(DataKind::Number(number.negate()));
+    }
+    match call_binary_metamethod(context, "__unm", &value, &value)? {
+        Some(result) => Ok(result),
+        None => Err(value.arithmetic_error(Operand::First, context)),
+    }
+}
+
+pub fn bitwise_not(context: &mut Context, value: DataKind) -> Result<DataKind, RuntimeFailure> {
+    if let Some(number) = value.coerce_to_number() {
+        return match number.as_integer() {
+            Some(int) => Ok(DataKind::integer(!int)),
+            None => Err(RuntimeFailure::NoIntegerRepresentation(context.current_line)),
+        };
+    }
+    match call_binary_metamethod(context, "__bnot", &value, &value)? {
+        Some(result) => Ok(result),
+        None => Err(RuntimeFailure::WrongType(
+            format!("attempt to perform bitwise operation on a {} value", value.type_name()),
+            Operand::First,
+            context.current_line,
+        )),
+    }
+}
+
+pub fn length(context: &mut Context, value: DataKind) -> Result<DataKind, RuntimeFailure> {
+    if let DataKind::String(string) = &value {
+        return Ok(DataKind::integer(string.len() as i64));
+    }
+    let handler = get_metamethod(context, &value, "__len");
+    if !handler.is_nil() {
+        return Ok(first_value(call_function(context, &handler, vec![value.clone(), value])?));
+    }
+    match &value {
+        DataKind::Table(table) => Ok(DataKind::integer(table.length())),
+        _ => Err(RuntimeFailure::WrongType(
+            format!("attempt to get length of a {} value", value.type_name()),
+            Operand::First,
+            context.current_line,
+        )),
+    }
+}
+
+pub fn concat(context: &mut Context, lhs: DataKind, rhs: DataKind) -> Result<DataKind, RuntimeFailure> {
+    if let (Some(lhs_bytes), Some(rhs_bytes)) = (concat_operand(&lhs), concat_operand(&rhs)) {
+        gc::allocate(context, lhs_bytes.len() + rhs_bytes.len())?;
+        let mut bytes = lhs_bytes;
+        bytes.extend_from_slice(&rhs_bytes);
+        return Ok(DataKind::String(LuaString::from(bytes)));
+    }
+    match call_binary_metamethod(context, "__concat", &lhs, &rhs)? {
+        Some(result) => Ok(result),
+        None => {
+            let (culprit, operand) = match concat_operand(&lhs) {
+                None => (&lhs, Operand::First),
+                Some(_) => (&rhs, Operand::Second),
+            };
+            Err(RuntimeFailure::WrongType(
+                format!("attempt to concatenate a {} value", culprit.type_name()),
+                operand,
+                context.current_line,
+            ))
+        }
+    }
+}
+
+fn concat_operand(value: &DataKind) -> Option<Vec<u8>> {
+    match value {
+        DataKind::String(string) => Some(string.as_bytes().to_vec()),
+        DataKind::Number(number) => Some(number.to_string().into_bytes()),
+        _ => None,
+    }
+}
+
+pub fn equals(context: &mut Context, lhs: &DataKind, rhs: &DataKind) -> Result<bool, RuntimeFailure> {
+    if lhs == rhs {
+        return Ok(true);
+    }
+    // __eq is only tried for two tables or two full userdata that are not raw equal
+    if let (DataKind::Table(_), DataKind::Table(_)) | (DataKind::UserData(_), DataKind::UserData(_)) = (lhs, rhs) {
+        if let Some(result) = call_binary_metamethod(context, "__eq", lhs, rhs)? {
+            return Ok(result.is_true());
+        }
+    }
+    Ok(false)
+}
+
+pub fn less_than(context: &mut Context, lhs: &DataKind, rhs: &DataKind) -> Result<bool, RuntimeFailure> {
+    match (lhs, rhs) {
+        (DataKind::Number(lhs_num), DataKind::Number(rhs_num)) => Ok(lhs_num < rhs_num),
+        (DataKind::String(lhs_str), DataKind::String(rhs_str)) => Ok(lhs_str < rhs_str),
+        _ => match call_binary_metamethod(context, "__lt", lhs, rhs)? {
+            Some(result) => Ok(result.is_true()),
+            None => Err(compare_error(context, lhs, rhs)),
+        },
+    }
+}
+
+pub fn less_equal(context: &mut Context, lhs: &DataKind, rhs: &DataKind) -> Result<bool, RuntimeFailure> {
+    match (lhs, rhs) {
+        (DataKind::Number(lhs_num), DataKind::Number(rhs_num)) => Ok(lhs_num <= rhs_num),
+        (DataKind::String(lhs_str), DataKind::String(rhs_str)) => Ok(lhs_str <= rhs_str),
+        _ => match call_binary_metamethod(context, "__le", lhs, rhs)? {
+            Some(result) => Ok(result.is_true()),
+            None => Err(compare_error(context, lhs, rhs)),
+        },
+    }
+}
+
+fn compare_error(context: &Context, lhs: &DataKind, rhs: &DataKind) -> RuntimeFailure {
+    let (lhs_type, rhs_type) = (lhs.type_name(), rhs.type_name());
+    let msg = if lhs_type == rhs_type {
+        format!("attempt to compare two {} values", lhs_type)
+    } else {
+        format!("attempt to compare {} with {}", lhs_type, rhs_type)
+    };
+    RuntimeFailure::BadOperation(msg, context.current_line)
+}
+
+// Converts any value to a string the way tostring does, using __tostring and __name
+pub fn to_string(context: &mut Context, value: &DataKind) -> Result<LuaString, RuntimeFailure> {
+    let handler = get_metamethod(context, value, "__tostring");
+    if !handler.is_nil() {
+        return match first_value(call_function(context, &handler, vec![value.clone()])?) {
+            DataKind::String(string) => Ok(string),
+            _ => Err(RuntimeFailure::BadOperation(
+                "'__tostring' must return a string".to_owned(),
+                context.current_line,
+            )),
+        };
+    }
+    let pointer = match value {
+        DataKind::Table(table) => Some(table.as_ptr()),
+        DataKind::UserData(userdata) => Some(userdata.as_ptr()),
+        _ => None,
+    };
+    if let (Some(pointer), DataKind::String(name)) = (pointer, get_metamethod(context, value, "__name")) {
+        return Ok(LuaString::from(format!("{}: {:p}", name, pointer)));
+    }
+    Ok(LuaString::from(value.to_string()))
+}
@@ -0,0 +1,360 @@
+// Lua values to Rust values, the reverse of the serializer. Numbers and strings convert into each other like they do
+// for typed host functions, other values must have the type asked for. Errors carry the path of the field which
+// could not be read.
+
+use std::cell::RefCell;
+
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, Visitor};
+
+use crate::frontend::convert::ConversionError;
+use crate::frontend::data::{DataKind, LuaString, NumberKind};
+use crate::frontend::serialize::{array_metatable, is_null, DeserializeOptions, SerdeError};
+use crate::frontend::state::GlobalState;
+use crate::frontend::table::TableRef;
+
+pub struct Deserializer<'a> {
+    value: DataKind,
+    state: &'a GlobalState,
+    options: DeserializeOptions,
+    // The tables being read, a table which contains itself cannot be converted
+    visiting: &'a RefCell<Vec<TableRef>>,
+}
+
+pub fn from_value<T: DeserializeOwned>(value: DataKind, state: &GlobalState, options: DeserializeOptions) -> Result<T, SerdeError> {
+    let visiting = RefCell::new(Vec::new());
+    T::deserialize(Deserializer { value, state, options, visiting: &visiting })
+}
+
+fn wrong_type(expected: &'static str, value: &DataKind) -> SerdeError {
+    SerdeError::new(ConversionError::wrong_type(expected, value))
+}
+
+// The entries of a table, in traversal order
+fn entries(table: &TableRef) -> Vec<(DataKind, DataKind)> {
+    let table = table.borrow();
+    let array = table.array_values().iter().enumerate().map(|(index, value)| (DataKind::integer(index as i64 + 1), value.clone()));
+    let hash = table.hash_entries().map(|(key, value)| (key.clone(), value.clone()));
+    array.chain(hash).filter(|(_, value)| !value.is_nil()).collect()
+}
+
+// The positive integer key of a sequence entry
+fn sequence_index(key: &DataKind) -> Option<i64> {
+    match key {
+        DataKind::Number(number) => number.as_integer().filter(|index| *index >= 1),
+        _ => None,
+    }
+}
+
+impl<'a> Deserializer<'a> {
+    fn with(&self, value: DataKind) -> Self {
+        Self { value, state: self.state, options: self.options, visiting: self.visiting }
+    }
+    fn table(&self, expected: &'static str) -> Result<TableRef, SerdeError> {
+        match &self.value {
+            DataKind::Table(table) => Ok(table.clone()),
+            value => Err(wrong_type(expected, value)),
+        }
+    }
+    // Runs `visit` while the table is being read
+    fn visit_table<T>(&self, table: &TableRef, visit: impl FnOnce() -> Result<T, SerdeError>) -> Result<T, SerdeError> {
+        if self.visiting.borrow().contains(table) {
+            return Err(SerdeError::new("recursive table"));
+        }
+        self.visiting.borrow_mut().push(table.clone());
+        let result = visit();
+        self.visiting.borrow_mut().pop();
+        result
+    }
+    // Whether a table which could be either is a sequence: it has the array metatable or only keys 1 to n
+    fn is_sequence(&self, table: &TableRef, entries: &[(DataKind, DataKind)]) -> bool {
+        if table.metatable().is_some_and(|metatable| metatable == array_metatable(self.state)) {
+            return true;
+        }
+        match entries.is_empty() {
+            true => self.options.empty_tables_as_arrays,
+            false => entries.iter().all(|(key, _)| sequence_index(key).is_some_and(|index| index as usize <= entries.len())),
+        }
+    }
+    fn visit_seq<'de, V: Visitor<'de>>(&self, entries: Vec<(DataKind, DataKind)>, visitor: V) -> Result<V::Value, SerdeError> {
+        // The elements go up to the highest index, the missing ones are nil
+        let mut length = 0;
+        for (key, _) in &entries {
+            match sequence_index(key) {
+                Some(index) => length = length.max(index),
+                None => return Err(SerdeError::new(format!("sequence expected, got a table with key {}", key))),
+            }
+        }
+        let mut values = vec![DataKind::Null; length as usize];
+        for (key, value) in entries {
+            let index = sequence_index(&key).unwrap_or_default();
+            values[index as usize - 1] = value;
+        }
+        let mut access = SeqAccess { deserializer: self, values: values.into_iter(), index: 0 };
+        let value = visitor.visit_seq(&mut access)?;
+        match access.values.len() {
+            0 => Ok(value),
+            _ => Err(SerdeError::new(format!("sequence of {} elements is too long", length))),
+        }
+    }
+    fn visit_map<'de, V: Visitor<'de>>(&self, entries: Vec<(DataKind, DataKind)>, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_map(MapAccess { deserializer: self, entries: entries.into_iter(), key: None })
+    }
+    fn integer<T: TryFrom<i64>>(&self) -> Result<T, SerdeError> {
+        let number = self.value.coerce_to_number().ok_or_else(|| wrong_type("integer", &self.value))?;
+        let integer = number.as_integer().ok_or_else(|| SerdeError::new("number has no integer representation"))?;
+        T::try_from(integer).map_err(|_| SerdeError::new("value out of range"))
+    }
+    fn float(&self) -> Result<f64, SerdeError> {
+        match self.value.coerce_to_number() {
+            Some(number) => Ok(number.as_float()),
+            None => Err(wrong_type("number", &self.value)),
+        }
+    }
+    fn string(&self) -> Result<LuaString, SerdeError> {
+        match &self.value {
+            DataKind::String(string) => Ok(string.clone()),
+            DataKind::Number(number) => Ok(LuaString::from(number.to_string())),
+            value => Err(wrong_type("string", value)),
+        }
+    }
+}
+
+macro_rules! deserialize_integer {
+    ($($method:ident => $visit:ident),+) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+                visitor.$visit(self.integer()?)
+            }
+        )+
+    };
+}
+
+impl<'de> de::Deserializer<'de> for Deserializer<'_> {
+    type Error = SerdeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match &self.value {
+            value if is_null(value) => visitor.visit_unit(),
+            DataKind::Bool(value) => visitor.visit_bool(*value),
+            DataKind::Number(NumberKind::Integer(integer)) => visitor.visit_i64(*integer),
+            DataKind::Number(NumberKind::Float(float)) => visitor.visit_f64(*float),
+            DataKind::String(string) => match std::str::from_utf8(string.as_bytes()) {
+                Ok(string) => visitor.visit_str(string),
+                Err(_) => visitor.visit_bytes(string.as_bytes()),
+            },
+            DataKind::Table(table) => self.visit_table(table, || {
+                let entries = entries(table);
+                match self.is_sequence(table, &entries) {
+                    true => self.visit_seq(entries, visitor),
+                    false => self.visit_map(entries, visitor),
+                }
+            }),
+            value => Err(SerdeError::new(format!("cannot convert a {} value", value.type_name()))),
+        }
+    }
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.value {
+            DataKind::Bool(value) => visitor.visit_bool(value),
+            value => Err(wrong_type("boolean", &value)),
+        }
+    }
+    deserialize_integer!(
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64
+    );
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_f32(self.float()? as f32)
+    }
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_f64(self.float()?)
+    }
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        let string = self.string()?;
+        let mut chars = string.to_str_lossy().chars().collect::<Vec<_>>();
+        match chars.len() {
+            1 => visitor.visit_char(chars.remove(0)),
+            _ => Err(SerdeError::new("string of one character expected")),
+        }
+    }
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        let string = self.string()?;
+        match std::str::from_utf8(string.as_bytes()) {
+            Ok(string) => visitor.visit_str(string),
+            Err(_) => Err(SerdeError::new("string is not valid UTF-8")),
+        }
+    }
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.deserialize_str(visitor)
+    }
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_bytes(self.string()?.as_bytes())
+    }
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.deserialize_bytes(visitor)
+    }
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match is_null(&self.value) {
+            true => visitor.visit_none(),
+            false => visitor.visit_some(self),
+        }
+    }
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match is_null(&self.value) {
+            true => visitor.visit_unit(),
+            false => Err(wrong_type("nil", &self.value)),
+        }
+    }
+    fn deserialize_unit_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, SerdeError> {
+        self.deserialize_unit(visitor)
+    }
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_newtype_struct(self)
+    }
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        let table = self.table("table")?;
+        self.visit_table(&table, || self.visit_seq(entries(&table), visitor))
+    }
+    fn deserialize_tuple<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, SerdeError> {
+        self.deserialize_seq(visitor)
+    }
+    fn deserialize_tuple_struct<V: Visitor<'de>>(self, _name: &'static str, _len: usize, visitor: V) -> Result<V::Value, SerdeError> {
+        self.deserialize_seq(visitor)
+    }
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        let table = self.table("table")?;
+        self.visit_table(&table, || self.visit_map(entries(&table), visitor))
+    }
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        self.deserialize_map(visitor)
+    }
+    // A unit variant is its name, the other ones are a table with the name as the only key
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        match &self.value {
+            DataKind::String(_) => {
+                let name: String = self.string()?.to_str_lossy().into_owned();
+                visitor.visit_enum(IntoDeserializer::<SerdeError>::into_deserializer(name))
+            }
+            DataKind::Table(table) => {
+                let mut entries = entries(table);
+                match entries.len() {
+                    1 => {
+                        let (name, value) = entries.remove(0);
+                        self.visit_table(table, || visitor.visit_enum(EnumAccess { deserializer: &self, name, value }))
+                    }
+                    _ => Err(SerdeError::new("table with a single key expected for an enum")),
+                }
+            }
+            value => Err(wrong_type("string or table", value)),
+        }
+    }
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.deserialize_str(visitor)
+    }
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_unit()
+    }
+}
+
+struct SeqAccess<'a, 'b> {
+    deserializer: &'b Deserializer<'a>,
+    values: std::vec::IntoIter<DataKind>,
+    index: i64,
+}
+
+impl<'de> de::SeqAccess<'de> for SeqAccess<'_, '_> {
+    type Error = SerdeError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, SerdeError> {
+        let Some(value) = self.values.next() else {
+            return Ok(None);
+        };
+        self.index += 1;
+        let index = DataKind::integer(self.index);
+        seed.deserialize(self.deserializer.with(value)).map(Some).map_err(|error| error.at(&index))
+    }
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.values.len())
+    }
+}
+
+struct MapAccess<'a, 'b> {
+    deserializer: &'b Deserializer<'a>,
+    entries: std::vec::IntoIter<(DataKind, DataKind)>,
+    // The key and value of the entry whose key was read last
+    key: Option<(DataKind, DataKind)>,
+}
+
+impl<'de> de::MapAccess<'de> for MapAccess<'_, '_> {
+    type Error = SerdeError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, SerdeError> {
+        let Some((key, value)) = self.entries.next() else {
+            return Ok(None);
+        };
+        let result = seed.deserialize(self.deserializer.with(key.clone())).map_err(|error| error.at(&key));
+        self.key = Some((key, value));
+        result.map(Some)
+    }
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, SerdeError> {
+        let (key, value) = self.key.take().ok_or_else(|| SerdeError::new("map value read before its key"))?;
+        seed.deserialize(self.deserializer.with(value)).map_err(|error| error.at(&key))
+    }
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.entries.len())
+    }
+}
+
+struct EnumAccess<'a, 'b> {
+    deserializer: &'b Deserializer<'a>,
+    name: DataKind,
+    value: DataKind,
+}
+
+impl<'a, 'b, 'de> de::EnumAccess<'de> for EnumAccess<'a, 'b> {
+    type Error = SerdeError;
+    type Variant = VariantAccess<'a, 'b>;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, VariantAccess<'a, 'b>), SerdeError> {
+        let variant = seed.deserialize(self.deserializer.with(self.name.clone()))?;
+        Ok((variant, VariantAccess { deserializer: self.deserializer, name: self.name, value: self.value }))
+    }
+}
+
+struct VariantAccess<'a, 'b> {
+    deserializer: &'b Deserializer<'a>,
+    name: DataKind,
+    value: DataKind,
+}
+
+impl<'de> de::VariantAccess<'de> for VariantAccess<'_, '_> {
+    type Error = SerdeError;
+
+    fn unit_variant(self) -> Result<(), SerdeError> {
+        de::Deserialize::deserialize(self.deserializer.with(self.value)).map_err(|error: SerdeError| error.at(&self.name))
+    }
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, SerdeError> {
+        seed.deserialize(self.deserializer.with(self.value)).map_err(|error| error.at(&self.name))
+    }
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, SerdeError> {
+        de::Deserializer::deserialize_seq(self.deserializer.with(self.value), visitor).map_err(|error| error.at(&self.name))
+    }
+    fn struct_variant<V: Visitor<'de>>(self, _fields: &'static [&'static str], visitor: V) -> Result<V::Value, SerdeError> {
+        de::Deserializer::deserialize_map(self.deserializer.with(self.value), visitor).map_err(|error| error.at(&self.name))
+    }
+}
@@ -0,0 +1,151 @@
+// Conversions between Lua values and any Rust type implementing serde's Serialize or Deserialize, like configuration
+// structs read from the tables a script returns. Tables are read without their metamethods.
+
+mod de;
+mod ser;
+
+use std::fmt::{Display, Formatter};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::frontend::convert::{ConversionError, FromLua};
+use crate::frontend::data::DataKind;
+use crate::frontend::state::GlobalState;
+use crate::frontend::table::TableRef;
+use crate::frontend::userdata::LightUserData;
+use crate::frontend::Context;
+
+// The name the array metatable is kept under in the registry
+const ARRAY_METATABLE: &str = "serde.array";
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SerializeOptions {
+    // Converts None and () to `null()` instead of nil, so that they keep their place in sequences and tables
+    pub none_as_null: bool,
+    // Gives the tables made from sequences the array metatable, so that an empty one is still read back as a sequence
+    pub mark_arrays: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DeserializeOptions {
+    // Reads an empty table as a sequence rather than a map where the type accepts either, like serde_json's Value. A
+    // table with the array metatable is always a sequence.
+    pub empty_tables_as_arrays: bool,
+}
+
+// A value which converts with serde where a typed host function takes an argument or a table field is read
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Serde<T>(pub T);
+
+impl<T: DeserializeOwned> FromLua for Serde<T> {
+    fn from_lua(value: DataKind, state: &GlobalState) -> Result<Self, ConversionError> {
+        from_value(value, state, DeserializeOptions::default())
+            .map(Serde)
+            .map_err(|error| ConversionError::Invalid(error.to_string()))
+    }
+}
+
+pub fn to_value<T: ?Sized + Serialize>(context: &Context, value: &T, options: SerializeOptions) -> Result<DataKind, SerdeError> {
+    ser::to_value(context, value, options)
+}
+
+pub fn from_value<T: DeserializeOwned>(value: DataKind, state: &GlobalState, options: DeserializeOptions) -> Result<T, SerdeError> {
+    de::from_value(value, state, options)
+}
+
+// The value Rust's None and () become with `SerializeOptions::none_as_null`, a null light userdata. Reading it back
+// gives None.
+pub fn null() -> DataKind {
+    DataKind::LightUserData(LightUserData(std::ptr::null_mut()))
+}
+
+fn is_null(value: &DataKind) -> bool {
+    matches!(value, DataKind::Null) || *value == null()
+}
+
+// The metatable of the tables made from sequences, shared by every one of them. It has no fields, scripts can give it
+// to their own tables so that they convert as sequences even when they are empty.
+pub fn array_metatable(state: &GlobalState) -> TableRef {
+    match state.registry.get_str(ARRAY_METATABLE) {
+        DataKind::Table(metatable) => metatable,
+        _ => {
+            let metatable = TableRef::new(&state.heap);
+            state.registry.set_str(ARRAY_METATABLE, DataKind::Table(metatable.clone()));
+            metatable
+        }
+    }
+}
+
+// Why a value could not be converted, with the path of the field it happened at
+#[derive(Debug, Clone)]
+pub struct SerdeError {
+    // The outermost field first
+    path: Vec<PathSegment>,
+    message: String,
+}
+
+#[derive(Debug, Clone)]
+enum PathSegment {
+    Field(String),
+    Index(i64),
+    // Any other key, already rendered like `["two words"]` renders it
+    Key(String),
+}
+
+impl SerdeError {
+    pub fn new(message: impl Display) -> Self {
+        Self { path: Vec::new(), message: message.to_string() }
+    }
+    // Adds the key of the table the error happened in, on the way out of it
+    fn at(mut self, key: &DataKind) -> Self {
+        let segment = match key {
+            DataKind::Number(number) if number.as_integer().is_some() => PathSegment::Index(number.as_integer().unwrap_or_default()),
+            DataKind::String(name) => match std::str::from_utf8(name.as_bytes()) {
+                Ok(name) if is_identifier(name) => PathSegment::Field(name.to_owned()),
+                _ => PathSegment::Key(format!("{:?}", name)),
+            },
+            key => PathSegment::Key(key.to_string()),
+        };
+        self.path.insert(0, segment);
+        self
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|char| char.is_ascii_alphanumeric() || char == '_')
+}
+
+// Rendered like Lua code would index the field, like "servers[2].port: integer expected, got string"
+impl Display for SerdeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (index, segment) in self.path.iter().enumerate() {
+            match segment {
+                PathSegment::Field(name) if index == 0 => write!(f, "{}", name)?,
+                PathSegment::Field(name) => write!(f, ".{}", name)?,
+                PathSegment::Index(index) => write!(f, "[{}]", index)?,
+                PathSegment::Key(key) => write!(f, "[{}]", key)?,
+            }
+        }
+        match self.path.is_empty() {
+            true => write!(f, "{}", self.message),
+            false => write!(f, ": {}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for SerdeError {}
+
+impl serde::ser::Error for SerdeError {
+    fn custom<T: Display>(msg: T) -> Self {
+        Self::new(msg)
+    }
+}
+
+impl serde::de::Error for SerdeError {
+    fn custom<T: Display>(msg: T) -> Self {
+        Self::new(msg)
+    }
+}
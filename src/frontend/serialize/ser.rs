@@ -0,0 +1,307 @@
+// Rust values to Lua values. Structs and maps become tables, sequences and tuples become tables numbered from 1 and
+// enums are represented like serde_json represents them: a unit variant is its name and the other ones are a table with
+// the name as the only key.
+
+use serde::ser::{self, Serialize};
+
+use crate::frontend::data::{DataKind, LuaString, NumberKind};
+use crate::frontend::gc;
+use crate::frontend::serialize::{array_metatable, null, SerdeError, SerializeOptions};
+use crate::frontend::table::TableRef;
+use crate::frontend::Context;
+
+// New tables and strings are accounted for like the ones Lua code makes, they fail past the memory limit
+#[derive(Clone, Copy)]
+pub struct Serializer<'a, 'c> {
+    context: &'a Context<'c>,
+    options: SerializeOptions,
+}
+
+impl<'a, 'c> Serializer<'a, 'c> {
+    pub fn new(context: &'a Context<'c>, options: SerializeOptions) -> Self {
+        Self { context, options }
+    }
+    fn string(&self, string: LuaString) -> Result<DataKind, SerdeError> {
+        gc::allocate(self.context, string.len()).map_err(|failure| SerdeError::new(failure.message()))?;
+        Ok(DataKind::String(string))
+    }
+    fn none(&self) -> DataKind {
+        match self.options.none_as_null {
+            true => null(),
+            false => DataKind::Null,
+        }
+    }
+    fn table(self, is_array: bool) -> SerializeTable<'a, 'c> {
+        let table = TableRef::new(&self.context.state.heap);
+        if is_array && self.options.mark_arrays {
+            table.set_metatable(Some(array_metatable(&self.context.state)));
+        }
+        SerializeTable { serializer: self, table, next_index: 1, key: None }
+    }
+    // The table of a variant which is not a unit, its value is stored under its name
+    fn variant(self, name: &'static str, value: DataKind) -> Result<DataKind, SerdeError> {
+        let table = TableRef::new(&self.context.state.heap);
+        set(self.context, &table, DataKind::string(name), value)?;
+        Ok(DataKind::Table(table))
+    }
+}
+
+fn set(context: &Context, table: &TableRef, key: DataKind, value: DataKind) -> Result<(), SerdeError> {
+    table.set(context, key, value).map_err(|failure| SerdeError::new(failure.message()))
+}
+
+impl<'a, 'c> ser::Serializer for Serializer<'a, 'c> {
+    type Ok = DataKind;
+    type Error = SerdeError;
+    type SerializeSeq = SerializeTable<'a, 'c>;
+    type SerializeTuple = SerializeTable<'a, 'c>;
+    type SerializeTupleStruct = SerializeTable<'a, 'c>;
+    type SerializeTupleVariant = SerializeVariant<'a, 'c>;
+    type SerializeMap = SerializeTable<'a, 'c>;
+    type SerializeStruct = SerializeTable<'a, 'c>;
+    type SerializeStructVariant = SerializeVariant<'a, 'c>;
+
+    fn serialize_bool(self, value: bool) -> Result<DataKind, SerdeError> {
+        Ok(DataKind::Bool(value))
+    }
+    fn serialize_i8(self, value: i8) -> Result<DataKind, SerdeError> {
+        self.serialize_i64(value as i64)
+    }
+    fn serialize_i16(self, value: i16) -> Result<DataKind, SerdeError> {
+        self.serialize_i64(value as i64)
+    }
+    fn serialize_i32(self, value: i32) -> Result<DataKind, SerdeError> {
+        self.serialize_i64(value as i64)
+    }
+    fn serialize_i64(self, value: i64) -> Result<DataKind, SerdeError> {
+        Ok(DataKind::integer(value))
+    }
+    fn serialize_u8(self, value: u8) -> Result<DataKind, SerdeError> {
+        self.serialize_i64(value as i64)
+    }
+    fn serialize_u16(self, value: u16) -> Result<DataKind, SerdeError> {
+        self.serialize_i64(value as i64)
+    }
+    fn serialize_u32(self, value: u32) -> Result<DataKind, SerdeError> {
+        self.serialize_i64(value as i64)
+    }
+    // Integers which do not fit in a Lua integer become floats, like `IntoLua` converts them
+    fn serialize_u64(self, value: u64) -> Result<DataKind, SerdeError> {
+        match i64::try_from(value) {
+            Ok(int) => self.serialize_i64(int),
+            Err(_) => self.serialize_f64(value as f64),
+        }
+    }
+    fn serialize_f32(self, value: f32) -> Result<DataKind, SerdeError> {
+        self.serialize_f64(value as f64)
+    }
+    fn serialize_f64(self, value: f64) -> Result<DataKind, SerdeError> {
+        Ok(DataKind::Number(NumberKind::Float(value)))
+    }
+    fn serialize_char(self, value: char) -> Result<DataKind, SerdeError> {
+        self.string(LuaString::from(value.to_string()))
+    }
+    fn serialize_str(self, value: &str) -> Result<DataKind, SerdeError> {
+        self.string(LuaString::from(value))
+    }
+    fn serialize_bytes(self, value: &[u8]) -> Result<DataKind, SerdeError> {
+        self.string(LuaString::from(value))
+    }
+    fn serialize_none(self) -> Result<DataKind, SerdeError> {
+        Ok(self.none())
+    }
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<DataKind, SerdeError> {
+        value.serialize(self)
+    }
+    fn serialize_unit(self) -> Result<DataKind, SerdeError> {
+        Ok(self.none())
+    }
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<DataKind, SerdeError> {
+        Ok(self.none())
+    }
+    fn serialize_unit_variant(self, _name: &'static str, _index: u32, variant: &'static str) -> Result<DataKind, SerdeError> {
+        self.serialize_str(variant)
+    }
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(self, _name: &'static str, value: &T) -> Result<DataKind, SerdeError> {
+        value.serialize(self)
+    }
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<DataKind, SerdeError> {
+        let value = value.serialize(self).map_err(|error| error.at(&DataKind::string(variant)))?;
+        self.variant(variant, value)
+    }
+    fn serialize_seq(self, _len: Option<usize>) -> Result<SerializeTable<'a, 'c>, SerdeError> {
+        Ok(self.table(true))
+    }
+    fn serialize_tuple(self, _len: usize) -> Result<SerializeTable<'a, 'c>, SerdeError> {
+        Ok(self.table(true))
+    }
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<SerializeTable<'a, 'c>, SerdeError> {
+        Ok(self.table(true))
+    }
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<SerializeVariant<'a, 'c>, SerdeError> {
+        Ok(SerializeVariant { name: variant, table: self.table(true) })
+    }
+    fn serialize_map(self, _len: Option<usize>) -> Result<SerializeTable<'a, 'c>, SerdeError> {
+        Ok(self.table(false))
+    }
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<SerializeTable<'a, 'c>, SerdeError> {
+        Ok(self.table(false))
+    }
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<SerializeVariant<'a, 'c>, SerdeError> {
+        Ok(SerializeVariant { name: variant, table: self.table(false) })
+    }
+}
+
+// A table being filled with the elements of a sequence or the fields of a struct or map
+pub struct SerializeTable<'a, 'c> {
+    serializer: Serializer<'a, 'c>,
+    table: TableRef,
+    // Elements which are nil still take their index
+    next_index: i64,
+    // The key of a map entry whose value comes next
+    key: Option<DataKind>,
+}
+
+impl SerializeTable<'_, '_> {
+    fn set(&self, key: DataKind, value: DataKind) -> Result<(), SerdeError> {
+        set(self.serializer.context, &self.table, key.clone(), value).map_err(|error| error.at(&key))
+    }
+    fn element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), SerdeError> {
+        let key = DataKind::integer(self.next_index);
+        self.next_index += 1;
+        let value = value.serialize(self.serializer).map_err(|error| error.at(&key))?;
+        self.set(key, value)
+    }
+    fn field<T: ?Sized + Serialize>(&mut self, name: &'static str, value: &T) -> Result<(), SerdeError> {
+        let key = DataKind::string(name);
+        let value = value.serialize(self.serializer).map_err(|error| error.at(&key))?;
+        self.set(key, value)
+    }
+}
+
+impl ser::SerializeSeq for SerializeTable<'_, '_> {
+    type Ok = DataKind;
+    type Error = SerdeError;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.element(value)
+    }
+    fn end(self) -> Result<DataKind, SerdeError> {
+        Ok(DataKind::Table(self.table))
+    }
+}
+
+impl ser::SerializeTuple for SerializeTable<'_, '_> {
+    type Ok = DataKind;
+    type Error = SerdeError;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.element(value)
+    }
+    fn end(self) -> Result<DataKind, SerdeError> {
+        Ok(DataKind::Table(self.table))
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeTable<'_, '_> {
+    type Ok = DataKind;
+    type Error = SerdeError;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.element(value)
+    }
+    fn end(self) -> Result<DataKind, SerdeError> {
+        Ok(DataKind::Table(self.table))
+    }
+}
+
+impl ser::SerializeMap for SerializeTable<'_, '_> {
+    type Ok = DataKind;
+    type Error = SerdeError;
+
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<(), SerdeError> {
+        self.key = Some(key.serialize(self.serializer)?);
+        Ok(())
+    }
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), SerdeError> {
+        let key = self.key.take().ok_or_else(|| SerdeError::new("map value serialized before its key"))?;
+        let value = value.serialize(self.serializer).map_err(|error| error.at(&key))?;
+        self.set(key, value)
+    }
+    fn end(self) -> Result<DataKind, SerdeError> {
+        Ok(DataKind::Table(self.table))
+    }
+}
+
+impl ser::SerializeStruct for SerializeTable<'_, '_> {
+    type Ok = DataKind;
+    type Error = SerdeError;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, name: &'static str, value: &T) -> Result<(), SerdeError> {
+        self.field(name, value)
+    }
+    fn end(self) -> Result<DataKind, SerdeError> {
+        Ok(DataKind::Table(self.table))
+    }
+}
+
+// The table of a tuple or struct variant, wrapped in the table naming the variant once it is complete
+pub struct SerializeVariant<'a, 'c> {
+    name: &'static str,
+    table: SerializeTable<'a, 'c>,
+}
+
+impl SerializeVariant<'_, '_> {
+    fn end(self) -> Result<DataKind, SerdeError> {
+        let serializer = self.table.serializer;
+        serializer.variant(self.name, DataKind::Table(self.table.table))
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeVariant<'_, '_> {
+    type Ok = DataKind;
+    type Error = SerdeError;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), SerdeError> {
+        let name = DataKind::string(self.name);
+        self.table.element(value).map_err(|error| error.at(&name))
+    }
+    fn end(self) -> Result<DataKind, SerdeError> {
+        SerializeVariant::end(self)
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeVariant<'_, '_> {
+    type Ok = DataKind;
+    type Error = SerdeError;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, name: &'static str, value: &T) -> Result<(), SerdeError> {
+        let variant = DataKind::string(self.name);
+        self.table.field(name, value).map_err(|error| error.at(&variant))
+    }
+    fn end(self) -> Result<DataKind, SerdeError> {
+        SerializeVariant::end(self)
+    }
+}
+
+pub fn to_value<T: ?Sized + Serialize>(context: &Context, value: &T, options: SerializeOptions) -> Result<DataKind, SerdeError> {
+    value.serialize(Serializer::new(context, options))
+}
@@ -0,0 +1,117 @@
+use std::any::TypeId;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::frontend::coroutine::{HostStack, ThreadRef};
+use crate::frontend::future::AsyncState;
+use crate::frontend::gc::Heap;
+use crate::frontend::hook::Hooks;
+use crate::frontend::table::TableRef;
+
+// How deep calls may nest before they fail with a "stack overflow" error, and how much memory scripts may use
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    // Lua functions running in one thread
+    pub call_depth: usize,
+    // Calls which recurse on the native stack, like metamethods, library functions calling back into Lua and the
+    // functions they call. Lua code runs on native stacks of 16 MiB, a coroutine's or the one the state keeps for the
+    // calls of the host, this has to fit in them.
+    pub native_depth: usize,
+    // Bytes the objects, strings and coroutine stacks of the state may take before allocations fail with "not enough
+    // memory", None for no limit. This is the estimate collectgarbage("count") reports.
+    pub memory: Option<usize>,
+}
+
+impl Default for Limits {
+    // The native limit is the one of the reference implementation, LUAI_MAXCCALLS
+    fn default() -> Self {
+        Self { call_depth: 200_000, native_depth: 200, memory: None }
+    }
+}
+
+// State shared by every coroutine of a program
+pub struct GlobalState {
+    // Tracks the objects of the program to collect the cycles among them
+    pub heap: Heap,
+    pub globals: TableRef,
+    // Values the host keeps alive without putting them in globals, Lua code reaches it with debug.getregistry
+    pub registry: TableRef,
+    pub string_metatable: RefCell<Option<TableRef>>,
+    // The metatable shared by the userdata of each Rust type, built the first time a value of the type is wrapped
+    pub userdata_metatables: RefCell<HashMap<TypeId, TableRef>>,
+    pub limits: Cell<Limits>,
+    pub hooks: Hooks,
+    pub async_state: AsyncState,
+    // The main thread is at the bottom, the currently running coroutine is at the top
+    threads: RefCell<Vec<ThreadRef>>,
+    host_stack: HostStack,
+    // The integer keys of the registry given to registry keys, a key holds its slot even while its value is nil
+    registry_slots: RefCell<RegistrySlots>,
+}
+
+#[derive(Default)]
+struct RegistrySlots {
+    last: i64,
+    // Slots of dropped keys, reused before new ones
+    free: Vec<i64>,
+}
+
+impl GlobalState {
+    pub fn new(limits: Limits) -> Rc<Self> {
+        let heap = Heap::new();
+        let globals = TableRef::new(&heap);
+        let registry = TableRef::new(&heap);
+        Rc::new(Self {
+            heap,
+            globals,
+            registry,
+            string_metatable: RefCell::new(None),
+            userdata_metatables: RefCell::new(HashMap::new()),
+            limits: Cell::new(limits),
+            hooks: Hooks::default(),
+            async_state: AsyncState::default(),
+            threads: RefCell::new(vec![ThreadRef::new_main()]),
+            host_stack: HostStack::default(),
+            registry_slots: RefCell::new(RegistrySlots::default()),
+        })
+    }
+    pub fn current_thread(&self) -> ThreadRef {
+        self.threads
+            .borrow()
+            .last()
+            .expect("The main thread is never popped")
+            .clone()
+    }
+    pub fn is_main_thread(&self, thread: &ThreadRef) -> bool {
+        self.threads.borrow()[0] == *thread
+    }
+    pub fn push_thread(&self, thread: ThreadRef) {
+        self.threads.borrow_mut().push(thread);
+    }
+    pub fn pop_thread(&self) {
+        let mut threads = self.threads.borrow_mut();
+        if threads.len() > 1 {
+            threads.pop();
+        }
+    }
+    // Runs a call from the host on a native stack as large as the one of a coroutine. Inside of a coroutine it already
+    // is on one.
+    pub fn on_host_stack<R>(&self, call: impl FnOnce() -> R) -> R {
+        let in_coroutine = self.threads.borrow().len() > 1;
+        match in_coroutine {
+            true => call(),
+            false => self.host_stack.run(call),
+        }
+    }
+    pub fn reserve_registry_slot(&self) -> i64 {
+        let mut slots = self.registry_slots.borrow_mut();
+        slots.free.pop().unwrap_or_else(|| {
+            slots.last += 1;
+            slots.last
+        })
+    }
+    pub fn release_registry_slot(&self, slot: i64) {
+        self.registry_slots.borrow_mut().free.push(slot);
+    }
+}
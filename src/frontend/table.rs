@@ -0,0 +1,295 @@
+use std::cell::{Ref, RefCell, RefMut};
+use std::fmt::{Debug, Formatter};
+use std::hash::{Hash, Hasher};
+use std::rc::{Rc, Weak};
+
+use indexmap::IndexMap;
+
+use crate::err_handle::RuntimeFailure;
+use crate::frontend::Context;
+use crate::frontend::data::{DataKind, NumberKind, float_to_integer};
+use crate::frontend::gc::{self, Heap, WeakObject};
+
+pub struct Table {
+    // Keys 1..=array.len() live in the array part, the last element of the array is never nil
+    array: Vec<DataKind>,
+    // Removed keys are kept as nil tombstones so that `next` can keep traversing while fields are cleared
+    hash: IndexMap<TableKey, DataKind>,
+    tombstones: usize,
+    pub metatable: Option<TableRef>,
+}
+
+impl Table {
+    pub fn new() -> Self {
+        Self { array: Vec::new(), hash: IndexMap::new(), tombstones: 0, metatable: None }
+    }
+    pub fn get(&self, key: &DataKind) -> DataKind {
+        match TableKey::new(key.clone()) {
+            Some(TableKey(DataKind::Number(NumberKind::Integer(int)))) if int >= 1 && (int as u64) <= self.array.len() as u64 => {
+                self.array[int as usize - 1].clone()
+            }
+            Some(table_key) => self.hash.get(&table_key).cloned().unwrap_or(DataKind::Null),
+            None => DataKind::Null,
+        }
+    }
+    pub fn get_str(&self, key: &str) -> DataKind {
+        self.get(&DataKind::string(key))
+    }
+    pub fn set(&mut self, key: DataKind, value: DataKind) -> Result<(), &'static str> {
+        let table_key = match key {
+            DataKind::Null => return Err("index is nil"),
+            DataKind::Number(NumberKind::Float(float)) if float.is_nan() => return Err("index is NaN"),
+            key => TableKey::new(key).expect("Key was checked for nil and NaN"),
+        };
+        if let DataKind::Number(NumberKind::Integer(int)) = table_key.0 {
+            let len = self.array.len() as i64;
+            if int >= 1 && int <= len {
+                self.array[int as usize - 1] = value;
+                while matches!(self.array.last(), Some(DataKind::Null)) {
+                    self.array.pop();
+                }
+                return Ok(());
+            }
+            if int == len + 1 && !value.is_nil() {
+                self.array.push(value);
+                self.migrate_to_array();
+                return Ok(());
+            }
+        }
+        self.set_hash(table_key, value);
+        Ok(())
+    }
+    fn set_hash(&mut self, table_key: TableKey, value: DataKind) {
+        match self.hash.get_mut(&table_key) {
+            Some(existing) => {
+                match (existing.is_nil(), value.is_nil()) {
+                    (false, true) => self.tombstones += 1,
+                    (true, false) => self.tombstones -= 1,
+                    _ => (),
+                }
+                *existing = value;
+            }
+            None => {
+                if value.is_nil() {
+                    return;
+                }
+                if self.tombstones > 0 && self.tombstones * 2 > self.hash.len() {
+                    self.hash.retain(|_, value| !value.is_nil());
+                    self.tombstones = 0;
+                }
+                self.hash.insert(table_key, value);
+            }
+        }
+    }
+    // Moves keys that directly follow the array part from the hash part into the array part
+    fn migrate_to_array(&mut self) {
+        loop {
+            let next_key = TableKey(DataKind::integer(self.array.len() as i64 + 1));
+            match self.hash.get(&next_key) {
+                Some(value) if !value.is_nil() => {
+                    let value = self.hash.swap_remove(&next_key).expect("Key was just found");
+                    self.array.push(value);
+                }
+                _ => return,
+            }
+        }
+    }
+    // The border of the table, the array part always ends at a border
+    pub fn length(&self) -> i64 {
+        self.array.len() as i64
+    }
+    // Returns the key-value pair following `key` in traversal order, Err if `key` is not in the table
+    pub fn next(&self, key: &DataKind) -> Result<Option<(DataKind, DataKind)>, ()> {
+        let array_start = match key {
+            DataKind::Null => 0,
+            _ => match TableKey::new(key.clone()) {
+                Some(TableKey(DataKind::Number(NumberKind::Integer(int)))) if int >= 1 && (int as u64) <= self.array.len() as u64 => {
+                    int as usize
+                }
+                Some(table_key) => match self.hash.get_index_of(&table_key) {
+                    Some(index) => return Ok(self.next_in_hash(index + 1)),
+                    // The key was in the array part, which shrunk after it was set to nil during traversal
+                    None if matches!(table_key.0, DataKind::Number(NumberKind::Integer(int)) if int >= 1) => self.array.len(),
+                    None => return Err(()),
+                },
+                None => return Err(()),
+            },
+        };
+        for (index, value) in self.array.iter().enumerate().skip(array_start) {
+            if !value.is_nil() {
+                return Ok(Some((DataKind::integer(index as i64 + 1), value.clone())));
+            }
+        }
+        Ok(self.next_in_hash(0))
+    }
+    // The values of the array part, the collector traverses these and the entries of the hash part
+    pub fn array_values(&self) -> &[DataKind] {
+        &self.array
+    }
+    pub fn hash_entries(&self) -> impl Iterator<Item = (&DataKind, &DataKind)> {
+        self.hash.iter().map(|(key, value)| (&key.0, value))
+    }
+    // Removes the entries a collection found dead from a weak table, nil values left behind by removed keys included
+    pub fn retain_entries(&mut self, mut keep: impl FnMut(&DataKind, &DataKind) -> bool) {
+        for (index, value) in self.array.iter_mut().enumerate() {
+            if !value.is_nil() && !keep(&DataKind::integer(index as i64 + 1), value) {
+                *value = DataKind::Null;
+            }
+        }
+        while matches!(self.array.last(), Some(DataKind::Null)) {
+            self.array.pop();
+        }
+        self.hash.retain(|key, value| keep(&key.0, value));
+        self.tombstones = self.hash.values().filter(|value| value.is_nil()).count();
+    }
+    // An estimate of the memory the table uses, without the strings it holds
+    pub fn allocated_size(&self) -> usize {
+        let entry = std::mem::size_of::<(TableKey, DataKind)>() + std::mem::size_of::<usize>();
+        std::mem::size_of::<Table>()
+            + self.array.capacity() * std::mem::size_of::<DataKind>()
+            + self.hash.capacity() * entry
+    }
+    fn next_in_hash(&self, start: usize) -> Option<(DataKind, DataKind)> {
+        self.hash
+            .iter()
+            .skip(start)
+            .find(|(_, value)| !value.is_nil())
+            .map(|(key, value)| (key.0.clone(), value.clone()))
+    }
+}
+
+impl Default for Table {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone)]
+pub struct TableRef(Rc<RefCell<Table>>);
+
+impl TableRef {
+    pub fn new(heap: &Heap) -> Self {
+        let table = Self(Rc::new(RefCell::new(Table::new())));
+        heap.track(WeakObject::Table(table.downgrade()));
+        table
+    }
+    pub(crate) fn from_weak(weak: &Weak<RefCell<Table>>) -> Option<Self> {
+        weak.upgrade().map(Self)
+    }
+    pub(crate) fn downgrade(&self) -> Weak<RefCell<Table>> {
+        Rc::downgrade(&self.0)
+    }
+    pub(crate) fn borrow(&self) -> Ref<'_, Table> {
+        self.0.borrow()
+    }
+    pub(crate) fn borrow_mut(&self) -> RefMut<'_, Table> {
+        self.0.borrow_mut()
+    }
+    // The collector skips tables which are being modified while it runs
+    pub(crate) fn try_borrow(&self) -> Option<Ref<'_, Table>> {
+        self.0.try_borrow().ok()
+    }
+    pub(crate) fn try_borrow_mut(&self) -> Option<RefMut<'_, Table>> {
+        self.0.try_borrow_mut().ok()
+    }
+    pub fn strong_count(&self) -> usize {
+        Rc::strong_count(&self.0)
+    }
+    pub fn get(&self, key: &DataKind) -> DataKind {
+        self.0.borrow().get(key)
+    }
+    pub fn get_str(&self, key: &str) -> DataKind {
+        self.0.borrow().get_str(key)
+    }
+    // Sets a field from Lua code, the memory the table grows by counts against the memory limit
+    pub fn set(&self, context: &Context, key: DataKind, value: DataKind) -> Result<(), RuntimeFailure> {
+        let grown = {
+            let mut table = self.0.borrow_mut();
+            let size = table.allocated_size();
+            table.set(key, value).map_err(|msg| RuntimeFailure::BadOperation(msg.to_owned(), context.current_line))?;
+            table.allocated_size().saturating_sub(size)
+        };
+        match grown {
+            0 => Ok(()),
+            grown => gc::allocate(context, grown),
+        }
+    }
+    // Used when building tables from Rust where the key is known to be valid
+    pub fn set_str(&self, key: &str, value: DataKind) {
+        let _ = self.0.borrow_mut().set(DataKind::string(key), value);
+    }
+    pub fn length(&self) -> i64 {
+        self.0.borrow().length()
+    }
+    pub fn metatable(&self) -> Option<TableRef> {
+        self.0.borrow().metatable.clone()
+    }
+    pub fn set_metatable(&self, metatable: Option<TableRef>) {
+        self.0.borrow_mut().metatable = metatable;
+    }
+    pub fn as_ptr(&self) -> *const () {
+        Rc::as_ptr(&self.0) as *const ()
+    }
+}
+
+impl PartialEq for TableRef {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Debug for TableRef {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "table: {:p}", self.as_ptr())
+    }
+}
+
+// A table key is never nil or NaN, and floats with an integer value are stored as integers so that t[1] and t[1.0]
+// refer to the same field
+#[derive(Clone)]
+struct TableKey(DataKind);
+
+impl TableKey {
+    fn new(key: DataKind) -> Option<Self> {
+        match key {
+            DataKind::Null => None,
+            DataKind::Number(NumberKind::Float(float)) => match float_to_integer(float) {
+                Some(int) => Some(Self(DataKind::integer(int))),
+                None if float.is_nan() => None,
+                None => Some(Self(DataKind::Number(NumberKind::Float(float)))),
+            },
+            key => Some(Self(key)),
+        }
+    }
+}
+
+impl PartialEq for TableKey {
+    fn eq(&self, other: &Self) -> bool {
+        match (&self.0, &other.0) {
+            // Keys are normalized, so an integer key never equals a float key
+            (DataKind::Number(NumberKind::Integer(lhs)), DataKind::Number(NumberKind::Integer(rhs))) => lhs == rhs,
+            (DataKind::Number(NumberKind::Float(lhs)), DataKind::Number(NumberKind::Float(rhs))) => lhs.to_bits() == rhs.to_bits(),
+            (DataKind::Number(_), DataKind::Number(_)) => false,
+            (lhs, rhs) => lhs == rhs,
+        }
+    }
+}
+
+impl Eq for TableKey {}
+
+impl Hash for TableKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match &self.0 {
+            DataKind::String(string) => string.hash(state),
+            DataKind::Number(NumberKind::Integer(int)) => int.hash(state),
+            DataKind::Number(NumberKind::Float(float)) => float.to_bits().hash(state),
+            DataKind::Bool(bool) => bool.hash(state),
+            DataKind::Null => (),
+            DataKind::Table(table) => table.as_ptr().hash(state),
+            DataKind::Function(function) => function.as_ptr().hash(state),
+            DataKind::Thread(thread) => thread.as_ptr().hash(state),
+            DataKind::UserData(userdata) => userdata.as_ptr().hash(state),
+            DataKind::LightUserData(pointer) => pointer.hash(state),
+        }
+    }
+}
@@ -0,0 +1,451 @@
+// Rust values exposed to Lua. A type implementing `UserData` declares the fields and methods scripts can use, they
+// are gathered once per type into a metatable which every userdata of the type shares.
+
+use std::any::{Any, TypeId};
+use std::cell::{Ref, RefCell, RefMut};
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::fmt::{Debug, Formatter};
+use std::marker::PhantomData;
+use std::rc::{Rc, Weak};
+
+use crate::err_handle::RuntimeFailure;
+use crate::frontend::{call_function, first_value, operations, Context};
+use crate::frontend::convert::{host_error, Arguments, BadArgument, ConversionError, FromLua, FromLuaMulti, IntoLua, IntoLuaMulti};
+use crate::frontend::data::DataKind;
+use crate::frontend::function::FunctionRef;
+use crate::frontend::gc::{Heap, WeakObject};
+use crate::frontend::state::GlobalState;
+use crate::frontend::table::TableRef;
+
+pub trait UserData: Sized + 'static {
+    // The name of the type in error messages and in the default tostring, like "Point: 0x55d0c8f0"
+    fn type_name() -> &'static str {
+        let name = std::any::type_name::<Self>();
+        let name = name.split('<').next().unwrap_or(name);
+        name.rsplit("::").next().unwrap_or(name)
+    }
+    fn add_fields(_fields: &mut UserDataFields<Self>) {}
+    fn add_methods(_methods: &mut UserDataMethods<Self>) {}
+}
+
+pub struct UserDataObject {
+    type_name: &'static str,
+    value: RefCell<Box<dyn Any>>,
+    metatable: RefCell<Option<TableRef>>,
+    // Lua values attached to the userdata, numbered from 1 like the user values of the reference implementation
+    user_values: RefCell<Vec<DataKind>>,
+}
+
+#[derive(Clone)]
+pub struct UserDataRef(Rc<UserDataObject>);
+
+impl UserDataRef {
+    // Wraps a value with the metatable of its type, which is built the first time a value of the type is wrapped
+    pub fn new<T: UserData>(state: &GlobalState, value: T) -> Self {
+        let userdata = Self::without_metatable(state, value);
+        userdata.set_metatable(Some(metatable_of::<T>(state)));
+        userdata
+    }
+    // Wraps a value with no metatable, for userdata whose metatable is set afterwards like the C API does
+    pub fn without_metatable<T: UserData>(state: &GlobalState, value: T) -> Self {
+        let userdata = Self(Rc::new(UserDataObject {
+            type_name: T::type_name(),
+            value: RefCell::new(Box::new(value)),
+            metatable: RefCell::new(None),
+            user_values: RefCell::new(Vec::new()),
+        }));
+        state.heap.track(WeakObject::UserData(userdata.downgrade()));
+        userdata
+    }
+    pub fn from_weak(weak: &Weak<UserDataObject>) -> Option<Self> {
+        weak.upgrade().map(Self)
+    }
+    pub fn downgrade(&self) -> Weak<UserDataObject> {
+        Rc::downgrade(&self.0)
+    }
+    pub fn type_name(&self) -> &'static str {
+        self.0.type_name
+    }
+    pub fn is<T: UserData>(&self) -> bool {
+        self.0.value.try_borrow().map_or_else(|_| false, |value| value.is::<T>())
+    }
+    // Borrows the Rust value, failing instead of panicking when it has another type or is mutably borrowed
+    pub fn borrow<T: UserData>(&self) -> Result<Ref<'_, T>, ConversionError> {
+        let value = self
+            .0
+            .value
+            .try_borrow()
+            .map_err(|_| ConversionError::Invalid("userdata already mutably borrowed".to_owned()))?;
+        Ref::filter_map(value, |value| value.downcast_ref::<T>())
+            .map_err(|_| ConversionError::WrongType { expected: T::type_name(), got: self.type_name() })
+    }
+    pub fn borrow_mut<T: UserData>(&self) -> Result<RefMut<'_, T>, ConversionError> {
+        let value = self
+            .0
+            .value
+            .try_borrow_mut()
+            .map_err(|_| ConversionError::Invalid("userdata already borrowed".to_owned()))?;
+        RefMut::filter_map(value, |value| value.downcast_mut::<T>())
+            .map_err(|_| ConversionError::WrongType { expected: T::type_name(), got: self.type_name() })
+    }
+    pub fn metatable(&self) -> Option<TableRef> {
+        self.0.metatable.borrow().clone()
+    }
+    pub fn set_metatable(&self, metatable: Option<TableRef>) {
+        *self.0.metatable.borrow_mut() = metatable;
+    }
+    // The n-th user value, nil when it was never set
+    pub fn user_value(&self, n: usize) -> DataKind {
+        n.checked_sub(1)
+            .and_then(|index| self.0.user_values.borrow().get(index).cloned())
+            .unwrap_or(DataKind::Null)
+    }
+    pub fn has_user_value(&self, n: usize) -> bool {
+        n >= 1 && n <= self.0.user_values.borrow().len()
+    }
+    // Sets the n-th user value, n starts at 1. The values before it are nil until they are set.
+    pub fn set_user_value(&self, n: usize, value: DataKind) {
+        let Some(index) = n.checked_sub(1) else {
+            return;
+        };
+        let mut user_values = self.0.user_values.borrow_mut();
+        if user_values.len() <= index {
+            user_values.resize(index + 1, DataKind::Null);
+        }
+        user_values[index] = value;
+    }
+    // The collector traverses the user values and the metatable, the Rust value is opaque to it
+    pub fn try_user_values(&self) -> Option<Ref<'_, Vec<DataKind>>> {
+        self.0.user_values.try_borrow().ok()
+    }
+    // The memory the Rust value takes, not counting what it owns on the heap
+    pub fn value_size(&self) -> usize {
+        self.0.value.try_borrow().map_or(0, |value| std::mem::size_of_val(&**value))
+    }
+    // Drops the Lua values the userdata holds, which breaks the cycles it is part of
+    pub fn clear_references(&self) -> (Vec<DataKind>, Option<TableRef>) {
+        let user_values = self.0.user_values.try_borrow_mut().map(|mut values| std::mem::take(&mut *values));
+        let metatable = self.0.metatable.try_borrow_mut().ok().and_then(|mut metatable| metatable.take());
+        (user_values.unwrap_or_default(), metatable)
+    }
+    pub fn strong_count(&self) -> usize {
+        Rc::strong_count(&self.0)
+    }
+    pub fn as_ptr(&self) -> *const () {
+        Rc::as_ptr(&self.0) as *const ()
+    }
+}
+
+impl PartialEq for UserDataRef {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Debug for UserDataRef {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {:p}", self.type_name(), self.as_ptr())
+    }
+}
+
+// An opaque pointer owned by the host, Lua can only pass it around and compare it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LightUserData(pub *mut c_void);
+
+type Getter = Box<dyn Fn(&mut Context, &UserDataRef) -> Result<DataKind, RuntimeFailure>>;
+type Setter = Box<dyn Fn(&mut Context, &UserDataRef, DataKind) -> Result<(), RuntimeFailure>>;
+
+// The fields of a userdata type, read and written through the methods of the Rust value
+pub struct UserDataFields<T> {
+    getters: HashMap<&'static str, Getter>,
+    setters: HashMap<&'static str, Setter>,
+    marker: PhantomData<T>,
+}
+
+impl<T: UserData> UserDataFields<T> {
+    pub fn add_field_method_get<R, F>(&mut self, name: &'static str, getter: F)
+    where
+        R: IntoLua,
+        F: Fn(&T) -> Result<R, RuntimeFailure> + 'static,
+    {
+        let getter: Getter = Box::new(move |context, userdata| {
+            let this = userdata.borrow::<T>().map_err(|error| BadArgument { position: 1, error }.into_failure(name, context))?;
+            let value = getter(&this).map_err(|failure| host_error(context, failure))?;
+            drop(this);
+            Ok(value.into_lua(&context.state))
+        });
+        self.getters.insert(name, getter);
+    }
+    pub fn add_field_method_set<V, F>(&mut self, name: &'static str, setter: F)
+    where
+        V: FromLua,
+        F: Fn(&mut T, V) -> Result<(), RuntimeFailure> + 'static,
+    {
+        let setter: Setter = Box::new(move |context, userdata, value| {
+            let value = V::from_lua(value, &context.state)
+                .map_err(|error| BadArgument { position: 3, error }.into_failure(name, context))?;
+            let mut this =
+                userdata.borrow_mut::<T>().map_err(|error| BadArgument { position: 1, error }.into_failure(name, context))?;
+            setter(&mut this, value).map_err(|failure| host_error(context, failure))
+        });
+        self.setters.insert(name, setter);
+    }
+}
+
+// The methods and metamethods of a userdata type. Methods get the Rust value as their first argument, the remaining
+// arguments convert to `A`, which is a tuple when there are several of them.
+pub struct UserDataMethods<T> {
+    methods: Vec<(&'static str, FunctionRef)>,
+    meta_methods: Vec<(&'static str, FunctionRef)>,
+    marker: PhantomData<T>,
+}
+
+impl<T: UserData> UserDataMethods<T> {
+    // Called as obj:name(...), the value is borrowed while the method runs
+    pub fn add_method<A, R, M>(&mut self, name: &'static str, method: M)
+    where
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+        M: Fn(&T, A) -> Result<R, RuntimeFailure> + 'static,
+    {
+        self.methods.push((name, method_function::<T, _, _, _>(name, move |this: &UserDataRef, args| Ok(method(&*this.borrow::<T>()?, args)?))));
+    }
+    // Like add_method, the value is borrowed mutably so calling another method on it from inside fails
+    pub fn add_method_mut<A, R, M>(&mut self, name: &'static str, method: M)
+    where
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+        M: Fn(&mut T, A) -> Result<R, RuntimeFailure> + 'static,
+    {
+        self.methods.push((name, method_function::<T, _, _, _>(name, move |this: &UserDataRef, args| Ok(method(&mut *this.borrow_mut::<T>()?, args)?))));
+    }
+    // A function in the method table which does not take the value, like a constructor
+    pub fn add_function<A, R, F>(&mut self, name: &'static str, function: F)
+    where
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+        F: Fn(A) -> Result<R, RuntimeFailure> + 'static,
+    {
+        self.methods.push((name, plain_function(name, function)));
+    }
+    // A metamethod like "__tostring" or "__close" whose first operand is the value. The operands of binary operators
+    // like "__add" come in the order they were written, which only puts the value first when it is on the left.
+    pub fn add_meta_method<A, R, M>(&mut self, name: &'static str, method: M)
+    where
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+        M: Fn(&T, A) -> Result<R, RuntimeFailure> + 'static,
+    {
+        self.meta_methods.push((name, method_function::<T, _, _, _>(name, move |this: &UserDataRef, args| Ok(method(&*this.borrow::<T>()?, args)?))));
+    }
+    pub fn add_meta_method_mut<A, R, M>(&mut self, name: &'static str, method: M)
+    where
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+        M: Fn(&mut T, A) -> Result<R, RuntimeFailure> + 'static,
+    {
+        self.meta_methods.push((name, method_function::<T, _, _, _>(name, move |this: &UserDataRef, args| Ok(method(&mut *this.borrow_mut::<T>()?, args)?))));
+    }
+    // A metamethod which gets all of its operands converted, for binary operators where the value can be on either side
+    pub fn add_meta_function<A, R, F>(&mut self, name: &'static str, function: F)
+    where
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+        F: Fn(A) -> Result<R, RuntimeFailure> + 'static,
+    {
+        self.meta_methods.push((name, plain_function(name, function)));
+    }
+}
+
+// A failure to borrow the value of a method is reported against its first argument
+enum MethodError {
+    Borrow(ConversionError),
+    Failure(RuntimeFailure),
+}
+
+impl From<ConversionError> for MethodError {
+    fn from(error: ConversionError) -> Self {
+        MethodError::Borrow(error)
+    }
+}
+
+impl From<RuntimeFailure> for MethodError {
+    fn from(failure: RuntimeFailure) -> Self {
+        MethodError::Failure(failure)
+    }
+}
+
+fn method_function<T, A, R, M>(name: &'static str, method: M) -> FunctionRef
+where
+    T: UserData,
+    A: FromLuaMulti,
+    R: IntoLuaMulti,
+    M: Fn(&UserDataRef, A) -> Result<R, MethodError> + 'static,
+{
+    FunctionRef::internal(name, move |context, args| {
+        let this = match args.first() {
+            Some(DataKind::UserData(userdata)) => userdata.clone(),
+            value => {
+                let got = value.map_or("no value", DataKind::type_name);
+                let error = ConversionError::WrongType { expected: T::type_name(), got };
+                return Err(BadArgument { position: 1, error }.into_failure(name, context));
+            }
+        };
+        let mut args = Arguments::starting_at(args.into_iter().skip(1).collect(), 2);
+        let args = A::from_lua_args(&mut args, &context.state).map_err(|bad| bad.into_failure(name, context))?;
+        match method(&this, args) {
+            Ok(results) => Ok(results.into_lua_multi(&context.state)),
+            Err(MethodError::Borrow(error)) => Err(BadArgument { position: 1, error }.into_failure(name, context)),
+            Err(MethodError::Failure(failure)) => Err(host_error(context, failure)),
+        }
+    })
+}
+
+fn plain_function<A, R, F>(name: &'static str, function: F) -> FunctionRef
+where
+    A: FromLuaMulti,
+    R: IntoLuaMulti,
+    F: Fn(A) -> Result<R, RuntimeFailure> + 'static,
+{
+    FunctionRef::internal(name, move |context, args| {
+        let args = A::from_lua_args(&mut Arguments::new(args), &context.state).map_err(|bad| bad.into_failure(name, context))?;
+        match function(args) {
+            Ok(results) => Ok(results.into_lua_multi(&context.state)),
+            Err(failure) => Err(host_error(context, failure)),
+        }
+    })
+}
+
+fn metatable_of<T: UserData>(state: &GlobalState) -> TableRef {
+    if let Some(metatable) = state.userdata_metatables.borrow().get(&TypeId::of::<T>()) {
+        return metatable.clone();
+    }
+    let metatable = build_metatable::<T>(&state.heap);
+    state.userdata_metatables.borrow_mut().insert(TypeId::of::<T>(), metatable.clone());
+    metatable
+}
+
+// Indexing a userdata looks for a field, then a method, then goes to the __index metamethod the type declared
+fn build_metatable<T: UserData>(heap: &Heap) -> TableRef {
+    let mut fields = UserDataFields::<T> { getters: HashMap::new(), setters: HashMap::new(), marker: PhantomData };
+    T::add_fields(&mut fields);
+    let mut methods = UserDataMethods::<T> { methods: Vec::new(), meta_methods: Vec::new(), marker: PhantomData };
+    T::add_methods(&mut methods);
+
+    let metatable = TableRef::new(heap);
+    let method_table = TableRef::new(heap);
+    for (name, function) in methods.methods {
+        method_table.set_str(name, DataKind::Function(function));
+    }
+    let mut index = DataKind::Null;
+    let mut new_index = DataKind::Null;
+    for (name, function) in methods.meta_methods {
+        match name {
+            "__index" => index = DataKind::Function(function),
+            "__newindex" => new_index = DataKind::Function(function),
+            name => metatable.set_str(name, DataKind::Function(function)),
+        }
+    }
+    metatable.set_str("__name", DataKind::string(T::type_name()));
+
+    let getters = fields.getters;
+    let index_handler = FunctionRef::internal("__index", move |context, args| {
+        let mut args = args.into_iter();
+        let (object, key) = (args.next().unwrap_or(DataKind::Null), args.next().unwrap_or(DataKind::Null));
+        if let (DataKind::UserData(userdata), DataKind::String(name)) = (&object, &key) {
+            if let Some(getter) = std::str::from_utf8(name.as_bytes()).ok().and_then(|name| getters.get(name)) {
+                return Ok(vec![getter(context, userdata)?]);
+            }
+        }
+        let method = method_table.get(&key);
+        if !method.is_nil() {
+            return Ok(vec![method]);
+        }
+        match &index {
+            DataKind::Null => Ok(vec![DataKind::Null]),
+            DataKind::Function(_) => Ok(vec![first_value(call_function(context, &index, vec![object, key])?)]),
+            index => Ok(vec![operations::index_value(context, index, &key)?]),
+        }
+    });
+    metatable.set_str("__index", DataKind::Function(index_handler));
+
+    let setters = fields.setters;
+    if !setters.is_empty() || !new_index.is_nil() {
+        let new_index_handler = FunctionRef::internal("__newindex", move |context, args| {
+            let mut args = args.into_iter();
+            let object = args.next().unwrap_or(DataKind::Null);
+            let key = args.next().unwrap_or(DataKind::Null);
+            let value = args.next().unwrap_or(DataKind::Null);
+            if let (DataKind::UserData(userdata), DataKind::String(name)) = (&object, &key) {
+                if let Some(setter) = std::str::from_utf8(name.as_bytes()).ok().and_then(|name| setters.get(name)) {
+                    setter(context, userdata, value)?;
+                    return Ok(Vec::new());
+                }
+            }
+            match &new_index {
+                DataKind::Null => Err(RuntimeFailure::BadOperation(
+                    format!("cannot set field '{}' of a {} value", key, object_type_name(&object)),
+                    context.current_line,
+                )),
+                DataKind::Function(_) => call_function(context, &new_index, vec![object, key, value]).map(|_| Vec::new()),
+                new_index => operations::set_index(context, new_index, key, value).map(|_| Vec::new()),
+            }
+        });
+        metatable.set_str("__newindex", DataKind::Function(new_index_handler));
+    }
+    metatable
+}
+
+fn object_type_name(value: &DataKind) -> &'static str {
+    match value {
+        DataKind::UserData(userdata) => userdata.type_name(),
+        value => value.type_name(),
+    }
+}
+
+impl FromLua for UserDataRef {
+    fn from_lua(value: DataKind, _state: &GlobalState) -> Result<Self, ConversionError> {
+        match value {
+            DataKind::UserData(userdata) => Ok(userdata),
+            value => Err(ConversionError::wrong_type("userdata", &value)),
+        }
+    }
+}
+
+impl IntoLua for UserDataRef {
+    fn into_lua(self, _state: &GlobalState) -> DataKind {
+        DataKind::UserData(self)
+    }
+}
+
+// Rust values become userdata, and are copied back out of userdata of their type
+impl<T: UserData> IntoLua for T {
+    fn into_lua(self, state: &GlobalState) -> DataKind {
+        DataKind::UserData(UserDataRef::new(state, self))
+    }
+}
+
+impl<T: UserData + Clone> FromLua for T {
+    fn from_lua(value: DataKind, _state: &GlobalState) -> Result<Self, ConversionError> {
+        match value {
+            DataKind::UserData(userdata) => Ok(userdata.borrow::<T>()?.clone()),
+            value => Err(ConversionError::WrongType { expected: T::type_name(), got: value.type_name() }),
+        }
+    }
+}
+
+impl FromLua for LightUserData {
+    fn from_lua(value: DataKind, _state: &GlobalState) -> Result<Self, ConversionError> {
+        match value {
+            DataKind::LightUserData(pointer) => Ok(pointer),
+            value => Err(ConversionError::wrong_type("light userdata", &value)),
+        }
+    }
+}
+
+impl IntoLua for LightUserData {
+    fn into_lua(self, _state: &GlobalState) -> DataKind {
+        DataKind::LightUserData(self)
+    }
+}
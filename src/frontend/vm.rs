@@ -0,0 +1,925 @@
+// The virtual machine which runs compiled prototypes. Calls between Lua functions push a frame onto the call stack
+// and continue in the same dispatch loop, so deep recursion in Lua does not recurse in Rust. Registers live in the
+// thread's value stack, a frame's registers start at its base.
+
+use std::rc::Rc;
+
+use crate::ast::lua_program::{BitwiseOperator, MathOperator};
+use crate::compiler::opcode::{OpCode, MAX_C};
+use crate::compiler::prototype::Prototype;
+use crate::err_handle::{Operand, RuntimeFailure};
+use crate::frontend::call_stack::CallInfo;
+use crate::frontend::data::{bitwise_integers, DataKind, NumberKind, TWO_POW_63};
+use crate::frontend::debug_info;
+use crate::frontend::function::{FunctionKind, FunctionRef, LuaClosure, UpvalueRef, ValueStack};
+use crate::frontend::gc::{self, Heap};
+use crate::frontend::hook::{self, HookEvent};
+use crate::frontend::table::TableRef;
+use crate::frontend::{call_function, call_native, error_value, operations, Context};
+
+// A thread may not use more stack slots than this, calls past it fail with "stack overflow"
+const MAX_STACK_SIZE: usize = 1_000_000;
+
+pub fn new_closure(heap: &Heap, prototype: Rc<Prototype>, upvalues: Vec<UpvalueRef>) -> DataKind {
+    DataKind::Function(FunctionRef::closure(heap, LuaClosure { prototype, upvalues }))
+}
+
+// Calls a Lua function from Rust and runs it until it returns
+pub fn call_closure(context: &mut Context, function: FunctionRef, args: Vec<DataKind>) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let FunctionKind::External(closure) = function.kind() else {
+        unreachable!("Only Lua functions are run by the virtual machine")
+    };
+    let prototype = closure.prototype.clone();
+    context.enter_native_call()?;
+    let base = context.stack.borrow().len();
+    let arg_count = args.len();
+    context.stack.borrow_mut().extend(args);
+    if let Err(failure) = enter_frame(context, function, &prototype, base, arg_count, -1) {
+        context.stack.borrow_mut().truncate(base);
+        context.leave_native_call();
+        return Err(failure);
+    }
+    let result = hook::on_call(context, HookEvent::Call).and_then(|_| execute(context));
+    let call_info = context.call_stack.pop().expect("The frame was pushed above");
+    context.stack.borrow_mut().truncate(base);
+    context.current_line = call_info.call_line;
+    context.leave_native_call();
+    result
+}
+
+// The first register past the ones the innermost Lua function uses while it waits for a native function it called
+pub fn call_top(context: &Context) -> Option<usize> {
+    let frame = context.call_stack.iter().rev().find(|frame| !frame.is_native())?;
+    let instruction = frame.prototype()?.code.get(frame.pc)?;
+    match instruction.opcode() {
+        OpCode::Call => Some(frame.base + instruction.a() as usize),
+        _ => None,
+    }
+}
+
+// Pushes the frame of a Lua function whose `arg_count` arguments are in the stack from `base` on
+fn enter_frame(
+    context: &mut Context,
+    function: FunctionRef,
+    prototype: &Prototype,
+    base: usize,
+    arg_count: usize,
+    wanted_results: i32,
+) -> Result<(), RuntimeFailure> {
+    let frame_top = base + prototype.max_stack_size as usize;
+    if frame_top > MAX_STACK_SIZE || context.call_stack.len() >= context.state.limits.get().call_depth {
+        return Err(RuntimeFailure::BadOperation("stack overflow".to_owned(), context.current_line));
+    }
+    let mut stack = context.stack.borrow_mut();
+    let params = prototype.num_params as usize;
+    // Missing arguments are nil, extra arguments are dropped unless the function takes `...`
+    let varargs = match prototype.is_vararg && arg_count > params {
+        true => stack[base + params..base + arg_count].to_vec(),
+        false => Vec::new(),
+    };
+    stack.truncate(base + arg_count.min(params));
+    stack.resize(frame_top, DataKind::Null);
+    drop(stack);
+    context.call_stack.push(CallInfo {
+        function,
+        call_line: context.current_line,
+        base,
+        pc: 0,
+        varargs,
+        wanted_results,
+        tail_call: false,
+    });
+    Ok(())
+}
+
+// Runs the innermost frame until it returns. An error closes the upvalues and to-be-closed variables of every frame
+// it unwinds, down to and including this one.
+fn execute(context: &mut Context) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let entry = context.call_stack.len() - 1;
+    let failure = match run(context, entry) {
+        Ok(values) => return Ok(values),
+        Err(failure) => failure,
+    };
+    // The message handler sees the error before any frame is left
+    let failure = match context.handle_error(failure) {
+        failure @ (RuntimeFailure::ErrorObject(_) | RuntimeFailure::OutOfMemory) => failure,
+        failure if !failure.is_catchable() => failure,
+        // The position is added while the frame which failed is still there, the chunk its caller is in can differ
+        failure => RuntimeFailure::ErrorObject(error_value(context, &failure)),
+    };
+    let mut result = Err(failure);
+    loop {
+        let base = context.call_stack.last().expect("The entry frame is on the stack").base;
+        result = close_level(context, base, result);
+        if context.call_stack.len() - 1 == entry {
+            break;
+        }
+        context.call_stack.pop();
+    }
+    match result {
+        Err(failure) => Err(failure),
+        Ok(()) => unreachable!("Closing variables cannot clear an error"),
+    }
+}
+
+// Closes the upvalues and to-be-closed variables at or above `level` in the stack. `result` is how their scope is
+// left, an error raised by a __close metamethod replaces it.
+fn close_level(context: &mut Context, level: usize, mut result: Result<(), RuntimeFailure>) -> Result<(), RuntimeFailure> {
+    while let Some((index, upvalue)) = context.open_upvalues.last() {
+        if *index < level {
+            break;
+        }
+        if upvalue.close().is_none() {
+            result = Err(dangling_upvalue(context));
+        }
+        context.open_upvalues.pop();
+    }
+    while let Some(&slot) = context.to_be_closed.last() {
+        if slot < level {
+            break;
+        }
+        context.to_be_closed.pop();
+        let Some(value) = context.stack.borrow().get(slot).cloned() else {
+            result = Err(dangling_upvalue(context));
+            continue;
+        };
+        let error = match &result {
+            Ok(_) | Err(RuntimeFailure::CoroutineClosed) => DataKind::Null,
+            Err(failure) => error_value(context, failure),
+        };
+        let handler = operations::get_metamethod(context, &value, "__close");
+        if let Err(failure) = call_function(context, &handler, vec![value, error]) {
+            result = Err(failure);
+        }
+    }
+    result
+}
+
+// The error of an open upvalue or to-be-closed variable whose register is gone, only a corrupted binary chunk can make
+// one
+// An upvalue of the Lua function running in `frame`
+fn frame_upvalue<'c>(context: &'c Context, frame: usize, index: u32) -> &'c UpvalueRef {
+    match context.call_stack[frame].function.kind() {
+        FunctionKind::External(closure) => &closure.upvalues[index as usize],
+        FunctionKind::Internal(_) => unreachable!("Only Lua functions have frames in the virtual machine"),
+    }
+}
+
+fn dangling_upvalue(context: &Context) -> RuntimeFailure {
+    RuntimeFailure::BadOperation("variable refers to a register past the top of the stack".to_owned(), context.current_line)
+}
+
+// The upvalue for a stack slot, open upvalues are shared by every closure capturing the slot
+fn find_upvalue(context: &mut Context, index: usize) -> UpvalueRef {
+    match context.open_upvalues.binary_search_by_key(&index, |(slot, _)| *slot) {
+        Ok(position) => context.open_upvalues[position].1.clone(),
+        Err(position) => {
+            let upvalue = UpvalueRef::open(&context.state.heap, context.stack.clone(), index);
+            context.open_upvalues.insert(position, (index, upvalue.clone()));
+            upvalue
+        }
+    }
+}
+
+// Where an operand of an instruction came from, to name it in error messages
+#[derive(Clone, Copy)]
+enum Culprit {
+    Register(u32),
+    Upvalue(u32),
+    Unnamed,
+}
+
+// Describes a wrong-typed operand by the variable it came from, like "attempt to index a nil value (global 'x')"
+fn describe<T>(
+    result: Result<T, RuntimeFailure>,
+    prototype: &Prototype,
+    pc: usize,
+    culprits: (Culprit, Culprit),
+) -> Result<T, RuntimeFailure> {
+    match result {
+        Err(RuntimeFailure::WrongType(message, operand, line)) => {
+            let culprit = match operand {
+                Operand::First => culprits.0,
+                Operand::Second => culprits.1,
+            };
+            let name = match culprit {
+                Culprit::Register(register) => debug_info::object_name(prototype, pc, register),
+                Culprit::Upvalue(index) => Some(("upvalue", prototype.upvalue_name(index as usize).to_owned())),
+                Culprit::Unnamed => None,
+            };
+            let message = match name {
+                Some((kind, name)) => format!("{} ({} '{}')", message, kind, name),
+                None => message,
+            };
+            Err(RuntimeFailure::BadOperation(message, line))
+        }
+        result => result,
+    }
+}
+
+// Operands the compiler swapped to put a constant second are swapped back, metamethods see them in source order
+fn arithmetic(
+    context: &mut Context,
+    op: MathOperator,
+    lhs: DataKind,
+    rhs: DataKind,
+    flipped: bool,
+) -> Result<DataKind, RuntimeFailure> {
+    let (lhs, rhs) = if flipped { (rhs, lhs) } else { (lhs, rhs) };
+    if let (DataKind::Number(lhs), DataKind::Number(rhs)) = (&lhs, &rhs) {
+        if let Some(result) = lhs.arithmetic(op, rhs) {
+            return Ok(DataKind::Number(result));
+        }
+    }
+    operations::arithmetic(context, op, lhs, rhs)
+}
+
+fn bitwise(
+    context: &mut Context,
+    op: BitwiseOperator,
+    lhs: DataKind,
+    rhs: DataKind,
+    flipped: bool,
+) -> Result<DataKind, RuntimeFailure> {
+    let (lhs, rhs) = if flipped { (rhs, lhs) } else { (lhs, rhs) };
+    if let (DataKind::Number(NumberKind::Integer(lhs)), DataKind::Number(NumberKind::Integer(rhs))) = (&lhs, &rhs) {
+        return Ok(DataKind::integer(bitwise_integers(op, *lhs, *rhs)));
+    }
+    operations::bitwise(context, op, lhs, rhs)
+}
+
+// The culprits of a binary instruction with a register and a constant operand
+fn constant_culprits(register: u32, flipped: bool) -> (Culprit, Culprit) {
+    match flipped {
+        true => (Culprit::Unnamed, Culprit::Register(register)),
+        false => (Culprit::Register(register), Culprit::Unnamed),
+    }
+}
+
+fn math_operator(op: OpCode) -> MathOperator {
+    match op {
+        OpCode::AddI | OpCode::AddK | OpCode::Add => MathOperator::Plus,
+        OpCode::SubK | OpCode::Sub => MathOperator::Minus,
+        OpCode::MulK | OpCode::Mul => MathOperator::Multiply,
+        OpCode::ModK | OpCode::Mod => MathOperator::Mod,
+        OpCode::PowK | OpCode::Pow => MathOperator::Exponent,
+        OpCode::DivK | OpCode::Div => MathOperator::FloatDivision,
+        _ => MathOperator::FloorDivision,
+    }
+}
+
+fn bitwise_operator(op: OpCode) -> BitwiseOperator {
+    match op {
+        OpCode::BAndK | OpCode::BAnd => BitwiseOperator::And,
+        OpCode::BOrK | OpCode::BOr => BitwiseOperator::Or,
+        OpCode::BXorK | OpCode::BXor => BitwiseOperator::ExclusiveOr,
+        OpCode::ShlI | OpCode::Shl => BitwiseOperator::LeftShift,
+        _ => BitwiseOperator::RightShift,
+    }
+}
+
+// Moves the results of a call to `func_slot` onwards, padded or truncated to `wanted` values unless that is -1.
+// Returns where the results end.
+fn place_results(stack: &ValueStack, func_slot: usize, results: Vec<DataKind>, wanted: i32) -> usize {
+    let mut stack = stack.borrow_mut();
+    let count = match wanted {
+        -1 => results.len(),
+        wanted => wanted as usize,
+    };
+    let end = func_slot + count;
+    if stack.len() < end {
+        stack.resize(end, DataKind::Null);
+    }
+    let mut results = results.into_iter();
+    for slot in &mut stack[func_slot..end] {
+        *slot = results.next().unwrap_or(DataKind::Null);
+    }
+    end
+}
+
+// The number of values from `start` up to where the last call or `...` which kept all of its results left them. Code
+// loaded from a binary chunk may use that before setting it, which must not read past the stack.
+fn values_to_top(stack: &ValueStack, start: usize, top: usize) -> usize {
+    top.min(stack.borrow().len()).saturating_sub(start)
+}
+
+// Pops the frame of a returning Lua function and hands its results to the Lua function that called it
+fn return_to_caller(context: &mut Context, values: Vec<DataKind>) -> usize {
+    let call_info = context.call_stack.pop().expect("The returning frame is on the stack");
+    let end = place_results(&context.stack, call_info.base - 1, values, call_info.wanted_results);
+    let caller = context.call_stack.last_mut().expect("A Lua function called the returning one");
+    caller.pc += 1;
+    let caller_top = caller.base + caller.prototype().map_or(0, |prototype| prototype.max_stack_size as usize);
+    // The registers of the returning function are dropped, the caller's are restored if the callee's frame was smaller
+    context.stack.borrow_mut().resize(end.max(caller_top), DataKind::Null);
+    end
+}
+
+// Moves the frame a tail call just pushed into the place of the frame that made it, so that a chain of tail calls runs
+// in constant space. The callee returns to where its caller would have.
+fn replace_caller(context: &mut Context, entry: usize) {
+    let mut callee = context.call_stack.pop().expect("The tail call pushed a frame");
+    let caller = context.call_stack.pop().expect("A Lua function made the tail call");
+    if context.call_stack.len() == entry {
+        // The frame the host or a coroutine entered has no function slot, neither does the callee taking its place
+        context.stack.borrow_mut().drain(caller.base..callee.base);
+    } else {
+        // The called function and its registers move down to the caller's function slot
+        context.stack.borrow_mut().drain(caller.base - 1..callee.base - 1);
+    }
+    callee.base = caller.base;
+    callee.call_line = caller.call_line;
+    callee.wanted_results = caller.wanted_results;
+    callee.tail_call = true;
+    context.call_stack.push(callee);
+}
+
+// Calls the value in `func_slot` of a Lua frame with the `arg_count` arguments after it. Lua functions get a frame for
+// the dispatch loop to continue with and None is returned, other calls are run to completion and leave their results
+// from `func_slot` on, returning where they end.
+fn call_value(
+    context: &mut Context,
+    prototype: &Prototype,
+    pc: usize,
+    base: usize,
+    register: u32,
+    mut arg_count: usize,
+    wanted: i32,
+) -> Result<Option<usize>, RuntimeFailure> {
+    let stack = context.stack.clone();
+    let func_slot = base + register as usize;
+    loop {
+        let function = stack.borrow()[func_slot].clone();
+        match &function {
+            DataKind::Function(function_ref) => match function_ref.kind() {
+                FunctionKind::External(closure) => {
+                    let prototype = &closure.prototype;
+                    enter_frame(context, function_ref.clone(), prototype, func_slot + 1, arg_count, wanted)?;
+                    return Ok(None);
+                }
+                FunctionKind::Internal(native) => {
+                    let args = stack.borrow()[func_slot + 1..func_slot + 1 + arg_count].to_vec();
+                    let results = call_native(context, function_ref, native, args)?;
+                    return Ok(Some(place_results(&stack, func_slot, results, wanted)));
+                }
+            },
+            _ => {
+                let handler = operations::get_metamethod(context, &function, "__call");
+                if handler.is_nil() {
+                    let failure = RuntimeFailure::WrongType(
+                        format!("attempt to call a {} value", function.type_name()),
+                        Operand::First,
+                        context.current_line,
+                    );
+                    let culprit = match func_slot == base + register as usize {
+                        true => Culprit::Register(register),
+                        false => Culprit::Unnamed,
+                    };
+                    return describe(Err(failure), prototype, pc, (culprit, Culprit::Unnamed));
+                }
+                // The called value becomes the first argument of its __call metamethod
+                stack.borrow_mut().insert(func_slot, handler);
+                arg_count += 1;
+            }
+        }
+    }
+}
+
+fn for_number(value: DataKind, what: &str, context: &Context) -> Result<NumberKind, RuntimeFailure> {
+    match value {
+        DataKind::Number(number) => Ok(number),
+        _ => Err(RuntimeFailure::BadOperation(
+            format!("'for' {} value must be a number", what),
+            context.current_line,
+        )),
+    }
+}
+
+// Converts the limit of an integer loop to an integer, None if the loop must not run at all
+fn for_integer_limit(limit: NumberKind, step: i64) -> Option<i64> {
+    match limit {
+        NumberKind::Integer(limit) => Some(limit),
+        NumberKind::Float(limit) if limit.is_nan() => None,
+        NumberKind::Float(limit) => {
+            let limit = if step > 0 { limit.floor() } else { limit.ceil() };
+            if limit >= TWO_POW_63 {
+                Some(i64::MAX)
+            } else if limit < -TWO_POW_63 {
+                Some(i64::MIN)
+            } else {
+                Some(limit as i64)
+            }
+        }
+    }
+}
+
+// Prepares the registers of a numeric for loop, false if it must not run at all. Integer loops keep their iteration
+// count in place of the limit so that the loop variable can never overflow.
+fn for_prep(context: &Context, stack: &ValueStack, a: usize) -> Result<bool, RuntimeFailure> {
+    let (start, limit, step) = {
+        let stack = stack.borrow();
+        (stack[a].clone(), stack[a + 1].clone(), stack[a + 2].clone())
+    };
+    let start = for_number(start, "initial", context)?;
+    let limit = for_number(limit, "limit", context)?;
+    let step = for_number(step, "step", context)?;
+    if step == NumberKind::Integer(0) {
+        return Err(RuntimeFailure::BadOperation("'for' step is zero".to_owned(), context.current_line));
+    }
+    let mut stack = stack.borrow_mut();
+    match (start, step) {
+        (NumberKind::Integer(start), NumberKind::Integer(step)) => {
+            let limit = match for_integer_limit(limit, step) {
+                Some(limit) => limit,
+                None => return Ok(false),
+            };
+            if (step > 0 && start > limit) || (step < 0 && start < limit) {
+                return Ok(false);
+            }
+            let count = if step > 0 {
+                (limit as u64).wrapping_sub(start as u64) / step as u64
+            } else {
+                (start as u64).wrapping_sub(limit as u64) / (step as u64).wrapping_neg()
+            };
+            stack[a + 1] = DataKind::integer(count as i64);
+            stack[a + 3] = DataKind::integer(start);
+        }
+        (start, step) => {
+            let (start, limit, step) = (start.as_float(), limit.as_float(), step.as_float());
+            if !((step > 0.0 && start <= limit) || (step < 0.0 && start >= limit)) {
+                return Ok(false);
+            }
+            stack[a] = DataKind::Number(NumberKind::Float(start));
+            stack[a + 1] = DataKind::Number(NumberKind::Float(limit));
+            stack[a + 2] = DataKind::Number(NumberKind::Float(step));
+            stack[a + 3] = DataKind::Number(NumberKind::Float(start));
+        }
+    }
+    Ok(true)
+}
+
+// Advances a numeric for loop, true if it runs again. FORPREP left numbers in its registers, unless a corrupted binary
+// chunk jumps to the loop around it.
+fn for_loop(context: &Context, stack: &ValueStack, a: usize) -> Result<bool, RuntimeFailure> {
+    let mut stack = stack.borrow_mut();
+    match (&stack[a], &stack[a + 1], &stack[a + 2]) {
+        (
+            DataKind::Number(NumberKind::Integer(value)),
+            DataKind::Number(NumberKind::Integer(count)),
+            DataKind::Number(NumberKind::Integer(step)),
+        ) => {
+            if *count == 0 {
+                return Ok(false);
+            }
+            // The count is unsigned, a loop over the whole integer range starts with more than i64::MAX iterations
+            let (value, count) = (value.wrapping_add(*step), count.wrapping_sub(1));
+            stack[a] = DataKind::integer(value);
+            stack[a + 1] = DataKind::integer(count);
+            stack[a + 3] = DataKind::integer(value);
+            Ok(true)
+        }
+        (DataKind::Number(value), DataKind::Number(limit), DataKind::Number(step)) => {
+            let (limit, step) = (limit.as_float(), step.as_float());
+            let value = value.as_float() + step;
+            if (step > 0.0 && value <= limit) || (step < 0.0 && value >= limit) {
+                stack[a] = DataKind::Number(NumberKind::Float(value));
+                stack[a + 3] = DataKind::Number(NumberKind::Float(value));
+                Ok(true)
+            } else {
+                Ok(false)
+            }
+        }
+        _ => Err(RuntimeFailure::BadOperation("'for' loop was not prepared".to_owned(), context.current_line)),
+    }
+}
+
+// Marks a register as to-be-closed, nil and false need no closing
+fn mark_to_be_closed(context: &mut Context, slot: usize, name: &str) -> Result<(), RuntimeFailure> {
+    let value = context.stack.borrow()[slot].clone();
+    if !value.is_true() {
+        return Ok(());
+    }
+    if operations::get_metamethod(context, &value, "__close").is_nil() {
+        return Err(RuntimeFailure::BadOperation(
+            format!("variable '{}' got a non-closable value", name),
+            context.current_line,
+        ));
+    }
+    context.to_be_closed.push(slot);
+    Ok(())
+}
+
+// The dispatch loop. Runs frames from the innermost one until the frame at `entry` returns.
+fn run(context: &mut Context, entry: usize) -> Result<Vec<DataKind>, RuntimeFailure> {
+    let stack = context.stack.clone();
+    // Where the values of the last call or `...` which kept all of its results end
+    let mut top = 0;
+    'frames: loop {
+        let frame = context.call_stack.len() - 1;
+        // Only the frame holds the function, the collector would not see a reference kept here while a coroutine is
+        // suspended
+        let FunctionKind::External(closure) = context.call_stack[frame].function.kind() else {
+            unreachable!("Only Lua functions have frames in the virtual machine")
+        };
+        let prototype = closure.prototype.clone();
+        let prototype = &*prototype;
+        let constants = &prototype.constants;
+        let base = context.call_stack[frame].base;
+        let mut pc = context.call_stack[frame].pc;
+        // The instruction this frame ran last, a frame which is just starting has run none
+        let mut previous_pc = pc.checked_sub(1);
+
+        macro_rules! register {
+            ($index:expr) => {{
+                let value = stack.borrow()[base + $index as usize].clone();
+                value
+            }};
+        }
+        macro_rules! set_register {
+            ($index:expr, $value:expr) => {{
+                let value = $value;
+                stack.borrow_mut()[base + $index as usize] = value;
+            }};
+        }
+        // A register or a constant, chosen by the k bit
+        macro_rules! rk {
+            ($instruction:expr, $index:expr) => {
+                match $instruction.k() {
+                    true => constants[$index as usize].clone(),
+                    false => register!($index),
+                }
+            };
+        }
+
+        loop {
+            let instruction = prototype.code[pc];
+            context.current_line = prototype.line_at(pc) as i32;
+            context.call_stack[frame].pc = pc;
+            if context.state.hooks.watches_instructions() {
+                hook::on_instruction(context, prototype, pc, &mut previous_pc)?;
+            }
+            let current = pc;
+            pc += 1;
+            let (a, b, c) = (instruction.a(), instruction.b(), instruction.c());
+            match instruction.opcode() {
+                OpCode::Move => set_register!(a, register!(b)),
+                OpCode::LoadI => set_register!(a, DataKind::integer(instruction.sbx() as i64)),
+                OpCode::LoadF => set_register!(a, DataKind::Number(NumberKind::Float(instruction.sbx() as f64))),
+                OpCode::LoadK => set_register!(a, constants[instruction.bx() as usize].clone()),
+                OpCode::LoadKX => {
+                    let index = prototype.code[pc].ax_arg();
+                    pc += 1;
+                    set_register!(a, constants[index as usize].clone());
+                }
+                OpCode::LoadFalse => set_register!(a, DataKind::Bool(false)),
+                OpCode::LFalseSkip => {
+                    set_register!(a, DataKind::Bool(false));
+                    pc += 1;
+                }
+                OpCode::LoadTrue => set_register!(a, DataKind::Bool(true)),
+                OpCode::LoadNil => {
+                    let start = base + a as usize;
+                    stack.borrow_mut()[start..=start + b as usize].fill(DataKind::Null);
+                }
+                OpCode::GetUpval => {
+                    let value = frame_upvalue(context, frame, b).get().ok_or_else(|| dangling_upvalue(context))?;
+                    set_register!(a, value);
+                }
+                OpCode::SetUpval => frame_upvalue(context, frame, b).set(register!(a)).ok_or_else(|| dangling_upvalue(context))?,
+                OpCode::GetTabUp => {
+                    let table = frame_upvalue(context, frame, b).get().ok_or_else(|| dangling_upvalue(context))?;
+                    let value = operations::index_value(context, &table, &constants[c as usize]);
+                    set_register!(a, describe(value, prototype, current, (Culprit::Upvalue(b), Culprit::Unnamed))?);
+                }
+                OpCode::GetTable | OpCode::GetI | OpCode::GetField => {
+                    let key = match instruction.opcode() {
+                        OpCode::GetTable => register!(c),
+                        OpCode::GetI => DataKind::integer(c as i64),
+                        _ => constants[c as usize].clone(),
+                    };
+                    let value = operations::index_value(context, &register!(b), &key);
+                    set_register!(a, describe(value, prototype, current, (Culprit::Register(b), Culprit::Unnamed))?);
+                }
+                OpCode::SetTabUp => {
+                    let table = frame_upvalue(context, frame, a).get().ok_or_else(|| dangling_upvalue(context))?;
+                    let result = operations::set_index(context, &table, constants[b as usize].clone(), rk!(instruction, c));
+                    describe(result, prototype, current, (Culprit::Upvalue(a), Culprit::Unnamed))?;
+                }
+                OpCode::SetTable | OpCode::SetI | OpCode::SetField => {
+                    let key = match instruction.opcode() {
+                        OpCode::SetTable => register!(b),
+                        OpCode::SetI => DataKind::integer(b as i64),
+                        _ => constants[b as usize].clone(),
+                    };
+                    let result = operations::set_index(context, &register!(a), key, rk!(instruction, c));
+                    describe(result, prototype, current, (Culprit::Register(a), Culprit::Unnamed))?;
+                }
+                OpCode::NewTable => {
+                    // The EXTRAARG with the size of the array part is skipped
+                    pc += 1;
+                    set_register!(a, DataKind::Table(TableRef::new(&context.state.heap)));
+                    gc::check(context, base + a as usize + 1)?;
+                }
+                OpCode::SelfMethod => {
+                    let object = register!(b);
+                    let method = operations::index_value(context, &object, &rk!(instruction, c));
+                    let method = describe(method, prototype, current, (Culprit::Register(b), Culprit::Unnamed))?;
+                    set_register!(a + 1, object);
+                    set_register!(a, method);
+                }
+                OpCode::AddI => {
+                    let immediate = DataKind::integer(instruction.sc() as i64);
+                    let value = arithmetic(context, MathOperator::Plus, register!(b), immediate, instruction.k());
+                    set_register!(a, describe(value, prototype, current, constant_culprits(b, instruction.k()))?);
+                }
+                OpCode::AddK
+                | OpCode::SubK
+                | OpCode::MulK
+                | OpCode::ModK
+                | OpCode::PowK
+                | OpCode::DivK
+                | OpCode::IDivK => {
+                    let op = math_operator(instruction.opcode());
+                    let value = arithmetic(context, op, register!(b), constants[c as usize].clone(), instruction.k());
+                    set_register!(a, describe(value, prototype, current, constant_culprits(b, instruction.k()))?);
+                }
+                OpCode::BAndK | OpCode::BOrK | OpCode::BXorK => {
+                    let op = bitwise_operator(instruction.opcode());
+                    let value = bitwise(context, op, register!(b), constants[c as usize].clone(), instruction.k());
+                    set_register!(a, describe(value, prototype, current, constant_culprits(b, instruction.k()))?);
+                }
+                OpCode::ShrI => {
+                    let immediate = DataKind::integer(instruction.sc() as i64);
+                    let value = bitwise(context, BitwiseOperator::RightShift, register!(b), immediate, false);
+                    set_register!(a, describe(value, prototype, current, constant_culprits(b, false))?);
+                }
+                OpCode::ShlI => {
+                    // The immediate is shifted by the register
+                    let immediate = DataKind::integer(instruction.sc() as i64);
+                    let value = bitwise(context, BitwiseOperator::LeftShift, immediate, register!(b), false);
+                    set_register!(a, describe(value, prototype, current, constant_culprits(b, true))?);
+                }
+                OpCode::Add
+                | OpCode::Sub
+                | OpCode::Mul
+                | OpCode::Mod
+                | OpCode::Pow
+                | OpCode::Div
+                | OpCode::IDiv => {
+                    let op = math_operator(instruction.opcode());
+                    let value = arithmetic(context, op, register!(b), register!(c), false);
+                    let culprits = (Culprit::Register(b), Culprit::Register(c));
+                    set_register!(a, describe(value, prototype, current, culprits)?);
+                }
+                OpCode::BAnd | OpCode::BOr | OpCode::BXor | OpCode::Shl | OpCode::Shr => {
+                    let op = bitwise_operator(instruction.opcode());
+                    let value = bitwise(context, op, register!(b), register!(c), false);
+                    let culprits = (Culprit::Register(b), Culprit::Register(c));
+                    set_register!(a, describe(value, prototype, current, culprits)?);
+                }
+                OpCode::Unm | OpCode::BNot | OpCode::Len => {
+                    let operand = register!(b);
+                    let value = match instruction.opcode() {
+                        OpCode::Unm => operations::unary_minus(context, operand),
+                        OpCode::BNot => operations::bitwise_not(context, operand),
+                        _ => operations::length(context, operand),
+                    };
+                    set_register!(a, describe(value, prototype, current, (Culprit::Register(b), Culprit::Unnamed))?);
+                }
+                OpCode::Not => set_register!(a, DataKind::Bool(!register!(b).is_true())),
+                OpCode::Concat => {
+                    // Concatenates R[A] .. ... .. R[A + B - 1] from the right
+                    let last = a + b - 1;
+                    let mut value = register!(last);
+                    for index in (a..last).rev() {
+                        let result = operations::concat(context, register!(index), value);
+                        let culprits = (Culprit::Register(index), Culprit::Register(index + 1));
+                        value = describe(result, prototype, current, culprits)?;
+                    }
+                    set_register!(a, value);
+                    gc::check(context, base + a as usize + 1)?;
+                }
+                OpCode::Close => close_level(context, base + a as usize, Ok(()))?,
+                OpCode::Tbc => {
+                    let name = prototype.local_name(a, current).unwrap_or("?");
+                    mark_to_be_closed(context, base + a as usize, name)?;
+                }
+                OpCode::Jmp => pc = (pc as isize + instruction.sj_arg() as isize) as usize,
+                // Comparisons skip the jump after them unless their result is k
+                OpCode::Eq | OpCode::Lt | OpCode::Le => {
+                    let (lhs, rhs) = (register!(a), register!(b));
+                    let result = match instruction.opcode() {
+                        OpCode::Eq => operations::equals(context, &lhs, &rhs),
+                        OpCode::Lt => operations::less_than(context, &lhs, &rhs),
+                        _ => operations::less_equal(context, &lhs, &rhs),
+                    };
+                    let culprits = (Culprit::Register(a), Culprit::Register(b));
+                    if describe(result, prototype, current, culprits)? != instruction.k() {
+                        pc += 1;
+                    }
+                }
+                OpCode::EqK => {
+                    if (register!(a) == constants[b as usize]) != instruction.k() {
+                        pc += 1;
+                    }
+                }
+                OpCode::EqI => {
+                    let equal = match register!(a) {
+                        DataKind::Number(number) => number == immediate_number(instruction),
+                        _ => false,
+                    };
+                    if equal != instruction.k() {
+                        pc += 1;
+                    }
+                }
+                OpCode::LtI | OpCode::LeI | OpCode::GtI | OpCode::GeI => {
+                    let value = register!(a);
+                    let immediate = DataKind::Number(immediate_number(instruction));
+                    let result = match instruction.opcode() {
+                        OpCode::LtI => operations::less_than(context, &value, &immediate),
+                        OpCode::LeI => operations::less_equal(context, &value, &immediate),
+                        OpCode::GtI => operations::less_than(context, &immediate, &value),
+                        _ => operations::less_equal(context, &immediate, &value),
+                    };
+                    let culprits = match instruction.opcode() {
+                        OpCode::LtI | OpCode::LeI => (Culprit::Register(a), Culprit::Unnamed),
+                        _ => (Culprit::Unnamed, Culprit::Register(a)),
+                    };
+                    if describe(result, prototype, current, culprits)? != instruction.k() {
+                        pc += 1;
+                    }
+                }
+                OpCode::Test => {
+                    if register!(a).is_true() != instruction.k() {
+                        pc += 1;
+                    }
+                }
+                OpCode::TestSet => {
+                    let value = register!(b);
+                    if value.is_true() != instruction.k() {
+                        pc += 1;
+                    } else {
+                        set_register!(a, value);
+                    }
+                }
+                OpCode::Call => {
+                    let arg_count = match b {
+                        0 => values_to_top(&stack, base + a as usize + 1, top),
+                        b => b as usize - 1,
+                    };
+                    match call_value(context, prototype, current, base, a, arg_count, c as i32 - 1)? {
+                        // The strings a native function returned count towards the next collection
+                        Some(end) => {
+                            top = end;
+                            gc::check(context, end)?;
+                        }
+                        None => {
+                            hook::on_call(context, HookEvent::Call)?;
+                            continue 'frames;
+                        }
+                    }
+                }
+                OpCode::TailCall => {
+                    let arg_count = match b {
+                        0 => values_to_top(&stack, base + a as usize + 1, top),
+                        b => b as usize - 1,
+                    };
+                    // The variables the arguments were computed from go away with the frame
+                    if instruction.k() {
+                        close_level(context, base, Ok(()))?;
+                    }
+                    let values = match call_value(context, prototype, current, base, a, arg_count, -1)? {
+                        Some(end) => stack.borrow()[base + a as usize..end].to_vec(),
+                        None => {
+                            replace_caller(context, entry);
+                            hook::on_call(context, HookEvent::TailCall)?;
+                            continue 'frames;
+                        }
+                    };
+                    // A native function was called, its results are returned
+                    hook::on_return(context)?;
+                    if frame == entry {
+                        return Ok(values);
+                    }
+                    top = return_to_caller(context, values);
+                    continue 'frames;
+                }
+                OpCode::Return | OpCode::Return0 | OpCode::Return1 => {
+                    let start = base + a as usize;
+                    let count = match (instruction.opcode(), b) {
+                        (OpCode::Return0, _) => 0,
+                        (OpCode::Return1, _) => 1,
+                        (_, 0) => values_to_top(&stack, start, top),
+                        (_, b) => b as usize - 1,
+                    };
+                    let values = match count {
+                        0 => Vec::new(),
+                        count => stack.borrow()[start..start + count].to_vec(),
+                    };
+                    if instruction.k() {
+                        close_level(context, base, Ok(()))?;
+                    }
+                    hook::on_return(context)?;
+                    if frame == entry {
+                        return Ok(values);
+                    }
+                    top = return_to_caller(context, values);
+                    continue 'frames;
+                }
+                OpCode::ForLoop => {
+                    if for_loop(context, &stack, base + a as usize)? {
+                        pc -= instruction.bx() as usize;
+                    }
+                }
+                OpCode::ForPrep => {
+                    if !for_prep(context, &stack, base + a as usize)? {
+                        pc += instruction.bx() as usize + 1;
+                    }
+                }
+                OpCode::TForPrep => {
+                    mark_to_be_closed(context, base + a as usize + 3, "(for state)")?;
+                    pc += instruction.bx() as usize;
+                }
+                OpCode::TForCall => {
+                    let (iterator, state, control) = (register!(a), register!(a + 1), register!(a + 2));
+                    let results = call_function(context, &iterator, vec![state, control]);
+                    let results = describe(results, prototype, current, (Culprit::Unnamed, Culprit::Unnamed))?;
+                    place_results(&stack, base + a as usize + 4, results, c as i32);
+                }
+                OpCode::TForLoop => {
+                    let control = register!(a + 4);
+                    if !control.is_nil() {
+                        set_register!(a + 2, control);
+                        pc -= instruction.bx() as usize;
+                    }
+                }
+                OpCode::SetList => {
+                    let count = match b {
+                        0 => values_to_top(&stack, base + a as usize + 1, top),
+                        b => b as usize,
+                    };
+                    let mut first = c as i64;
+                    if instruction.k() {
+                        first += prototype.code[pc].ax_arg() as i64 * (MAX_C as i64 + 1);
+                        pc += 1;
+                    }
+                    // Compiled code only stores into the table it just created, a loaded chunk could store elsewhere
+                    let table = match register!(a) {
+                        DataKind::Table(table) => table,
+                        value => {
+                            let message = format!("attempt to index a {} value", value.type_name());
+                            return Err(RuntimeFailure::BadOperation(message, context.current_line));
+                        }
+                    };
+                    let start = base + a as usize + 1;
+                    let values = stack.borrow()[start..start + count].to_vec();
+                    for (index, value) in values.into_iter().enumerate() {
+                        table.set(context, DataKind::integer(first + index as i64 + 1), value)?;
+                    }
+                }
+                OpCode::Closure => {
+                    let child = prototype.prototypes[instruction.bx() as usize].clone();
+                    let upvalues = child
+                        .upvalues
+                        .iter()
+                        .map(|upvalue| match upvalue.in_stack {
+                            true => find_upvalue(context, base + upvalue.index as usize),
+                            false => frame_upvalue(context, frame, upvalue.index as u32).clone(),
+                        })
+                        .collect();
+                    set_register!(a, new_closure(&context.state.heap, child, upvalues));
+                    gc::check(context, base + a as usize + 1)?;
+                }
+                OpCode::VarArg => {
+                    let varargs = &context.call_stack[frame].varargs;
+                    let count = match c {
+                        0 => varargs.len(),
+                        c => c as usize - 1,
+                    };
+                    let start = base + a as usize;
+                    let mut stack = stack.borrow_mut();
+                    if stack.len() < start + count {
+                        stack.resize(start + count, DataKind::Null);
+                    }
+                    for (index, slot) in stack[start..start + count].iter_mut().enumerate() {
+                        *slot = varargs.get(index).cloned().unwrap_or(DataKind::Null);
+                    }
+                    if c == 0 {
+                        top = start + count;
+                    }
+                }
+                // Varargs are set aside when the frame is entered
+                OpCode::VarArgPrep => {}
+                OpCode::ExtraArg => unreachable!("EXTRAARG is read by the instruction before it"),
+            }
+        }
+    }
+}
+
+// The immediate operand of EQI and the order comparisons, a float when C is set
+fn immediate_number(instruction: crate::compiler::opcode::Instruction) -> NumberKind {
+    match instruction.c() {
+        0 => NumberKind::Integer(instruction.sb() as i64),
+        _ => NumberKind::Float(instruction.sb() as f64),
+    }
+}
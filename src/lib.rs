@@ -0,0 +1,25 @@
+// The interpreter as a library, for Rust programs which run Lua scripts. A `Lua` holds the state of an interpreter,
+// chunks loaded into it share its globals:
+//
+// let lua = Lua::new();
+// lua.load("function shout(s) return s:upper() .. '!' end", "setup").exec()?;
+// let shout: FunctionRef = lua.globals().get("shout")?;
+// let loud: String = lua.call(&shout, "hi")?;
+
+mod ast;
+mod capi;
+mod compiler;
+mod diagnostic;
+mod err_handle;
+mod frontend;
+mod lua;
+
+pub use err_handle::RuntimeFailure;
+pub use frontend::convert::{ConversionError, FromLua, FromLuaMulti, IntoLua, IntoLuaMulti, Variadic};
+pub use frontend::data::{DataKind, LuaString, NumberKind};
+pub use frontend::serialize::{DeserializeOptions, Serde, SerializeOptions};
+pub use frontend::{
+    CoroutineStatus, FunctionRef, HookAction, HookEvent, HookInfo, HookTriggers, LightUserData, Limits, TableRef,
+    ThreadRef, UserData, UserDataFields, UserDataMethods, UserDataRef,
+};
+pub use lua::{Chunk, Error, Lua, RegistryKey, Table};
@@ -0,0 +1,417 @@
+use std::fmt::{Display, Formatter};
+use std::rc::{Rc, Weak};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::compiler;
+use crate::compiler::prototype::Prototype;
+use crate::err_handle::RuntimeFailure;
+use crate::frontend;
+use crate::frontend::convert::{
+    Arguments, AsyncHostFunction, ConversionError, FromLua, FromLuaMulti, HostFunction, IntoLua, IntoLuaMulti,
+};
+use crate::frontend::data::DataKind;
+use crate::frontend::hook::{Hook, HookFunction};
+use crate::frontend::serialize::{self, DeserializeOptions, SerializeOptions};
+use crate::frontend::{
+    FunctionRef, GlobalState, HookAction, HookInfo, HookTriggers, Limits, TableRef, ThreadRef, UserData, UserDataRef,
+};
+
+// Why loading or running Lua code from the host failed
+#[derive(Debug, Clone)]
+pub enum Error {
+    // Source which did not compile, every error is rendered with the line it is on
+    Syntax(String),
+    // A binary chunk which could not be loaded, like "fib.luac: bad binary format (truncated chunk)"
+    BadChunk(String),
+    // An error Lua code raised and did not catch. Messages carry their position and a traceback.
+    Runtime(RuntimeFailure),
+    // A value which could not be converted to the Rust type the host asked for
+    FromLua(ConversionError),
+    // A Rust value which could not be converted with serde, like a map with a key Lua does not allow
+    ToLua(String),
+    // A registry key which was created by another interpreter
+    MismatchedRegistryKey,
+    // An allocation failed because of the memory limit, the interpreter can still be used once memory is freed or the
+    // limit is raised
+    Memory,
+}
+
+impl Error {
+    // Renders the error the way the command line interpreter reports it, `source` is the text of the chunk it
+    // happened in when it is at hand
+    pub fn render(&self, chunk_name: &str, source: Option<&str>) -> String {
+        match self {
+            Error::Syntax(rendered) => rendered.clone(),
+            Error::Runtime(failure) => {
+                let mut diagnostic = failure.diagnostic(chunk_name);
+                if source.is_none() {
+                    diagnostic.line = 0;
+                }
+                diagnostic.render(chunk_name, source.unwrap_or_default())
+            }
+            error => format!("error: {}\n", error),
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Syntax(rendered) => write!(f, "{}", rendered.trim_end()),
+            Error::BadChunk(msg) => write!(f, "{}", msg),
+            Error::Runtime(failure) => write!(f, "{}", failure.message()),
+            Error::FromLua(error) => write!(f, "{}", error),
+            Error::ToLua(msg) => write!(f, "{}", msg),
+            Error::Memory => write!(f, "not enough memory"),
+            Error::MismatchedRegistryKey => write!(f, "registry key used with a Lua state which did not create it"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<RuntimeFailure> for Error {
+    fn from(failure: RuntimeFailure) -> Self {
+        match failure {
+            RuntimeFailure::OutOfMemory => Error::Memory,
+            failure => Error::Runtime(failure),
+        }
+    }
+}
+
+impl From<ConversionError> for Error {
+    fn from(error: ConversionError) -> Self {
+        Error::FromLua(error)
+    }
+}
+
+// The state of an interpreter. Globals, loaded functions and the objects they created are kept from one chunk to the
+// next until it is dropped.
+pub struct Lua {
+    state: Rc<GlobalState>,
+}
+
+impl Lua {
+    pub fn new() -> Self {
+        Self::with_limits(Limits::default())
+    }
+    pub fn with_limits(limits: Limits) -> Self {
+        Self { state: frontend::new_state(limits) }
+    }
+    // Loads Lua source, or a chunk compiled before when `code` starts with the binary signature. It is compiled when
+    // it is run, errors in it are reported as "chunk_name:line:".
+    pub fn load(&self, code: impl AsRef<[u8]>, chunk_name: &str) -> Chunk<'_> {
+        let code = code.as_ref();
+        let code = match code.starts_with(compiler::dump::SIGNATURE) {
+            true => ChunkCode::Binary(code.to_vec()),
+            false => ChunkCode::Source(String::from_utf8_lossy(code).into_owned()),
+        };
+        Chunk { lua: self, name: chunk_name.to_owned(), code, environment: None }
+    }
+    pub fn globals(&self) -> Table<'_> {
+        self.table(self.state.globals.clone())
+    }
+    // Gives access to the fields of a table, through its metamethods like Lua code would
+    pub fn table(&self, table: TableRef) -> Table<'_> {
+        Table { lua: self, table }
+    }
+    pub fn create_table(&self) -> TableRef {
+        TableRef::new(&self.state.heap)
+    }
+    // Wraps a Rust value, scripts use it through the fields and methods its type declares
+    pub fn create_userdata<T: UserData>(&self, value: T) -> UserDataRef {
+        UserDataRef::new(&self.state, value)
+    }
+    // Wraps a Rust function to be called from Lua, see `FunctionRef::typed`
+    pub fn create_function<F, Args>(&self, name: &'static str, func: F) -> FunctionRef
+    where
+        F: HostFunction<Args>,
+    {
+        FunctionRef::typed(name, func)
+    }
+    // Wraps a Rust function returning a future, like an `async fn`. Lua code calling it is suspended until the future
+    // completes, it can only be called from code run by `call_async`, `Chunk::exec_async` or `Chunk::eval_async`.
+    pub fn create_async_function<F, Args>(&self, name: &'static str, func: F) -> FunctionRef
+    where
+        F: AsyncHostFunction<Args>,
+    {
+        FunctionRef::typed_async(name, func)
+    }
+    // Calls a Lua or host function with arguments converted from Rust values, a tuple passes several of them
+    pub fn call<A: IntoLuaMulti, R: FromLuaMulti>(&self, function: &FunctionRef, args: A) -> Result<R, Error> {
+        let function = DataKind::Function(function.clone());
+        let results = frontend::call_from_host(&self.state, &function, args.into_lua_multi(&self.state))?;
+        self.convert_results(results)
+    }
+    // Calls a function as a future, Lua code runs when it is polled and it is pending while an async host function
+    // waits. A yield in Lua code gives the other tasks of the executor a turn. Dropping the future stops the call.
+    pub async fn call_async<A: IntoLuaMulti, R: FromLuaMulti>(&self, function: &FunctionRef, args: A) -> Result<R, Error> {
+        let args = args.into_lua_multi(&self.state);
+        let results = frontend::call_async_from_host(&self.state, function, args)?.await?;
+        self.convert_results(results)
+    }
+    // A coroutine running `function`, resumed with `Lua::resume`
+    pub fn create_thread(&self, function: &FunctionRef) -> Result<ThreadRef, Error> {
+        Ok(frontend::new_thread_from_host(&self.state, function)?)
+    }
+    // Runs a coroutine until it yields or returns, its status tells which. Errors kill the coroutine.
+    pub fn resume<A: IntoLuaMulti, R: FromLuaMulti>(&self, thread: &ThreadRef, args: A) -> Result<R, Error> {
+        let results = frontend::resume_from_host(&self.state, thread, args.into_lua_multi(&self.state))?;
+        self.convert_results(results)
+    }
+    // Calls `hook` for the events of `triggers` in every coroutine, it replaces a hook set before, debug.sethook
+    // included. The hook can raise an error, interrupt the script or make the running coroutine yield.
+    pub fn set_hook<F>(&self, triggers: HookTriggers, hook: F)
+    where
+        F: Fn(&HookInfo) -> Result<HookAction, RuntimeFailure> + 'static,
+    {
+        let hook = Hook { triggers, function: HookFunction::Host(Rc::new(hook)) };
+        self.state.hooks.set_hook(Some(hook));
+    }
+    pub fn remove_hook(&self) {
+        self.state.hooks.set_hook(None);
+    }
+    // Limits how many more instructions Lua code may run, across every call and coroutine, before it is stopped with
+    // an error pcall cannot catch. None removes the limit.
+    pub fn set_instruction_budget(&self, budget: Option<u64>) {
+        self.state.hooks.set_budget(budget);
+    }
+    // The instructions left of the budget
+    pub fn instruction_budget(&self) -> Option<u64> {
+        self.state.hooks.budget()
+    }
+    // Limits how many bytes the objects and strings of the interpreter may take, see `Limits::memory`
+    pub fn set_memory_limit(&self, limit: Option<usize>) {
+        self.state.limits.set(Limits { memory: limit, ..self.state.limits.get() });
+    }
+    // An estimate of the memory in use, in bytes, like collectgarbage("count") reports it in kilobytes
+    pub fn used_memory(&self) -> usize {
+        self.state.heap.allocated_size()
+    }
+    // Converts any serializable Rust value, like a struct to a table with its fields
+    pub fn to_value<T: ?Sized + Serialize>(&self, value: &T) -> Result<DataKind, Error> {
+        self.to_value_with(value, SerializeOptions::default())
+    }
+    pub fn to_value_with<T: ?Sized + Serialize>(&self, value: &T, options: SerializeOptions) -> Result<DataKind, Error> {
+        frontend::to_value_from_host(&self.state, value, options).map_err(|error| Error::ToLua(error.to_string()))
+    }
+    // Reads a Rust value out of a Lua value, like a struct out of the table a configuration script returns. Errors
+    // name the field which is wrong, like "servers[2].port: integer expected, got string".
+    pub fn from_value<T: DeserializeOwned>(&self, value: DataKind) -> Result<T, Error> {
+        self.from_value_with(value, DeserializeOptions::default())
+    }
+    pub fn from_value_with<T: DeserializeOwned>(&self, value: DataKind, options: DeserializeOptions) -> Result<T, Error> {
+        serialize::from_value(value, &self.state, options).map_err(|error| Error::FromLua(ConversionError::Invalid(error.to_string())))
+    }
+    // The value which stands for None with `SerializeOptions::none_as_null`, unlike nil it can be stored in a table
+    pub fn null(&self) -> DataKind {
+        serialize::null()
+    }
+    // The metatable which marks tables as sequences for serde, see `SerializeOptions::mark_arrays`
+    pub fn array_metatable(&self) -> TableRef {
+        serialize::array_metatable(&self.state)
+    }
+    // Keeps a value alive until the key is dropped or removed, for host code which holds on to tables, functions,
+    // threads or userdata across calls, like event handlers
+    pub fn create_registry_value<T: IntoLua>(&self, value: T) -> RegistryKey {
+        let slot = self.state.reserve_registry_slot();
+        let key = RegistryKey { state: Rc::downgrade(&self.state), slot };
+        key.set(&self.state, value.into_lua(&self.state));
+        key
+    }
+    pub fn registry_value<T: FromLua>(&self, key: &RegistryKey) -> Result<T, Error> {
+        self.check_key(key)?;
+        Ok(T::from_lua(self.state.registry.get(&DataKind::integer(key.slot)), &self.state)?)
+    }
+    pub fn replace_registry_value<T: IntoLua>(&self, key: &RegistryKey, value: T) -> Result<(), Error> {
+        self.check_key(key)?;
+        key.set(&self.state, value.into_lua(&self.state));
+        Ok(())
+    }
+    // Releases the value of a key now, dropping the key does the same
+    pub fn remove_registry_value(&self, key: RegistryKey) -> Result<(), Error> {
+        self.check_key(&key)
+    }
+    pub fn owns_registry_value(&self, key: &RegistryKey) -> bool {
+        key.state.as_ptr() == Rc::as_ptr(&self.state)
+    }
+    // Values the host stores in the registry under a name, Lua code finds them in debug.getregistry()
+    pub fn set_named_registry_value<T: IntoLua>(&self, name: &str, value: T) {
+        self.state.registry.set_str(name, value.into_lua(&self.state));
+    }
+    pub fn named_registry_value<T: FromLua>(&self, name: &str) -> Result<T, Error> {
+        Ok(T::from_lua(self.state.registry.get_str(name), &self.state)?)
+    }
+    fn check_key(&self, key: &RegistryKey) -> Result<(), Error> {
+        match self.owns_registry_value(key) {
+            true => Ok(()),
+            false => Err(Error::MismatchedRegistryKey),
+        }
+    }
+    fn convert_results<R: FromLuaMulti>(&self, results: Vec<DataKind>) -> Result<R, Error> {
+        R::from_lua_args(&mut Arguments::new(results), &self.state).map_err(|bad| Error::FromLua(bad.error))
+    }
+}
+
+impl Default for Lua {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Tables with a __gc metamethod are finalized when the interpreter goes away, like when a Lua state is closed
+impl Drop for Lua {
+    fn drop(&mut self) {
+        frontend::close_state(&self.state);
+    }
+}
+
+// A value kept in the registry of the interpreter which created the key, so that it is not collected while the host
+// holds the key. It can be stored in Rust structs, the value is released when the key is dropped.
+pub struct RegistryKey {
+    // Only compared against the interpreter the key is used with, holding it keeps its address from being reused
+    state: Weak<GlobalState>,
+    slot: i64,
+}
+
+impl RegistryKey {
+    fn set(&self, state: &GlobalState, value: DataKind) {
+        // Integer keys are always valid, so this cannot fail
+        let _ = state.registry.borrow_mut().set(DataKind::integer(self.slot), value);
+    }
+}
+
+impl Drop for RegistryKey {
+    fn drop(&mut self) {
+        // A key can be dropped while the state is being torn down, when the registry may be in use
+        let Some(state) = self.state.upgrade() else {
+            return;
+        };
+        let cleared = match state.registry.try_borrow_mut() {
+            Some(mut registry) => registry.set(DataKind::integer(self.slot), DataKind::Null).is_ok(),
+            None => false,
+        };
+        // The slot is only reused once its value is gone
+        if cleared {
+            state.release_registry_slot(self.slot);
+        }
+    }
+}
+
+fn compile(source: &str, chunk_name: &str) -> Result<Rc<Prototype>, Error> {
+    compiler::compile_source(source, chunk_name)
+        .map_err(|errors| Error::Syntax(errors.iter().map(|e| e.diagnostic().render(chunk_name, source)).collect()))
+}
+
+// Lua code loaded into an interpreter, it can be run any number of times
+pub struct Chunk<'lua> {
+    lua: &'lua Lua,
+    name: String,
+    code: ChunkCode,
+    environment: Option<TableRef>,
+}
+
+enum ChunkCode {
+    Source(String),
+    Binary(Vec<u8>),
+}
+
+impl<'lua> Chunk<'lua> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    // The text of the chunk, binary chunks have none
+    pub fn source(&self) -> Option<&str> {
+        match &self.code {
+            ChunkCode::Source(source) => Some(source),
+            ChunkCode::Binary(_) => None,
+        }
+    }
+    // Runs the chunk with its own table of globals instead of the shared one, it sees nothing else of the interpreter.
+    // Scripts which are not trusted can be given only the functions they are allowed to use.
+    pub fn set_environment(mut self, environment: TableRef) -> Self {
+        self.environment = Some(environment);
+        self
+    }
+    pub fn exec(&self) -> Result<(), Error> {
+        self.run(self.compile()?)?;
+        Ok(())
+    }
+    // Runs the chunk and converts what it returns. Source which is an expression, like "1 + x", evaluates to its
+    // value.
+    pub fn eval<R: FromLuaMulti>(&self) -> Result<R, Error> {
+        let results = self.run(self.compile_expression()?)?;
+        self.lua.convert_results(results)
+    }
+    // Like `exec`, for chunks which call async host functions
+    pub async fn exec_async(&self) -> Result<(), Error> {
+        let function = self.load(self.compile()?);
+        self.lua.call_async(&function, ()).await
+    }
+    // Like `eval`, for chunks which call async host functions
+    pub async fn eval_async<R: FromLuaMulti>(&self) -> Result<R, Error> {
+        let function = self.load(self.compile_expression()?);
+        self.lua.call_async(&function, ()).await
+    }
+    // The chunk as a Lua function, which takes its arguments as `...`
+    pub fn into_function(self) -> Result<FunctionRef, Error> {
+        Ok(self.load(self.compile()?))
+    }
+    // The chunk as a binary chunk which `Lua::load` accepts, without debug information when stripped
+    pub fn dump(&self, strip: bool) -> Result<Vec<u8>, Error> {
+        Ok(compiler::dump::dump(&*self.compile()?, strip))
+    }
+    // The bytecode of every function in the chunk, like `luac -l` lists it
+    pub fn listing(&self) -> Result<String, Error> {
+        Ok(compiler::listing::list(&*self.compile()?))
+    }
+    fn compile(&self) -> Result<Rc<Prototype>, Error> {
+        match &self.code {
+            ChunkCode::Source(source) => compile(source, &self.name),
+            ChunkCode::Binary(bytes) => compiler::dump::undump(bytes)
+                .map_err(|reason| Error::BadChunk(format!("{}: bad binary format ({})", self.name, reason))),
+        }
+    }
+    fn compile_expression(&self) -> Result<Rc<Prototype>, Error> {
+        let expression = match &self.code {
+            ChunkCode::Source(source) => compile(&format!("return {}", source), &self.name).ok(),
+            ChunkCode::Binary(_) => None,
+        };
+        match expression {
+            Some(main) => Ok(main),
+            None => self.compile(),
+        }
+    }
+    fn load(&self, main: Rc<Prototype>) -> FunctionRef {
+        let environment = self.environment.clone().unwrap_or_else(|| self.lua.state.globals.clone());
+        frontend::load_chunk(&self.lua.state, main, DataKind::Table(environment))
+    }
+    fn run(&self, main: Rc<Prototype>) -> Result<Vec<DataKind>, Error> {
+        let function = DataKind::Function(self.load(main));
+        Ok(frontend::call_from_host(&self.lua.state, &function, Vec::new())?)
+    }
+}
+
+// A table of an interpreter, indexed with keys and values converted from and to Rust values
+pub struct Table<'lua> {
+    lua: &'lua Lua,
+    table: TableRef,
+}
+
+impl<'lua> Table<'lua> {
+    pub fn get<K: IntoLua, V: FromLua>(&self, key: K) -> Result<V, Error> {
+        let state = &self.lua.state;
+        let value = frontend::index_from_host(state, &DataKind::Table(self.table.clone()), &key.into_lua(state))?;
+        Ok(V::from_lua(value, state)?)
+    }
+    pub fn set<K: IntoLua, V: IntoLua>(&self, key: K, value: V) -> Result<(), Error> {
+        let state = &self.lua.state;
+        let (key, value) = (key.into_lua(state), value.into_lua(state));
+        Ok(frontend::set_index_from_host(state, &DataKind::Table(self.table.clone()), key, value)?)
+    }
+    pub fn table(&self) -> &TableRef {
+        &self.table
+    }
+}
@@ -1,19 +1,33 @@
-mod ast;
-mod err_handle;
-mod frontend;
-
 use std::fs;
 use std::path::PathBuf;
 use std::str::FromStr;
 
 use clap::Parser;
 
+use r_lua::{Limits, Lua};
+
 #[derive(Parser, Debug)]
 #[command(version)]
 struct Args {
-    /// Path to a Lua file
+    /// Path to a Lua file, or to a chunk compiled with --compile
     #[arg()]
     path: String,
+    /// Compile the file to a binary chunk at this path instead of running it
+    #[arg(long, value_name = "OUTPUT")]
+    compile: Option<String>,
+    /// List the bytecode of every function instead of running the file, like `luac -l`
+    #[arg(short, long)]
+    list: bool,
+    /// How many Lua calls can be nested before they fail with "stack overflow"
+    #[arg(long, value_name = "DEPTH", default_value_t = Limits::default().call_depth)]
+    max_call_depth: usize,
+    /// How many calls through metamethods and library functions can be nested before they fail with "C stack
+    /// overflow". Each of them uses native stack, Lua code runs on 16 MiB of it.
+    #[arg(long, value_name = "DEPTH", default_value_t = Limits::default().native_depth)]
+    max_native_depth: usize,
+    /// How many bytes of memory a script may use before allocations fail with "not enough memory"
+    #[arg(long, value_name = "BYTES")]
+    max_memory: Option<usize>,
 }
 
 fn main() {
@@ -24,20 +38,31 @@ fn main() {
         panic!("Provided path is not valid")
     }
     let extension = path.extension();
-    if !path.is_file() | extension.is_none() || extension.unwrap() != "lua" {
+    if !path.is_file() || !extension.is_some_and(|extension| extension == "lua" || extension == "luac") {
         panic!("Provided path does not correspond to a Lua file")
     }
 
-    let file_contents =
-        fs::read_to_string(path.as_path()).unwrap_or_else(|_| panic!("Failed to read Lua file"));
+    let file_contents = fs::read(path.as_path()).unwrap_or_else(|_| panic!("Failed to read Lua file"));
 
-    match ast::parse_lua_program(file_contents.as_str()) {
-        Ok(parsed_lua_program) => {
-            match frontend::enter_program(parsed_lua_program) {
-                Ok(res) => (),
-                Err(e) => e.print_error()
+    let limits = Limits { call_depth: args.max_call_depth, native_depth: args.max_native_depth, memory: args.max_memory };
+    let lua = Lua::with_limits(limits);
+    let chunk = lua.load(&file_contents, &args.path);
+    let result = match (args.list, &args.compile) {
+        (false, None) => chunk.exec(),
+        (list, compile) => chunk.listing().and_then(|listing| {
+            if list {
+                print!("{}", listing);
+            }
+            if let Some(output) = compile {
+                if let Err(e) = fs::write(output, chunk.dump(false)?) {
+                    eprintln!("error: cannot write {}: {}", output, e);
+                }
             }
-        }
-        Err(e) => e.print_error(),
+            Ok(())
+        }),
+    };
+    // A binary chunk has no lines to show
+    if let Err(e) = result {
+        eprint!("{}", e.render(chunk.name(), chunk.source()))
     }
 }
@@ -0,0 +1,41 @@
+-- Every call gets its own frame, parameters and locals never touch globals or the caller's variables
+
+m = "global m"
+local function fib(m)
+  if m < 2 then return m end
+  local a = fib(m - 1)
+  local b = fib(m - 2)
+  return a + b
+end
+assert(fib(15) == 610)
+assert(m == "global m")
+
+-- Missing arguments are nil and extra arguments are dropped
+local function pair(a, b) return a, b end
+local a, b = pair(1)
+assert(a == 1 and b == nil)
+a, b = pair(1, 2, 3, 4)
+assert(a == 1 and b == 2)
+assert(select("#", pair()) == 2)
+
+-- Extra arguments are kept by vararg functions
+local function count(first, ...) return first, select("#", ...) end
+local first, rest = count("x", nil, nil)
+assert(first == "x" and rest == 2)
+
+-- A call which fails part way leaves nothing behind
+local function fails(n)
+  local temporary = n
+  error("failed with " .. temporary)
+end
+assert(not pcall(fails, 1))
+assert(n == nil and temporary == nil)
+
+-- Each activation keeps its own locals
+local function make(label)
+  return function() return label end
+end
+local x, y = make("x"), make("y")
+assert(x() == "x" and y() == "y")
+
+print("calls ok")
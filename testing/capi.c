@@ -0,0 +1,296 @@
+/*
+** Exercises the C API from C, like an embedder and a small C module would use it. Build the library first:
+**
+**   cargo build
+**   gcc -Wall -fexceptions -Iinclude testing/capi.c -Ltarget/debug -lr_lua -o target/capi
+**   LD_LIBRARY_PATH=target/debug target/capi
+*/
+
+#include <string.h>
+
+#include "lauxlib.h"
+#include "lua.h"
+#include "lualib.h"
+
+#define CHECK(condition)                                                       \
+    do {                                                                       \
+        if (!(condition)) {                                                    \
+            fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__, __LINE__, #condition); \
+            exit(1);                                                           \
+        }                                                                      \
+    } while (0)
+
+/* Runs a chunk which must succeed */
+static void run(lua_State *L, const char *code) {
+    if (luaL_dostring(L, code) != LUA_OK) {
+        fprintf(stderr, "error: %s\n", lua_tostring(L, -1));
+        exit(1);
+    }
+}
+
+static int add(lua_State *L) {
+    lua_Integer a = luaL_checkinteger(L, 1);
+    lua_Integer b = luaL_optinteger(L, 2, 10);
+    lua_pushinteger(L, a + b);
+    return 1;
+}
+
+/* Counts its calls in an upvalue */
+static int counter(lua_State *L) {
+    lua_Integer count = lua_tointeger(L, lua_upvalueindex(1)) + 1;
+    lua_pushinteger(L, count);
+    lua_copy(L, -1, lua_upvalueindex(1));
+    return 1;
+}
+
+/* Errors raised by luaL_check* unwind the C function, it never sees a NULL string */
+static int slen(lua_State *L) {
+    lua_pushinteger(L, (lua_Integer)strlen(luaL_checkstring(L, 1)));
+    return 1;
+}
+
+static int fail(lua_State *L) {
+    return luaL_error(L, "failed with %d", 42);
+}
+
+/* Calls its first argument with the others, from C */
+static int call(lua_State *L) {
+    lua_call(L, lua_gettop(L) - 1, LUA_MULTRET);
+    return lua_gettop(L);
+}
+
+/* Sums the values of a sequence with lua_next */
+static int sum(lua_State *L) {
+    luaL_checktype(L, 1, LUA_TTABLE);
+    lua_Number total = 0;
+    lua_pushnil(L);
+    while (lua_next(L, 1) != 0) {
+        total += lua_tonumber(L, -1);
+        lua_pop(L, 1);
+    }
+    lua_pushnumber(L, total);
+    return 1;
+}
+
+/* A small module with a userdata type */
+typedef struct {
+    double x, y;
+} Point;
+
+static int point_new(lua_State *L) {
+    Point *point = (Point *)lua_newuserdatauv(L, sizeof(Point), 0);
+    point->x = luaL_checknumber(L, 1);
+    point->y = luaL_checknumber(L, 2);
+    luaL_setmetatable(L, "Point");
+    return 1;
+}
+
+static int point_length2(lua_State *L) {
+    Point *point = (Point *)luaL_checkudata(L, 1, "Point");
+    lua_pushnumber(L, point->x * point->x + point->y * point->y);
+    return 1;
+}
+
+static int point_tostring(lua_State *L) {
+    Point *point = (Point *)luaL_checkudata(L, 1, "Point");
+    lua_pushfstring(L, "(%g, %g)", point->x, point->y);
+    return 1;
+}
+
+static const luaL_Reg point_methods[] = {
+    {"length2", point_length2},
+    {NULL, NULL},
+};
+
+static const luaL_Reg point_module[] = {
+    {"new", point_new},
+    {NULL, NULL},
+};
+
+static int open_point(lua_State *L) {
+    luaL_newmetatable(L, "Point");
+    luaL_newlib(L, point_methods);
+    lua_setfield(L, -2, "__index");
+    lua_pushcfunction(L, point_tostring);
+    lua_setfield(L, -2, "__tostring");
+    lua_pop(L, 1);
+    luaL_newlib(L, point_module);
+    return 1;
+}
+
+static const char *reader(lua_State *L, void *data, size_t *size) {
+    const char **pieces = (const char **)data;
+    const char *piece = *pieces;
+    (void)L;
+    if (piece == NULL)
+        return NULL;
+    *pieces = NULL;
+    *size = strlen(piece);
+    return piece;
+}
+
+int main(void) {
+    lua_State *L = luaL_newstate();
+    luaL_openlibs(L);
+    CHECK(lua_version(L) == 504);
+
+    /* Values pushed and read back */
+    lua_pushinteger(L, 7);
+    lua_pushnumber(L, 2.5);
+    lua_pushstring(L, "text");
+    lua_pushboolean(L, 1);
+    lua_pushnil(L);
+    CHECK(lua_gettop(L) == 5);
+    CHECK(lua_isinteger(L, 1) && lua_tointeger(L, 1) == 7);
+    CHECK(lua_tonumber(L, 2) == 2.5);
+    CHECK(strcmp(lua_tostring(L, -3), "text") == 0);
+    CHECK(lua_toboolean(L, 4) && lua_isnil(L, -1) && lua_isnone(L, 6));
+    CHECK(strcmp(luaL_typename(L, 3), "string") == 0);
+    size_t len;
+    CHECK(strcmp(lua_tolstring(L, 1, &len), "7") == 0 && len == 1 && lua_type(L, 1) == LUA_TSTRING);
+    lua_pushstring(L, "0x10");
+    CHECK(lua_isnumber(L, -1) && lua_tointeger(L, -1) == 16);
+    lua_settop(L, 0);
+
+    /* Stack manipulation */
+    lua_pushinteger(L, 1);
+    lua_pushinteger(L, 2);
+    lua_pushinteger(L, 3);
+    lua_insert(L, 1);
+    CHECK(lua_tointeger(L, 1) == 3 && lua_tointeger(L, 3) == 2);
+    lua_remove(L, 1);
+    CHECK(lua_gettop(L) == 2 && lua_tointeger(L, 1) == 1);
+    lua_replace(L, 1);
+    CHECK(lua_gettop(L) == 1 && lua_tointeger(L, 1) == 2);
+    CHECK(lua_absindex(L, -1) == 1);
+    lua_pop(L, 1);
+
+    /* Globals, fields and chunks */
+    lua_pushinteger(L, 40);
+    lua_setglobal(L, "answer");
+    run(L, "t = {name = 'lua', list = {10, 20, 30}}; answer = answer + 2");
+    CHECK(lua_getglobal(L, "answer") == LUA_TNUMBER && lua_tointeger(L, -1) == 42);
+    CHECK(lua_getglobal(L, "t") == LUA_TTABLE);
+    CHECK(lua_getfield(L, -1, "name") == LUA_TSTRING && strcmp(lua_tostring(L, -1), "lua") == 0);
+    lua_pop(L, 1);
+    CHECK(lua_getfield(L, -1, "list") == LUA_TTABLE && luaL_len(L, -1) == 3);
+    CHECK(lua_geti(L, -1, 2) == LUA_TNUMBER && lua_tointeger(L, -1) == 20);
+    lua_pop(L, 2);
+    lua_pushstring(L, "new");
+    lua_setfield(L, -2, "name");
+    lua_settop(L, 0);
+    run(L, "assert(t.name == 'new')");
+    CHECK(luaL_loadstring(L, "return 1, 2, 3") == LUA_OK);
+    CHECK(lua_pcall(L, 0, LUA_MULTRET, 0) == LUA_OK && lua_gettop(L) == 3 && lua_tointeger(L, 3) == 3);
+    lua_settop(L, 0);
+
+    /* Loading with a reader, and failures to load */
+    const char *source = "return ...";
+    CHECK(lua_load(L, reader, &source, "=reader", NULL) == LUA_OK);
+    lua_pushstring(L, "arg");
+    lua_call(L, 1, 1);
+    CHECK(strcmp(lua_tostring(L, -1), "arg") == 0);
+    lua_pop(L, 1);
+    CHECK(luaL_loadbuffer(L, "x =", 3, "=broken") == LUA_ERRSYNTAX);
+    CHECK(strstr(lua_tostring(L, -1), "broken:1:") != NULL);
+    lua_pop(L, 1);
+    CHECK(luaL_loadfile(L, "testing/missing.lua") == LUA_ERRFILE);
+    CHECK(strstr(lua_tostring(L, -1), "cannot open testing/missing.lua") != NULL);
+    lua_pop(L, 1);
+
+    /* C functions called from Lua */
+    lua_register(L, "add", add);
+    lua_pushinteger(L, 0);
+    lua_pushcclosure(L, counter, 1);
+    lua_setglobal(L, "counter");
+    lua_register(L, "fail", fail);
+    lua_register(L, "call", call);
+    lua_register(L, "sum", sum);
+    lua_register(L, "slen", slen);
+    run(L, "assert(add(1, 2) == 3 and add(5) == 15)");
+    run(L, "assert(counter() == 1 and counter() == 2 and counter() == 3)");
+    run(L, "assert(sum({1, 2, 3.5}) == 6.5)");
+    run(L, "local a, b = call(function(x, y) return y, x end, 1, 2); assert(a == 2 and b == 1)");
+
+    /* Errors raised by C functions, and errors going through them */
+    run(L, "local ok, msg = pcall(add, 'x')\n"
+           "assert(not ok and msg == \"bad argument #1 to 'add' (number expected, got string)\", msg)");
+    run(L, "local ok, msg = pcall(add, 1.5)\n"
+           "assert(msg == \"bad argument #1 to 'add' (number has no integer representation)\", msg)");
+    run(L, "local ok, msg = pcall(function() add() end)\n"
+           "assert(msg == \"[string \\\"local ok, msg = pcall(function() add() end)...\\\"]:1: bad argument #1 to 'add' (number expected, got no value)\", msg)");
+    run(L, "local ok, msg = pcall(function()\n fail()\n end)\n"
+           "assert(msg:find(':2: failed with 42$'), msg)");
+    run(L, "local ok, msg = pcall(call, error, {code = 1})\n"
+           "assert(not ok and msg.code == 1)");
+    run(L, "assert(slen('hello') == 5)\n"
+           "local ok, msg = pcall(slen, nil)\n"
+           "assert(not ok and msg == \"bad argument #1 to 'slen' (string expected, got nil)\", msg)");
+    run(L, "local ok, msg = pcall(sum, 5)\n"
+           "assert(msg == \"bad argument #1 to 'sum' (table expected, got number)\", msg)");
+
+    /* Protected calls from C */
+    lua_getglobal(L, "error");
+    lua_pushstring(L, "boom");
+    CHECK(lua_pcall(L, 1, 0, 0) == LUA_ERRRUN && strcmp(lua_tostring(L, -1), "boom") == 0);
+    lua_pop(L, 1);
+    run(L, "function handler(msg) return 'handled: ' .. msg end");
+    lua_getglobal(L, "handler");
+    lua_getglobal(L, "fail");
+    CHECK(lua_pcall(L, 0, 0, 1) == LUA_ERRRUN);
+    CHECK(strcmp(lua_tostring(L, -1), "handled: failed with 42") == 0);
+    lua_settop(L, 0);
+
+    /* Coroutines yield across C functions calling Lua */
+    run(L, "local co = coroutine.wrap(function() return call(function() coroutine.yield(1) return 2 end) end)\n"
+           "assert(co() == 1 and co() == 2)");
+
+    /* A module with a userdata type */
+    CHECK(open_point(L) == 1);
+    lua_setglobal(L, "point");
+    run(L, "local p = point.new(3, 4)\n"
+           "assert(p:length2() == 25 and tostring(p) == '(3, 4)')\n"
+           "local ok, msg = pcall(function() return p.length2({}) end)\n"
+           "assert(msg:find(\":3: bad argument #1 to 'length2' %(Point expected, got table%)$\"), msg)");
+    void *block = lua_newuserdatauv(L, 64, 2);
+    CHECK(block != NULL && ((uintptr_t)block % 16) == 0 && lua_rawlen(L, -1) == 64);
+    CHECK(lua_touserdata(L, -1) == block && luaL_testudata(L, -1, "Point") == NULL);
+    lua_pushstring(L, "uservalue");
+    CHECK(lua_setiuservalue(L, -2, 2) == 1);
+    CHECK(lua_getiuservalue(L, -1, 2) == LUA_TSTRING && lua_getiuservalue(L, -2, 3) == LUA_TNONE);
+    lua_settop(L, 0);
+
+    /* Registry references */
+    lua_newtable(L);
+    int ref = luaL_ref(L, LUA_REGISTRYINDEX);
+    CHECK(ref > 0 && lua_gettop(L) == 0);
+    CHECK(lua_rawgeti(L, LUA_REGISTRYINDEX, ref) == LUA_TTABLE);
+    lua_pop(L, 1);
+    lua_pushnil(L);
+    CHECK(luaL_ref(L, LUA_REGISTRYINDEX) == LUA_REFNIL);
+    luaL_unref(L, LUA_REGISTRYINDEX, ref);
+    CHECK(lua_rawgeti(L, LUA_REGISTRYINDEX, ref) == LUA_TNIL);
+    lua_pop(L, 1);
+    lua_pushglobaltable(L);
+    CHECK(lua_getfield(L, -1, "answer") == LUA_TNUMBER);
+    lua_settop(L, 0);
+
+    /* Comparisons, concatenation and length with metamethods */
+    run(L, "v = setmetatable({}, {__len = function() return 5 end, __concat = function(a, b) return 'meta' end})");
+    lua_getglobal(L, "v");
+    CHECK(luaL_len(L, -1) == 5);
+    lua_pushstring(L, "x");
+    lua_concat(L, 2);
+    CHECK(strcmp(lua_tostring(L, -1), "meta") == 0);
+    lua_pushinteger(L, 1);
+    lua_pushnumber(L, 1.0);
+    CHECK(lua_compare(L, -1, -2, LUA_OPEQ) && lua_rawequal(L, -1, -2));
+    lua_pushinteger(L, 2);
+    CHECK(lua_compare(L, -2, -1, LUA_OPLT) && !lua_compare(L, -1, -2, LUA_OPLE));
+    CHECK(strcmp(luaL_tolstring(L, -1, NULL), "2") == 0);
+    lua_settop(L, 0);
+
+    lua_close(L);
+    printf("capi ok\n");
+    return 0;
+}
@@ -0,0 +1,114 @@
+-- Values passed through resume and yield
+local co = coroutine.create(function(a, b)
+  assert(coroutine.isyieldable())
+  local c = coroutine.yield(a + b)
+  local d, e = coroutine.yield(c * 2)
+  return d + e, "done"
+end)
+
+assert(coroutine.status(co) == "suspended")
+local ok, value = coroutine.resume(co, 1, 2)
+assert(ok and value == 3)
+ok, value = coroutine.resume(co, 10)
+assert(ok and value == 20)
+local ok2, sum, msg = coroutine.resume(co, 3, 4)
+assert(ok2 and sum == 7 and msg == "done")
+assert(coroutine.status(co) == "dead")
+ok, msg = coroutine.resume(co)
+assert(not ok and msg == "cannot resume dead coroutine")
+assert(not coroutine.isyieldable())
+
+-- Generators with wrap, yielding from nested Lua calls
+local function walk(t)
+  for _, v in ipairs(t) do
+    if type(v) == "table" then
+      walk(v)
+    else
+      coroutine.yield(v)
+    end
+  end
+end
+
+local values = {}
+for v in coroutine.wrap(function() walk({1, {2, {3, 4}}, 5}) end) do
+  values[#values + 1] = v
+end
+assert(#values == 5 and values[3] == 3 and values[5] == 5)
+
+-- Yielding from inside of a metamethod
+local meta = setmetatable({}, {
+  __index = function(_, key)
+    return coroutine.yield(key)
+  end,
+})
+local lookup = coroutine.wrap(function()
+  return "got " .. meta.answer
+end)
+assert(lookup() == "answer")
+assert(lookup(42) == "got 42")
+
+-- status from the inside and the outside
+local outer
+outer = coroutine.create(function()
+  assert(coroutine.status(outer) == "running")
+  local inner = coroutine.create(function()
+    assert(coroutine.status(outer) == "normal")
+    local running, is_main = coroutine.running()
+    assert(not is_main and running ~= outer)
+  end)
+  assert(coroutine.resume(inner))
+end)
+assert(coroutine.resume(outer))
+local _, is_main = coroutine.running()
+assert(is_main)
+
+-- Errors inside of a coroutine are returned by resume
+local failing = coroutine.create(function()
+  local x = nil
+  return x.field
+end)
+ok, msg = coroutine.resume(failing)
+assert(not ok and msg:find("attempt to index a nil value (local 'x')", 1, true))
+assert(coroutine.status(failing) == "dead")
+
+-- wrap propagates errors
+local ok3 = coroutine.resume(coroutine.create(function()
+  coroutine.wrap(function() local y = {} .. "" end)()
+end))
+assert(not ok3)
+
+-- close runs pending to-be-closed variables
+local log = {}
+local function closable(name)
+  return setmetatable({}, {
+    __close = function(_, err)
+      log[#log + 1] = name
+      assert(err == nil)
+    end,
+  })
+end
+
+local pending = coroutine.create(function()
+  local first <close> = closable("first")
+  local second <close> = closable("second")
+  coroutine.yield()
+  log[#log + 1] = "unreachable"
+end)
+coroutine.resume(pending)
+assert(coroutine.close(pending))
+assert(coroutine.status(pending) == "dead")
+assert(#log == 2 and log[1] == "second" and log[2] == "first")
+
+-- Closing a coroutine that died with an error reports the error
+ok, msg = coroutine.close(failing)
+assert(not ok and msg:find("attempt to index a nil value (local 'x')", 1, true))
+assert(coroutine.close(coroutine.create(print)))
+
+-- Code loaded inside a coroutine compiles, and nesting it too deeply fails with an error there as well
+local loaded = coroutine.wrap(function() return load("return 1 + 1") end)()
+assert(loaded() == 2)
+local deep = string.rep("(", 300) .. "1" .. string.rep(")", 300)
+local _, err = coroutine.wrap(function() return load("return " .. deep) end)()
+assert(err:find("too many syntax levels", 1, true))
+
+print("coroutines ok")
@@ -0,0 +1,57 @@
+-- string.dump saves Lua functions as the binary chunks that `r_lua --compile` writes
+
+local function add(a, b) return a + b end
+local chunk = string.dump(add)
+assert(chunk:sub(1, 4) == "\27Lua")
+
+-- Stripping leaves out the debug information
+local stripped = string.dump(add, true)
+assert(stripped:sub(1, 4) == "\27Lua" and #stripped < #chunk)
+
+-- Nested functions are saved along with the function containing them
+local function outer()
+  local function inner() return "inner" end
+  return inner
+end
+assert(#string.dump(outer) > #string.dump(add))
+
+local ok, msg = pcall(string.dump, print)
+assert(not ok and msg:find("unable to dump given function"))
+ok, msg = pcall(string.dump, 1)
+assert(not ok and msg:find("bad argument #1 to 'dump' %(function expected, got number%)"))
+
+-- A changed chunk can leave an upvalue open after its register is gone, using it fails instead of reading past the stack
+local function make()
+  local x = 5
+  return function() return x end
+end
+local function checksum(bytes)
+  local hash = 0xcbf29ce484222325
+  for i = 1, #bytes do
+    hash = (hash ~ bytes:byte(i)) * 0x100000001b3
+  end
+  local out = ""
+  for i = 0, 7 do
+    out = out .. string.char((hash >> (8 * i)) & 0xff)
+  end
+  return out
+end
+-- Clears the k flag of `RETURN 1 2 0k`, so that the return of make no longer closes x
+local patched, count = string.dump(make, true):gsub("\xc3\x80\x02\x00", "\xc3\x00\x02\x00")
+assert(count == 1)
+local body = patched:sub(33, -9)
+local broken = assert(load(patched:sub(1, 32) .. body .. checksum(body), "broken", "b"))
+local get = coroutine.wrap(function() local a, b, c = 1, 2, 3 return (broken()) end)()
+ok, msg = pcall(get)
+assert(not ok and msg:find("past the top of the stack"))
+
+-- Turns `FORPREP 1 0` into `JMP 0`, so that the loop runs on values its prep never checked
+local function loop(a) for i = a, 2 do end end
+patched, count = string.dump(loop, true):gsub("\xc7\x00\x00\x00", "\xb5\xff\xff\x7f")
+assert(count == 1)
+body = patched:sub(33, -9)
+broken = assert(load(patched:sub(1, 32) .. body .. checksum(body), "broken", "b"))
+ok, msg = pcall(broken, "x")
+assert(not ok and msg:find("'for' loop was not prepared"))
+
+print("dump ok")
@@ -0,0 +1,135 @@
+-- error values can be anything and pcall returns them untouched
+local ok, err = pcall(error, {code = 42})
+assert(not ok and type(err) == "table" and err.code == 42)
+
+ok, err = pcall(error)
+assert(not ok and err == nil)
+
+ok, err = pcall(error, "plain", 0)
+assert(not ok and err == "plain")
+
+-- pcall returns every result of a successful call
+local results = {pcall(function(...) return ... end, 1, 2, 3)}
+assert(#results == 4 and results[1] == true and results[4] == 3)
+
+-- Internal failures are catchable with the standard message text
+local function message_of(f)
+  local ok, err = pcall(f)
+  assert(not ok)
+  return err
+end
+
+local up
+assert(message_of(function() local x; return x.y end):find("attempt to index a nil value (local 'x')", 1, true))
+assert(message_of(function() return missing + 1 end):find("attempt to perform arithmetic on a nil value (global 'missing')", 1, true))
+assert(message_of(function() return up .. "" end):find("attempt to concatenate a nil value (upvalue 'up')", 1, true))
+assert(message_of(function() local t = {}; t.field.x = 1 end):find("attempt to index a nil value (field 'field')", 1, true))
+assert(message_of(function() local t = {}; t:method() end):find("attempt to call a nil value (method 'method')", 1, true))
+assert(message_of(function() return {} < {} end):find("attempt to compare two table values", 1, true))
+assert(message_of(function() return 1 // 0 end):find("attempt to perform 'n//0'", 1, true))
+assert(message_of(function() setmetatable(1, {}) end):find("bad argument #1 to 'setmetatable' (table expected, got number)", 1, true))
+
+-- xpcall passes the error to the message handler
+ok, err = xpcall(function() error({}) end, function(e) return type(e) end)
+assert(not ok and err == "table")
+ok, err = xpcall(function(a, b) return a + b end, print, 20, 22)
+assert(ok and err == 42)
+
+-- Errors thrown by the message handler replace the original error
+ok, err = xpcall(error, function() error("handler failed", 0) end, "original")
+assert(not ok and err == "handler failed")
+
+-- assert raises its message as is
+ok, err = pcall(assert, false, {1})
+assert(not ok and type(err) == "table")
+ok, err = pcall(assert, nil)
+assert(not ok and err == "assertion failed!")
+
+-- to-be-closed variables see the error that is unwinding through them
+local seen
+ok, err = pcall(function()
+  local guard <close> = setmetatable({}, {__close = function(_, e) seen = e end})
+  error("unwinding", 0)
+end)
+assert(not ok and err == "unwinding" and seen == "unwinding")
+
+-- A coroutine can yield from inside of a pcall
+local co = coroutine.wrap(function()
+  local ok, err = pcall(function()
+    coroutine.yield("paused")
+    error("resumed", 0)
+  end)
+  return ok, err
+end)
+assert(co() == "paused")
+local status, message = co()
+assert(status == false and message == "resumed")
+
+-- Messages carry the chunk name and the line the error happened on
+local _, here = pcall(function() error("", 1) end)
+local chunk = here:match("^(.*):%d+: $")
+assert(chunk)
+local function position(line)
+  return chunk .. ":" .. line .. ": "
+end
+
+ok, err = pcall(function()
+  local t = {}
+  return t.missing
+    .field
+end)
+assert(err == position(79) .. "attempt to index a nil value (field 'missing')")
+
+ok, err = pcall(function()
+  return 1 +
+    nil
+end)
+assert(err == position(84) .. "attempt to perform arithmetic on a nil value")
+
+local function raise(level)
+  error("raised", level)
+end
+ok, err = pcall(function() raise(1) end)
+assert(err == position(90) .. "raised")
+ok, err = pcall(function() raise(2) end)
+assert(err == position(94) .. "raised")
+
+-- Natives called directly by a native have no position to report
+ok, err = pcall(error, "direct")
+assert(err == "direct")
+ok, err = pcall(string.rep)
+assert(err == "bad argument #1 to 'rep' (string expected, got no value)")
+ok, err = pcall(function() string.rep() end)
+assert(err == position(102) .. "bad argument #1 to 'rep' (string expected, got no value)")
+
+-- xpcall handlers run before the stack unwinds, so debug.traceback sees where the error happened
+local function fails()
+  local value = nil
+  return value.field
+end
+local function calls_fails()
+  return fails()
+end
+ok, err = xpcall(calls_fails, debug.traceback)
+assert(not ok)
+assert(err:find(position(108) .. "attempt to index a nil value (local 'value')\nstack traceback:\n", 1, true) == 1)
+-- calls_fails returned by calling fails, its frame was replaced by the tail call
+assert(err:find("\t" .. position(108) .. "in function <", 1, true))
+assert(err:find("\n\t(...tail calls...)\n\t[C]: in function 'xpcall'", 1, true))
+
+-- Non-string messages are passed through
+local object = {}
+assert(debug.traceback(object) == object)
+assert(debug.traceback("message", 50) == "message\nstack traceback:")
+ok, err = xpcall(error, debug.traceback, object)
+assert(err == object)
+
+-- A pcall inside of an xpcall hides errors from the outer handler
+local handled = 0
+ok, err = xpcall(function()
+  pcall(error, "inner")
+  error("outer", 0)
+end, function(e) handled = handled + 1; return e end)
+assert(err == "outer" and handled == 1)
+
+print("errors ok")
@@ -0,0 +1,178 @@
+-- collectgarbage frees tables and closures which only reference each other
+
+local function cycles(n)
+  for _ = 1, n do
+    local object = {}
+    object.self = object
+    object.method = function() return object end
+    local a, b = {}, {}
+    a.next, b.next = b, a
+  end
+end
+
+collectgarbage()
+local before = collectgarbage("count")
+collectgarbage("stop")
+assert(collectgarbage("isrunning") == false)
+cycles(2000)
+local grown = collectgarbage("count")
+assert(grown > before)
+collectgarbage("collect")
+assert(collectgarbage("count") < grown)
+collectgarbage("restart")
+assert(collectgarbage("isrunning"))
+
+-- The collector also runs on its own while the program allocates
+cycles(20000)
+assert(collectgarbage("count") < grown * 4)
+
+-- Reachable objects survive, even when they form a cycle
+local kept = {}
+kept.self = kept
+kept.name = "kept"
+local function capture() return kept end
+collectgarbage()
+assert(kept.self == kept and capture().name == "kept")
+
+-- Values referenced only from registers and upvalues are roots
+local counter = 0
+local function increment() counter = counter + 1; return counter end
+collectgarbage()
+assert(increment() == 1)
+
+-- Switching modes returns the previous one
+assert(collectgarbage("generational") == "incremental")
+cycles(2000)
+assert(collectgarbage("step") == true)
+assert(collectgarbage("incremental", 150, 200) == "generational")
+assert(collectgarbage("incremental") == "incremental")
+assert(type(collectgarbage("step", 1)) == "boolean")
+
+-- Weak tables do not keep their keys or values alive
+local weak_values = setmetatable({}, { __mode = "v" })
+local weak_keys = setmetatable({}, { __mode = "k" })
+local kept_key = {}
+weak_values[1], weak_values[2], weak_values.name = {}, kept, "strings stay"
+weak_keys[{}], weak_keys[kept_key] = true, true
+collectgarbage()
+assert(weak_values[1] == nil and weak_values[2] == kept and weak_values.name == "strings stay")
+local count = 0
+for key in pairs(weak_keys) do
+  count = count + 1
+  assert(key == kept_key)
+end
+assert(count == 1)
+
+-- A weak-keyed table is an ephemeron table, a value referencing its own key does not keep the entry alive
+local cache = setmetatable({}, { __mode = "k" })
+do
+  local key = {}
+  cache[key] = { owner = key }
+end
+cache[kept_key] = { owner = kept_key }
+collectgarbage()
+count = 0
+for key, value in pairs(cache) do
+  count = count + 1
+  assert(key == kept_key and value.owner == kept_key)
+end
+assert(count == 1)
+
+-- Coroutines are removed from weak tables once nothing else holds them, the main thread never is
+local thread_keys = setmetatable({}, { __mode = "k" })
+local thread_values = setmetatable({}, { __mode = "v" })
+local kept_thread = coroutine.create(print)
+thread_keys[coroutine.create(print)], thread_keys[kept_thread], thread_keys[coroutine.running()] = 1, 2, 3
+thread_values[1], thread_values[2] = coroutine.create(print), kept_thread
+collectgarbage()
+count = 0
+for key, value in pairs(thread_keys) do
+  count = count + 1
+  assert((key == kept_thread and value == 2) or (key == coroutine.running() and value == 3))
+end
+assert(count == 2 and thread_values[1] == nil and thread_values[2] == kept_thread)
+
+-- Finalizers run once when a table becomes unreachable, the table can be resurrected by its finalizer
+local finalized = {}
+local resurrected
+do
+  local object = setmetatable({ name = "first" }, { __gc = function(o) finalized[#finalized + 1] = o.name end })
+  local other = setmetatable({ name = "second" }, { __gc = function(o)
+    finalized[#finalized + 1] = o.name
+    resurrected = o
+  end })
+  object.other, other.object = other, object
+end
+collectgarbage()
+assert(#finalized == 2 and finalized[1] == "second" and finalized[2] == "first")
+assert(resurrected.name == "second" and resurrected.object.name == "first")
+resurrected = nil
+collectgarbage()
+assert(#finalized == 2)
+
+-- Only a __gc field present when the metatable is set counts, and errors in finalizers are ignored
+local late = {}
+setmetatable({}, late)
+late.__gc = function() finalized[#finalized + 1] = "late" end
+setmetatable({}, { __gc = function() error("ignored") end })
+collectgarbage()
+assert(#finalized == 2)
+
+-- Tables which are still reachable are finalized when the program ends
+kept_alive = setmetatable({}, { __gc = function() print("finalized at exit") end })
+
+-- The registry keeps what it holds alive without any other reference to it
+local registry = debug.getregistry()
+local watched = setmetatable({}, {__mode = "v"})
+registry.kept = {}
+watched[1] = registry.kept
+collectgarbage()
+assert(watched[1] == debug.getregistry().kept)
+registry.kept = nil
+collectgarbage()
+assert(watched[1] == nil)
+
+-- Suspended coroutines are traversed like other objects, one only reachable from itself is collected with what it holds
+local freed = 0
+for _ = 1, 100 do
+  local co
+  co = coroutine.create(function()
+    local me, guard = co, setmetatable({}, { __gc = function() freed = freed + 1 end })
+    coroutine.yield()
+  end)
+  coroutine.resume(co)
+end
+collectgarbage()
+assert(freed == 100)
+
+-- What a suspended coroutine holds survives while the coroutine is reachable, and the variables closures captured from
+-- it while the closures are
+local escaped
+local suspended = coroutine.wrap(function()
+  local t = { 5 }
+  coroutine.yield()
+  collectgarbage()
+  coroutine.yield(t[1])
+end)
+suspended()
+do
+  local co = coroutine.create(function() local t = { 6 } escaped = function() return t end coroutine.yield() end)
+  coroutine.resume(co)
+end
+collectgarbage()
+assert(suspended() == 5 and escaped()[1] == 6)
+
+-- The count follows what is allocated and drops once garbage is collected
+collectgarbage()
+local before = collectgarbage("count")
+local big = {}
+for i = 1, 10000 do big[i] = string.rep("x", 100) .. i end
+assert(collectgarbage("count") > before + 1000)
+big = nil
+collectgarbage()
+assert(collectgarbage("count") < before + 100)
+
+local ok, msg = pcall(collectgarbage, "bogus")
+assert(not ok and msg:find("bad argument #1 to 'collectgarbage' %(invalid option 'bogus'%)"))
+
+print("gc ok")
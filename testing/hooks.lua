@@ -0,0 +1,74 @@
+-- Line hooks see each new line, and lines a loop jumps back to
+local lines = {}
+local function record(event, line)
+  lines[#lines + 1] = line
+end
+debug.sethook(record, "l")
+local a = 1
+for i = 1, 2 do
+  a = a + i
+end
+debug.sethook()
+assert(#lines == 7 and lines[1] == 7 and lines[2] == 8 and lines[3] == 9 and lines[4] == 8 and lines[7] == 11)
+
+-- A return on a line of its own is a new line
+local function returns(c)
+  c = c + 1
+  return c
+end
+lines = {}
+debug.sethook(record, "l")
+returns(1)
+debug.sethook()
+assert(#lines == 4 and lines[1] == 21 and lines[2] == 16 and lines[3] == 17 and lines[4] == 22)
+
+-- Call and return hooks name their event, tail calls are told apart
+local events = {}
+local function leaf() return 1 end
+local function tail() return leaf() end
+debug.sethook(function(event) events[#events + 1] = event end, "cr")
+tail()
+debug.sethook()
+local seen = ""
+for _, event in ipairs(events) do seen = seen .. event .. ";" end
+assert(seen == "return;call;tail call;return;call;")
+
+-- Count hooks run every count instructions
+local count = 0
+debug.sethook(function(event) assert(event == "count"); count = count + 1 end, "", 10)
+for i = 1, 1000 do end
+debug.sethook()
+assert(count >= 100 and count < 1000)
+
+-- gethook returns what sethook set
+local function hook() end
+debug.sethook(hook, "cl", 5)
+local current, mask, every = debug.gethook()
+debug.sethook()
+assert(current == hook and mask == "cl" and every == 5)
+assert(debug.gethook() == nil)
+
+-- Errors raised by a hook can be caught, which stops a loop that never ends
+local ok, err = pcall(function()
+  debug.sethook(function() error("too long", 0) end, "", 1000)
+  while true do end
+end)
+debug.sethook()
+assert(not ok and err == "too long")
+
+-- A hook which yields suspends the coroutine it runs in
+local co = coroutine.create(function()
+  local sum = 0
+  for i = 1, 100 do sum = sum + i end
+  return sum
+end)
+local resumes = 0
+repeat
+  debug.sethook(function() coroutine.yield() end, "", 50)
+  local ok, sum = coroutine.resume(co)
+  debug.sethook()
+  resumes = resumes + 1
+until coroutine.status(co) == "dead"
+assert(resumes > 1)
+
+print("hooks ok")
@@ -0,0 +1,83 @@
+-- Lua calls run on the virtual machine's own stack, so recursion is only limited by the size of that stack
+
+local function depth(n)
+  if n == 0 then return 0 end
+  return 1 + depth(n - 1)
+end
+assert(depth(100000) == 100000)
+
+local function fib(n)
+  if n < 2 then return n end
+  return fib(n - 1) + fib(n - 2)
+end
+assert(fib(20) == 6765)
+
+-- Running out of stack is an error which can be caught
+local function forever(n) return 1 + forever(n + 1) end
+local ok, msg = pcall(forever, 1)
+assert(not ok and msg:find("stack overflow"))
+
+-- Recursion through metamethods and library functions runs on the native stack, which has its own limit
+local recursive = setmetatable({}, { __index = function(t, k) return t[k] end })
+ok, msg = pcall(function() return recursive.field end)
+assert(not ok and msg:find("C stack overflow"))
+local function through_gsub() return (string.gsub("x", "x", through_gsub)) end
+ok, msg = coroutine.wrap(function() return pcall(through_gsub) end)()
+assert(not ok and msg:find("C stack overflow"))
+-- A message handler still runs when the limit is reached
+ok, msg = xpcall(function() return recursive.field end, debug.traceback)
+assert(not ok and msg:find("C stack overflow") and msg:find("stack traceback:", 1, true))
+
+-- Tail calls reuse the caller's frame, so unbounded tail recursion runs in constant space
+local function countdown(n)
+  if n == 0 then return "done" end
+  return countdown(n - 1)
+end
+assert(countdown(3000000) == "done")
+
+local is_odd
+local function is_even(n)
+  if n == 0 then return true end
+  return is_odd(n - 1)
+end
+function is_odd(n)
+  if n == 0 then return false end
+  return is_even(n - 1)
+end
+assert(is_even(1000000) and not is_even(1000001))
+
+-- Tail calls to native functions and callable tables return their results
+local function to_string(x) return tostring(x) end
+assert(to_string(5) == "5" and select("#", to_string(5)) == 1)
+local callable = setmetatable({}, { __call = function(_, x) return x * 2 end })
+local function call_table(x) return callable(x) end
+assert(call_table(21) == 42)
+local co = coroutine.wrap(function(...) return select("#", ...) end)
+assert(co(1, 2, 3) == 3)
+
+-- Tail calls from a chunk or a coroutine body replace the frame the chunk or the coroutine was entered with, in place
+assert(load("local function f() return 5 end return f()")() == 5)
+local body = coroutine.wrap(function() local function f(...) return ... end return f(1, 2) end)
+assert(select("#", body()) == 2)
+local deep = coroutine.wrap(function() return is_even(1000) end)
+assert(deep() == true)
+local ok, message = pcall(load("local function f() error('tail') end return f()"))
+assert(not ok and message:find("tail"))
+
+-- The frames replaced by tail calls are marked in tracebacks
+local function inner() error("in tail call") end
+local function outer() return inner() end
+local _, traceback = xpcall(outer, debug.traceback)
+assert(traceback:find("(...tail calls...)", 1, true))
+
+-- Upvalues stay shared after the frame which declared them returns
+local function counter()
+  local count = 0
+  return function() count = count + 1 return count end, function() return count end
+end
+local increment, get = counter()
+increment()
+increment()
+assert(get() == 2)
+
+print("recursion ok")
@@ -0,0 +1,108 @@
+-- Locals, upvalues and globals are resolved before the program runs
+
+local a = 1
+local function outer()
+  local b = 2
+  local function inner()
+    a = a + b
+    return a
+  end
+  return inner
+end
+local counter = outer()
+assert(counter() == 3 and counter() == 5 and a == 5)
+
+-- A redeclared local shadows the earlier one, and its value is read before it is declared
+local x = 1
+local x = x + 1
+assert(x == 2)
+do
+  local x = 10
+  assert(x == 10)
+end
+assert(x == 2)
+
+for i = 1, 3 do
+  local i = i * 2
+  assert(i % 2 == 0)
+end
+
+-- Recursive local functions see themselves
+local function fact(n)
+  if n <= 1 then return 1 end
+  return n * fact(n - 1)
+end
+assert(fact(5) == 120)
+
+-- Jumping backwards leaves the scope of the locals declared after the label
+local n = 0
+::top::
+local last = n
+n = n + 1
+if n < 3 then goto top end
+assert(last == 2)
+
+repeat local done = true until done
+
+-- Each iteration gets its own locals
+local closures = {}
+for i = 1, 3 do
+  closures[i] = function() return i end
+end
+assert(closures[1]() == 1 and closures[3]() == 3)
+
+global_value = 7
+local function read_global() return global_value end
+assert(read_global() == 7)
+
+local ok, msg = pcall(function() return undefined_global.x end)
+assert(not ok and msg:find("global 'undefined_global'"))
+local missing = nil
+ok, msg = pcall(function() return missing.x end)
+assert(not ok and msg:find("upvalue 'missing'"))
+ok, msg = pcall(function() local here; return here.x end)
+assert(not ok and msg:find("local 'here'"))
+
+-- Globals are fields of _ENV, which can be replaced like any other variable
+do
+  local _ENV = {value = 1, assert = assert}
+  value = value + 1
+  assert(value == 2)
+end
+assert(value == nil)
+local function with_env(_ENV) return field end
+assert(with_env({field = "seen"}) == "seen")
+
+-- Chunks loaded with an environment see only what it holds
+local sandbox = {tostring = tostring}
+local plugin = load("runs = (runs or 0) + 1; return tostring(runs), type, os", "=plugin", "t", sandbox)
+local count, type_function, os_library = plugin()
+assert(count == "1" and type_function == nil and os_library == nil)
+assert(sandbox.runs == 1 and runs == nil)
+assert(load("return _ENV", "=global")() == _G)
+ok, msg = pcall(load("return x", "=noenv", "t", nil))
+assert(not ok and msg == "noenv:1: attempt to index a nil value (upvalue '_ENV')")
+
+-- Chunks which do not compile are reported rather than raised
+local chunk, err = load("return return", "=broken")
+assert(chunk == nil and err:find("^broken:1: "))
+chunk, err = load("x =", nil)
+assert(chunk == nil and err:find('^%[string "x ="%]:1: '))
+assert(select(2, load("return 1", "=text", "b")) == "attempt to load a text chunk (mode is 'b')")
+-- Expressions nested too deeply to compile are refused, chains of the right-associative `^` and `..` and of unary
+-- operators nest too
+for _, code in ipairs({"return 1" .. string.rep(" ^ 1", 20000), "return 1" .. string.rep(" .. 'a'", 20000),
+    "return " .. string.rep("- ", 300) .. "1", "return " .. string.rep("(", 200) .. "1" .. string.rep(")", 200)}) do
+  chunk, err = load(code, "=deep")
+  assert(chunk == nil and err:find("^deep:1: chunk has too many syntax levels %(limit is 200%)"), err)
+end
+-- Chains of left-associative operators do not
+assert(load("return 1" .. string.rep(" + 1", 250))() == 251)
+assert(load("return true" .. string.rep(" and true", 300))() == true)
+assert(load("return 1" .. string.rep(" + 1", 20000))() == 20001)
+assert(load("return -1" .. string.rep(" + -1 * 2", 300))() == -601)
+local pieces = {"return ", "1 ", "+ 2"}
+local next_piece = 0
+assert(load(function() next_piece = next_piece + 1; return pieces[next_piece] end)() == 3)
+
+print("scopes ok")
@@ -0,0 +1,63 @@
+-- Methods are found through the shared string metatable
+assert(("hello"):upper() == "HELLO")
+assert(("abc"):rep(3, "-") == "abc-abc-abc")
+assert(("hello"):sub(2, -2) == "ell" and ("hello"):sub(-3) == "llo" and ("hello"):sub(10) == "")
+assert(("abc"):reverse() == "cba" and ("ABC"):lower() == "abc")
+assert(string.len(123) == 3)
+
+local a, b, c = string.byte("ABC", 1, -1)
+assert(a == 65 and b == 66 and c == 67)
+assert(string.char(72, 105) == "Hi")
+
+-- Plain and pattern searches
+local s, e = string.find("hello world", "o w")
+assert(s == 5 and e == 7)
+s, e = string.find("a.b", ".", 1, true)
+assert(s == 2 and e == 2)
+assert(string.find("abc", "d") == nil)
+local key, value = string.match("key=val", "(%w+)=(%w+)")
+assert(key == "key" and value == "val")
+assert(string.match("  trim  ", "^%s*(.-)%s*$") == "trim")
+assert(string.match("f(a(b)c)", "%b()") == "(a(b)c)")
+local open, close = string.match("hello", "()ll()")
+assert(open == 3 and close == 5)
+assert(string.match("THE (quick) fox", "%f[%a]%a+", 5) == "quick")
+assert(string.match("abcabc", "(a)(b)c%1%2") == "a")
+
+local parts = {}
+for part in ("a,b,,c"):gmatch("([^,]*)") do
+  parts[#parts + 1] = part
+end
+assert(#parts == 4 and parts[3] == "" and parts[4] == "c")
+
+-- Replacements with strings, tables and functions
+assert(string.gsub("hello world", "o", "0") == "hell0 w0rld")
+local replaced, count = string.gsub("abc", "%w", "%0%0")
+assert(replaced == "aabbcc" and count == 3)
+assert(string.gsub("hello", "", "-") == "-h-e-l-l-o-")
+assert(string.gsub("$x $y", "%$(%w+)", {x = 1}) == "1 $y")
+assert(string.gsub("one two", "(%w+)", function(word) return #word end) == "3 3")
+assert(string.gsub("aaa", "a", "b", 2) == "bba")
+
+-- format follows C's printf
+assert(string.format("%5d|%-5d|%05.1f", 42, 7, 3.14159) == "   42|7    |003.1")
+assert(string.format("%x %X %#x %o", 255, 255, 255, 8) == "ff FF 0xff 10")
+assert(string.format("%g %g %g %g", 1e20, 0.1, 100, 2^53) == "1e+20 0.1 100 9.0072e+15")
+assert(string.format("%.3e %+d %c %5.2s|", 12345.678, 5, 65, "abc") == "1.235e+04 +5 A    ab|")
+assert(string.format("%q", "a\nb\"") == '"a\\\nb\\""')
+assert(string.format("%s %s %s", nil, true, 1.5) == "nil true 1.5")
+
+-- Errors
+assert(not pcall(string.find, "a", "[a"))
+assert(not pcall(string.format, "%y", 1))
+assert(not pcall(string.rep))
+local ok, err = pcall(string.rep, "x", nil)
+assert(err == "bad argument #2 to 'rep' (number expected, got nil)")
+ok, err = pcall(string.len)
+assert(err == "bad argument #1 to 'len' (string expected, got no value)")
+ok, err = pcall(string.char, 72, 256)
+assert(err == "bad argument #2 to 'char' (value out of range)")
+ok, err = pcall(string.rep, "x", 1.5)
+assert(err == "bad argument #2 to 'rep' (number has no integer representation)")
+
+print("strings ok")
@@ -0,0 +1,206 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread;
+use std::time::Duration;
+
+use r_lua::{Error, FunctionRef, Lua};
+
+// The tasks ready to be polled again, woken from timer threads
+#[derive(Default)]
+struct ReadyQueue {
+    ready: Mutex<VecDeque<usize>>,
+    woken: Condvar,
+}
+
+struct TaskWaker {
+    queue: Arc<ReadyQueue>,
+    task: usize,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.queue.ready.lock().unwrap().push_back(self.task);
+        self.queue.woken.notify_one();
+    }
+}
+
+// Runs the futures on the current thread until every one of them completes, polling each only when it was woken
+fn run_all<'a>(tasks: Vec<Pin<Box<dyn Future<Output = ()> + 'a>>>) {
+    let queue = Arc::new(ReadyQueue::default());
+    queue.ready.lock().unwrap().extend(0..tasks.len());
+    let mut tasks: Vec<_> = tasks.into_iter().map(Some).collect();
+    let mut left = tasks.len();
+    while left > 0 {
+        let task = {
+            let mut ready = queue.ready.lock().unwrap();
+            loop {
+                match ready.pop_front() {
+                    Some(task) => break task,
+                    None => ready = queue.woken.wait(ready).unwrap(),
+                }
+            }
+        };
+        let Some(future) = tasks[task].as_mut() else {
+            continue;
+        };
+        let waker = Waker::from(Arc::new(TaskWaker { queue: queue.clone(), task }));
+        if future.as_mut().poll(&mut Context::from_waker(&waker)).is_ready() {
+            tasks[task] = None;
+            left -= 1;
+        }
+    }
+}
+
+fn block_on<'a, T: 'a>(future: impl Future<Output = T> + 'a) -> T {
+    let output = Rc::new(RefCell::new(None));
+    let slot = output.clone();
+    run_all(vec![Box::pin(async move {
+        *slot.borrow_mut() = Some(future.await);
+    })]);
+    let value = output.borrow_mut().take().unwrap();
+    value
+}
+
+// Completes after a delay, a thread wakes the task when it is over
+struct Sleep {
+    duration: Duration,
+    done: Option<Arc<AtomicBool>>,
+}
+
+fn sleep(ms: u64) -> Sleep {
+    Sleep { duration: Duration::from_millis(ms), done: None }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        match &self.done {
+            Some(done) if done.load(Ordering::Acquire) => Poll::Ready(()),
+            Some(_) => Poll::Pending,
+            None => {
+                let done = Arc::new(AtomicBool::new(false));
+                let (flag, waker, duration) = (done.clone(), cx.waker().clone(), self.duration);
+                thread::spawn(move || {
+                    thread::sleep(duration);
+                    flag.store(true, Ordering::Release);
+                    waker.wake();
+                });
+                self.done = Some(done);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+fn setup() -> (Lua, Rc<RefCell<Vec<String>>>) {
+    let lua = Lua::new();
+    let sleep = lua.create_async_function("sleep", |ms: u64| async move {
+        sleep(ms).await;
+        Ok(())
+    });
+    lua.globals().set("sleep", sleep).unwrap();
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let entries = log.clone();
+    let record = lua.create_function("log", move |entry: String| {
+        entries.borrow_mut().push(entry);
+        Ok(())
+    });
+    lua.globals().set("log", record).unwrap();
+    (lua, log)
+}
+
+#[test]
+fn scripts_wait_for_async_functions() {
+    let (lua, log) = setup();
+    let sum: i64 = block_on(lua.load("log('before') sleep(5) log('after') return 1 + 2", "wait").eval_async()).unwrap();
+    assert_eq!(sum, 3);
+    assert_eq!(*log.borrow(), ["before", "after"]);
+}
+
+#[test]
+fn waiting_scripts_let_other_tasks_run() {
+    let (lua, log) = setup();
+    let slow = lua.load("sleep(60) log('slow')", "slow");
+    let fast = lua.load("sleep(5) log('fast')", "fast");
+    run_all(vec![
+        Box::pin(async { slow.exec_async().await.unwrap() }),
+        Box::pin(async { fast.exec_async().await.unwrap() }),
+    ]);
+    assert_eq!(*log.borrow(), ["fast", "slow"]);
+}
+
+#[test]
+fn yielding_scripts_take_turns() {
+    let (lua, log) = setup();
+    let first = lua.load("for i = 1, 3 do log('a' .. i) coroutine.yield() end", "first");
+    let second = lua.load("for i = 1, 3 do log('b' .. i) coroutine.yield() end", "second");
+    run_all(vec![
+        Box::pin(async { first.exec_async().await.unwrap() }),
+        Box::pin(async { second.exec_async().await.unwrap() }),
+    ]);
+    assert_eq!(*log.borrow(), ["a1", "b1", "a2", "b2", "a3", "b3"]);
+}
+
+#[test]
+fn async_functions_are_awaited_inside_coroutines_and_pcall() {
+    let (lua, _) = setup();
+    let function: FunctionRef = lua
+        .load(
+            "return function(ms)
+               local co = coroutine.wrap(function() sleep(ms) coroutine.yield('inner') return 'done' end)
+               local ok = pcall(sleep, ms)
+               return co(), co(), ok
+             end",
+            "nested",
+        )
+        .eval()
+        .unwrap();
+    let results: (String, String, bool) = block_on(lua.call_async(&function, 5)).unwrap();
+    assert_eq!(results, ("inner".to_owned(), "done".to_owned(), true));
+}
+
+#[test]
+fn async_errors_are_raised_at_the_call() {
+    let lua = Lua::new();
+    let fail = lua.create_async_function("fail", |message: String| async move {
+        sleep(1).await;
+        Err::<(), _>(message.into())
+    });
+    lua.globals().set("fail", fail).unwrap();
+    let caught: (bool, String) = block_on(lua.load("return pcall(fail, 'refused')", "errors").eval_async()).unwrap();
+    assert!(!caught.0);
+    assert!(caught.1.contains("refused"), "{}", caught.1);
+    let error = block_on(lua.load("\nfail('refused')", "errors").exec_async()).unwrap_err();
+    assert!(error.to_string().contains("errors:2: refused"), "{}", error);
+}
+
+#[test]
+fn async_functions_fail_outside_of_async_calls() {
+    let (lua, _) = setup();
+    let error = lua.load("sleep(1)", "sync").exec().unwrap_err();
+    assert!(error.to_string().contains("async function 'sleep' called outside of an async call"), "{}", error);
+    assert!(matches!(error, Error::Runtime(_)));
+}
+
+#[test]
+fn dropping_the_future_closes_the_script() {
+    let (lua, log) = setup();
+    let chunk = lua.load(
+        "local guard <close> = setmetatable({}, { __close = function() log('closed') end })
+         sleep(1000)
+         log('finished')",
+        "dropped",
+    );
+    let mut future = Box::pin(chunk.exec_async());
+    let waker = Waker::from(Arc::new(TaskWaker { queue: Arc::new(ReadyQueue::default()), task: 0 }));
+    assert!(future.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+    drop(future);
+    assert_eq!(*log.borrow(), ["closed"]);
+}
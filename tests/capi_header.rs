@@ -0,0 +1,305 @@
+// The headers in include/ are written by hand, this checks them against the functions and constants src/capi.rs exports
+// so that a C program compiled against them calls each function with the types it takes.
+
+use std::collections::HashMap;
+use std::fs;
+
+const HEADERS: [&str; 3] = ["include/lua.h", "include/lauxlib.h", "include/lualib.h"];
+
+// The Rust type each C type the headers use is passed as, after their typedefs are resolved
+const C_TYPES: [(&str, &str); 13] = [
+    ("int", "c_int"),
+    ("int *", "*mut c_int"),
+    ("size_t", "usize"),
+    ("size_t *", "*mut usize"),
+    ("double", "f64"),
+    ("long long", "i64"),
+    ("unsigned long long", "u64"),
+    ("intptr_t", "isize"),
+    ("const char *", "*const c_char"),
+    ("void *", "*mut c_void"),
+    ("const void *", "*const c_void"),
+    ("lua_State *", "*mut LuaState"),
+    ("const luaL_Reg *", "*const LuaReg"),
+];
+
+// Function pointers the Rust side declares with a type of its own, or as an untyped pointer when it ignores them
+const FUNCTION_TYPES: [(&str, &[&str]); 4] = [
+    ("lua_CFunction", &["CFunction", "Option<CFunction>"]),
+    ("lua_Reader", &["Reader", "Option<Reader>"]),
+    ("lua_KFunction", &["*const c_void"]),
+    ("lua_Alloc", &["*const c_void"]),
+];
+
+struct Signature {
+    params: Vec<String>,
+    result: String,
+}
+
+fn headers() -> String {
+    HEADERS
+        .iter()
+        .map(|path| fs::read_to_string(path).unwrap())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// `typedef double lua_Number;` and the like, structs keep their names and function pointers are left to FUNCTION_TYPES
+fn typedefs(header: &str) -> HashMap<String, String> {
+    header
+        .lines()
+        .filter_map(|line| line.strip_prefix("typedef ")?.strip_suffix(';'))
+        .filter(|typedef| !typedef.starts_with("struct ") && !typedef.contains('('))
+        .filter_map(|typedef| {
+            let (c_type, name) = typedef.rsplit_once(' ')?;
+            Some((name.to_owned(), c_type.to_owned()))
+        })
+        .collect()
+}
+
+// The Rust types a C type can be declared as, None for the ones the check does not know
+fn rust_types(c_type: &str, typedefs: &HashMap<String, String>) -> Option<Vec<String>> {
+    let c_type = c_type.trim();
+    if let Some((_, types)) = FUNCTION_TYPES.iter().find(|(name, _)| *name == c_type) {
+        return Some(types.iter().map(|name| name.to_string()).collect());
+    }
+    let (base, pointer) = match c_type.strip_suffix('*') {
+        Some(base) => (base.trim(), " *"),
+        None => (c_type, ""),
+    };
+    let (constness, name) = match base.strip_prefix("const ") {
+        Some(name) => ("const ", name),
+        None => ("", base),
+    };
+    let resolved = format!(
+        "{}{}{}",
+        constness,
+        typedefs.get(name).map_or(name, String::as_str),
+        pointer
+    );
+    let rust = C_TYPES
+        .iter()
+        .find(|(c, _)| *c == resolved)
+        .map(|(_, rust)| rust.to_string())?;
+    Some(vec![rust])
+}
+
+// `LUA_API int (lua_gettop)(lua_State *L);` and `typedef int (*lua_CFunction)(lua_State *L);`,
+// the parameter names are dropped
+fn header_functions(header: &str) -> HashMap<String, Signature> {
+    let mut functions = HashMap::new();
+    for line in header.lines() {
+        let declaration = ["LUA_API ", "LUALIB_API ", "typedef "]
+            .iter()
+            .find_map(|prefix| line.strip_prefix(prefix));
+        let Some((result, rest)) = declaration.and_then(|declaration| declaration.split_once('('))
+        else {
+            continue;
+        };
+        let (name, rest) = rest.split_once(")(").unwrap();
+        let params = rest.strip_suffix(");").unwrap();
+        let params = match params {
+            "void" => Vec::new(),
+            params => params
+                .split(", ")
+                .map(|param| {
+                    param
+                        .trim_end_matches(|c: char| c.is_alphanumeric() || c == '_')
+                        .trim_end()
+                        .to_owned()
+                })
+                .collect(),
+        };
+        let name = name.trim_start_matches('*').to_owned();
+        functions.insert(
+            name,
+            Signature {
+                params,
+                result: result.trim().to_owned(),
+            },
+        );
+    }
+    functions
+}
+
+// `pub unsafe extern "C-unwind" fn lua_gettop(l: *mut LuaState) -> c_int {`, possibly over several lines, and
+// `type CFunction = unsafe extern "C-unwind" fn(*mut LuaState) -> c_int;` under the name of the typedef it stands for
+fn rust_functions(source: &str) -> HashMap<String, Signature> {
+    const PREFIX: &str = "extern \"C-unwind\" fn";
+    let mut functions = HashMap::new();
+    for (at, _) in source.match_indices(PREFIX) {
+        let (name, rest) = source[at + PREFIX.len()..].split_once('(').unwrap();
+        let name = match name.trim() {
+            "" => {
+                let line = source[..at].rsplit('\n').next().unwrap();
+                let alias = line
+                    .trim()
+                    .strip_prefix("type ")
+                    .unwrap()
+                    .split_once(" =")
+                    .unwrap()
+                    .0;
+                let (typedef, _) = FUNCTION_TYPES
+                    .iter()
+                    .find(|(_, types)| types[0] == alias)
+                    .unwrap();
+                typedef
+            }
+            name => name,
+        };
+        let (params, rest) = rest.split_once(')').unwrap();
+        let end = rest.find(['{', ';']).unwrap();
+        let result = rest[..end]
+            .trim()
+            .strip_prefix("->")
+            .map_or("", str::trim)
+            .to_owned();
+        let params = params
+            .split(',')
+            .map(str::trim)
+            .filter(|param| !param.is_empty())
+            .map(|param| {
+                param
+                    .split_once(": ")
+                    .map_or(param, |(_, param_type)| param_type)
+                    .to_owned()
+            })
+            .collect();
+        functions.insert(name.to_owned(), Signature { params, result });
+    }
+    functions
+}
+
+#[test]
+fn headers_declare_the_exported_functions() {
+    let header = headers();
+    let typedefs = typedefs(&header);
+    let declared = header_functions(&header);
+    let exported = rust_functions(&fs::read_to_string("src/capi.rs").unwrap());
+    let mut mismatches = Vec::new();
+    for (name, declaration) in &declared {
+        let Some(definition) = exported.get(name) else {
+            // Function pointers the Rust side only passes around untyped
+            if FUNCTION_TYPES.iter().any(|(typedef, _)| typedef == name) {
+                continue;
+            }
+            mismatches.push(format!("{} is declared but not exported", name));
+            continue;
+        };
+        let result = match declaration.result.as_str() {
+            "void" => Some(vec![String::new()]),
+            result => rust_types(result, &typedefs),
+        };
+        let params: Vec<_> = declaration
+            .params
+            .iter()
+            .map(|param| rust_types(param, &typedefs))
+            .collect();
+        let matches = |c: &Option<Vec<String>>, rust: &String| {
+            c.as_ref().is_some_and(|types| types.contains(rust))
+        };
+        if declaration.params.len() != definition.params.len()
+            || !params
+                .iter()
+                .zip(&definition.params)
+                .all(|(c, rust)| matches(c, rust))
+            || !matches(&result, &definition.result)
+        {
+            mismatches.push(format!(
+                "{}: declared ({}) -> {}, exported ({}) -> {}",
+                name,
+                declaration.params.join(", "),
+                declaration.result,
+                definition.params.join(", "),
+                definition.result
+            ));
+        }
+    }
+    for name in exported.keys().filter(|name| !declared.contains_key(*name)) {
+        mismatches.push(format!("{} is exported but not declared", name));
+    }
+    mismatches.sort();
+    assert!(mismatches.is_empty(), "{}", mismatches.join("\n"));
+}
+
+// Evaluates the integer expressions of the #defines, like `(-LUAI_MAXSTACK - 1000)`
+fn evaluate(expression: &str, defines: &HashMap<String, String>) -> Option<i64> {
+    let tokens: Vec<String> = expression
+        .replace('(', " ( ")
+        .replace(')', " ) ")
+        .replace('-', " - ")
+        .replace('+', " + ")
+        .split_whitespace()
+        .map(str::to_owned)
+        .collect();
+    let mut position = 0;
+    let value = sum(&tokens, &mut position, defines)?;
+    (position == tokens.len()).then_some(value)
+}
+
+fn sum(tokens: &[String], position: &mut usize, defines: &HashMap<String, String>) -> Option<i64> {
+    let mut value = term(tokens, position, defines)?;
+    while let Some(operator @ ("+" | "-")) = tokens.get(*position).map(String::as_str) {
+        *position += 1;
+        let rhs = term(tokens, position, defines)?;
+        value = if operator == "+" {
+            value + rhs
+        } else {
+            value - rhs
+        };
+    }
+    Some(value)
+}
+
+fn term(tokens: &[String], position: &mut usize, defines: &HashMap<String, String>) -> Option<i64> {
+    let token = tokens.get(*position)?.as_str();
+    *position += 1;
+    match token {
+        "-" => term(tokens, position, defines).map(|value| -value),
+        "(" => {
+            let value = sum(tokens, position, defines)?;
+            (tokens.get(*position)? == ")").then(|| *position += 1)?;
+            Some(value)
+        }
+        _ => token
+            .parse()
+            .ok()
+            .or_else(|| evaluate(defines.get(token)?, defines)),
+    }
+}
+
+#[test]
+fn headers_define_the_constants_the_library_uses() {
+    let header = headers();
+    let defines: HashMap<String, String> = header
+        .lines()
+        .filter_map(|line| line.strip_prefix("#define "))
+        .filter_map(|define| define.split_once(' '))
+        .filter(|(name, _)| !name.contains('('))
+        .map(|(name, value)| (name.to_owned(), value.trim().to_owned()))
+        .collect();
+    let source = fs::read_to_string("src/capi.rs").unwrap();
+    let mut checked = 0;
+    for line in source.lines() {
+        let Some(constant) = line.strip_prefix("const LUA_") else {
+            continue;
+        };
+        let (name, value) = constant.split_once(':').unwrap();
+        let name = format!("LUA_{}", name);
+        let value: i64 = value
+            .split_once('=')
+            .unwrap()
+            .1
+            .trim()
+            .trim_end_matches(';')
+            .replace('_', "")
+            .parse()
+            .unwrap();
+        let defined = defines
+            .get(&name)
+            .and_then(|expression| evaluate(expression, &defines));
+        assert_eq!(defined, Some(value), "{}", name);
+        checked += 1;
+    }
+    assert!(checked > 0);
+}
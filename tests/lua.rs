@@ -0,0 +1,241 @@
+use r_lua::{CoroutineStatus, DataKind, Error, FunctionRef, HookAction, HookTriggers, Lua, TableRef, Variadic};
+
+#[test]
+fn globals_are_kept_across_chunks() {
+    let lua = Lua::new();
+    lua.load("counter = 1 function bump(n) counter = counter + (n or 1) return counter end", "setup").exec().unwrap();
+    lua.load("bump() bump(5)", "run").exec().unwrap();
+    let counter: i64 = lua.globals().get("counter").unwrap();
+    assert_eq!(counter, 7);
+    lua.globals().set("counter", 40).unwrap();
+    let counter: i64 = lua.load("bump(2)", "run").eval().unwrap();
+    assert_eq!(counter, 42);
+}
+
+#[test]
+fn chunks_evaluate_expressions_and_statements() {
+    let lua = Lua::new();
+    let sum: i64 = lua.load("1 + 2", "expression").eval().unwrap();
+    assert_eq!(sum, 3);
+    let (a, b, rest): (i64, String, Variadic<i64>) = lua.load("return 1, 'two', 3, 4", "results").eval().unwrap();
+    assert_eq!((a, b.as_str(), rest.0), (1, "two", vec![3, 4]));
+    let missing: (Option<i64>, Option<String>) = lua.load("local x = 1", "statement").eval().unwrap();
+    assert_eq!(missing, (None, None));
+    let error = lua.load("return 'text'", "wrong").eval::<i64>().unwrap_err();
+    assert!(matches!(error, Error::FromLua(_)), "{:?}", error);
+}
+
+#[test]
+fn host_functions_convert_their_arguments() {
+    let lua = Lua::new();
+    let add = lua.create_function("add", |a: i64, b: Option<i64>| Ok(a + b.unwrap_or(1)));
+    lua.globals().set("add", add).unwrap();
+    let sums: (i64, i64) = lua.load("add(2, 3), add(2)", "add").eval().unwrap();
+    assert_eq!(sums, (5, 3));
+    let error = lua.load("return add('x')", "add").exec().unwrap_err();
+    assert!(error.to_string().contains("bad argument #1 to 'add' (number expected, got string)"), "{}", error);
+}
+
+#[test]
+fn errors_carry_the_chunk_and_line() {
+    let lua = Lua::new();
+    match lua.load("local x = 1\nlocal = 2", "broken").exec() {
+        Err(Error::Syntax(rendered)) => assert!(rendered.contains("broken:2:"), "{}", rendered),
+        result => panic!("expected a syntax error, got {:?}", result),
+    }
+    let error = lua.load("local t = nil\n\nreturn t.x", "runtime").exec().unwrap_err();
+    assert!(matches!(error, Error::Runtime(_)));
+    assert!(error.to_string().starts_with("runtime:3: attempt to index a nil value"), "{}", error);
+    let rendered = error.render("runtime", Some("local t = nil\n\nreturn t.x"));
+    assert!(rendered.contains("return t.x"), "{}", rendered);
+    let error = lua.load("error({ code = 1 })", "object").exec().unwrap_err();
+    assert!(error.to_string().starts_with("(error object is a table value)"), "{}", error);
+}
+
+#[test]
+fn tables_are_indexed_through_metamethods() {
+    let lua = Lua::new();
+    let table: TableRef = lua
+        .load("return setmetatable({ a = 1 }, { __index = function(_, key) return key .. '!' end })", "meta")
+        .eval()
+        .unwrap();
+    let table = lua.table(table);
+    let (a, b): (i64, String) = (table.get("a").unwrap(), table.get("b").unwrap());
+    assert_eq!((a, b.as_str()), (1, "b!"));
+    table.set(1, "first").unwrap();
+    let first: String = table.get(1).unwrap();
+    assert_eq!(first, "first");
+
+    let guarded: TableRef = lua.load("return setmetatable({}, { __newindex = function() error('read only') end })", "meta").eval().unwrap();
+    let error = lua.table(guarded).set("x", 1).unwrap_err();
+    assert!(error.to_string().contains("read only"), "{}", error);
+}
+
+#[test]
+fn functions_are_called_from_the_host() {
+    let lua = Lua::new();
+    let function: FunctionRef = lua.load("function(a, b) return a * b, a + b end", "function").eval().unwrap();
+    let results: (i64, i64) = lua.call(&function, (6, 7)).unwrap();
+    assert_eq!(results, (42, 13));
+    let chunk = lua.load("local n = ... return n * 2", "chunk").into_function().unwrap();
+    let doubled: i64 = lua.call(&chunk, 21).unwrap();
+    assert_eq!(doubled, 42);
+}
+
+#[test]
+fn chunks_are_dumped_and_loaded() {
+    let lua = Lua::new();
+    let chunk = lua.load("local a = ...\nreturn a + 1", "dumped");
+    assert!(chunk.listing().unwrap().contains("RETURN"));
+    let binary = chunk.dump(false).unwrap();
+    let loaded = lua.load(&binary, "binary");
+    assert_eq!(loaded.source(), None);
+    let result: i64 = lua.call(&loaded.into_function().unwrap(), 41).unwrap();
+    assert_eq!(result, 42);
+    let truncated = lua.load(&binary[..binary.len() / 2], "truncated").exec().unwrap_err();
+    assert!(matches!(truncated, Error::BadChunk(_)), "{:?}", truncated);
+    assert!(truncated.to_string().starts_with("truncated: bad binary format"), "{}", truncated);
+}
+
+#[test]
+fn registry_values_outlive_their_scripts() {
+    let lua = Lua::new();
+    let handler: FunctionRef = lua.load("function(name) return 'hello ' .. name end", "handler").eval().unwrap();
+    let key = lua.create_registry_value(handler);
+    lua.load("collectgarbage()", "gc").exec().unwrap();
+    let handler: FunctionRef = lua.registry_value(&key).unwrap();
+    let greeting: String = lua.call(&handler, "host").unwrap();
+    assert_eq!(greeting, "hello host");
+    lua.replace_registry_value(&key, 7).unwrap();
+    assert_eq!(lua.registry_value::<i64>(&key).unwrap(), 7);
+    lua.remove_registry_value(key).unwrap();
+
+    let other = Lua::new();
+    let foreign = other.create_registry_value(1);
+    assert!(!lua.owns_registry_value(&foreign));
+    assert!(matches!(lua.registry_value::<i64>(&foreign), Err(Error::MismatchedRegistryKey)));
+
+    lua.set_named_registry_value("config", "named");
+    let named: String = lua.load("return debug.getregistry().config", "named").eval().unwrap();
+    assert_eq!(named, "named");
+}
+
+#[test]
+fn environments_sandbox_chunks() {
+    let lua = Lua::new();
+    lua.globals().set("secret", "hidden").unwrap();
+    let sandbox = lua.create_table();
+    lua.table(sandbox.clone()).set("visible", 1).unwrap();
+    let seen: (Option<String>, i64) = lua.load("return secret, visible", "sandboxed").set_environment(sandbox.clone()).eval().unwrap();
+    assert_eq!(seen, (None, 1));
+    lua.load("leaked = true", "sandboxed").set_environment(sandbox.clone()).exec().unwrap();
+    let leaked: Option<bool> = lua.globals().get("leaked").unwrap();
+    assert_eq!(leaked, None);
+    let kept: bool = lua.table(sandbox).get("leaked").unwrap();
+    assert!(kept);
+}
+
+#[test]
+fn threads_are_resumed_from_the_host() {
+    let lua = Lua::new();
+    let body: FunctionRef = lua.load("function(a) local b = coroutine.yield(a + 1) return b * 2 end", "thread").eval().unwrap();
+    let thread = lua.create_thread(&body).unwrap();
+    let first: i64 = lua.resume(&thread, 1).unwrap();
+    assert_eq!((first, thread.status()), (2, CoroutineStatus::Suspended));
+    let last: i64 = lua.resume(&thread, 5).unwrap();
+    assert_eq!((last, thread.status()), (10, CoroutineStatus::Dead));
+    assert!(lua.resume::<_, ()>(&thread, ()).is_err());
+}
+
+#[test]
+fn budgets_and_hooks_stop_scripts() {
+    let lua = Lua::new();
+    lua.set_instruction_budget(Some(10_000));
+    let error = lua.load("while true do end", "spin").exec().unwrap_err();
+    assert!(matches!(error, Error::Runtime(_)));
+    let caught = lua.load("pcall(function() while true do end end)", "spin").exec();
+    assert!(caught.is_err());
+    lua.set_instruction_budget(None);
+
+    let triggers = HookTriggers { every_nth_instruction: Some(100), ..Default::default() };
+    lua.set_hook(triggers, |_| Ok(HookAction::Interrupt("timeout".to_owned())));
+    let error = lua.load("while true do end", "spin").exec().unwrap_err();
+    assert!(error.to_string().contains("timeout"), "{}", error);
+    lua.remove_hook();
+    lua.load("for i = 1, 1000 do end", "spin").exec().unwrap();
+}
+
+#[test]
+fn memory_limits_fail_allocations() {
+    let lua = Lua::new();
+    lua.set_memory_limit(Some(lua.used_memory() + 1024 * 1024));
+    assert!(matches!(lua.load("return string.rep('x', 1 << 30)", "big").exec(), Err(Error::Memory)));
+    assert!(matches!(lua.load("return (string.rep('x', 1 << 19):gsub('x', 'xxxx'))", "big").exec(), Err(Error::Memory)));
+    let caught: (bool, String) = lua.load("return pcall(string.rep, 'x', 1 << 30)", "big").eval().unwrap();
+    assert_eq!(caught, (false, "not enough memory".to_owned()));
+    let threads = "local threads, ok = {}, true
+                   for i = 1, 1000 do ok, threads[i] = pcall(coroutine.create, print) if not ok then break end end
+                   return ok, threads[#threads]";
+    let caught: (bool, String) = lua.load(threads, "threads").eval().unwrap();
+    assert_eq!(caught, (false, "not enough memory".to_owned()));
+    let created: String = lua.load("return type(coroutine.create(print))", "threads").eval().unwrap();
+    assert_eq!(created, "thread");
+    lua.set_memory_limit(None);
+    let length: i64 = lua.load("return #string.rep('x', 1 << 21)", "big").eval().unwrap();
+    assert_eq!(length, 1 << 21);
+}
+
+#[test]
+fn tail_calls_return_to_the_host() {
+    let lua = Lua::new();
+    let function: FunctionRef = lua.load("function() local function g() return 5 end return g() end", "tail").eval().unwrap();
+    let result: i64 = lua.call(&function, ()).unwrap();
+    assert_eq!(result, 5);
+    let result: i64 = lua.load("local function g(n) return n end return g(5)", "tail").eval().unwrap();
+    assert_eq!(result, 5);
+    let count: FunctionRef = lua
+        .load("function(n) local function down(n) if n == 0 then return 'done' end return down(n - 1) end return down(n) end", "tail")
+        .eval()
+        .unwrap();
+    let result: String = lua.call(&count, 10000).unwrap();
+    assert_eq!(result, "done");
+    let thread = lua.create_thread(&count).unwrap();
+    let result: String = lua.resume(&thread, 100).unwrap();
+    assert_eq!(result, "done");
+}
+
+#[test]
+fn deeply_nested_code_fails_to_compile() {
+    let lua = Lua::new();
+    let nested = format!("return {}1{}", "(".repeat(300), ")".repeat(300));
+    match lua.load(nested, "deep").exec() {
+        Err(Error::Syntax(rendered)) => {
+            assert!(rendered.contains("chunk has too many syntax levels (limit is 200)\n --> deep:1:"), "{}", rendered)
+        }
+        result => panic!("expected a syntax error, got {:?}", result),
+    }
+    let nested = format!("return {}1{}", "(".repeat(150), ")".repeat(150));
+    assert_eq!(lua.load(nested, "deep").eval::<i64>().unwrap(), 1);
+    let long = format!("return 1{}", " + 1".repeat(250));
+    let sum: i64 = lua.load(long, "long").eval().unwrap();
+    assert_eq!(sum, 251);
+}
+
+#[test]
+fn deep_native_recursion_fails_with_an_error() {
+    let lua = Lua::new();
+    let error = lua.load("local t = setmetatable({}, { __index = function(t, k) return t[k] end }) return t.x", "index").exec();
+    let error = error.unwrap_err();
+    assert!(error.to_string().contains("stack overflow"), "{}", error);
+}
+
+#[test]
+fn registry_keys_holding_nil_keep_their_slot() {
+    let lua = Lua::new();
+    let empty = lua.create_registry_value(DataKind::Null);
+    let five = lua.create_registry_value(5);
+    drop(empty);
+    let other = lua.create_registry_value(6);
+    assert_eq!(lua.registry_value::<i64>(&five).unwrap(), 5);
+    assert_eq!(lua.registry_value::<i64>(&other).unwrap(), 6);
+}
@@ -0,0 +1,162 @@
+use std::collections::{BTreeMap, HashMap};
+
+use r_lua::{DataKind, DeserializeOptions, Error, Lua, Serde, SerializeOptions};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Config {
+    name: String,
+    retries: u8,
+    ratio: f64,
+    enabled: bool,
+    tags: Vec<String>,
+    limits: BTreeMap<String, i64>,
+    servers: Vec<Server>,
+    fallback: Option<Server>,
+    mode: Mode,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Server {
+    host: String,
+    port: u16,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+enum Mode {
+    Off,
+    Fixed(u32),
+    Range { low: i32, high: i32 },
+}
+
+fn config() -> Config {
+    Config {
+        name: "main".to_owned(),
+        retries: 3,
+        ratio: 0.5,
+        enabled: true,
+        tags: vec!["a".to_owned(), "b".to_owned()],
+        limits: BTreeMap::from([("cpu".to_owned(), 2), ("memory".to_owned(), 512)]),
+        servers: vec![Server { host: "a".to_owned(), port: 80 }, Server { host: "b".to_owned(), port: 8080 }],
+        fallback: None,
+        mode: Mode::Range { low: -1, high: 1 },
+    }
+}
+
+fn error_message<T: std::fmt::Debug>(result: Result<T, Error>) -> String {
+    match result {
+        Err(Error::FromLua(error)) => error.to_string(),
+        result => panic!("expected a conversion error, got {:?}", result),
+    }
+}
+
+#[test]
+fn values_round_trip() {
+    let lua = Lua::new();
+    let value = lua.to_value(&config()).unwrap();
+    assert_eq!(lua.from_value::<Config>(value).unwrap(), config());
+    for mode in [Mode::Off, Mode::Fixed(7), Mode::Range { low: 2, high: 5 }] {
+        let value = lua.to_value(&mode).unwrap();
+        assert_eq!(lua.from_value::<Mode>(value).unwrap(), mode);
+    }
+    let pair: (i64, String) = lua.from_value(lua.to_value(&(1, "one")).unwrap()).unwrap();
+    assert_eq!(pair, (1, "one".to_owned()));
+}
+
+#[test]
+fn tables_written_by_scripts_are_read() {
+    let lua = Lua::new();
+    let value: DataKind = lua
+        .load(
+            "return {
+               name = 'main', retries = 3, ratio = 0.5, enabled = true, tags = { 'a', 'b' },
+               limits = { cpu = 2, memory = 512 },
+               servers = { { host = 'a', port = 80 }, { host = 'b', port = 8080.0 } },
+               mode = { Range = { low = -1, high = 1 } },
+             }",
+            "config",
+        )
+        .eval()
+        .unwrap();
+    assert_eq!(lua.from_value::<Config>(value).unwrap(), config());
+}
+
+#[test]
+fn serialized_values_are_seen_by_scripts() {
+    let lua = Lua::new();
+    lua.globals().set("config", lua.to_value(&config()).unwrap()).unwrap();
+    let (port, tags, cpu, low): (i64, i64, i64, i64) =
+        lua.load("return config.servers[2].port, #config.tags, config.limits.cpu, config.mode.Range.low", "read").eval().unwrap();
+    assert_eq!((port, tags, cpu, low), (8080, 2, 2, -1));
+    let fallback: bool = lua.load("return config.fallback == nil", "read").eval().unwrap();
+    assert!(fallback);
+    lua.globals().set("mode", lua.to_value(&Mode::Off).unwrap()).unwrap();
+    let unit: String = lua.load("return mode", "read").eval().unwrap();
+    assert_eq!(unit, "Off");
+}
+
+#[test]
+fn errors_name_the_path_of_the_field() {
+    let lua = Lua::new();
+    let load = |code: &str| -> DataKind { lua.load(code, "config").eval().unwrap() };
+    let servers = load("return { { host = 'a', port = 80 }, { host = 'b', port = 'http' } }");
+    assert_eq!(error_message(lua.from_value::<Vec<Server>>(servers)), "[2].port: integer expected, got string");
+    let wrapped = load("return { servers = { { host = 'a', port = 70000 } } }");
+    #[derive(Deserialize, Debug)]
+    #[allow(dead_code)]
+    struct Wrapper {
+        servers: Vec<Server>,
+    }
+    assert_eq!(error_message(lua.from_value::<Wrapper>(wrapped)), "servers[1].port: value out of range");
+    let keyed = load("return { ['two words'] = { host = true, port = 1 } }");
+    let message = error_message(lua.from_value::<HashMap<String, Server>>(keyed));
+    assert_eq!(message, "[\"two words\"].host: string expected, got boolean");
+    let missing = load("return { host = 'a' }");
+    assert_eq!(error_message(lua.from_value::<Server>(missing)), "missing field `port`");
+    let fraction = load("return { host = 'a', port = 1.5 }");
+    assert_eq!(error_message(lua.from_value::<Server>(fraction)), "port: number has no integer representation");
+    #[derive(Deserialize, Debug)]
+    #[allow(dead_code)]
+    struct Node {
+        next: Option<Box<Node>>,
+    }
+    let recursive = load("local node = {} node.next = { next = node } return node");
+    assert_eq!(error_message(lua.from_value::<Node>(recursive)), "next.next: recursive table");
+}
+
+#[test]
+fn options_keep_nulls_and_empty_sequences() {
+    let lua = Lua::new();
+    let options = SerializeOptions { none_as_null: true, mark_arrays: true };
+    let value = lua.to_value_with(&(None::<i64>, Vec::<i64>::new(), 1), options).unwrap();
+    lua.globals().set("value", value.clone()).unwrap();
+    lua.globals().set("null", lua.null()).unwrap();
+    lua.globals().set("array", lua.array_metatable()).unwrap();
+    let checks: (i64, bool, bool) =
+        lua.load("return #value, value[1] == null, getmetatable(value[2]) == array", "options").eval().unwrap();
+    assert_eq!(checks, (3, true, true));
+    assert_eq!(lua.from_value::<(Option<i64>, Vec<i64>, i64)>(value).unwrap(), (None, vec![], 1));
+    #[derive(Deserialize, Debug, PartialEq)]
+    #[serde(untagged)]
+    enum Table {
+        Sequence(Vec<i64>),
+        Map(HashMap<String, i64>),
+    }
+    let empty: DataKind = lua.load("return {}", "options").eval().unwrap();
+    assert_eq!(lua.from_value::<Table>(empty.clone()).unwrap(), Table::Map(HashMap::new()));
+    let options = DeserializeOptions { empty_tables_as_arrays: true };
+    assert_eq!(lua.from_value_with::<Table>(empty, options).unwrap(), Table::Sequence(Vec::new()));
+    let marked: DataKind = lua.load("return setmetatable({}, array)", "options").eval().unwrap();
+    assert_eq!(lua.from_value::<Table>(marked).unwrap(), Table::Sequence(Vec::new()));
+}
+
+#[test]
+fn host_functions_take_serde_arguments() {
+    let lua = Lua::new();
+    let address = lua.create_function("address", |Serde(server): Serde<Server>| Ok(format!("{}:{}", server.host, server.port)));
+    lua.globals().set("address", address).unwrap();
+    let address: String = lua.load("return address({ host = 'a', port = 80 })", "args").eval().unwrap();
+    assert_eq!(address, "a:80");
+    let error = lua.load("return address({ host = 'a' })", "args").exec().unwrap_err();
+    assert!(error.to_string().contains("bad argument #1 to 'address' (missing field `port`)"), "{}", error);
+}
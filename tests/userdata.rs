@@ -0,0 +1,156 @@
+use r_lua::{DataKind, Lua, RuntimeFailure, UserData, UserDataFields, UserDataMethods, UserDataRef};
+
+#[derive(Clone, Debug, PartialEq)]
+struct Point {
+    x: f64,
+    y: f64,
+}
+
+impl UserData for Point {
+    fn add_fields(fields: &mut UserDataFields<Self>) {
+        fields.add_field_method_get("x", |point| Ok(point.x));
+        fields.add_field_method_get("y", |point| Ok(point.y));
+        fields.add_field_method_set("x", |point, x: f64| {
+            point.x = x;
+            Ok(())
+        });
+    }
+    fn add_methods(methods: &mut UserDataMethods<Self>) {
+        methods.add_function("new", |(x, y): (f64, f64)| Ok(Point { x, y }));
+        methods.add_method("length", |point, ()| Ok((point.x * point.x + point.y * point.y).sqrt()));
+        methods.add_method_mut("scale", |point, factor: f64| {
+            point.x *= factor;
+            point.y *= factor;
+            Ok(())
+        });
+        // Host errors are raised at the call, with its position
+        methods.add_method_mut("visit", |_point, _callback: r_lua::FunctionRef| -> Result<(), RuntimeFailure> {
+            Err("visit is not reentrant".into())
+        });
+        methods.add_meta_function("__add", |(a, b): (Point, Point)| Ok(Point { x: a.x + b.x, y: a.y + b.y }));
+        methods.add_meta_method("__eq", |point, other: Point| Ok(*point == other));
+        methods.add_meta_method("__tostring", |point, ()| Ok(format!("({}, {})", point.x, point.y)));
+        methods.add_meta_method("__len", |_point, ()| Ok(2));
+    }
+}
+
+struct Counter(i64);
+
+impl UserData for Counter {
+    fn add_methods(methods: &mut UserDataMethods<Self>) {
+        methods.add_method_mut("add", |counter, n: i64| {
+            counter.0 += n;
+            Ok(counter.0)
+        });
+    }
+}
+
+fn setup() -> Lua {
+    let lua = Lua::new();
+    lua.globals().set("p", Point { x: 3.0, y: 4.0 }).unwrap();
+    lua
+}
+
+#[test]
+fn fields_are_read_and_written() {
+    let lua = setup();
+    let (x, y): (f64, f64) = lua.load("return p.x, p.y", "fields").eval().unwrap();
+    assert_eq!((x, y), (3.0, 4.0));
+    lua.load("p.x = 6", "fields").exec().unwrap();
+    let point: Point = lua.globals().get("p").unwrap();
+    assert_eq!(point, Point { x: 6.0, y: 4.0 });
+}
+
+#[test]
+fn fields_without_setters_cannot_be_written() {
+    let lua = setup();
+    let error = lua.load("p.y = 1", "fields").exec().unwrap_err();
+    assert!(error.to_string().contains("cannot set field 'y' of a Point value"), "{}", error);
+    let error = lua.load("p.x = 'far'", "fields").exec().unwrap_err();
+    assert!(error.to_string().contains("bad argument #3 to 'x' (number expected, got string)"), "{}", error);
+}
+
+#[test]
+fn methods_borrow_the_value() {
+    let lua = setup();
+    let length: f64 = lua.load("return p:length()", "methods").eval().unwrap();
+    assert_eq!(length, 5.0);
+    let length: f64 = lua.load("p:scale(2) return p:length()", "methods").eval().unwrap();
+    assert_eq!(length, 10.0);
+    let point: Point = lua.load("return p.new(1, 2)", "methods").eval().unwrap();
+    assert_eq!(point, Point { x: 1.0, y: 2.0 });
+}
+
+#[test]
+fn methods_check_their_receiver() {
+    let lua = setup();
+    let error = lua.load("local length = p.length return length(42)", "methods").exec().unwrap_err();
+    assert!(error.to_string().contains("bad argument #1 to 'length' (Point expected, got number)"), "{}", error);
+    lua.globals().set("c", Counter(0)).unwrap();
+    let error = lua.load("return p.length(c)", "methods").exec().unwrap_err();
+    assert!(error.to_string().contains("bad argument #1 to 'length' (Point expected, got Counter)"), "{}", error);
+    let error = lua.load("return p:scale('twice')", "methods").exec().unwrap_err();
+    assert!(error.to_string().contains("bad argument #2 to 'scale' (number expected, got string)"), "{}", error);
+}
+
+#[test]
+fn metamethods_apply_to_the_type() {
+    let lua = setup();
+    let sum: Point = lua.load("return p + p", "meta").eval().unwrap();
+    assert_eq!(sum, Point { x: 6.0, y: 8.0 });
+    let (equal, text, len): (bool, String, i64) = lua.load("return p == p + p.new(0, 0), tostring(p), #p", "meta").eval().unwrap();
+    assert!(equal);
+    assert_eq!(text, "(3, 4)");
+    assert_eq!(len, 2);
+    let name: String = lua.load("return getmetatable(p).__name", "meta").eval().unwrap();
+    assert_eq!(name, "Point");
+}
+
+#[test]
+fn borrowed_values_fail_instead_of_panicking() {
+    let lua = setup();
+    let userdata: UserDataRef = lua.globals().get("p").unwrap();
+    let held = userdata.borrow_mut::<Point>().unwrap();
+    let error = lua.load("return p.x", "borrow").exec().unwrap_err();
+    assert!(error.to_string().contains("bad argument #1 to 'x' (userdata already mutably borrowed)"), "{}", error);
+    let error = lua.load("return p:length()", "borrow").exec().unwrap_err();
+    assert!(error.to_string().contains("userdata already mutably borrowed"), "{}", error);
+    drop(held);
+
+    let held = userdata.borrow::<Point>().unwrap();
+    let length: f64 = lua.load("return p:length()", "borrow").eval().unwrap();
+    assert_eq!(length, 5.0);
+    let error = lua.load("p:scale(2)", "borrow").exec().unwrap_err();
+    assert!(error.to_string().contains("userdata already borrowed"), "{}", error);
+    drop(held);
+    assert!(userdata.borrow::<Counter>().is_err());
+}
+
+#[test]
+fn method_errors_carry_the_position_of_the_call() {
+    let lua = setup();
+    let error = lua.load("\np:visit(print)", "visit").exec().unwrap_err();
+    assert!(error.to_string().contains("visit:2: visit is not reentrant"), "{}", error);
+    let caught: (bool, String) = lua.load("return pcall(p.visit, p, print)", "visit").eval().unwrap();
+    assert!(!caught.0);
+    assert!(caught.1.contains("visit is not reentrant"), "{}", caught.1);
+}
+
+#[test]
+fn user_values_are_kept_with_the_userdata() {
+    let lua = Lua::new();
+    let counter = lua.create_userdata(Counter(0));
+    counter.set_user_value(2, DataKind::string("second"));
+    assert!(counter.has_user_value(1) && counter.has_user_value(2) && !counter.has_user_value(3));
+    assert!(counter.user_value(1).is_nil());
+    lua.globals().set("c", counter.clone()).unwrap();
+    let (first, second, has_third): (Option<String>, String, bool) =
+        lua.load("local _, has = debug.getuservalue(c, 3) return debug.getuservalue(c, 1), debug.getuservalue(c, 2), has", "uv")
+            .eval()
+            .unwrap();
+    assert_eq!((first, second.as_str(), has_third), (None, "second", false));
+    lua.load("debug.setuservalue(c, { count = 1 }, 1)", "uv").exec().unwrap();
+    let count: i64 = lua.load("return debug.getuservalue(c, 1).count + c:add(4)", "uv").eval().unwrap();
+    assert_eq!(count, 5);
+    assert!(matches!(counter.user_value(1), DataKind::Table(_)));
+}